@@ -15,6 +15,20 @@ pub enum Syscalls {
     SendMessage,
     MessageHandler,
     ExitMessageHandler,
+    SetTLS,
+    SetPriority,
+    GetPriority,
+    FutexWait,
+    FutexWake,
+    ReceiveMessage,
+    Yield,
+    ReadSchedInfo,
+    SetAffinity,
+    Sleep,
+    SendAndWait,
+    Reply,
+    TakeReply,
+    SetQuantum,
 }
 
 #[bitmask(u8)]
@@ -1,7 +1,7 @@
 #![no_std]
 
 use common::types::{Errno, MmapAccess, MmapFlags, ProcessID, Result, Syscalls};
-use core::arch::asm;
+use core::{arch::asm, sync::atomic::AtomicU32};
 
 #[inline]
 #[cfg(target_arch = "x86")]
@@ -187,25 +187,60 @@ pub fn share_memory(addr: *const u8, length: usize, access: MmapAccess) -> Resul
     }
 }
 
+/// gets the address and length of the given message data as syscall arguments
+fn message_data(data: Option<&[u8]>) -> Result<(u32, u32)> {
+    match data {
+        Some(slice) => Ok((
+            (slice.as_ptr() as usize).try_into().map_err(|_| Errno::ValueOverflow)?,
+            slice.len().try_into().map_err(|_| Errno::ValueOverflow)?,
+        )),
+        None => Ok((0, 0)),
+    }
+}
+
 pub fn send_message(target: u32, message: u32, data: Option<&[u8]>) -> Result<()> {
-    let addr;
-    let len;
+    let (addr, len) = message_data(data)?;
 
-    if let Some(slice) = data {
-        addr = (slice.as_ptr() as usize).try_into().map_err(|_| Errno::ValueOverflow)?;
-        len = slice.len().try_into().map_err(|_| Errno::ValueOverflow)?;
-    } else {
-        addr = 0;
-        len = 0;
+    unsafe {
+        syscall_4_args(Syscalls::SendMessage, target, message, addr, len)?;
     }
 
+    Ok(())
+}
+
+/// sends a message to the given process and blocks until it's replied to, returning the token the reply was sent with.
+/// the data sent with the reply can then be collected with [take_reply]
+pub fn send_and_wait(target: u32, message: u32, data: Option<&[u8]>) -> Result<u32> {
+    let (addr, len) = message_data(data)?;
+
+    unsafe { syscall_4_args(Syscalls::SendAndWait, target, message, addr, len) }
+}
+
+/// replies to a message sent with [send_and_wait], given the process and token passed to the message handler
+pub fn reply(to: u32, token: u32, data: Option<&[u8]>) -> Result<()> {
+    let (addr, len) = message_data(data)?;
+
     unsafe {
-        syscall_4_args(Syscalls::SendMessage, target, message, addr, len)?;
+        syscall_4_args(Syscalls::Reply, to, token, addr, len)?;
     }
 
     Ok(())
 }
 
+/// copies the data sent with the reply with the given token into the given buffer, returning how many bytes were sent with it.
+/// fails with [Errno::TryAgain] if the reply hasn't arrived yet
+pub fn take_reply(token: u32, buf: &mut [u8]) -> Result<usize> {
+    unsafe {
+        syscall_3_args(
+            Syscalls::TakeReply,
+            token,
+            (buf.as_mut_ptr() as usize).try_into().map_err(|_| Errno::ValueOverflow)?,
+            buf.len().try_into().map_err(|_| Errno::ValueOverflow)?,
+        )
+        .map(|len| len as usize)
+    }
+}
+
 pub fn set_message_handler(message: u32, priority: u8, handler: extern "fastcall" fn(u32)) -> Result<()> {
     unsafe {
         syscall_4_args(Syscalls::MessageHandler, message, priority as u32, handler as u32, 0)?;
@@ -229,3 +264,95 @@ pub fn exit_message_handler() -> Result<()> {
 
     Ok(())
 }
+
+pub fn receive_message() -> Result<()> {
+    unsafe {
+        syscall_0_args(Syscalls::ReceiveMessage)?;
+    }
+
+    Ok(())
+}
+
+/// gives up the rest of this thread's time slice to any other thread of the same or higher priority that's waiting to run.
+/// returns right away if there aren't any
+pub fn yield_now() -> Result<()> {
+    unsafe {
+        syscall_0_args(Syscalls::Yield)?;
+    }
+
+    Ok(())
+}
+
+pub fn set_tls(base: *mut u8) -> Result<()> {
+    unsafe {
+        syscall_1_args(Syscalls::SetTLS, (base as usize).try_into().map_err(|_| Errno::ValueOverflow)?)?;
+    }
+
+    Ok(())
+}
+
+pub fn set_priority(id: ProcessID, priority: i8) -> Result<()> {
+    unsafe {
+        syscall_3_args(Syscalls::SetPriority, id.process, id.thread, priority as i32 as u32)?;
+    }
+
+    Ok(())
+}
+
+/// sets which CPU threads the given thread is allowed to run on. bit n of the mask allows the nth CPU thread, and a mask of 0 lets it run anywhere
+pub fn set_affinity(id: ProcessID, mask: u32) -> Result<()> {
+    unsafe {
+        syscall_3_args(Syscalls::SetAffinity, id.process, id.thread, mask)?;
+    }
+
+    Ok(())
+}
+
+/// sets how many timer ticks each thread gets to run for on the CPU thread with the given index before it's preempted, or goes back to the default if it's 0.
+/// only privileged processes can do this
+pub fn set_quantum(cpu: u32, ticks: u32) -> Result<()> {
+    unsafe {
+        syscall_2_args(Syscalls::SetQuantum, cpu, ticks)?;
+    }
+
+    Ok(())
+}
+
+/// blocks the calling thread for at least the given number of milliseconds
+pub fn sleep(ms: u32) -> Result<()> {
+    unsafe {
+        syscall_1_args(Syscalls::Sleep, ms)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_priority(id: ProcessID) -> Result<i8> {
+    unsafe { syscall_2_args(Syscalls::GetPriority, id.process, id.thread).map(|priority| priority as i32 as i8) }
+}
+
+pub fn futex_wait(futex: &AtomicU32, expected: u32) -> Result<()> {
+    unsafe {
+        syscall_2_args(Syscalls::FutexWait, (futex.as_ptr() as usize).try_into().map_err(|_| Errno::ValueOverflow)?, expected)?;
+    }
+
+    Ok(())
+}
+
+pub fn futex_wake(futex: &AtomicU32, count: u32) -> Result<u32> {
+    unsafe { syscall_2_args(Syscalls::FutexWake, (futex.as_ptr() as usize).try_into().map_err(|_| Errno::ValueOverflow)?, count) }
+}
+
+/// reads the kernel's scheduler statistics (what would be /proc/sched) starting at the given offset into the given buffer, returning how many bytes were read.
+/// 0 means the end has been reached
+pub fn read_sched_info(buf: &mut [u8], offset: usize) -> Result<usize> {
+    unsafe {
+        syscall_3_args(
+            Syscalls::ReadSchedInfo,
+            (buf.as_mut_ptr() as usize).try_into().map_err(|_| Errno::ValueOverflow)?,
+            buf.len().try_into().map_err(|_| Errno::ValueOverflow)?,
+            offset.try_into().map_err(|_| Errno::ValueOverflow)?,
+        )
+        .map(|len| len as usize)
+    }
+}
@@ -57,6 +57,11 @@ pub fn interrupt(metadata: TokenStream, input: TokenStream) -> TokenStream {
                             "mov ds, bx",
                             "mov es, bx",
                             "mov fs, bx",
+
+                            "test bx, 3", // if we're going back to user mode, gs points to the thread-local storage segment (GDT index 6, ring 3)
+                            "jz 2f",
+                            "mov bx, 0x33",
+                            "2:",
                             "mov gs, bx",
 
                             "popa",
@@ -108,6 +113,11 @@ pub fn interrupt(metadata: TokenStream, input: TokenStream) -> TokenStream {
                             "mov ds, bx",
                             "mov es, bx",
                             "mov fs, bx",
+
+                            "test bx, 3", // if we're going back to user mode, gs points to the thread-local storage segment (GDT index 6, ring 3)
+                            "jz 2f",
+                            "mov bx, 0x33",
+                            "2:",
                             "mov gs, bx",
 
                             "popa",
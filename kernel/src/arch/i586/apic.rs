@@ -621,6 +621,15 @@ pub fn get_local_apic() -> Option<&'static mut LocalAPIC> {
     unsafe { LOCAL_APIC.as_mut() }
 }
 
+/// where the bootstrap code's parameters are in the page it's copied into. these have to match cpu_bootstrap.S
+const BOOTSTRAP_PAGE_DIR: usize = 0xbf8;
+const BOOTSTRAP_ENTRY_POINT: usize = 0xbfc;
+
+/// each CPU being brought up gets its own stack pointer in this table, indexed by its APIC ID
+const BOOTSTRAP_STACKS: usize = 0xc00;
+
+const _: () = assert!(BOOTSTRAP_STACKS + 256 * 4 <= PAGE_SIZE, "CPU bootstrap stack table doesn't fit in a page");
+
 pub fn bring_up_cpus(apic_ids: &[u8]) {
     let thread_id = super::get_thread_id();
     let thread = crate::task::get_cpus().expect("CPUs not initialized").get_thread(thread_id).unwrap();
@@ -647,17 +656,17 @@ pub fn bring_up_cpus(apic_ids: &[u8]) {
 
     let bootstrap_area = unsafe { &mut *(bootstrap_addr as *mut [u8; PAGE_SIZE]) };
 
+    assert!(bootstrap_bytes.len() <= BOOTSTRAP_PAGE_DIR, "CPU bootstrap code overlaps its parameters");
+
     bootstrap_area[0..bootstrap_bytes.len()].copy_from_slice(bootstrap_bytes);
 
+    let mut set_parameter = |offset: usize, value: u32| bootstrap_area[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+
     // specify protected mode entry point
-    unsafe {
-        *(&mut bootstrap_area[bootstrap_area.len() - 8] as *mut u8 as *mut u32) = cpu_entry_point as *const u8 as u32;
-    }
+    set_parameter(BOOTSTRAP_ENTRY_POINT, cpu_entry_point as *const u8 as u32);
 
     // specify page table physical address
-    unsafe {
-        *(&mut bootstrap_area[bootstrap_area.len() - 12] as *mut u8 as *mut u32) = get_kernel_page_dir().lock().inner().tables_physical_addr;
-    }
+    set_parameter(BOOTSTRAP_PAGE_DIR, get_kernel_page_dir().lock().inner().tables_physical_addr);
 
     let local_apic = get_local_apic().expect("local APIC not mapped");
     let local_apic_id = local_apic.id();
@@ -674,10 +683,8 @@ pub fn bring_up_cpus(apic_ids: &[u8]) {
 
             let stack_end = stack as usize + stack_layout.size() - 1;
 
-            // specify stack pointer
-            unsafe {
-                *(&mut bootstrap_area[bootstrap_area.len() - 4] as *mut u8 as *mut u32) = stack_end.try_into().unwrap();
-            }
+            // specify stack pointer. every CPU has its own, so this can't change under a CPU that's still starting up
+            set_parameter(BOOTSTRAP_STACKS + id as usize * 4, stack_end.try_into().unwrap());
 
             local_apic.send_sipi(timer_num, id, (crate::platform::get_cpu_bootstrap_addr() / 0x1000).try_into().unwrap());
         }
@@ -695,6 +702,10 @@ unsafe extern "C" fn cpu_entry_point() {
 
     super::gdt::init_other_cpu(super::STACK_SIZE);
 
+    if super::paging_pae::is_nx_enabled() && !super::paging_pae::enable_nx() {
+        panic!("CPU doesn't support NX when the bootstrap CPU does");
+    }
+
     let local_apic = get_local_apic().expect("local APIC not mapped");
 
     local_apic.set_spurious_interrupt(SpuriousIntVectorBuilder::new().vector(0xf0).finish());
@@ -792,6 +803,10 @@ pub fn init_bsp_apic() {
     // calibrate BSP's APIC timer
     calibrate_apic_timer_from(super::ints::pit_timer_num());
 
+    // the PIT is about to stop ticking, so the BSP's APIC timer takes over the clock
+    let timer = crate::task::get_cpus().expect("CPUs not initialized").get_thread(super::get_thread_id()).unwrap().timer;
+    crate::clock::set_source(timer, crate::timer::get_timer(timer).unwrap().hz());
+
     // disable PIT timer
     super::ints::disable_pit();
 }
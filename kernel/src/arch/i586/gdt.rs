@@ -6,14 +6,17 @@ use core::mem::size_of;
 use log::debug;
 use x86::{
     bits32::task::TaskStateSegment,
-    dtables::{lgdt, DescriptorTablePointer},
+    dtables::{lgdt, sgdt, DescriptorTablePointer},
     segmentation::{BuildDescriptor, CodeSegmentType, DataSegmentType, Descriptor, DescriptorBuilder, SegmentDescriptorBuilder, SegmentSelector},
     task::load_tr,
     Ring,
 };
 
 /// how many entries do we want in our GDT
-const GDT_ENTRIES: usize = 6;
+const GDT_ENTRIES: usize = 7;
+
+/// index of the user mode segment used for thread-local storage in the GDT. its base is changed to the current thread's TLS area on every context switch
+pub const TLS_SEGMENT: u16 = 6;
 
 /// the GDT itself (aligned to 32 bits for performance)
 static mut GDT: Aligned<A4, [Descriptor; GDT_ENTRIES]> = Aligned([Descriptor::NULL; GDT_ENTRIES]);
@@ -69,6 +72,32 @@ fn populate_gdt(gdt: &mut [Descriptor; GDT_ENTRIES], tss: &TaskStateSegment) {
 
     // clear the system bit because rust-x86 completely fucking refuses to
     gdt[5].upper &= 0xffffefff;
+
+    gdt[TLS_SEGMENT as usize] = tls_descriptor(0);
+}
+
+/// builds a user mode data segment descriptor for thread-local storage starting at the given address
+fn tls_descriptor(base: u32) -> Descriptor {
+    DescriptorBuilder::data_descriptor(base, 0x000fffff, DataSegmentType::ReadWrite)
+        .present()
+        .dpl(Ring::Ring3)
+        .limit_granularity_4kb()
+        .db()
+        .finish()
+}
+
+/// sets the base address of the thread-local storage segment in the current CPU's GDT
+///
+/// the segment registers are reloaded from the GDT whenever we return from an interrupt, so this takes effect as soon as we return to user mode
+pub fn set_tls_base(base: usize) {
+    let mut pointer = DescriptorTablePointer::<Descriptor>::default();
+
+    unsafe {
+        sgdt(&mut pointer);
+
+        // every CPU has its own GDT, so nothing else can be touching this
+        *(pointer.base as *mut Descriptor).add(TLS_SEGMENT as usize) = tls_descriptor(base as u32);
+    }
 }
 
 /// initialize GDT and TSS
@@ -2,7 +2,11 @@
 
 use super::halt;
 use crate::{
-    task::{get_cpus, nmi_all_other_cpus, syscalls::exit_current_thread},
+    mm::paging::{classify_fault, get_kernel_page_dir, get_page_dir, FaultKind},
+    task::{
+        get_cpus, nmi_all_other_cpus,
+        syscalls::{exit_current_process, exit_current_thread},
+    },
     util::debug::FormatHex,
 };
 use aligned::{Aligned, A16};
@@ -380,6 +384,10 @@ unsafe fn generic_exception(name: &str, regs: &mut InterruptRegisters) {
 
         info!("{:#?}", regs);
 
+        if let Some(process) = crate::task::get_process(task_id.unwrap().process) {
+            debug!("address space of process {}:\n{}", task_id.unwrap().process, process.page_directory.task.mappings());
+        }
+
         exit_current_thread(thread_id, thread, regs);
     }
 
@@ -487,19 +495,73 @@ unsafe extern "x86-interrupt" fn page_fault_handler(regs: &mut InterruptRegister
 
     let task_id = thread.task_queue.lock().current().map(|c| c.id());
 
-    if in_kernel || task_id.is_none() {
+    // this bit of the error code is only ever set when NX is enabled
+    let instruction_fetch = regs.error_code & 0x10 != 0;
+
+    // work out what this was caused by, so we can give a more helpful error message
+    let kind = if address as usize >= super::KERNEL_PAGE_DIR_SPLIT || task_id.is_none() {
+        classify_fault(&get_kernel_page_dir(), address as usize, instruction_fetch)
+    } else {
+        classify_fault(&get_page_dir(Some(thread_id)), address as usize, instruction_fetch)
+    };
+
+    // only a fault in the kernel itself can have overflowed a kernel stack, a process poking at one of the guard pages is only fatal to that process
+    let kernel_fault = in_kernel || task_id.is_none();
+
+    match kind {
+        FaultKind::StackOverflow if kernel_fault => {
+            error!("kernel stack overflow at {:#x} (accessed {:#x})", regs.eip, address);
+            nmi_all_other_cpus();
+            halt();
+        }
+        FaultKind::InterruptStackOverflow if kernel_fault => {
+            error!("kernel interrupt stack overflow at {:#x} (accessed {:#x})", regs.eip, address);
+            nmi_all_other_cpus();
+            halt();
+        }
+        FaultKind::GuardPage => error!("guard page hit @ {:#x} (accessed {:#x}), likely a stack overflow", regs.eip, address),
+        FaultKind::NoExecute => error!("tried to execute non-executable page @ {:#x}", address),
+        FaultKind::StackOverflow | FaultKind::InterruptStackOverflow | FaultKind::CopyOnWrite | FaultKind::Unknown => (),
+    }
+
+    let is_guard_page = kind == FaultKind::GuardPage;
+    let is_kernel_guard_page = matches!(kind, FaultKind::StackOverflow | FaultKind::InterruptStackOverflow);
+
+    if kernel_fault {
         error!("PANIC (CPU {thread_id}): page fault @ {:#x} (accessed {:#x}), error code {:#x}", regs.eip, address, regs.error_code);
 
         info!("{:#?}", regs);
 
         nmi_all_other_cpus();
         halt();
-    } else if regs.error_code & 0x7 != 0x7
-        || !crate::mm::paging::try_copy_on_write(thread, address as usize).unwrap_or_else(|err| {
-            error!("copy on write failed: {err:?}");
-
-            false
-        })
+    } else if is_kernel_guard_page {
+        error!("process {} accessed a kernel stack guard page @ {:#x} (accessed {:#x}), killing it", task_id.unwrap(), regs.eip, address);
+
+        exit_current_process(thread_id, thread, regs);
+    } else if is_guard_page
+        || (regs.error_code & 0x1 == 0
+            && !crate::mm::swap::try_swap_in(thread, address as usize).unwrap_or_else(|err| {
+                error!("swap in failed: {err:?}");
+
+                false
+            })
+            && !crate::mm::paging::try_grow_stack(thread, address as usize).unwrap_or_else(|err| {
+                error!("growing stack failed: {err:?}");
+
+                false
+            })
+            && !crate::mm::paging::try_zero_fill(thread, address as usize).unwrap_or_else(|err| {
+                error!("filling in zeroed page failed: {err:?}");
+
+                false
+            }))
+        || (regs.error_code & 0x1 != 0 && regs.error_code & 0x7 != 0x7)
+        || (regs.error_code & 0x7 == 0x7
+            && !crate::mm::paging::try_copy_on_write(thread, address as usize).unwrap_or_else(|err| {
+                error!("copy on write failed: {err:?}");
+
+                false
+            }))
     {
         error!(
             "page fault in process {} @ {:#x} (accessed {:#x}), error code {:#x}",
@@ -576,6 +638,11 @@ unsafe fn irq0_handler(regs: &mut InterruptRegisters) {
         timer.try_tick(regs, was_in_kernel);
     }
 
+    // only sample threads driven by the PIT, since the others are sampled by their own timers
+    if let Some(thread) = thread && thread.timer == PIT_TIMER_NUM {
+        thread.sample_idle();
+    }
+
     if !was_in_kernel {
         thread.unwrap().leave_kernel();
     }
@@ -849,8 +916,11 @@ unsafe fn apic_timer_handler(regs: &mut InterruptRegisters) {
 
     if let Some(timer) = crate::timer::get_timer(timer) {
         timer.try_tick(regs, was_in_kernel);
+        thread.tick_timers(timer.jiffies());
     }
 
+    thread.sample_idle();
+
     if !was_in_kernel {
         thread.leave_kernel();
     }
@@ -1005,6 +1075,7 @@ pub fn init_pit(hz: usize) {
     // register timer
     unsafe {
         PIT_TIMER_NUM = crate::timer::register_timer(Some(crate::task::cpu::ThreadID { core: 0, thread: 0 }), hz as u64).expect("couldn't register PIT timer");
+        crate::clock::set_source(PIT_TIMER_NUM, hz as u64);
     }
 }
 
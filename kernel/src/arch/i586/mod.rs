@@ -3,12 +3,14 @@ pub mod apic;
 pub mod gdt;
 pub mod ints;
 pub mod paging;
+pub mod paging_pae;
 
 use crate::{
     arch::paging::{is_page_dir_current, PageTable},
     mm::{
         heap::{ExpandAllocCallback, ExpandFreeCallback, ALLOCATOR},
         paging::{get_kernel_page_dir, get_page_manager, PageFrame},
+        sync::{MutexedPageDir, PageDirTracker},
     },
     task::{
         cpu::{ThreadID, CPU},
@@ -34,6 +36,9 @@ pub const HEAP_RESERVED: usize = PAGE_SIZE * 4;
 // reasonable stack size
 pub const STACK_SIZE: usize = 0x1000 * 8;
 
+/// the machine type of ELF executables that can run here
+pub const ELF_MACHINE: u16 = goblin::elf::header::EM_386;
+
 fn cpuid_reader(leaf: u32, subleaf: u32) -> CpuIdResult {
     let eax: u32;
     let ebx: u32;
@@ -240,6 +245,9 @@ pub type Registers = ints::InterruptRegisters;
 /// page directory type
 pub type PageDirectory<'a> = paging::PageDir<'a>;
 
+/// physical memory at or above this address can't be mapped by `PageDirectory`, since its entries are only 32 bits wide
+pub const PHYS_ADDR_LIMIT: u64 = 1 << 32;
+
 pub const KERNEL_PAGE_DIR_SPLIT: usize = 0xe0000000;
 
 #[derive(Copy, Clone, Debug)]
@@ -272,6 +280,53 @@ pub unsafe fn halt() -> ! {
     }
 }
 
+/// exit code passed to emulators when exiting after a panic, distinct from the one `halt` uses
+pub const PANIC_EXIT_CODE: u8 = 0x32;
+
+/// exits emulators with the given exit code if applicable, then completely halts the CPU
+///
+/// # Safety
+///
+/// yeah
+pub unsafe fn exit_emulator(code: u8) -> ! {
+    // exit qemu (isa-debug-exit), its exit status will be (code << 1) | 1
+    x86::io::outb(0x501, code);
+
+    // exit bochs
+    x86::io::outw(0x8a00, 0x8a00);
+    x86::io::outw(0x8a00, 0x8ae0);
+
+    loop {
+        asm!("cli; hlt");
+    }
+}
+
+/// resets the machine thru the 8042 keyboard controller
+///
+/// # Safety
+///
+/// this obviously throws away everything we were doing
+pub unsafe fn reboot() -> ! {
+    asm!("cli");
+
+    // wait for the 8042's input buffer to be empty, then pulse the reset line
+    for _i in 0..0x10000 {
+        if x86::io::inb(0x64) & 0x02 == 0 {
+            break;
+        }
+    }
+    x86::io::outb(0x64, 0xfe);
+
+    // if that didn't work, load an empty IDT and cause an exception to triple fault
+    let idt = x86::dtables::DescriptorTablePointer::<u8> { limit: 0, base: core::ptr::null() };
+    x86::dtables::lidt(&idt);
+    asm!("int3");
+
+    loop {
+        asm!("hlt");
+    }
+}
+
 /// halts the CPU until an interrupt occurs
 #[inline(always)]
 pub fn halt_until_interrupt() {
@@ -358,6 +413,8 @@ pub fn get_thread_id() -> ThreadID {
 
 pub use apic::{send_interrupt_to_cpu, send_nmi_to_cpu};
 
+pub use gdt::set_tls_base;
+
 /// refreshes the page at the provided address in the TLB
 pub fn refresh_page(addr: usize) {
     trace!("flushing {:#x} in tlb", addr);
@@ -366,6 +423,39 @@ pub fn refresh_page(addr: usize) {
     }
 }
 
+/// how many pages a range has to have before it's faster to flush the whole TLB than to flush each page in it
+const FULL_FLUSH_PAGES: usize = 32;
+
+/// refreshes every page in the provided range in the TLB, flushing the whole TLB instead if the range is big enough
+pub fn refresh_range(start: usize, len: usize) {
+    trace!("flushing {start:#x} - {:#x} in tlb", start + (len - 1));
+
+    if len / PAGE_SIZE <= FULL_FLUSH_PAGES {
+        // the range can go right up to the top of the address space, so don't calculate its end
+        for offset in (0..len).step_by(PAGE_SIZE) {
+            unsafe {
+                x86::tlb::flush(start + offset);
+            }
+        }
+    } else if start + (len - 1) >= KERNEL_PAGE_DIR_SPLIT {
+        // kernel pages are global, so reloading cr3 won't get rid of them. toggling global pages off and back on flushes everything
+        unsafe {
+            let cr4 = x86::controlregs::cr4();
+
+            if cr4.contains(x86::controlregs::Cr4::CR4_ENABLE_GLOBAL_PAGES) {
+                x86::controlregs::cr4_write(cr4 - x86::controlregs::Cr4::CR4_ENABLE_GLOBAL_PAGES);
+                x86::controlregs::cr4_write(cr4);
+            } else {
+                x86::tlb::flush_all();
+            }
+        }
+    } else {
+        unsafe {
+            x86::tlb::flush_all();
+        }
+    }
+}
+
 pub const MESSAGE_INT: usize = 0x31;
 pub const SYSCALL_INT: usize = 0x80;
 
@@ -381,13 +471,22 @@ pub fn safely_halt_cpu(regs: &mut Registers) {
 // basically just halts and waits for an interrupt
 #[naked]
 unsafe extern "C" fn set_interrups_and_halt() {
-    asm!(
-        "2:", // 0 and 1 don't work lmao
-        "sti",
-        "hlt",
-        "jmp 2b", // jump backwards to nearest label 2
-        options(noreturn),
-    );
+    // this is entered every time a CPU runs out of things to do without the stack being reset, so it can't push anything that won't be popped
+    asm!("jmp {}", sym idle_loop, options(noreturn));
+}
+
+/// halts and waits for interrupts forever, keeping track of idle time if we can
+pub extern "C" fn idle_loop() -> ! {
+    let thread_id = get_thread_id();
+
+    match crate::task::get_cpus().and_then(|cpus| cpus.get_thread(thread_id)) {
+        Some(thread) => loop {
+            thread.idle();
+        },
+        None => loop {
+            halt_until_interrupt();
+        },
+    }
 }
 
 fn init_single_core(timer: usize) {
@@ -415,10 +514,46 @@ pub fn init_alloc() {
 
     ALLOCATOR.reserve_memory(Some(Layout::from_size_align(HEAP_RESERVED, PAGE_SIZE).unwrap()));
 
-    fn expand(old_top: usize, new_top: usize, alloc: &ExpandAllocCallback, _free: &ExpandFreeCallback) -> Result<usize, ()> {
+    /// makes sure the kernel's page directory has a page table covering the given address, returning false if one couldn't be allocated.
+    /// the lock is held from the check until the table is added, since another CPU could otherwise add a table there in between
+    fn add_kernel_page_table(page_dir: &MutexedPageDir<'static, PageDirTracker<paging::PageDir<'static>>>, addr: usize, alloc: &ExpandAllocCallback, free: &ExpandFreeCallback) -> Result<bool, ()> {
+        let mut page_dir = page_dir.lock();
+
+        if page_dir.inner().has_page_table(addr.try_into().unwrap()) {
+            return Ok(true);
+        }
+
+        trace!("allocating new page table (kernel)");
+
+        let layout = Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap();
+        let virt = match alloc(layout) {
+            Ok(ptr) => ptr,
+            Err(()) => return Ok(false),
+        };
+
+        let Some(phys) = page_dir.inner().virt_to_phys(virt as usize) else {
+            free(virt, layout);
+            return Err(());
+        };
+
+        unsafe {
+            page_dir.inner_mut().add_page_table(addr.try_into().unwrap(), &mut *(virt as *mut PageTable), phys.try_into().unwrap(), true).map_err(|err| {
+                error!("couldn't add page table for heap: {err:?}");
+                free(virt, layout);
+            })?;
+        }
+
+        Ok(true)
+    }
+
+    fn expand(old_top: usize, new_top: usize, alloc: &ExpandAllocCallback, free: &ExpandFreeCallback) -> Result<usize, ()> {
         debug!("expand (old_top: {old_top:#x}, new_top: {new_top:#x})");
-        if new_top <= crate::platform::HEAP_START + crate::platform::KHEAP_MAX_SIZE {
-            let new_top = (new_top / PAGE_SIZE) * PAGE_SIZE + PAGE_SIZE;
+
+        let max_top = crate::platform::HEAP_START + crate::platform::KHEAP_MAX_SIZE;
+
+        if old_top < max_top {
+            // grow as much as we can even if we can't grow as much as was asked for, the allocator will try again and fail gracefully once we're out of room
+            let new_top = ((new_top / PAGE_SIZE) * PAGE_SIZE + PAGE_SIZE).min(max_top);
             let old_top = (old_top / PAGE_SIZE) * PAGE_SIZE;
             debug!("new_top aligned to {new_top:#x}, old_top aligned to {old_top:#x}");
 
@@ -428,21 +563,8 @@ pub fn init_alloc() {
                 for addr in (old_top..new_top).step_by(PAGE_SIZE) {
                     let mut page_dir = get_kernel_page_dir();
 
-                    if !page_dir.lock().inner().has_page_table(addr.try_into().unwrap()) {
-                        trace!("allocating new page table");
-
-                        let virt = match alloc(Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap()) {
-                            Ok(ptr) => ptr,
-                            Err(()) => return Ok(addr), // fail gracefully if we can't allocate
-                        };
-                        let phys = page_dir.lock().inner().virt_to_phys(virt as usize).ok_or(())?;
-
-                        unsafe {
-                            page_dir
-                                .lock()
-                                .inner_mut()
-                                .add_page_table(addr.try_into().unwrap(), &mut *(virt as *mut PageTable), phys.try_into().unwrap(), true);
-                        }
+                    if !add_kernel_page_table(&page_dir, addr, alloc, free)? {
+                        return Ok(addr); // fail gracefully if we can't allocate
                     }
 
                     let phys_addr = get_page_manager().alloc_frame().map_err(|err| {
@@ -476,21 +598,8 @@ pub fn init_alloc() {
                     trace!("getting kernel page directory");
                     let page_dir = get_kernel_page_dir();
 
-                    if !page_dir.lock().inner().has_page_table(addr.try_into().unwrap()) {
-                        trace!("allocating new page table (kernel)");
-
-                        let virt = match alloc(Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap()) {
-                            Ok(ptr) => ptr,
-                            Err(()) => return Ok(addr), // fail gracefully if we can't allocate
-                        };
-                        let phys = page_dir.lock().inner().virt_to_phys(virt as usize).ok_or(())?;
-
-                        unsafe {
-                            page_dir
-                                .lock()
-                                .inner_mut()
-                                .add_page_table(addr.try_into().unwrap(), &mut *(virt as *mut PageTable), phys.try_into().unwrap(), true);
-                        }
+                    if !add_kernel_page_table(&page_dir, addr, alloc, free)? {
+                        return Ok(addr); // fail gracefully if we can't allocate
                     }
 
                     {
@@ -502,7 +611,8 @@ pub fn init_alloc() {
                         if !process.page_directory.task.has_page_table(addr.try_into().unwrap()) {
                             trace!("allocating new page table (task)");
 
-                            let virt = match alloc(Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap()) {
+                            let layout = Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap();
+                            let virt = match alloc(layout) {
                                 Ok(ptr) => ptr,
                                 Err(()) => return Ok(addr), // fail gracefully if we can't allocate
                             };
@@ -512,7 +622,11 @@ pub fn init_alloc() {
                                 process
                                     .page_directory
                                     .task
-                                    .add_page_table(addr.try_into().unwrap(), &mut *(virt as *mut PageTable), phys.try_into().unwrap(), true);
+                                    .add_page_table(addr.try_into().unwrap(), &mut *(virt as *mut PageTable), phys.try_into().unwrap(), true)
+                                    .map_err(|err| {
+                                        error!("couldn't add page table for heap: {err:?}");
+                                        free(virt, layout);
+                                    })?;
                             }
                         }
                     }
@@ -564,6 +678,7 @@ pub fn init_alloc() {
                 }
             }
         } else {
+            error!("kernel heap has reached its maximum size ({:#x} bytes)", crate::platform::KHEAP_MAX_SIZE);
             Err(())
         }
     }
@@ -574,13 +689,21 @@ pub fn init_alloc() {
 static mut BROUGHT_UP_CPUS: usize = 1;
 static mut CAN_START_CTX_SWITCHING: bool = false;
 
-pub fn init(args: Option<BTreeMap<&str, &str>>, modules: BTreeMap<String, &'static [u8]>) {
+pub fn init(args: crate::platform::cmdline::CmdLine, modules: BTreeMap<String, &'static [u8]>) {
     unsafe {
         ints::init_irqs();
     }
 
     let cpuid = read_cpuid();
 
+    if paging_pae::is_supported() {
+        debug!("CPU supports PAE");
+    }
+
+    if paging_pae::enable_nx() {
+        debug!("CPU supports NX");
+    }
+
     // try to get cpu topology from cpuid
     let res = get_cpu_topology(&cpuid);
     let topology = res.as_ref().map(|(t, _)| t);
@@ -590,7 +713,8 @@ pub fn init(args: Option<BTreeMap<&str, &str>>, modules: BTreeMap<String, &'stat
         info!("detected {} CPUs ({} cores, {} threads per core)", t.logical_processors, t.num_cores, t.threads_per_core);
     }
 
-    let can_use_acpi = args.as_ref().and_then(|a| a.get("acpi").cloned()).unwrap_or("yes") == "yes";
+    // ACPI is used unless acpi is set to anything other than "yes", so a bare `acpi` still turns it off like it always has
+    let can_use_acpi = args.get_str("acpi").map(|value| value == "yes").unwrap_or(true);
 
     if can_use_acpi && let Some((final_mapping, cpus)) = acpi::detect_cpus(topology, mapping) {
         // we have ACPI, so we for sure have an APIC
@@ -661,16 +785,53 @@ pub fn init(args: Option<BTreeMap<&str, &str>>, modules: BTreeMap<String, &'stat
 
     // TODO: maybe intel MP support?
 
+    #[cfg(test)]
+    crate::test_main();
+
+    match launch_init(args.get_str("init"), &modules) {
+        Ok(process) => {
+            info!("started init as process {process}");
+            start_context_switching();
+        }
+        Err(err) => error!("couldn't start init ({err:?}), nothing left to do"),
+    }
+}
+
+/// finds the init module, loads it into a new process, and queues it on a CPU
+///
+/// the module named by `init=` on the command line is used if it exists, otherwise we fall back on the module named "init"
+fn launch_init(name: Option<&str>, modules: &BTreeMap<String, &'static [u8]>) -> common::types::Result<u32> {
     const DEFAULT_INIT: &str = "init";
-    let init_name = args.as_ref().and_then(|a| a.get("init").cloned()).unwrap_or(DEFAULT_INIT);
-    let init_data = modules.get(init_name).unwrap_or_else(|| modules.get(DEFAULT_INIT).expect("couldn't find init in modules"));
 
-    let process = crate::task::create_process(paging::PageDir::new()).expect("failed to create process for init");
-    crate::task::exec::exec_as::<paging::PageDir>(None, &mut crate::task::get_process(process).unwrap(), init_data).expect("failed to exec init");
+    let init_data = match name.and_then(|name| modules.get(name)) {
+        Some(data) => data,
+        None => {
+            if let Some(name) = name {
+                warn!("couldn't find init module {name:?}, falling back on {DEFAULT_INIT:?}");
+            }
 
-    queue_process(common::types::ProcessID { process, thread: 1 }).expect("failed to queue init on a cpu");
+            modules.get(DEFAULT_INIT).ok_or_else(|| {
+                error!("couldn't find init in modules (have {:?})", modules.keys().collect::<Vec<_>>());
+                common::types::Errno::NoSuchFileOrDir
+            })?
+        }
+    };
 
-    start_context_switching();
+    let process = crate::task::exec::load(init_data).map_err(|err| {
+        error!("failed to load init: {err:?}");
+        err
+    })?;
+
+    // init has to be known as init before it gets a chance to run
+    crate::task::set_init_process(process);
+
+    if let Err(err) = queue_process(common::types::ProcessID { process, thread: 1 }) {
+        error!("failed to queue init on a CPU: {err:?}");
+        crate::task::remove_process(process);
+        return Err(err);
+    }
+
+    Ok(process)
 }
 
 /*unsafe extern "C" fn test_thread_1() {
@@ -702,7 +863,7 @@ fn test_create_thread(entry_point: unsafe extern "C" fn()) {
 
     use crate::mm::paging::PageDirectory;
 
-    let mut page_dir = paging::PageDir::new();
+    let mut page_dir = paging::PageDir::new().unwrap();
 
     for addr in (KERNEL_PAGE_DIR_SPLIT - STACK_SIZE..KERNEL_PAGE_DIR_SPLIT).step_by(PAGE_SIZE) {
         // make sure there's actually something here so we don't deadlock if we need to allocate something and the page manager is busy
@@ -757,3 +918,85 @@ fn start_context_switching() {
 
     crate::task::switch::wait_for_context_switch(thread.timer, thread_id);
 }
+
+/// where the executable built by [spinning_executable] is loaded
+#[cfg(test)]
+const TEST_VADDR: u32 = 0x0804_8000;
+
+/// builds a tiny ELF executable with a single read-only, executable segment that just spins forever, for tests that need a real process
+#[cfg(test)]
+pub fn spinning_executable() -> Vec<u8> {
+    const HEADER_SIZE: u32 = 52;
+    const PROGRAM_HEADER_SIZE: u32 = 32;
+    // jmp $
+    const CODE: [u8; 2] = [0xeb, 0xfe];
+
+    let file_size = HEADER_SIZE + PROGRAM_HEADER_SIZE + CODE.len() as u32;
+    let mut elf = Vec::new();
+
+    // ELF header
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.extend_from_slice(&goblin::elf::header::ET_EXEC.to_le_bytes());
+    elf.extend_from_slice(&ELF_MACHINE.to_le_bytes());
+    elf.extend_from_slice(&1_u32.to_le_bytes());
+    elf.extend_from_slice(&(TEST_VADDR + HEADER_SIZE + PROGRAM_HEADER_SIZE).to_le_bytes());
+    elf.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+    elf.extend_from_slice(&0_u32.to_le_bytes());
+    elf.extend_from_slice(&0_u32.to_le_bytes());
+    elf.extend_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    elf.extend_from_slice(&1_u16.to_le_bytes());
+    elf.extend_from_slice(&40_u16.to_le_bytes());
+    elf.extend_from_slice(&0_u16.to_le_bytes());
+    elf.extend_from_slice(&0_u16.to_le_bytes());
+
+    // program header, loading the whole file
+    elf.extend_from_slice(&goblin::elf::program_header::PT_LOAD.to_le_bytes());
+    elf.extend_from_slice(&0_u32.to_le_bytes());
+    elf.extend_from_slice(&TEST_VADDR.to_le_bytes());
+    elf.extend_from_slice(&TEST_VADDR.to_le_bytes());
+    elf.extend_from_slice(&file_size.to_le_bytes());
+    elf.extend_from_slice(&file_size.to_le_bytes());
+    elf.extend_from_slice(&(goblin::elf::program_header::PF_R | goblin::elf::program_header::PF_X).to_le_bytes());
+    elf.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+
+    elf.extend_from_slice(&CODE);
+
+    elf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn init_module_is_started() {
+        let executable: &'static [u8] = spinning_executable().leak();
+
+        let mut modules = BTreeMap::new();
+        modules.insert(String::from("init"), executable);
+
+        // a missing module named on the command line falls back on the default one
+        let process = launch_init(Some("missing"), &modules).unwrap();
+
+        assert!(crate::task::is_privileged(process));
+
+        // the first thread should be sitting in a CPU's queue ready to run, or already running
+        let cpu = get_process(process).and_then(|p| p.threads.get(1).and_then(|thread| thread.cpu)).expect("init wasn't queued");
+        let queue = crate::task::get_cpus().unwrap().get_thread(cpu).unwrap().task_queue.lock();
+        let id = common::types::ProcessID { process, thread: 1 };
+        assert!(queue.iter().any(|entry| entry.id() == id) || queue.current().map(|entry| entry.id() == id).unwrap_or(false));
+        drop(queue);
+
+        crate::task::syscalls::kill_process(get_thread_id(), process);
+        crate::task::set_init_process(0);
+    }
+
+    #[test_case]
+    fn missing_init_module_is_reported() {
+        let modules = BTreeMap::new();
+
+        assert_eq!(launch_init(Some("init"), &modules), Err(common::types::Errno::NoSuchFileOrDir));
+        assert!(!crate::task::is_privileged(1));
+    }
+}
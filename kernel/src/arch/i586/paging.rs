@@ -12,6 +12,7 @@ use alloc::alloc::{alloc, alloc_zeroed, dealloc, Layout};
 use bitmask_enum::bitmask;
 use core::{arch::asm, fmt, mem::size_of};
 use log::{error, trace};
+use spin::Mutex;
 use x86::tlb::flush;
 
 /// entry in a page table
@@ -64,6 +65,33 @@ impl PageTableEntry {
 impl From<PageTableEntry> for PageFrame {
     fn from(entry: PageTableEntry) -> Self {
         let flags = entry.get_flags();
+
+        if flags & PageTableFlags::Present.bits == 0 {
+            if flags & NotPresentFlags::Guard.bits > 0 {
+                return Self {
+                    guard: true,
+                    ..Default::default()
+                };
+            } else if flags & NotPresentFlags::ZeroFill.bits > 0 {
+                return Self {
+                    user_mode: flags & NotPresentFlags::SwappedUserMode.bits > 0,
+                    writable: flags & NotPresentFlags::SwappedWritable.bits > 0,
+                    executable: true,
+                    zero_fill: true,
+                    ..Default::default()
+                };
+            } else if flags & NotPresentFlags::Swapped.bits > 0 {
+                return Self {
+                    addr: (entry.get_address() >> 12) as u64,
+                    user_mode: flags & NotPresentFlags::SwappedUserMode.bits > 0,
+                    writable: flags & NotPresentFlags::SwappedWritable.bits > 0,
+                    executable: true,
+                    swapped: true,
+                    ..Default::default()
+                };
+            }
+        }
+
         Self {
             addr: entry.get_address() as u64,
             present: flags & PageTableFlags::Present.bits > 0,
@@ -73,6 +101,9 @@ impl From<PageTableEntry> for PageFrame {
             executable: true,
             referenced: flags & PageTableFlags::Referenced.bits > 0,
             shared: flags & PageTableFlags::Shared.bits > 0,
+            guard: false,
+            swapped: false,
+            zero_fill: false,
         }
     }
 }
@@ -81,6 +112,45 @@ impl TryFrom<PageFrame> for PageTableEntry {
     type Error = ();
 
     fn try_from(frame: PageFrame) -> Result<Self, Self::Error> {
+        if frame.guard && !frame.present {
+            return Ok(PageTableEntry(NotPresentFlags::Guard.bits as u32));
+        }
+
+        if frame.zero_fill && !frame.present {
+            let mut flags = NotPresentFlags::ZeroFill;
+
+            if frame.user_mode {
+                flags |= NotPresentFlags::SwappedUserMode;
+            }
+
+            if frame.writable {
+                flags |= NotPresentFlags::SwappedWritable;
+            }
+
+            return Ok(PageTableEntry(flags.bits as u32));
+        }
+
+        if frame.swapped && !frame.present {
+            // the slot number is stored where the address would usually be
+            let slot: u32 = frame.addr.try_into().map_err(|_| ())?;
+
+            if slot >= 1 << 20 {
+                return Err(());
+            }
+
+            let mut flags = NotPresentFlags::Swapped;
+
+            if frame.user_mode {
+                flags |= NotPresentFlags::SwappedUserMode;
+            }
+
+            if frame.writable {
+                flags |= NotPresentFlags::SwappedWritable;
+            }
+
+            return Ok(PageTableEntry((slot << 12) | flags.bits as u32));
+        }
+
         let mut flags = PageTableFlags::None;
 
         if frame.present {
@@ -231,6 +301,30 @@ impl fmt::Display for PageTableFlags {
     }
 }
 
+/// flags for page table entries that don't have the present bit set
+///
+/// the cpu ignores every other bit in these entries, so we're free to use them however we want
+#[bitmask(u16)]
+pub enum NotPresentFlags {
+    /// no flags?
+    None = 0,
+
+    /// this page is a guard page (i.e. below a stack), accessing it is always a bug
+    Guard = 1 << 1,
+
+    /// this page has been swapped out, and the address bits of this entry hold the slot it was swapped out to
+    Swapped = 1 << 2,
+
+    /// this page was writable before it was swapped out (or will be once it's filled in, for zero fill pages)
+    SwappedWritable = 1 << 3,
+
+    /// this page was accessible in user mode before it was swapped out (or will be once it's filled in, for zero fill pages)
+    SwappedUserMode = 1 << 4,
+
+    /// this page gets mapped in as a page of zeroes the first time it's touched
+    ZeroFill = 1 << 5,
+}
+
 /// entry in a page directory
 #[repr(transparent)]
 #[derive(Copy, Clone, Default)]
@@ -406,6 +500,67 @@ impl Default for PageTable {
     }
 }
 
+/// how many unused page tables we keep around to avoid reallocating them when mapping and unmapping sparse regions of memory
+const PAGE_TABLE_POOL_SIZE: usize = 16;
+
+/// pool of unused, zeroed page tables allocated on the heap
+///
+/// only the virtual addresses of tables are kept here, their physical addresses are looked up again when they're reused
+struct PageTablePool {
+    tables: [usize; PAGE_TABLE_POOL_SIZE],
+    len: usize,
+}
+
+static PAGE_TABLE_POOL: Mutex<PageTablePool> = Mutex::new(PageTablePool {
+    tables: [0; PAGE_TABLE_POOL_SIZE],
+    len: 0,
+});
+
+/// gets a zeroed page table from the pool, or allocates a new one if the pool is empty
+pub(super) fn alloc_page_table() -> *mut u8 {
+    {
+        let mut pool = PAGE_TABLE_POOL.lock();
+
+        if pool.len > 0 {
+            pool.len -= 1;
+            let len = pool.len;
+
+            trace!("reusing pooled page table @ {:#x}", pool.tables[len]);
+
+            return pool.tables[len] as *mut u8;
+        }
+    }
+
+    unsafe { alloc_zeroed(Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap()) }
+}
+
+/// zeroes a page table and puts it back in the pool, or frees it if the pool is full
+///
+/// # Safety
+///
+/// the table must have been allocated on the heap with the layout used in alloc_page_table, and must not be referenced by any page directory
+pub(super) unsafe fn free_page_table(table: &mut PageTable) {
+    for entry in table.entries.iter_mut() {
+        entry.set_unused();
+    }
+
+    let ptr = table as *mut PageTable as *mut u8;
+
+    {
+        let mut pool = PAGE_TABLE_POOL.lock();
+
+        if pool.len < PAGE_TABLE_POOL_SIZE {
+            let len = pool.len;
+            pool.tables[len] = ptr as usize;
+            pool.len += 1;
+
+            return;
+        }
+    }
+
+    dealloc(ptr, Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap());
+}
+
 /// wrapper for a reference to a page table to help us manage allocations
 ///
 /// allows us to store whether this reference was automatically allocated so it can be freed when its page directory is dropped
@@ -461,37 +616,98 @@ impl fmt::Debug for PageDir<'_> {
 /// additionally, there are checks in place to prevent freeing the current page table to prevent potential use-after-free bugs
 static mut CURRENT_PAGE_DIR: Option<&'static PageDir> = None;
 
+/// translates a virtual address using whichever page directory the MMU is using, PAE or not, if one has been set
+pub(super) fn current_virt_to_phys(virt: usize) -> Option<u64> {
+    match super::paging_pae::current_page_dir() {
+        Some(dir) => dir.virt_to_phys(virt),
+        None => unsafe { CURRENT_PAGE_DIR.as_ref() }.and_then(|dir| dir.virt_to_phys(virt)),
+    }
+}
+
 pub fn is_page_dir_current(page_dir: &PageDir) -> bool {
-    page_dir.tables_physical_addr == unsafe { x86::controlregs::cr3() as u32 }
+    is_page_dir_loaded(page_dir.tables_physical_addr)
+}
+
+/// checks whether the page directory with the given physical address is the one the MMU is using, without needing access to the directory itself
+pub fn is_page_dir_loaded(tables_physical_addr: u32) -> bool {
+    tables_physical_addr == unsafe { x86::controlregs::cr3() as u32 }
+}
+
+/// creates the page table entry for the given page at the given address
+///
+/// present pages in kernel memory are marked global so they stay in the tlb across page directory switches. entries that aren't present are left as is,
+/// since the cpu ignores their bits and an unmapped page has to stay unused.
+/// there's no no-execute bit without PAE, so present pages that aren't executable are refused rather than silently made executable
+fn page_table_entry_for(addr: usize, page: Option<PageFrame>) -> Result<PageTableEntry, PagingError> {
+    let Some(page) = page else {
+        return Ok(PageTableEntry::new_unused());
+    };
+
+    if page.present && !page.executable {
+        return Err(PagingError::Unsupported);
+    }
+
+    let mut entry: PageTableEntry = page.try_into().map_err(|_| PagingError::BadFrame)?;
+
+    if page.present && addr >= KERNEL_PAGE_DIR_SPLIT {
+        entry.set_flags(PageTableFlags {
+            bits: entry.get_flags() | PageTableFlags::Global.bits,
+        });
+    }
+
+    Ok(entry)
 }
 
 impl<'a> PageDir<'a> {
     /// constructs a new PageDir, allocating memory for it in the process
-    pub fn new() -> Self {
+    ///
+    /// this needs a current page directory to find the physical address of the memory it allocates, so it'll fail if there isn't one yet.
+    /// use [PageDir::bump_allocate] before then
+    pub fn new() -> Result<Self, PagingError> {
         unsafe {
-            let tables = {
-                // alloc_zeroed prolly doesnt work for this
-                let allocated = &mut *(alloc(Layout::new::<[Option<TableRef<'a>>; 1024]>()) as *mut [Option<TableRef<'a>>; 1024]);
-                for table_ref in allocated.iter_mut() {
-                    *table_ref = None;
+            let tables_layout = Layout::new::<[Option<TableRef<'a>>; 1024]>();
+            let tables_physical_layout = Layout::from_size_align(size_of::<[PageDirEntry; 1024]>(), PAGE_SIZE).unwrap();
+
+            let tables = alloc(tables_layout) as *mut [Option<TableRef<'a>>; 1024];
+            let tables_physical = alloc_zeroed(tables_physical_layout) as *mut [PageDirEntry; 1024];
+
+            let free = || {
+                for (ptr, layout) in [(tables as *mut u8, tables_layout), (tables_physical as *mut u8, tables_physical_layout)] {
+                    if !ptr.is_null() {
+                        dealloc(ptr, layout);
+                    }
                 }
-                allocated
             };
 
-            let tables_physical = alloc_zeroed(Layout::from_size_align(size_of::<[PageDirEntry; 1024]>(), PAGE_SIZE).unwrap());
+            if tables.is_null() || tables_physical.is_null() {
+                free();
+                return Err(PagingError::AllocError);
+            }
 
-            let tables_physical_addr = CURRENT_PAGE_DIR
-                .as_mut()
-                .expect("no current page directory")
-                .virt_to_phys(tables_physical as usize)
-                .expect("allocated memory not mapped into kernel memory");
+            // alloc_zeroed prolly doesnt work for this
+            for table_ref in (*tables).iter_mut() {
+                core::ptr::write(table_ref, None);
+            }
+
+            let tables_physical_addr = match CURRENT_PAGE_DIR.as_ref() {
+                Some(dir) => dir.virt_to_phys(tables_physical as usize).and_then(|phys| u32::try_from(phys).ok()),
+                None => {
+                    free();
+                    return Err(PagingError::NoCurrentPageDir);
+                }
+            };
+
+            let Some(tables_physical_addr) = tables_physical_addr else {
+                free();
+                return Err(PagingError::TableNotInKernel);
+            };
 
-            Self {
-                tables,
-                tables_physical: &mut *(tables_physical as *mut [PageDirEntry; 1024]),
-                tables_physical_addr: tables_physical_addr.try_into().unwrap(),
+            Ok(Self {
+                tables: &mut *tables,
+                tables_physical: &mut *tables_physical,
+                tables_physical_addr,
                 can_free: true,
-            }
+            })
         }
     }
 
@@ -526,13 +742,15 @@ impl<'a> PageDir<'a> {
     }
 
     /// adds an existing top level page table to the page directory
-    pub fn add_page_table(&mut self, addr: u32, table: &'a mut PageTable, physical_addr: u32, can_free: bool) {
+    pub fn add_page_table(&mut self, addr: u32, table: &'a mut PageTable, physical_addr: u32, can_free: bool) -> Result<(), PagingError> {
         //assert!(addr & ((1 << 22) - 1) == 0, "address is not page table aligned (22 bits)");
 
         let idx = (addr >> 22) as usize;
 
+        // overwriting a table would leak it along with every page mapped in it, so the caller has to deal with the one it made instead
         if self.tables[idx].is_some() {
-            error!("overwriting an existing page table at {:#x} ({:#x})", addr, idx);
+            error!("refusing to overwrite an existing page table at {:#x} ({:#x})", addr, idx);
+            return Err(PagingError::TableAlreadyPresent);
         }
 
         trace!("adding a new page table for virt {:#x} @ {:#x} (phys {:#x})", addr, table as *mut _ as usize, physical_addr);
@@ -546,6 +764,8 @@ impl<'a> PageDir<'a> {
         trace!("physical entry is {:#x} ({:?})", self.tables_physical[idx].0, self.tables_physical[idx]);
 
         self.tables[idx] = Some(TableRef { table, can_free });
+
+        Ok(())
     }
 
     /// removes a top level page table from the page directory
@@ -558,15 +778,15 @@ impl<'a> PageDir<'a> {
         if let Some(table_ref) = table.as_mut() {
             if table_ref.can_free {
                 // get pointer to page table
-                let ptr = table_ref.table as *mut PageTable as *mut u8;
+                let ptr = table_ref.table as *mut PageTable;
 
                 // mark page table as unused
                 *table = None;
                 self.tables_physical[idx].set_unused();
 
-                // free page table
+                // free page table, or rather put it back in the pool
                 unsafe {
-                    dealloc(ptr, Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap());
+                    free_page_table(&mut *ptr);
                 }
             } else {
                 // just mark page table as unused since we can't free it
@@ -576,6 +796,79 @@ impl<'a> PageDir<'a> {
         }
     }
 
+    /// writes out a summary of every mapping in this page directory below the given address, coalescing runs of contiguous pages with identical flags into ranges
+    pub fn dump_mappings(&self, f: &mut fmt::Formatter<'_>, end: usize) -> fmt::Result {
+        // the accessed and dirty flags change whenever the cpu feels like it, so they aren't worth splitting ranges over
+        let ignored_flags = (PageTableFlags::Accessed | PageTableFlags::Dirty).bits;
+
+        fn print_run(f: &mut fmt::Formatter<'_>, run: Option<(usize, usize, u32, u16)>) -> fmt::Result {
+            if let Some((virt_start, virt_end, phys_start, flags)) = run {
+                writeln!(f, "    {virt_start:#010x}..{virt_end:#010x} -> {phys_start:#010x} {}", PageTableFlags { bits: flags })
+            } else {
+                Ok(())
+            }
+        }
+
+        for (table_idx, table_ref) in self.tables.iter().enumerate() {
+            if table_idx * 1024 * PAGE_SIZE >= end {
+                break;
+            }
+
+            let table_ref = match table_ref {
+                Some(table_ref) => table_ref,
+                None => continue,
+            };
+
+            writeln!(
+                f,
+                "table {table_idx} ({:#010x}..{:#010x}) @ phys {:#x}, {}",
+                table_idx * 1024 * PAGE_SIZE,
+                (table_idx + 1) * 1024 * PAGE_SIZE,
+                self.tables_physical[table_idx].get_address(),
+                if table_ref.can_free { "can free" } else { "borrowed" }
+            )?;
+
+            // virtual start and end addresses, physical start address, and flags of the range we're currently building
+            let mut run: Option<(usize, usize, u32, u16)> = None;
+
+            for (entry_idx, entry) in table_ref.table.entries.iter().enumerate() {
+                let virt = (table_idx * 1024 + entry_idx) * PAGE_SIZE;
+                let flags = entry.get_flags() & !ignored_flags;
+
+                if entry.is_unused() || flags & PageTableFlags::Present.bits == 0 {
+                    print_run(f, run.take())?;
+                    continue;
+                }
+
+                match run.as_mut() {
+                    Some((virt_start, virt_end, phys_start, run_flags))
+                        if *virt_end == virt && *run_flags == flags && *phys_start as usize + (virt - *virt_start) == entry.get_address() as usize =>
+                    {
+                        *virt_end = virt + PAGE_SIZE;
+                    }
+                    _ => {
+                        print_run(f, run.take())?;
+                        run = Some((virt, virt + PAGE_SIZE, entry.get_address(), flags));
+                    }
+                }
+            }
+
+            print_run(f, run)?;
+        }
+
+        Ok(())
+    }
+
+    /// returns a wrapper around this page directory that formats as a summary of all its mappings
+    pub fn mappings(&self) -> DisplayMappings<'_, 'a> {
+        DisplayMappings(self, usize::MAX)
+    }
+
+    /// like `mappings`, but only includes user space
+    pub fn user_mappings(&self) -> DisplayMappings<'_, 'a> {
+        DisplayMappings(self, KERNEL_PAGE_DIR_SPLIT)
+    }
+
     /// checks whether we have a page table for this address already, or whether we have to allocate one
     pub fn has_page_table(&self, addr: u32) -> bool {
         //assert!(addr & ((1 << 22) - 1) == 0, "address is not page table aligned (22 bits)");
@@ -585,14 +878,18 @@ impl<'a> PageDir<'a> {
     }
 }
 
-impl<'a> Default for PageDir<'a> {
-    fn default() -> Self {
-        Self::new()
+/// formats a summary of all the mappings in a page directory, see `PageDir::dump_mappings`
+pub struct DisplayMappings<'b, 'a>(&'b PageDir<'a>, usize);
+
+impl fmt::Display for DisplayMappings<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.dump_mappings(f, self.1)
     }
 }
 
 impl<'a> PageDirectory for PageDir<'a> {
     const PAGE_SIZE: usize = PAGE_SIZE;
+    const NO_EXECUTE: bool = false;
 
     fn get_page(&self, mut addr: usize) -> Option<PageFrame> {
         addr /= PAGE_SIZE;
@@ -612,6 +909,44 @@ impl<'a> PageDirectory for PageDir<'a> {
         }
     }
 
+    fn count_pages(&self, end: usize) -> (usize, usize) {
+        let mut resident = 0;
+        let mut reserved = 0;
+
+        // only look at page tables that actually exist instead of every possible page
+        for (table_idx, table_ref) in self.tables.iter().enumerate() {
+            if table_idx * 1024 * PAGE_SIZE >= end {
+                break;
+            }
+
+            let Some(table_ref) = table_ref else { continue };
+
+            for (entry_idx, entry) in table_ref.table.entries.iter().enumerate() {
+                if (table_idx * 1024 + entry_idx) * PAGE_SIZE >= end {
+                    break;
+                }
+
+                if !entry.is_unused() {
+                    reserved += 1;
+
+                    if entry.get_flags() & PageTableFlags::Present.bits != 0 {
+                        resident += 1;
+                    }
+                }
+            }
+        }
+
+        (resident, reserved)
+    }
+
+    fn is_current(&self) -> bool {
+        is_page_dir_current(self)
+    }
+
+    fn physical_addr(&self) -> Option<u32> {
+        Some(self.tables_physical_addr)
+    }
+
     fn is_unused(&self, mut addr: usize) -> bool {
         addr /= PAGE_SIZE;
 
@@ -632,7 +967,7 @@ impl<'a> PageDirectory for PageDir<'a> {
         if let Some(table) = self.tables[table_idx].as_ref() {
             let entry = table.table.entries[(virt % 1024) as usize];
 
-            if entry.is_unused() {
+            if entry.is_unused() || entry.get_flags() & PageTableFlags::Present.bits == 0 {
                 None
             } else {
                 Some(entry.get_address() as u64)
@@ -643,50 +978,69 @@ impl<'a> PageDirectory for PageDir<'a> {
     }
 
     fn set_page(&mut self, mut addr: usize, page: Option<PageFrame>) -> Result<(), PagingError> {
+        // work out the entry first so a page that can't be represented doesn't leave a new table behind
+        let entry = page_table_entry_for(addr, page)?;
+
         addr /= PAGE_SIZE;
 
         let table_idx = (addr / 1024) as usize;
 
         if self.tables[table_idx].is_none() {
             // allocate memory for a new page-aligned page table
-            let layout = Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap();
-            let ptr = unsafe { alloc_zeroed(layout) };
+            let ptr = alloc_page_table();
 
             if ptr.is_null() {
                 Err(PagingError::AllocError)?;
             }
 
-            // make sure this newly allocated page table is located in kernel memory so its reference will be valid as long as our current page directory has an up to date copy of the kernel's page directory
-            assert!(ptr as usize >= KERNEL_PAGE_DIR_SPLIT, "new page table isn't in kernel memory");
-
-            // get the physical address of our new page table
-            let phys = unsafe {
-                CURRENT_PAGE_DIR
-                    .as_ref()
-                    .expect("no reference to current page directory")
-                    .virt_to_phys(ptr as usize)
-                    .expect("new page table isn't mapped into kernel memory")
+            // make sure this newly allocated page table is located in kernel memory so its reference will be valid as long as our current page directory has an up to date copy of the kernel's page directory,
+            // then get its physical address
+            let phys = if (ptr as usize) < KERNEL_PAGE_DIR_SPLIT {
+                Err(PagingError::TableNotInKernel)
+            } else {
+                unsafe { CURRENT_PAGE_DIR.as_ref() }
+                    .ok_or(PagingError::NoCurrentPageDir)
+                    .and_then(|dir| dir.virt_to_phys(ptr as usize).ok_or(PagingError::TableNotInKernel))
             };
 
-            self.add_page_table((addr * PAGE_SIZE).try_into().unwrap(), unsafe { &mut *(ptr as *mut PageTable) }, phys.try_into().unwrap(), true);
-        }
+            let phys = match phys {
+                Ok(phys) => phys,
+                Err(err) => {
+                    error!("couldn't add new page table: {err:?}");
 
-        let mut entry = if let Some(page) = page {
-            page.try_into().map_err(|_| PagingError::BadFrame)?
-        } else {
-            PageTableEntry::new_unused()
-        };
+                    // give the page table back so it isn't leaked
+                    unsafe {
+                        free_page_table(&mut *(ptr as *mut PageTable));
+                    }
+
+                    return Err(err);
+                }
+            };
 
-        if addr >= KERNEL_PAGE_DIR_SPLIT {
-            entry.set_flags(PageTableFlags {
-                bits: entry.get_flags() | PageTableFlags::Global.bits,
-            });
+            // we have exclusive access to this directory and just checked that there's no table here, but don't leak the new one if that ever changes
+            if let Err(err) = self.add_page_table((addr * PAGE_SIZE).try_into().unwrap(), unsafe { &mut *(ptr as *mut PageTable) }, phys.try_into().unwrap(), true) {
+                unsafe {
+                    free_page_table(&mut *(ptr as *mut PageTable));
+                }
+
+                return Err(err);
+            }
         }
 
         self.tables[table_idx].as_mut().unwrap().table.entries[(addr % 1024) as usize] = entry;
 
         //trace!("table is now {:?}", self.tables[table_idx].as_mut().unwrap().table.entries[(addr % 1024) as usize]);
 
+        // if that was the last page in this table, give the table back. kernel tables are shared between every page directory so they're never removed
+        if page.is_none() && addr * PAGE_SIZE < KERNEL_PAGE_DIR_SPLIT {
+            let table_ref = self.tables[table_idx].as_ref().unwrap();
+
+            if table_ref.can_free && table_ref.table.entries.iter().all(|e| e.is_unused()) {
+                trace!("page table for {:#x} is empty, removing it", addr * PAGE_SIZE);
+                self.remove_page_table((table_idx << 22) as u32);
+            }
+        }
+
         // invalidate this page in the tlb if we're modifying the current page directory
         if is_page_dir_current(self) {
             trace!("flushing {:#x} in tlb", addr * PAGE_SIZE);
@@ -744,3 +1098,203 @@ impl<'a> Drop for PageDir<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// an address in the user half of the address space that won't share a page table with anything else in a new page directory
+    const TEST_ADDR: usize = 0x4000_0000;
+
+    fn pool_len() -> usize {
+        PAGE_TABLE_POOL.lock().len
+    }
+
+    fn test_page() -> PageFrame {
+        PageFrame {
+            addr: 0x1000,
+            present: true,
+            ..Default::default()
+        }
+    }
+
+    #[test_case]
+    fn map_unmap_cycles_reuse_pooled_page_tables() {
+        let mut dir = PageDir::new().unwrap();
+
+        // make sure there's at least one table in the pool
+        dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+        dir.set_page(TEST_ADDR, None).unwrap();
+
+        for _i in 0..4 {
+            let pooled = pool_len();
+            assert!(pooled > 0);
+
+            let next = PAGE_TABLE_POOL.lock().tables[pooled - 1];
+
+            dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+            assert_eq!(pool_len(), pooled - 1);
+
+            let table = dir.tables[TEST_ADDR >> 22].as_ref().unwrap().table as *const PageTable as usize;
+            assert_eq!(table, next);
+
+            // the table's physical address has to have been looked up again when it was reused
+            assert_eq!(dir.tables_physical[TEST_ADDR >> 22].get_address() as u64, current_virt_to_phys(table).unwrap());
+            assert_eq!(dir.get_page(TEST_ADDR).map(|page| page.addr), Some(0x1000));
+
+            dir.set_page(TEST_ADDR, None).unwrap();
+            assert_eq!(pool_len(), pooled);
+            assert!(dir.tables[TEST_ADDR >> 22].is_none());
+        }
+    }
+
+    #[test_case]
+    fn only_present_kernel_pages_are_global() {
+        let is_global = |entry: PageTableEntry| entry.get_flags() & PageTableFlags::Global.bits != 0;
+
+        assert!(is_global(page_table_entry_for(KERNEL_PAGE_DIR_SPLIT, Some(test_page())).unwrap()));
+        assert!(!is_global(page_table_entry_for(TEST_ADDR, Some(test_page())).unwrap()));
+
+        // unmapping a kernel page has to leave an unused entry behind
+        assert!(page_table_entry_for(KERNEL_PAGE_DIR_SPLIT, None).unwrap().is_unused());
+
+        let zero_fill = PageFrame {
+            zero_fill: true,
+            writable: true,
+            ..Default::default()
+        };
+        let entry = page_table_entry_for(KERNEL_PAGE_DIR_SPLIT, Some(zero_fill)).unwrap();
+        assert!(!is_global(entry));
+        assert_eq!(entry.0, page_table_entry_for(TEST_ADDR, Some(zero_fill)).unwrap().0);
+    }
+
+    #[test_case]
+    fn non_executable_pages_are_unsupported() {
+        let mut dir = PageDir::new().unwrap();
+        let page = PageFrame { executable: false, ..test_page() };
+
+        assert!(matches!(dir.set_page(TEST_ADDR, Some(page)), Err(PagingError::Unsupported)));
+        assert!(dir.get_page(TEST_ADDR).is_none());
+        assert!(dir.tables[TEST_ADDR >> 22].is_none());
+
+        // it doesn't matter for pages that aren't present
+        let zero_fill = PageFrame {
+            zero_fill: true,
+            executable: false,
+            ..Default::default()
+        };
+        dir.set_page(TEST_ADDR, Some(zero_fill)).unwrap();
+        dir.set_page(TEST_ADDR, None).unwrap();
+    }
+
+    #[test_case]
+    fn frames_above_4gb_are_rejected() {
+        let mut dir = PageDir::new().unwrap();
+        let page = PageFrame { addr: 1 << 32, ..test_page() };
+
+        assert!(matches!(dir.set_page(TEST_ADDR, Some(page)), Err(PagingError::BadFrame)));
+        assert!(dir.get_page(TEST_ADDR).is_none());
+        assert!(dir.tables[TEST_ADDR >> 22].is_none());
+    }
+
+    #[test_case]
+    fn empty_page_tables_are_removed() {
+        let mut dir = PageDir::new().unwrap();
+
+        dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+        assert!(dir.tables[TEST_ADDR >> 22].is_some());
+
+        dir.set_page(TEST_ADDR, None).unwrap();
+        assert!(dir.tables[TEST_ADDR >> 22].is_none());
+        assert!(dir.tables_physical[TEST_ADDR >> 22].is_unused());
+    }
+
+    #[test_case]
+    fn page_tables_in_use_are_kept() {
+        let mut dir = PageDir::new().unwrap();
+
+        dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+        dir.set_page(TEST_ADDR + PAGE_SIZE, Some(test_page())).unwrap();
+
+        // the table still has a page in it
+        dir.set_page(TEST_ADDR, None).unwrap();
+        assert!(dir.tables[TEST_ADDR >> 22].is_some());
+        assert_eq!(dir.get_page(TEST_ADDR + PAGE_SIZE).map(|page| page.addr), Some(0x1000));
+
+        dir.set_page(TEST_ADDR + PAGE_SIZE, None).unwrap();
+        assert!(dir.tables[TEST_ADDR >> 22].is_none());
+    }
+
+    #[test_case]
+    fn pooled_page_tables_are_zeroed() {
+        let table = alloc_page_table() as *mut PageTable;
+
+        unsafe {
+            (*table).entries[0] = PageTableEntry::new(0x1000, PageTableFlags::Present);
+            free_page_table(&mut *table);
+        }
+
+        let reused = alloc_page_table() as *mut PageTable;
+        assert_eq!(reused, table);
+        assert!(unsafe { (*reused).entries.iter().all(|entry| entry.is_unused()) });
+
+        unsafe {
+            free_page_table(&mut *reused);
+        }
+    }
+
+    #[test_case]
+    fn virt_to_phys_range_merges_contiguous_pages() {
+        let mut dir = PageDir::new().unwrap();
+
+        dir.set_page(TEST_ADDR, Some(PageFrame { addr: 0x1000, ..test_page() })).unwrap();
+        dir.set_page(TEST_ADDR + PAGE_SIZE, Some(PageFrame { addr: 0x2000, ..test_page() })).unwrap();
+        dir.set_page(TEST_ADDR + PAGE_SIZE * 2, Some(PageFrame { addr: 0x5000, ..test_page() })).unwrap();
+
+        // crossing into a physically contiguous page extends the run
+        assert_eq!(dir.virt_to_phys_range(TEST_ADDR + 0xf00, 0x200).unwrap(), vec![(0x1f00, 0x200)]);
+
+        // crossing into one that isn't starts a new one
+        assert_eq!(dir.virt_to_phys_range(TEST_ADDR + PAGE_SIZE + 0xf00, 0x200).unwrap(), vec![(0x2f00, 0x100), (0x5000, 0x100)]);
+
+        for i in 0..3 {
+            dir.set_page(TEST_ADDR + PAGE_SIZE * i, None).unwrap();
+        }
+    }
+
+    #[test_case]
+    fn virt_to_phys_range_fails_across_holes() {
+        let mut dir = PageDir::new().unwrap();
+
+        dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+        dir.set_page(TEST_ADDR + PAGE_SIZE * 2, Some(test_page())).unwrap();
+
+        assert!(matches!(dir.virt_to_phys_range(TEST_ADDR, PAGE_SIZE * 3), Err(PagingError::BadAddress)));
+        assert!(matches!(dir.virt_to_phys_range(TEST_ADDR + 0xf00, 0x200), Err(PagingError::BadAddress)));
+
+        // either side of the hole is still fine on its own
+        assert_eq!(dir.virt_to_phys_range(TEST_ADDR, PAGE_SIZE).unwrap(), vec![(0x1000, PAGE_SIZE)]);
+        assert_eq!(dir.virt_to_phys_range(TEST_ADDR + PAGE_SIZE * 2, 0x10).unwrap(), vec![(0x1000, 0x10)]);
+
+        dir.set_page(TEST_ADDR, None).unwrap();
+        dir.set_page(TEST_ADDR + PAGE_SIZE * 2, None).unwrap();
+    }
+
+    #[test_case]
+    fn new_needs_a_current_page_dir() {
+        // nothing else can be allowed to use the current page directory while it's gone
+        crate::arch::cli();
+
+        let current = unsafe { CURRENT_PAGE_DIR.take() };
+        let result = PageDir::new();
+
+        unsafe {
+            CURRENT_PAGE_DIR = current;
+        }
+
+        crate::arch::sti();
+
+        assert!(matches!(result, Err(PagingError::NoCurrentPageDir)));
+    }
+}
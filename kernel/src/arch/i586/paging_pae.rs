@@ -0,0 +1,831 @@
+//! x86 PAE paging
+//!
+//! PAE splits the address space into 4 page directories of 512 entries each, which are pointed to by a page directory pointer table.
+//! every entry is 64 bits wide, which lets pages be mapped anywhere in the first 64 GB of physical memory instead of just the first 4 GB
+
+use super::{
+    paging::{alloc_page_table, current_virt_to_phys, free_page_table, NotPresentFlags, PageDirFlags, PageTable, PageTableFlags},
+    KERNEL_PAGE_DIR_SPLIT, PAGE_SIZE,
+};
+use crate::{
+    mm::{
+        paging::{PageDirectory, PageFrame, PagingError},
+        slab::ObjectCache,
+    },
+    util::debug::FormatHex,
+};
+use alloc::alloc::{alloc, alloc_zeroed, dealloc, Layout};
+use core::{
+    arch::asm,
+    fmt,
+    mem::size_of,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use log::{debug, error, trace};
+use spin::Mutex;
+use x86::{
+    msr::{rdmsr, wrmsr, IA32_EFER},
+    tlb::flush,
+};
+
+/// mask for the physical address bits in a PAE entry (52 bit physical addresses, page aligned)
+const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// mask for the flag bits in a PAE entry that we use
+const FLAGS_MASK: u64 = 0x0fff;
+
+/// how many entries are in a PAE page table or page directory
+const ENTRIES_PER_TABLE: usize = 512;
+
+/// how many page directories are pointed to by the page directory pointer table
+const NUM_DIRECTORIES: usize = 4;
+
+/// how many page tables there are in total across all the page directories
+const NUM_TABLES: usize = ENTRIES_PER_TABLE * NUM_DIRECTORIES;
+
+/// how much memory is covered by each page table
+const TABLE_SPAN: usize = ENTRIES_PER_TABLE * PAGE_SIZE;
+
+/// present bit in a page directory pointer table entry. the other flags in the usual place are reserved in these entries
+const PDPT_PRESENT: u64 = 1 << 0;
+
+/// no-execute bit in a page table entry. this is a reserved bit unless NX has been enabled in EFER, so it can only be set once it has been
+const NO_EXECUTE: u64 = 1 << 63;
+
+/// no-execute enable bit in EFER
+const EFER_NXE: u64 = 1 << 11;
+
+/// whether NX has been enabled, so the no-execute bit can be used
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// checks whether this cpu supports PAE
+pub fn is_supported() -> bool {
+    super::read_cpuid().get_feature_info().map(|info| info.has_pae()).unwrap_or(false)
+}
+
+/// checks whether this cpu supports the no-execute bit
+pub fn is_nx_supported() -> bool {
+    super::read_cpuid().get_extended_processor_and_feature_identifiers().map(|info| info.has_execute_disable()).unwrap_or(false)
+}
+
+/// enables the no-execute bit on this CPU if it's supported, returning whether it was. this has to be done on every CPU,
+/// and the bit is only used in page tables once the first CPU has enabled it, so all CPUs have to support it for it to be safe
+pub fn enable_nx() -> bool {
+    if !is_nx_supported() {
+        return false;
+    }
+
+    unsafe {
+        wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+    }
+
+    if !NX_ENABLED.swap(true, Ordering::AcqRel) {
+        debug!("no-execute bit enabled");
+    }
+
+    true
+}
+
+/// checks whether the no-execute bit can be used in page tables
+pub fn is_nx_enabled() -> bool {
+    NX_ENABLED.load(Ordering::Acquire)
+}
+
+/// entry in a PAE page table
+#[repr(transparent)]
+#[derive(Copy, Clone, Default)]
+pub struct PaePageTableEntry(u64);
+
+impl PaePageTableEntry {
+    /// create new page table entry
+    pub const fn new(addr: u64, flags: PageTableFlags) -> Self {
+        Self((addr & ADDRESS_MASK) | (flags.bits() as u64 & FLAGS_MASK))
+    }
+
+    /// create an unused page table entry
+    pub const fn new_unused() -> Self {
+        Self(0)
+    }
+
+    /// set address of page table entry
+    pub fn set_address(&mut self, addr: u64) {
+        self.0 = (self.0 & !ADDRESS_MASK) | (addr & ADDRESS_MASK);
+    }
+
+    /// set flags of page table entry
+    pub fn set_flags(&mut self, flags: PageTableFlags) {
+        self.0 = (self.0 & !FLAGS_MASK) | (flags.bits() as u64 & FLAGS_MASK);
+    }
+
+    /// checks if this page table entry is unused
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// set page as unused and clear its fields
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    /// gets address of page table entry
+    pub fn get_address(&self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    /// gets flags of page table entry
+    pub fn get_flags(&self) -> u16 {
+        (self.0 & FLAGS_MASK) as u16
+    }
+}
+
+impl From<PaePageTableEntry> for PageFrame {
+    fn from(entry: PaePageTableEntry) -> Self {
+        let flags = entry.get_flags();
+
+        if flags & PageTableFlags::Present.bits() == 0 {
+            if flags & NotPresentFlags::Guard.bits() > 0 {
+                return Self {
+                    guard: true,
+                    ..Default::default()
+                };
+            } else if flags & NotPresentFlags::ZeroFill.bits() > 0 {
+                return Self {
+                    user_mode: flags & NotPresentFlags::SwappedUserMode.bits() > 0,
+                    writable: flags & NotPresentFlags::SwappedWritable.bits() > 0,
+                    executable: entry.0 & NO_EXECUTE == 0,
+                    zero_fill: true,
+                    ..Default::default()
+                };
+            } else if flags & NotPresentFlags::Swapped.bits() > 0 {
+                return Self {
+                    addr: entry.get_address() >> 12,
+                    user_mode: flags & NotPresentFlags::SwappedUserMode.bits() > 0,
+                    writable: flags & NotPresentFlags::SwappedWritable.bits() > 0,
+                    // the CPU ignores every other bit in entries that aren't present, so the no-execute bit can be used here no matter what
+                    executable: entry.0 & NO_EXECUTE == 0,
+                    swapped: true,
+                    ..Default::default()
+                };
+            }
+        }
+
+        Self {
+            addr: entry.get_address(),
+            present: flags & PageTableFlags::Present.bits() > 0,
+            user_mode: flags & PageTableFlags::UserSupervisor.bits() > 0,
+            writable: flags & PageTableFlags::ReadWrite.bits() > 0,
+            copy_on_write: flags & PageTableFlags::CopyOnWrite.bits() > 0,
+            executable: entry.0 & NO_EXECUTE == 0,
+            referenced: flags & PageTableFlags::Referenced.bits() > 0,
+            shared: flags & PageTableFlags::Shared.bits() > 0,
+            guard: false,
+            swapped: false,
+            zero_fill: false,
+        }
+    }
+}
+
+impl TryFrom<PageFrame> for PaePageTableEntry {
+    type Error = ();
+
+    fn try_from(frame: PageFrame) -> Result<Self, Self::Error> {
+        if frame.guard && !frame.present {
+            return Ok(Self(NotPresentFlags::Guard.bits() as u64));
+        }
+
+        if frame.zero_fill && !frame.present {
+            let mut flags = NotPresentFlags::ZeroFill;
+
+            if frame.user_mode {
+                flags |= NotPresentFlags::SwappedUserMode;
+            }
+
+            if frame.writable {
+                flags |= NotPresentFlags::SwappedWritable;
+            }
+
+            let no_execute = if frame.executable { 0 } else { NO_EXECUTE };
+
+            return Ok(Self(flags.bits() as u64 | no_execute));
+        }
+
+        if frame.swapped && !frame.present {
+            // the slot number is stored where the address would usually be
+            if frame.addr > ADDRESS_MASK >> 12 {
+                return Err(());
+            }
+
+            let mut flags = NotPresentFlags::Swapped;
+
+            if frame.user_mode {
+                flags |= NotPresentFlags::SwappedUserMode;
+            }
+
+            if frame.writable {
+                flags |= NotPresentFlags::SwappedWritable;
+            }
+
+            let no_execute = if frame.executable { 0 } else { NO_EXECUTE };
+
+            return Ok(Self((frame.addr << 12) | flags.bits() as u64 | no_execute));
+        }
+
+        if frame.addr & !ADDRESS_MASK & !(PAGE_SIZE as u64 - 1) != 0 {
+            return Err(());
+        }
+
+        let mut flags = PageTableFlags::None;
+
+        if frame.present {
+            flags |= PageTableFlags::Present;
+        }
+
+        if frame.user_mode {
+            flags |= PageTableFlags::UserSupervisor;
+        }
+
+        if frame.writable {
+            flags |= PageTableFlags::ReadWrite;
+        }
+
+        if frame.copy_on_write {
+            flags |= PageTableFlags::CopyOnWrite;
+        }
+
+        if frame.referenced {
+            flags |= PageTableFlags::Referenced;
+        }
+
+        if frame.shared {
+            flags |= PageTableFlags::Shared;
+        }
+
+        let mut entry = Self::new(frame.addr, flags);
+
+        // without NX, every page that's present is executable whether we like it or not
+        if !frame.executable && is_nx_enabled() {
+            entry.0 |= NO_EXECUTE;
+        }
+
+        Ok(entry)
+    }
+}
+
+impl fmt::Debug for PaePageTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = PageTableFlags::from(self.get_flags());
+
+        f.debug_struct("PaePageTableEntry").field("address", &FormatHex(self.get_address())).field("flags", &flags).finish()
+    }
+}
+
+/// entry in a PAE page directory
+#[repr(transparent)]
+#[derive(Copy, Clone, Default)]
+pub struct PaePageDirEntry(u64);
+
+impl PaePageDirEntry {
+    /// create new page directory entry
+    pub const fn new(addr: u64, flags: PageDirFlags) -> Self {
+        Self((addr & ADDRESS_MASK) | (flags.bits() as u64 & FLAGS_MASK))
+    }
+
+    /// checks if this page dir entry is unused
+    pub fn is_unused(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// set page dir as unused and clear its fields
+    pub fn set_unused(&mut self) {
+        self.0 = 0;
+    }
+
+    /// gets address of page directory entry
+    pub fn get_address(&self) -> u64 {
+        self.0 & ADDRESS_MASK
+    }
+
+    /// gets flags of page directory entry
+    pub fn get_flags(&self) -> u16 {
+        (self.0 & FLAGS_MASK) as u16
+    }
+}
+
+impl fmt::Debug for PaePageDirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = PageDirFlags::from(self.get_flags());
+
+        f.debug_struct("PaePageDirEntry").field("address", &FormatHex(self.get_address())).field("flags", &flags).finish()
+    }
+}
+
+/// struct for PAE page table
+///
+/// these are the same size as non-PAE page tables, so they're allocated from the same pool
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct PaePageTable {
+    pub entries: [PaePageTableEntry; ENTRIES_PER_TABLE],
+}
+
+impl Default for PaePageTable {
+    fn default() -> Self {
+        Self {
+            entries: [PaePageTableEntry::new_unused(); ENTRIES_PER_TABLE],
+        }
+    }
+}
+
+/// wrapper for a reference to a PAE page table, see `paging::TableRef`
+#[repr(C)]
+pub struct PaeTableRef<'a> {
+    /// reference to the page table
+    pub table: &'a mut PaePageTable,
+
+    /// whether we allocated this page table and thus can free it
+    pub can_free: bool,
+}
+
+impl fmt::Debug for PaeTableRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PaeTableRef")
+            .field("table", &FormatHex(self.table as *const _ as usize))
+            .field("can_free", &self.can_free)
+            .finish()
+    }
+}
+
+/// the 4 page directories, laid out one after another
+type Directories = [[PaePageDirEntry; ENTRIES_PER_TABLE]; NUM_DIRECTORIES];
+
+/// the page directory pointer table. has to be 32 byte aligned and located below 4 GB
+#[repr(C, align(32))]
+#[derive(Default)]
+pub struct PageDirPointerTable {
+    pub entries: [u64; NUM_DIRECTORIES],
+}
+
+/// pointer tables are only 32 bytes each, so they're packed into slabs instead of each taking up a heap allocation of their own
+static POINTER_TABLES: Mutex<ObjectCache<PageDirPointerTable>> = Mutex::new(ObjectCache::new());
+
+/// contains a reference to the current PAE page directory if one has been set, see `paging::CURRENT_PAGE_DIR`
+static mut CURRENT_PAE_PAGE_DIR: Option<&'static PaePageDir> = None;
+
+/// gets the PAE page directory that was last switched to, as long as the MMU is still using it
+pub(super) fn current_page_dir() -> Option<&'static PaePageDir<'static>> {
+    unsafe { CURRENT_PAE_PAGE_DIR }.filter(|dir| is_page_dir_current(dir))
+}
+
+pub fn is_page_dir_current(page_dir: &PaePageDir) -> bool {
+    page_dir.pointer_table_addr == unsafe { x86::controlregs::cr3() as u32 }
+}
+
+/// creates the page table entry for the given page at the given address, see `paging::page_table_entry_for`.
+/// unlike without PAE, pages that aren't executable can always be represented, they just stay executable on CPUs without NX
+fn page_table_entry_for(addr: usize, page: Option<PageFrame>) -> Result<PaePageTableEntry, PagingError> {
+    let Some(page) = page else {
+        return Ok(PaePageTableEntry::new_unused());
+    };
+
+    let mut entry: PaePageTableEntry = page.try_into().map_err(|_| PagingError::BadFrame)?;
+
+    if page.present && addr >= KERNEL_PAGE_DIR_SPLIT {
+        entry.set_flags(PageTableFlags::from(entry.get_flags()) | PageTableFlags::Global);
+    }
+
+    Ok(entry)
+}
+
+/// x86 PAE PageDirectory implementation
+#[repr(C)]
+pub struct PaePageDir<'a> {
+    /// pointers to page tables
+    pub tables: &'a mut [Option<PaeTableRef<'a>>; NUM_TABLES],
+
+    /// the page directories, which hold the physical addresses of page tables
+    pub directories: &'a mut Directories,
+
+    /// the page directory pointer table, which holds the physical addresses of the page directories
+    pub pointer_table: &'a mut PageDirPointerTable,
+
+    /// physical address of pointer_table
+    pub pointer_table_addr: u32,
+}
+
+impl fmt::Debug for PaePageDir<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PaePageDir {{ tables: {:#x}, directories: {:#x}, pointer_table: {:#x}, pointer_table_addr: {:#x} }}",
+            &self.tables[0] as *const _ as usize, &self.directories[0] as *const _ as usize, self.pointer_table as *const _ as usize, self.pointer_table_addr,
+        )
+    }
+}
+
+impl<'a> PaePageDir<'a> {
+    /// constructs a new PaePageDir, allocating memory for it in the process
+    pub fn new() -> Result<Self, PagingError> {
+        unsafe {
+            let tables_layout = Layout::new::<[Option<PaeTableRef<'a>>; NUM_TABLES]>();
+            let directories_layout = Layout::from_size_align(size_of::<Directories>(), PAGE_SIZE).unwrap();
+
+            let tables = alloc(tables_layout) as *mut [Option<PaeTableRef<'a>>; NUM_TABLES];
+            let directories = alloc_zeroed(directories_layout) as *mut Directories;
+            let pointer_table = POINTER_TABLES.lock().alloc(PageDirPointerTable::default()).map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr());
+
+            let free = move || {
+                for (ptr, layout) in [(tables as *mut u8, tables_layout), (directories as *mut u8, directories_layout)] {
+                    if !ptr.is_null() {
+                        dealloc(ptr, layout);
+                    }
+                }
+
+                if let Some(pointer_table) = NonNull::new(pointer_table) {
+                    POINTER_TABLES.lock().free(pointer_table);
+                }
+            };
+
+            if tables.is_null() || directories.is_null() || pointer_table.is_null() {
+                free();
+                return Err(PagingError::AllocError);
+            }
+
+            // alloc_zeroed prolly doesnt work for this
+            for table_ref in (*tables).iter_mut() {
+                core::ptr::write(table_ref, None);
+            }
+
+            // each directory is its own page, so they aren't necessarily physically contiguous
+            for i in 0..NUM_DIRECTORIES {
+                match current_virt_to_phys(&(*directories)[i] as *const _ as usize) {
+                    Some(phys) => (*pointer_table).entries[i] = (phys & ADDRESS_MASK) | PDPT_PRESENT,
+                    None => {
+                        free();
+                        return Err(PagingError::TableNotInKernel);
+                    }
+                }
+            }
+
+            // cr3 is only 32 bits wide, so the pointer table has to be in the first 4 GB
+            let pointer_table_addr = match current_virt_to_phys(pointer_table as usize).and_then(|phys| u32::try_from(phys).ok()) {
+                Some(phys) => phys + (pointer_table as usize % PAGE_SIZE) as u32,
+                None => {
+                    free();
+                    return Err(PagingError::TableNotInKernel);
+                }
+            };
+
+            Ok(Self {
+                tables: &mut *tables,
+                directories: &mut *directories,
+                pointer_table: &mut *pointer_table,
+                pointer_table_addr,
+            })
+        }
+    }
+
+    /// adds an existing page table to the page directory
+    pub fn add_page_table(&mut self, addr: u32, table: &'a mut PaePageTable, physical_addr: u64, can_free: bool) -> Result<(), PagingError> {
+        let idx = addr as usize / TABLE_SPAN;
+
+        // overwriting a table would leak it along with every page mapped in it, so the caller has to deal with the one it made instead
+        if self.tables[idx].is_some() {
+            error!("refusing to overwrite an existing page table at {:#x} ({:#x})", addr, idx);
+            return Err(PagingError::TableAlreadyPresent);
+        }
+
+        trace!("adding a new PAE page table for virt {:#x} @ {:#x} (phys {:#x})", addr, table as *mut _ as usize, physical_addr);
+
+        let flags = if idx >= KERNEL_PAGE_DIR_SPLIT / TABLE_SPAN {
+            PageDirFlags::Present | PageDirFlags::ReadWrite | PageDirFlags::UserSupervisor | PageDirFlags::Global
+        } else {
+            PageDirFlags::Present | PageDirFlags::ReadWrite | PageDirFlags::UserSupervisor
+        };
+
+        self.directories[idx / ENTRIES_PER_TABLE][idx % ENTRIES_PER_TABLE] = PaePageDirEntry::new(physical_addr, flags);
+        self.tables[idx] = Some(PaeTableRef { table, can_free });
+
+        Ok(())
+    }
+
+    /// removes a page table from the page directory
+    pub fn remove_page_table(&mut self, addr: u32) {
+        let idx = addr as usize / TABLE_SPAN;
+        let table = &mut self.tables[idx];
+
+        if let Some(table_ref) = table.as_mut() {
+            let ptr = table_ref.table as *mut PaePageTable;
+            let can_free = table_ref.can_free;
+
+            *table = None;
+            self.directories[idx / ENTRIES_PER_TABLE][idx % ENTRIES_PER_TABLE].set_unused();
+
+            if can_free {
+                // PAE page tables are the same size as non-PAE ones, so they can go back in the same pool
+                unsafe {
+                    free_page_table(&mut *(ptr as *mut PageTable));
+                }
+            }
+        }
+    }
+
+    /// checks whether we have a page table for this address already, or whether we have to allocate one
+    pub fn has_page_table(&self, addr: u32) -> bool {
+        self.tables[addr as usize / TABLE_SPAN].is_some()
+    }
+}
+
+impl<'a> PageDirectory for PaePageDir<'a> {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+
+    // entries have room for the no-execute bit, it's only left clear on CPUs that can't enable NX
+    const NO_EXECUTE: bool = true;
+
+    fn get_page(&self, addr: usize) -> Option<PageFrame> {
+        let page = addr / PAGE_SIZE;
+
+        let entry = self.tables[page / ENTRIES_PER_TABLE].as_ref()?.table.entries[page % ENTRIES_PER_TABLE];
+
+        if entry.is_unused() {
+            None
+        } else {
+            Some(entry.into())
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        is_page_dir_current(self)
+    }
+
+    fn physical_addr(&self) -> Option<u32> {
+        Some(self.pointer_table_addr)
+    }
+
+    fn is_unused(&self, addr: usize) -> bool {
+        let page = addr / PAGE_SIZE;
+
+        match self.tables[page / ENTRIES_PER_TABLE].as_ref() {
+            Some(table) => table.table.entries[page % ENTRIES_PER_TABLE].is_unused(),
+            None => true,
+        }
+    }
+
+    fn virt_to_phys(&self, virt: usize) -> Option<u64> {
+        let page = virt / PAGE_SIZE;
+
+        let entry = self.tables[page / ENTRIES_PER_TABLE].as_ref()?.table.entries[page % ENTRIES_PER_TABLE];
+
+        if entry.is_unused() || entry.get_flags() & PageTableFlags::Present.bits() == 0 {
+            None
+        } else {
+            Some(entry.get_address())
+        }
+    }
+
+    fn set_page(&mut self, addr: usize, page: Option<PageFrame>) -> Result<(), PagingError> {
+        // work out the entry first so a page that can't be represented doesn't leave a new table behind
+        let entry = page_table_entry_for(addr, page)?;
+
+        let table_idx = addr / TABLE_SPAN;
+        let entry_idx = addr / PAGE_SIZE % ENTRIES_PER_TABLE;
+
+        if self.tables[table_idx].is_none() {
+            let ptr = alloc_page_table();
+
+            if ptr.is_null() {
+                Err(PagingError::AllocError)?;
+            }
+
+            // same as with non-PAE page tables, this has to be in kernel memory for its reference to stay valid
+            let phys = if (ptr as usize) < KERNEL_PAGE_DIR_SPLIT {
+                Err(PagingError::TableNotInKernel)
+            } else {
+                current_virt_to_phys(ptr as usize).ok_or(PagingError::NoCurrentPageDir)
+            };
+
+            let phys = match phys {
+                Ok(phys) => phys,
+                Err(err) => {
+                    error!("couldn't add new PAE page table: {err:?}");
+
+                    unsafe {
+                        free_page_table(&mut *(ptr as *mut PageTable));
+                    }
+
+                    return Err(err);
+                }
+            };
+
+            if let Err(err) = self.add_page_table((table_idx * TABLE_SPAN) as u32, unsafe { &mut *(ptr as *mut PaePageTable) }, phys, true) {
+                unsafe {
+                    free_page_table(&mut *(ptr as *mut PageTable));
+                }
+
+                return Err(err);
+            }
+        }
+
+        self.tables[table_idx].as_mut().unwrap().table.entries[entry_idx] = entry;
+
+        // if that was the last page in this table, give the table back. kernel tables are shared between every page directory so they're never removed
+        if page.is_none() && addr < KERNEL_PAGE_DIR_SPLIT {
+            let table_ref = self.tables[table_idx].as_ref().unwrap();
+
+            if table_ref.can_free && table_ref.table.entries.iter().all(|e| e.is_unused()) {
+                trace!("PAE page table for {:#x} is empty, removing it", addr);
+                self.remove_page_table((table_idx * TABLE_SPAN) as u32);
+            }
+        }
+
+        if is_page_dir_current(self) {
+            unsafe {
+                flush(addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// switches to this page directory. PAE has to have been enabled in cr4 already
+    unsafe fn switch_to(&self) {
+        assert!(self as *const _ as usize >= KERNEL_PAGE_DIR_SPLIT, "current page directory reference isn't in kernel memory");
+
+        trace!("switching to PAE page directory pointer table @ {:#x}", self.pointer_table_addr);
+
+        let flags = super::get_flags();
+
+        asm!(
+            "cli",
+            "mov cr3, {0}",
+
+            in(reg) self.pointer_table_addr,
+        );
+
+        CURRENT_PAE_PAGE_DIR = Some(core::mem::transmute(self));
+
+        super::set_flags(flags);
+    }
+}
+
+impl<'a> Drop for PaePageDir<'a> {
+    fn drop(&mut self) {
+        assert!(!is_page_dir_current(self), "attempted to free current page directory");
+
+        for i in 0..NUM_TABLES {
+            self.remove_page_table((i * TABLE_SPAN) as u32);
+        }
+
+        unsafe {
+            dealloc(self.tables as *mut _ as *mut u8, Layout::new::<[Option<PaeTableRef<'a>>; NUM_TABLES]>());
+            dealloc(self.directories as *mut _ as *mut u8, Layout::from_size_align(size_of::<Directories>(), PAGE_SIZE).unwrap());
+            POINTER_TABLES.lock().free(NonNull::from(&mut *self.pointer_table));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an address in the user half of the address space that won't share a page table with anything else in a new page directory
+    const TEST_ADDR: usize = 0x4000_0000;
+
+    /// a physical address that can't be represented without PAE
+    const HIGH_ADDR: u64 = 0x1_8000_0000;
+
+    fn test_page() -> PageFrame {
+        PageFrame {
+            addr: HIGH_ADDR,
+            present: true,
+            writable: true,
+            executable: true,
+            ..Default::default()
+        }
+    }
+
+    #[test_case]
+    fn entries_keep_addresses_above_4gb() {
+        let entry: PaePageTableEntry = test_page().try_into().unwrap();
+        assert_eq!(entry.get_address(), HIGH_ADDR);
+
+        let page = PageFrame::from(entry);
+        assert_eq!(page.addr, HIGH_ADDR);
+        assert!(page.present && page.writable && page.executable);
+
+        // addresses past what PAE can map are still rejected
+        assert!(PaePageTableEntry::try_from(PageFrame { addr: 1 << 52, ..test_page() }).is_err());
+    }
+
+    #[test_case]
+    fn swapped_entries_round_trip() {
+        let swapped = PageFrame {
+            addr: 0x12345,
+            user_mode: true,
+            writable: true,
+            executable: false,
+            swapped: true,
+            ..Default::default()
+        };
+        let page = PageFrame::from(PaePageTableEntry::try_from(swapped).unwrap());
+
+        assert!(page.swapped && !page.present);
+        assert_eq!(page.addr, 0x12345);
+        assert!(page.user_mode && page.writable && !page.executable);
+    }
+
+    #[test_case]
+    fn no_execute_is_only_set_when_enabled() {
+        let entry = PaePageTableEntry::try_from(PageFrame { executable: false, ..test_page() }).unwrap();
+
+        assert_eq!(entry.0 & NO_EXECUTE != 0, is_nx_enabled());
+        assert_eq!(PageFrame::from(entry).executable, !is_nx_enabled());
+    }
+
+    #[test_case]
+    fn only_present_kernel_pages_are_global() {
+        let is_global = |entry: PaePageTableEntry| entry.get_flags() & PageTableFlags::Global.bits() != 0;
+
+        assert!(is_global(page_table_entry_for(KERNEL_PAGE_DIR_SPLIT, Some(test_page())).unwrap()));
+        assert!(!is_global(page_table_entry_for(TEST_ADDR, Some(test_page())).unwrap()));
+        assert!(page_table_entry_for(KERNEL_PAGE_DIR_SPLIT, None).unwrap().is_unused());
+
+        let zero_fill = PageFrame {
+            zero_fill: true,
+            writable: true,
+            ..Default::default()
+        };
+        assert!(!is_global(page_table_entry_for(KERNEL_PAGE_DIR_SPLIT, Some(zero_fill)).unwrap()));
+    }
+
+    #[test_case]
+    fn pointer_table_points_at_directories() {
+        let dir = PaePageDir::new().unwrap();
+
+        for i in 0..NUM_DIRECTORIES {
+            let phys = current_virt_to_phys(&dir.directories[i] as *const _ as usize).unwrap();
+            assert_eq!(dir.pointer_table.entries[i], phys | PDPT_PRESENT);
+        }
+
+        assert_eq!(dir.physical_addr(), Some(dir.pointer_table_addr));
+        assert_eq!(dir.pointer_table_addr % 32, 0);
+        assert!(!dir.is_current());
+    }
+
+    #[test_case]
+    fn pointer_tables_come_from_the_cache() {
+        let in_use = POINTER_TABLES.lock().in_use();
+
+        let dir = PaePageDir::new().unwrap();
+        assert_eq!(POINTER_TABLES.lock().in_use(), in_use + 1);
+
+        let pointer_table = dir.pointer_table as *const PageDirPointerTable;
+        drop(dir);
+        assert_eq!(POINTER_TABLES.lock().in_use(), in_use);
+
+        // the freed slot is the next one handed out
+        let dir = PaePageDir::new().unwrap();
+        assert_eq!(dir.pointer_table as *const PageDirPointerTable, pointer_table);
+    }
+
+    #[test_case]
+    fn pages_above_4gb_can_be_mapped() {
+        let mut dir = PaePageDir::new().unwrap();
+
+        dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+        assert_eq!(dir.get_page(TEST_ADDR).map(|page| page.addr), Some(HIGH_ADDR));
+        assert_eq!(dir.virt_to_phys(TEST_ADDR), Some(HIGH_ADDR));
+        assert!(!dir.is_unused(TEST_ADDR));
+
+        let table = dir.tables[TEST_ADDR / TABLE_SPAN].as_ref().unwrap().table as *const PaePageTable as usize;
+        let entry = dir.directories[TEST_ADDR / TABLE_SPAN / ENTRIES_PER_TABLE][TEST_ADDR / TABLE_SPAN % ENTRIES_PER_TABLE];
+        assert_eq!(entry.get_address(), current_virt_to_phys(table).unwrap());
+
+        dir.set_page(TEST_ADDR, None).unwrap();
+        assert!(dir.is_unused(TEST_ADDR));
+        assert!(dir.virt_to_phys(TEST_ADDR).is_none());
+    }
+
+    #[test_case]
+    fn empty_page_tables_are_removed() {
+        let mut dir = PaePageDir::new().unwrap();
+
+        dir.set_page(TEST_ADDR, Some(test_page())).unwrap();
+        dir.set_page(TEST_ADDR + PAGE_SIZE, Some(test_page())).unwrap();
+
+        dir.set_page(TEST_ADDR, None).unwrap();
+        assert!(dir.has_page_table(TEST_ADDR as u32));
+
+        dir.set_page(TEST_ADDR + PAGE_SIZE, None).unwrap();
+        assert!(!dir.has_page_table(TEST_ADDR as u32));
+        assert!(dir.directories[TEST_ADDR / TABLE_SPAN / ENTRIES_PER_TABLE][TEST_ADDR / TABLE_SPAN % ENTRIES_PER_TABLE].is_unused());
+    }
+
+    #[test_case]
+    fn bad_frames_dont_leave_tables_behind() {
+        let mut dir = PaePageDir::new().unwrap();
+
+        assert!(matches!(dir.set_page(TEST_ADDR, Some(PageFrame { addr: 1 << 52, ..test_page() })), Err(PagingError::BadFrame)));
+        assert!(!dir.has_page_table(TEST_ADDR as u32));
+    }
+}
@@ -0,0 +1,99 @@
+//! system-wide monotonic clock
+//!
+//! one registered timer is picked as the clock's source, and every tick of it increments a global tick counter that can be read from anywhere.
+//! we don't have 64 bit atomics on i586, so the counter is protected by a seqlock instead
+
+use core::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+use log::debug;
+
+/// the timer that drives the clock, or usize::MAX if there isn't one
+static SOURCE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// sequence counter of the seqlock, odd while the clock is being updated
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// low and high halves of the tick counter
+static TICKS_LOW: AtomicU32 = AtomicU32::new(0);
+static TICKS_HIGH: AtomicU32 = AtomicU32::new(0);
+
+/// how many times per second the clock ticks
+static HZ: AtomicU32 = AtomicU32::new(0);
+
+/// reads a consistent snapshot of the tick counter and tick rate
+fn read() -> (u64, u64) {
+    loop {
+        let start = SEQUENCE.load(Ordering::Acquire);
+
+        if start & 1 != 0 {
+            crate::arch::spin();
+            continue;
+        }
+
+        let ticks = ((TICKS_HIGH.load(Ordering::Relaxed) as u64) << 32) | TICKS_LOW.load(Ordering::Relaxed) as u64;
+        let hz = HZ.load(Ordering::Relaxed) as u64;
+
+        fence(Ordering::Acquire);
+
+        if SEQUENCE.load(Ordering::Relaxed) == start {
+            return (ticks, hz);
+        }
+    }
+}
+
+/// updates the tick counter and tick rate. there must only ever be one writer at a time, which is the source timer's interrupt handler
+/// or [set_source] while there's no source
+fn write(ticks: u64, hz: u64) {
+    SEQUENCE.fetch_add(1, Ordering::Acquire);
+    fence(Ordering::Release);
+
+    TICKS_LOW.store(ticks as u32, Ordering::Relaxed);
+    TICKS_HIGH.store((ticks >> 32) as u32, Ordering::Relaxed);
+    HZ.store(hz.try_into().unwrap_or(u32::MAX), Ordering::Relaxed);
+
+    SEQUENCE.fetch_add(1, Ordering::Release);
+}
+
+/// makes the given timer drive the clock. the counter is rescaled to the new timer's tick rate so the clock's time carries over and never goes backwards
+pub fn set_source(timer: usize, hz: u64) {
+    // stop the old source from ticking the clock while it's being updated
+    SOURCE.store(usize::MAX, Ordering::Release);
+
+    let (ticks, old_hz) = read();
+
+    let ticks = if old_hz == 0 { 0 } else { ((ticks as u128 * hz as u128) / old_hz as u128).try_into().unwrap_or(u64::MAX) };
+
+    write(ticks, hz);
+
+    debug!("clock source is now timer {timer} ({hz} hz)");
+
+    SOURCE.store(timer, Ordering::Release);
+}
+
+/// called whenever the given timer ticks, and increments the clock if that timer is its source
+pub fn tick(timer: usize) {
+    if SOURCE.load(Ordering::Acquire) == timer {
+        let (ticks, hz) = read();
+        write(ticks + 1, hz);
+    }
+}
+
+/// returns how many times the clock has ticked since it started
+pub fn ticks() -> u64 {
+    read().0
+}
+
+/// returns how many times the clock ticks per second, or 0 if it has no source yet
+pub fn ticks_per_second() -> u64 {
+    read().1
+}
+
+/// returns how many seconds the clock has been running for
+pub fn uptime() -> u64 {
+    let (ticks, hz) = read();
+
+    if hz == 0 {
+        0
+    } else {
+        ticks / hz
+    }
+}
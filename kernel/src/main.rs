@@ -6,6 +6,9 @@
 #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
 #![feature(let_chains)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 
@@ -19,15 +22,155 @@ pub mod arch;
 #[path = "platform/ibmpc/mod.rs"]
 pub mod platform;
 
+pub mod clock;
 pub mod mm;
 pub mod task;
 pub mod timer;
 pub mod util;
 
+#[cfg(test)]
+pub mod test;
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use log::error;
 
+/// what the kernel should do once it's panicked
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PanicAction {
+    /// halt the CPU so its state can be inspected (the default)
+    Halt = 0,
+
+    /// reset the machine
+    Reboot = 1,
+
+    /// exit the emulator we're running in with a distinct exit code
+    Exit = 2,
+}
+
+impl PanicAction {
+    /// parses the value of the `panic` command line option
+    pub fn from_cmdline(value: &str) -> Option<Self> {
+        match value {
+            "halt" => Some(Self::Halt),
+            "reboot" => Some(Self::Reboot),
+            "exit" => Some(Self::Exit),
+            _ => None,
+        }
+    }
+}
+
+static PANIC_ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+/// whether we're already panicking, used to detect a panic in the panic handler
+static IS_PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// how many times to spin before rebooting or exiting after a panic, so the panic message has a chance to be seen
+const PANIC_DELAY_SPINS: usize = 0x40000;
+
+/// sets what the kernel should do after it panics
+pub fn set_panic_action(action: PanicAction) {
+    PANIC_ACTION.store(action as u8, Ordering::Release);
+}
+
+/// gets what the kernel should do after it panics
+fn panic_action() -> PanicAction {
+    match PANIC_ACTION.load(Ordering::Acquire) {
+        1 => PanicAction::Reboot,
+        2 => PanicAction::Exit,
+        _ => PanicAction::Halt,
+    }
+}
+
+/// whatever panic actions are carried out on. outside of tests this is always the machine itself, where none of these return
+trait PanicTarget {
+    fn halt(&mut self);
+    fn reboot(&mut self);
+    fn exit(&mut self, code: u8);
+}
+
+struct Machine;
+
+impl PanicTarget for Machine {
+    fn halt(&mut self) {
+        unsafe { arch::halt() }
+    }
+
+    fn reboot(&mut self) {
+        unsafe { arch::reboot() }
+    }
+
+    fn exit(&mut self, code: u8) {
+        unsafe { arch::exit_emulator(code) }
+    }
+}
+
+/// carries out the given panic action on the given target
+fn carry_out_panic_action(action: PanicAction, target: &mut impl PanicTarget) {
+    match action {
+        PanicAction::Halt => target.halt(),
+        PanicAction::Reboot => target.reboot(),
+        PanicAction::Exit => target.exit(arch::PANIC_EXIT_CODE),
+    }
+}
+
+/// performs the given panic action, never returning
+fn do_panic_action(action: PanicAction, delay: bool) -> ! {
+    if delay && action != PanicAction::Halt {
+        for _i in 0..PANIC_DELAY_SPINS {
+            arch::spin();
+        }
+    }
+
+    carry_out_panic_action(action, &mut Machine);
+
+    // none of the machine's panic actions return, but just in case
+    unsafe { arch::halt() }
+}
+
+/// prints out what the given CPU thread was doing when it panicked.
+/// the heap or any lock could be what broke, so this doesn't allocate and gives up on anything that's locked instead of waiting for it
+fn print_panic_context(thread_id: task::cpu::ThreadID) {
+    let Some(thread) = task::get_cpus().and_then(|cpus| cpus.get_thread(thread_id)) else {
+        error!("CPUs not initialized, no task context available");
+        return;
+    };
+
+    let Some(queue) = thread.task_queue.try_lock() else {
+        error!("task queue of CPU {thread_id} is locked, no task context available");
+        return;
+    };
+
+    let Some(current) = queue.current().map(|c| c.id()) else {
+        error!("no task was running on CPU {thread_id}");
+        return;
+    };
+
+    drop(queue);
+
+    error!("current task is {current}");
+
+    let Some(process) = task::try_get_process(current.process) else {
+        error!("process {} is locked or doesn't exist", current.process);
+        return;
+    };
+
+    if let Some(thread) = process.threads.get(current.thread as usize) {
+        error!("last saved registers: {:#?}", thread.register_queue.current().registers);
+    }
+
+    error!("user space mappings of process {}:\n{}", current.process, process.page_directory.task.user_mappings());
+}
+
 #[panic_handler]
 pub fn panic_implementation(info: &core::panic::PanicInfo) -> ! {
+    let action = panic_action();
+
+    // if we panicked while panicking, things are probably too broken to print anything, so just get out of here
+    if IS_PANICKING.swap(true, Ordering::AcqRel) {
+        do_panic_action(action, false);
+    }
+
     let thread_id = arch::get_thread_id();
 
     let (file, line) = match info.location() {
@@ -46,7 +189,60 @@ pub fn panic_implementation(info: &core::panic::PanicInfo) -> ! {
     // send NMI to all other CPUs, which should halt them
     task::nmi_all_other_cpus();
 
-    unsafe {
-        arch::halt();
+    print_panic_context(thread_id);
+
+    do_panic_action(action, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// records what panic actions were carried out instead of carrying them out
+    #[derive(Default)]
+    struct MockTarget {
+        halted: bool,
+        rebooted: bool,
+        exit_code: Option<u8>,
+    }
+
+    impl PanicTarget for MockTarget {
+        fn halt(&mut self) {
+            self.halted = true;
+        }
+
+        fn reboot(&mut self) {
+            self.rebooted = true;
+        }
+
+        fn exit(&mut self, code: u8) {
+            self.exit_code = Some(code);
+        }
+    }
+
+    #[test_case]
+    fn panic_exit_writes_exit_code() {
+        // the test runner relies on panics exiting, so put that back when we're done
+        let previous = panic_action();
+
+        set_panic_action(PanicAction::from_cmdline("exit").unwrap());
+
+        let mut target = MockTarget::default();
+        carry_out_panic_action(panic_action(), &mut target);
+
+        assert_eq!(target.exit_code, Some(arch::PANIC_EXIT_CODE));
+        assert!(!target.halted && !target.rebooted);
+
+        set_panic_action(PanicAction::from_cmdline("halt").unwrap());
+
+        let mut target = MockTarget::default();
+        carry_out_panic_action(panic_action(), &mut target);
+
+        assert!(target.halted);
+        assert_eq!(target.exit_code, None);
+
+        assert_eq!(PanicAction::from_cmdline("explode"), None);
+
+        set_panic_action(previous);
     }
 }
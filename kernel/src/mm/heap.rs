@@ -151,10 +151,14 @@ impl<'a> AllocState<'a> {
     /// allocate reserved memory
     unsafe fn alloc_reserved(&mut self) {
         trace!("allocating reserved memory");
-        self.reserved_area = Some(core::slice::from_raw_parts(
-            self.heap.allocate_first_fit(self.reserved_layout.unwrap()).expect("couldn't allocate reserved memory").as_ptr(),
-            self.reserved_layout.unwrap().size(),
-        ));
+        self.reserved_area = match self.heap.allocate_first_fit(self.reserved_layout.unwrap()) {
+            Ok(ptr) => Some(core::slice::from_raw_parts(ptr.as_ptr(), self.reserved_layout.unwrap().size())),
+            Err(_) => {
+                // without reserved memory the heap can't be expanded anymore, but that's better than panicking here
+                error!("couldn't allocate reserved memory");
+                None
+            }
+        };
     }
 
     /// free reserved memory
@@ -206,6 +210,40 @@ impl<'a> AllocState<'a> {
         }
     }
 
+    /// expands the heap until its top is at least at the given address, using the expand callback
+    fn expand(&mut self, new_top: usize) -> Result<(), ()> {
+        loop {
+            // allocate memory to expand the heap
+            let new_top_2 = (self.expand_callback)(self.heap.top(), new_top, &Self::external_alloc, &Self::external_dealloc)?;
+
+            // sanity check
+            if new_top_2 <= self.heap.top() {
+                error!("heap didn't expand");
+                Err(())?
+            }
+
+            // expand the heap
+            unsafe {
+                self.heap.extend(new_top_2 - self.heap.top());
+            }
+
+            // if the target top address hasn't been reached but we've at least been able to expand a little bit, just try again
+            // the heap has been expanded so the callback will be able to have more memory to work with
+            // we can do this as many times as we want (tho we probably shouldn't)
+            // TODO: maybe figure out some way to limit this?
+            if new_top_2 < new_top {
+                debug!("heap didn't expand enough (need top {:#x}, got {:#x}), trying again", new_top, new_top_2);
+            } else {
+                // expand callback has finished
+                break;
+            }
+        }
+
+        trace!("heap is now {:#x} - {:#x}", self.heap.bottom(), self.heap.top());
+
+        Ok(())
+    }
+
     fn _alloc(&mut self, layout: Layout) -> Result<*mut u8, ()> {
         match self.heap.allocate_first_fit(layout) {
             Ok(allocation) => Ok(allocation.as_ptr()),
@@ -231,40 +269,15 @@ impl<'a> AllocState<'a> {
                     (new_top / align) * align + align + layout.size() // heap top aligned to reserved layout align and alloc align plus alloc size
                 };
 
-                loop {
-                    // allocate memory to expand the heap
-                    let new_top_2 = (self.expand_callback)(self.heap.top(), new_top, &Self::external_alloc, &Self::external_dealloc)?;
-
-                    // sanity check
-                    if new_top_2 <= self.heap.top() {
-                        error!("heap didn't expand");
-                        Err(())?
-                    }
-
-                    // expand the heap
-                    unsafe {
-                        self.heap.extend(new_top_2 - self.heap.top());
-                    }
-
-                    // if the target top address hasn't been reached but we've at least been able to expand a little bit, just try again
-                    // the heap has been expanded so the callback will be able to have more memory to work with
-                    // we can do this as many times as we want (tho we probably shouldn't)
-                    // TODO: maybe figure out some way to limit this?
-                    if new_top_2 < new_top {
-                        debug!("heap didn't expand enough (need top {:#x}, got {:#x}), trying again", new_top, new_top_2);
-                    } else {
-                        // break out of the loop, expand callback has finished
-                        break;
-                    }
-                }
-
-                trace!("heap is now {:#x} - {:#x}", self.heap.bottom(), self.heap.top());
+                let expanded = self.expand(new_top);
 
-                // allocate new reserved memory
+                // allocate new reserved memory. this has to happen even if expanding failed, otherwise the next expansion would free memory that's already been freed
                 unsafe {
                     self.alloc_reserved();
                 }
 
+                expanded?;
+
                 trace!("trying allocation again");
 
                 // try allocating again
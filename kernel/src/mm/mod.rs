@@ -4,4 +4,6 @@ pub mod bump_alloc;
 pub mod heap;
 pub mod paging;
 pub mod shared;
+pub mod slab;
+pub mod swap;
 pub mod sync;
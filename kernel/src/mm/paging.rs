@@ -1,17 +1,21 @@
 //! paging abstraction layer
 
-use super::sync::PageDirTracker;
+use super::sync::{PageDirTracker, PageUpdateBatch};
 use crate::{
     mm::sync::MutexedPageDir,
     util::{array::BitSet, debug::FormatHex},
 };
 use alloc::{
     alloc::{alloc, dealloc, Layout},
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     vec::Vec,
 };
 use common::types::Errno;
-use core::fmt;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use lazy_static::lazy_static;
 use log::{debug, error, trace};
 use spin::{Mutex, MutexGuard};
@@ -24,6 +28,10 @@ pub enum PagingError {
     AllocError,
     BadFrame,
     BadAddress,
+    NoCurrentPageDir,
+    TableNotInKernel,
+    TableAlreadyPresent,
+    Unsupported,
 }
 
 impl fmt::Debug for PagingError {
@@ -35,6 +43,10 @@ impl fmt::Debug for PagingError {
             Self::AllocError => "error allocating memory",
             Self::BadFrame => "bad frame",
             Self::BadAddress => "address not mapped",
+            Self::NoCurrentPageDir => "no current page directory",
+            Self::TableNotInKernel => "page table isn't mapped into kernel memory",
+            Self::TableAlreadyPresent => "a page table is already present there",
+            Self::Unsupported => "not supported by this page directory",
         })
     }
 }
@@ -43,13 +55,14 @@ impl From<PagingError> for Errno {
     fn from(err: PagingError) -> Errno {
         match err {
             PagingError::BadAddress => Errno::BadAddress,
+            PagingError::Unsupported => Errno::NotSupported,
             _ => Errno::OutOfMemory,
         }
     }
 }
 
 /// hardware agnostic form of a page frame
-#[derive(Default, Copy, Clone)]
+#[derive(Copy, Clone)]
 pub struct PageFrame {
     /// physical address of this page frame
     ///
@@ -70,7 +83,8 @@ pub struct PageFrame {
     /// whether this page should be copied upon attempting to write to it (requires writable flag to be disabled)
     pub copy_on_write: bool,
 
-    /// whether code can be executed from this page. not supported on all platforms
+    /// whether code can be executed from this page. page directories that can't stop code from being executed (see [PageDirectory::NO_EXECUTE])
+    /// refuse present pages that aren't executable
     pub executable: bool,
 
     /// whether this page has more than one reference and its freeing should be handled by the reference counter
@@ -78,6 +92,34 @@ pub struct PageFrame {
 
     /// whether this page has been shared from another process
     pub shared: bool,
+
+    /// whether this page is a guard page. guard pages are never present, and are used to catch things like stack overflows
+    pub guard: bool,
+
+    /// whether this page has been swapped out. swapped out pages are never present, and their address is the slot in the swap device they were written to
+    pub swapped: bool,
+
+    /// whether this page is mapped in as a page full of zeroes the first time it's touched. these pages are never present, and have no memory behind them until then
+    pub zero_fill: bool,
+}
+
+impl Default for PageFrame {
+    /// pages are executable unless asked otherwise, since not every page directory can stop code from being executed
+    fn default() -> Self {
+        Self {
+            addr: 0,
+            present: false,
+            user_mode: false,
+            writable: false,
+            copy_on_write: false,
+            executable: true,
+            referenced: false,
+            shared: false,
+            guard: false,
+            swapped: false,
+            zero_fill: false,
+        }
+    }
 }
 
 impl fmt::Debug for PageFrame {
@@ -90,6 +132,10 @@ impl fmt::Debug for PageFrame {
             .field("copy_on_write", &self.copy_on_write)
             .field("executable", &self.executable)
             .field("referenced", &self.referenced)
+            .field("shared", &self.shared)
+            .field("guard", &self.guard)
+            .field("swapped", &self.swapped)
+            .field("zero_fill", &self.zero_fill)
             .finish()
     }
 }
@@ -98,6 +144,10 @@ impl fmt::Debug for PageFrame {
 pub trait PageDirectory {
     const PAGE_SIZE: usize;
 
+    /// whether pages can be mapped so code can't be executed from them. if not, [PageDirectory::set_page] fails with [PagingError::Unsupported]
+    /// when given a present page that isn't executable
+    const NO_EXECUTE: bool;
+
     /* -= Required functions -= */
 
     /// given a virtual address, get the page that contains it from this directory in a hardware agnostic form
@@ -105,6 +155,10 @@ pub trait PageDirectory {
 
     /// insert a page frame into the directory
     ///
+    /// this may allocate a new page table if there isn't one covering the address yet. page directories don't synchronize themselves,
+    /// so anything that can be touched by multiple CPUs at once has to be behind a lock that's held for the whole call
+    /// (i.e. the kernel's page directory is only ever modified thru the mutex in [get_kernel_page_dir])
+    ///
     /// # Arguments
     ///
     /// * `addr` - the virtual address to insert the page frame at
@@ -120,6 +174,50 @@ pub trait PageDirectory {
 
     /* -= Non required functions =- */
 
+    /// sets a page like [PageDirectory::set_page], but adds the TLB shootdown for it to the given batch instead of sending it right away.
+    /// whatever's left in the batch is sent by [PageDirectory::flush_batch], which [super::sync::BatchedPageDir] takes care of
+    fn set_page_batched(&mut self, addr: usize, page: Option<PageFrame>, _batch: &mut PageUpdateBatch) -> Result<(), PagingError> {
+        self.set_page(addr, page)
+    }
+
+    /// sends any TLB shootdowns held back in the given batch
+    fn flush_batch(&mut self, _batch: &mut PageUpdateBatch) {}
+
+    /// gets the executable flag for a page that only has to be executable if `executable` is set. without [PageDirectory::NO_EXECUTE] every
+    /// present page has to be executable, so this is for callers that only want to stop execution where it's possible
+    fn executable_if(&self, executable: bool) -> bool {
+        executable || !Self::NO_EXECUTE
+    }
+
+    /// counts how many pages below the given address are resident (present) and reserved (mapped at all, even if they're swapped out or guard pages), in that order
+    fn count_pages(&self, end: usize) -> (usize, usize) {
+        let mut resident = 0;
+        let mut reserved = 0;
+
+        for addr in (0..end).step_by(Self::PAGE_SIZE) {
+            if let Some(page) = self.get_page(addr) {
+                reserved += 1;
+
+                if page.present {
+                    resident += 1;
+                }
+            }
+        }
+
+        (resident, reserved)
+    }
+
+    /// checks whether this is the page directory the MMU is currently using. page directories that can't tell assume they are
+    fn is_current(&self) -> bool {
+        true
+    }
+
+    /// the physical address the MMU is given to load this page directory, so other CPUs can check whether they have it loaded
+    /// without needing access to the directory itself. page directories that can't tell return None
+    fn physical_addr(&self) -> Option<u32> {
+        None
+    }
+
     /// given an address, checks whether the page that contains it is unused and can be freely remapped
     fn is_unused(&self, addr: usize) -> bool {
         self.get_page(addr).is_none()
@@ -133,6 +231,39 @@ pub trait PageDirectory {
 
         self.get_page(page_addr).map(|page| page.addr | offset as u64)
     }
+
+    /// transforms the provided range of virtual addresses in this page directory into runs of physically contiguous memory,
+    /// as `(phys_base, byte_len)` pairs in order. useful for building scatter/gather lists for DMA
+    ///
+    /// returns an error if any page in the range isn't present
+    fn virt_to_phys_range(&self, start: usize, len: usize) -> Result<Vec<(u64, usize)>, PagingError> {
+        let end = start.checked_add(len).ok_or(PagingError::BadAddress)?;
+        let mut runs: Vec<(u64, usize)> = Vec::new();
+        let mut addr = start;
+
+        while addr < end {
+            let offset = addr & (Self::PAGE_SIZE - 1);
+            let chunk_len = (Self::PAGE_SIZE - offset).min(end - addr);
+
+            let phys = match self.get_page(addr - offset) {
+                Some(page) if page.present => page.addr + offset as u64,
+                _ => return Err(PagingError::BadAddress),
+            };
+
+            match runs.last_mut() {
+                // this chunk picks up right where the last one left off, so it can be merged into it
+                Some((base, run_len)) if *base + *run_len as u64 == phys => *run_len += chunk_len,
+                _ => {
+                    runs.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+                    runs.push((phys, chunk_len));
+                }
+            }
+
+            addr += chunk_len;
+        }
+
+        Ok(runs)
+    }
 }
 
 /// when run on the current page directory, this function maps the range `addr..addr + len` from the page table given in `from`
@@ -153,6 +284,31 @@ pub trait PageDirectory {
 /// page directory, and things can and will break if it's called on any other page directory
 pub unsafe fn map_memory_from<D: PageDirectory, O, R>(map_into: &mut D, from: &mut impl PageDirectory, addr: usize, len: usize, op: O) -> Result<R, PagingError>
 where O: FnOnce(&mut [u8]) -> R {
+    let (addresses, offset) = region_addresses::<D>(from, addr, len)?;
+
+    // map the memory
+    map_memory(map_into, &addresses, |s| op(&mut s[offset..offset + len]))
+}
+
+/// gets the page at the given address in the given page directory, bringing it back in first if it's been swapped out
+/// and giving it memory first if it's a zero fill page that hasn't been touched yet
+fn resident_page(page_dir: &mut impl PageDirectory, addr: usize) -> Result<Option<PageFrame>, PagingError> {
+    loop {
+        match page_dir.get_page(addr) {
+            Some(page) if page.swapped && !page.present => {
+                // this does nothing if something else is in the middle of swapping the page in or out, so wait for it to finish
+                super::swap::swap_in(page_dir, addr)?;
+                crate::arch::spin();
+            }
+            Some(page) if page.zero_fill && !page.present => return fill_zeroed_page(page_dir, addr, page).map(Some),
+            page => return Ok(page),
+        }
+    }
+}
+
+/// gets the physical addresses of all the pages in the range `addr..addr + len` in the given page directory, copying any copy on write pages
+/// and swapping in any swapped out pages first, along with the offset of `addr` into the first page
+fn region_addresses<D: PageDirectory>(from: &mut impl PageDirectory, addr: usize, len: usize) -> Result<(Vec<u64>, usize), PagingError> {
     let page_size = D::PAGE_SIZE;
 
     // get starting and ending addresses
@@ -182,17 +338,23 @@ where O: FnOnce(&mut [u8]) -> R {
 
     // attempt to safely reserve memory
     if let Err(err) = addresses.try_reserve_exact((end - start) / page_size) {
-        error!("error reserving memory in map_memory_from(): {err:?}");
+        error!("error reserving memory in region_addresses(): {err:?}");
 
         return Err(PagingError::AllocError);
     }
 
     // get physical addresses of this region
     for i in (start..=end).step_by(page_size) {
-        let phys_addr = match from.get_page(i) {
+        let phys_addr = match resident_page(from, i)? {
+            // guard pages and the like have no memory behind them, and whatever their address is doesn't point to any
+            Some(page) if !page.present => {
+                debug!("page @ {i:#x} isn't present");
+
+                return Err(PagingError::BadAddress);
+            }
             Some(page) => {
-                if !page.writable && page.copy_on_write && page.referenced {
-                    copy_on_write(from, addr, page)?.addr
+                if needs_copy_on_write(&page) {
+                    copy_on_write(from, i, page)?.addr
                 } else {
                     page.addr
                 }
@@ -209,12 +371,113 @@ where O: FnOnce(&mut [u8]) -> R {
 
     trace!("addresses: {addresses:x?}");
 
-    // map the memory
-    map_memory(map_into, &addresses, |s| op(&mut s[offset..offset + len]))
+    Ok((addresses, offset))
+}
+
+/// how many pages each CPU's scratch window for `map_memory` can hold
+pub const SCRATCH_WINDOW_PAGES: usize = 64;
+
+/// maps the given physical addresses in order into the kernel memory starting at `base`.
+/// every page is attempted even if one fails, and the first error is returned
+unsafe fn remap_pages<D: PageDirectory>(map_into: &mut D, base: usize, addresses: &[u64]) -> Result<(), PagingError> {
+    let mut result = Ok(());
+
+    for (i, phys_addr) in addresses.iter().enumerate() {
+        let page = PageFrame {
+            addr: *phys_addr,
+            present: true,
+            writable: true,
+            ..Default::default()
+        };
+
+        if let Err(err) = map_into.set_page(base + i * D::PAGE_SIZE, Some(page)) {
+            error!("couldn't remap page {:#x} to {phys_addr:#x}: {err:?}", base + i * D::PAGE_SIZE);
+            result = result.and(Err(err));
+        }
+    }
+
+    result
+}
+
+/// a region of kernel memory reserved for `map_memory` on a single CPU, so small mappings don't have to go thru the heap every time
+pub struct ScratchWindow {
+    /// virtual address of the start of the window
+    addr: usize,
+
+    /// size of the pages in this window
+    page_size: usize,
+
+    /// physical addresses the pages in the window are mapped to when they aren't being used
+    original: [u64; SCRATCH_WINDOW_PAGES],
+}
+
+impl fmt::Debug for ScratchWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScratchWindow")
+            .field("addr", &FormatHex(self.addr))
+            .field("page_size", &FormatHex(self.page_size))
+            .finish_non_exhaustive()
+    }
+}
+
+impl ScratchWindow {
+    /// allocates a new scratch window on the heap
+    fn new<D: PageDirectory>(map_into: &D) -> Option<Self> {
+        let page_size = D::PAGE_SIZE;
+        let layout = Layout::from_size_align(SCRATCH_WINDOW_PAGES * page_size, page_size).unwrap();
+        let ptr = unsafe { alloc(layout) };
+
+        if ptr.is_null() {
+            error!("error allocating scratch window");
+            return None;
+        }
+
+        let mut original = [0; SCRATCH_WINDOW_PAGES];
+
+        for (i, phys) in original.iter_mut().enumerate() {
+            match map_into.virt_to_phys(ptr as usize + i * page_size) {
+                Some(addr) => *phys = addr,
+                None => {
+                    unsafe {
+                        dealloc(ptr, layout);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        debug!("allocated scratch window @ {:#x}", ptr as usize);
+
+        Some(Self { addr: ptr as usize, page_size, original })
+    }
+
+    /// maps the given physical addresses into this window, calls `op` with a slice over them, then puts the window back the way it was
+    unsafe fn map<D: PageDirectory, O, R>(&self, map_into: &mut D, addresses: &[u64], op: O) -> Result<R, PagingError>
+    where O: FnOnce(&mut [u8]) -> R {
+        let original = &self.original[..addresses.len()];
+
+        for phys_addr in addresses.iter() {
+            assert!(!self.original.contains(phys_addr), "trampling on other page directory's memory");
+        }
+
+        if let Err(err) = remap_pages(map_into, self.addr, addresses) {
+            // put back whatever did get mapped before giving up
+            remap_pages(map_into, self.addr, original)?;
+            return Err(err);
+        }
+
+        let res = op(core::slice::from_raw_parts_mut(self.addr as *mut u8, addresses.len() * self.page_size));
+
+        remap_pages(map_into, self.addr, original)?;
+
+        Ok(res)
+    }
 }
 
 /// maps the given physical addresses in order into a region of memory allocated on the heap, then calls `op` with a slice over all the mapped memory
 ///
+/// mappings of up to `SCRATCH_WINDOW_PAGES` pages use the current CPU's scratch window instead of allocating, as long as it isn't already in use
+///
 /// # Arguments
 ///
 /// * `addresses` - a list of physical addresses to map into memory in order
@@ -228,100 +491,213 @@ pub unsafe fn map_memory<D: PageDirectory, O, R>(map_into: &mut D, addresses: &[
 where O: FnOnce(&mut [u8]) -> R {
     let page_size = D::PAGE_SIZE;
 
-    let buf_len = addresses.len() * page_size;
-
-    // allocate memory for us to remap
-    let layout = Layout::from_size_align(buf_len, page_size).unwrap();
-    let ptr = alloc(layout);
+    // the window is per CPU so nothing else can be using it, unless map_memory() is being called recursively or from an interrupt handler
+    if addresses.len() <= SCRATCH_WINDOW_PAGES {
+        if let Some(mut window) = crate::task::get_cpus().and_then(|cpus| cpus.get_thread(crate::arch::get_thread_id())).and_then(|thread| thread.scratch_window.try_lock()) {
+            if window.is_none() {
+                *window = ScratchWindow::new(map_into);
+            }
 
-    if ptr.is_null() {
-        error!("error allocating buffer in map_memory()");
-        return Err(PagingError::AllocError);
+            if let Some(window) = window.as_ref().filter(|window| window.page_size == page_size) {
+                return window.map(map_into, addresses, op);
+            }
+        }
     }
 
-    assert!(ptr as usize % page_size == 0); // make absolutely sure pointer is page aligned
+    let mut region = MappedRegion::map(map_into, addresses)?;
 
-    debug!("mapping {} pages to {:#x} (kernel mem)", addresses.len(), ptr as usize);
+    Ok(op(&mut *region))
+}
 
-    // get addresses of pages we're gonna remap so we can map them back later
-    let mut existing_phys: Vec<u64> = Vec::new();
+/// a region of memory mapped into the kernel's address space, which stays mapped for as long as this is held and is unmapped when it's dropped.
+/// derefs to a slice over the mapped memory
+///
+/// this is the same as [map_memory] and [map_memory_from], except the mapping can be used across any number of statements instead of in a single closure
+pub struct MappedRegion<'a, D: PageDirectory> {
+    /// the page directory the memory is mapped into
+    map_into: &'a mut D,
 
-    // attempt to safely reserve memory for our mapping
-    if let Err(err) = existing_phys.try_reserve_exact(addresses.len()) {
-        error!("error reserving memory in map_memory(): {err:?}");
-        dealloc(ptr, layout);
+    /// the buffer on the heap the memory is mapped over
+    ptr: *mut u8,
 
-        return Err(PagingError::AllocError);
+    /// layout of the buffer on the heap
+    layout: Layout,
+
+    /// physical addresses the buffer's pages were mapped to before, so they can be put back
+    existing_phys: Vec<u64>,
+
+    /// offset of the start of the slice into the mapped memory
+    offset: usize,
+
+    /// length of the slice
+    len: usize,
+}
+
+impl<'a, D: PageDirectory> MappedRegion<'a, D> {
+    /// maps the given physical addresses in order into a region of memory allocated on the heap
+    ///
+    /// # Safety
+    ///
+    /// the page directory must be the current one, which is checked where possible. the mapped memory can be anything,
+    /// so nothing else can be relying on its contents staying the same while it's mapped
+    pub unsafe fn new(map_into: &'a mut D, addresses: &[u64]) -> Result<Self, PagingError> {
+        assert!(map_into.is_current(), "can't map memory into a page directory that isn't the current one");
+
+        Self::map(map_into, addresses)
     }
 
-    for i in (ptr as usize..ptr as usize + buf_len).step_by(page_size) {
-        // virt to phys calculation from current page directory
-        let addr = match map_into.virt_to_phys(i) {
-            Some(a) => a,
-            None => {
-                // something bad happened, revert back to original state and return an error
-                debug!("aborting map (before remap), dealloc()ing");
-                dealloc(ptr, layout);
+    /// maps the range `addr..addr + len` from the page table given in `from` into a region of memory allocated on the heap, like [map_memory_from].
+    /// the slice only covers the requested range, not the whole pages it's in
+    ///
+    /// # Safety
+    ///
+    /// same as [MappedRegion::new]
+    pub unsafe fn new_from(map_into: &'a mut D, from: &mut impl PageDirectory, addr: usize, len: usize) -> Result<Self, PagingError> {
+        assert!(map_into.is_current(), "can't map memory into a page directory that isn't the current one");
 
-                return Err(PagingError::BadAddress);
-            }
-        };
-        trace!("existing: {i:#x} -> {addr:#x}");
-        existing_phys.push(addr);
+        let (addresses, offset) = region_addresses::<D>(from, addr, len)?;
+        let mut region = Self::map(map_into, &addresses)?;
+
+        region.offset = offset;
+        region.len = len;
+
+        Ok(region)
     }
 
-    trace!("existing_phys: {existing_phys:x?}");
+    /// does the actual mapping, without checking whether the page directory is current
+    unsafe fn map(map_into: &'a mut D, addresses: &[u64]) -> Result<Self, PagingError> {
+        let page_size = D::PAGE_SIZE;
+        let buf_len = addresses.len() * page_size;
 
-    // remap all pages in region
-    for (i, phys_addr) in addresses.iter().enumerate() {
-        let virt = ptr as usize + i * page_size;
+        // allocate memory for us to remap
+        let layout = Layout::from_size_align(buf_len, page_size).unwrap();
+        let ptr = alloc(layout);
+
+        if ptr.is_null() {
+            error!("error allocating buffer in map_memory()");
+            return Err(PagingError::AllocError);
+        }
+
+        assert!(ptr as usize % page_size == 0); // make absolutely sure pointer is page aligned
+
+        debug!("mapping {} pages to {:#x} (kernel mem)", addresses.len(), ptr as usize);
 
-        trace!("{virt:x} now @ phys addr: {phys_addr:x}");
+        // get addresses of pages we're gonna remap so we can map them back later
+        let mut existing_phys: Vec<u64> = Vec::new();
 
-        // todo: maybe change this to debug_assert at some point? its prolly hella slow
-        assert!(!existing_phys.contains(phys_addr), "trampling on other page directory's memory");
+        // attempt to safely reserve memory for our mapping
+        if let Err(err) = existing_phys.try_reserve_exact(addresses.len()) {
+            error!("error reserving memory in map_memory(): {err:?}");
+            dealloc(ptr, layout);
 
-        // remap memory
-        map_into
-            .set_page(
-                virt,
-                Some(PageFrame {
-                    addr: *phys_addr,
-                    present: true,
-                    writable: true,
-                    ..Default::default()
-                }),
-            )
-            .expect("couldn't remap page");
+            return Err(PagingError::AllocError);
+        }
+
+        for i in (ptr as usize..ptr as usize + buf_len).step_by(page_size) {
+            // virt to phys calculation from current page directory
+            let addr = match map_into.virt_to_phys(i) {
+                Some(a) => a,
+                None => {
+                    // something bad happened, revert back to original state and return an error
+                    debug!("aborting map (before remap), dealloc()ing");
+                    dealloc(ptr, layout);
+
+                    return Err(PagingError::BadAddress);
+                }
+            };
+            trace!("existing: {i:#x} -> {addr:#x}");
+            existing_phys.push(addr);
+        }
+
+        trace!("existing_phys: {existing_phys:x?}");
+
+        // sorted copy of the existing addresses so checking for trampling doesn't have to scan all of them for every page
+        let mut sorted_phys: Vec<u64> = Vec::new();
+
+        if let Err(err) = sorted_phys.try_reserve_exact(existing_phys.len()) {
+            error!("error reserving memory in map_memory(): {err:?}");
+            dealloc(ptr, layout);
+
+            return Err(PagingError::AllocError);
+        }
+
+        sorted_phys.extend_from_slice(&existing_phys);
+        sorted_phys.sort_unstable();
+
+        for phys_addr in addresses.iter() {
+            assert!(sorted_phys.binary_search(phys_addr).is_err(), "trampling on other page directory's memory");
+        }
+
+        trace!("{:x} now @ phys addrs: {addresses:x?}", ptr as usize);
+
+        // remap all pages in region
+        if let Err(err) = remap_pages(map_into, ptr as usize, addresses) {
+            debug!("aborting map (after remap), restoring original pages");
+
+            // the buffer can only go back on the heap if it's backed by its own memory again
+            if remap_pages(map_into, ptr as usize, &existing_phys).is_ok() {
+                dealloc(ptr, layout);
+            }
+
+            return Err(err);
+        }
+
+        trace!("slice @ {ptr:?}, len {buf_len:#x}");
+
+        Ok(Self {
+            map_into,
+            ptr,
+            layout,
+            existing_phys,
+            offset: 0,
+            len: buf_len,
+        })
     }
+}
 
-    trace!("slice @ {ptr:?}, len {buf_len:#x}");
+impl<D: PageDirectory> Deref for MappedRegion<'_, D> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { core::slice::from_raw_parts(self.ptr.add(self.offset), self.len) }
+    }
+}
 
-    // call function
-    let res = op(core::slice::from_raw_parts_mut(ptr as *mut u8, buf_len));
+impl<D: PageDirectory> DerefMut for MappedRegion<'_, D> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr.add(self.offset), self.len) }
+    }
+}
 
-    // map pages back to their original addresses
-    trace!("cleaning up mapping");
-    for (idx, addr) in (ptr as usize..ptr as usize + buf_len).step_by(page_size).enumerate() {
-        let phys_addr = existing_phys[idx];
-        trace!("virt @ {addr:x}, phys @ {phys_addr:x}");
-        map_into
-            .set_page(
-                addr,
-                Some(PageFrame {
-                    addr: phys_addr,
-                    present: true,
-                    writable: true,
-                    ..Default::default()
-                }),
-            )
-            .expect("couldn't remap page");
+impl<D: PageDirectory> MappedRegion<'_, D> {
+    /// unmaps this region, returning an error if the buffer's original pages couldn't be put back.
+    /// if that happens the buffer is leaked, since whatever's mapped over it can't be handed back to the heap
+    pub fn unmap(self) -> Result<(), PagingError> {
+        let mut region = core::mem::ManuallyDrop::new(self);
+        region.restore()
     }
 
-    // deallocate the buffer
-    dealloc(ptr, layout);
+    /// maps the buffer's pages back to their original addresses and deallocates it
+    fn restore(&mut self) -> Result<(), PagingError> {
+        trace!("cleaning up mapping");
+        unsafe {
+            remap_pages(self.map_into, self.ptr as usize, &self.existing_phys)?;
+            dealloc(self.ptr, self.layout);
+        }
+
+        // the vec has to be dropped by hand if this is being called from unmap()
+        self.existing_phys = Vec::new();
+
+        Ok(())
+    }
+}
 
-    Ok(res)
+impl<D: PageDirectory> Drop for MappedRegion<'_, D> {
+    fn drop(&mut self) {
+        if let Err(err) = self.restore() {
+            error!("couldn't unmap region @ {:#x}, leaking it: {err:?}", self.ptr as usize);
+        }
+    }
 }
 
 /// finds available area in this page directory's memory of given size. this area is guaranteed to be unused, unallocated, and aligned to a page boundary
@@ -337,20 +713,18 @@ pub fn find_hole<D: PageDirectory>(page_dir: &D, start: usize, end: usize, size:
     assert!(start % page_size == 0, "start address is not page aligned");
     assert!(end % page_size == 0, "end address is not page aligned");
 
-    let size = (size / page_size) * page_size + page_size;
+    // a zero sized hole still has to take up a page to be of any use
+    let size = size.div_ceil(page_size).max(1) * page_size;
 
     let mut hole_start: Option<usize> = None;
 
     for addr in (start..end).step_by(page_size) {
         if page_dir.is_unused(addr) {
-            if let Some(start) = hole_start {
-                if addr - start >= size {
-                    return hole_start;
-                }
-            /*} else if size <= page_size && addr >= start {
-            return Some(addr);*/
-            } else if hole_start.is_none() && addr >= start {
-                hole_start = Some(addr);
+            let hole = *hole_start.get_or_insert(addr);
+
+            // the hole extends to the end of this page
+            if addr + page_size - hole >= size {
+                return Some(hole);
             }
         } else {
             hole_start = None;
@@ -372,6 +746,11 @@ pub struct PageManager {
 
     /// the page size of this page manager
     pub page_size: usize,
+
+    /// index of the frame to start searching for free frames at
+    ///
+    /// this is moved past every frame we allocate and back down whenever a frame below it is freed, so we don't have to rescan the start of memory every time
+    next_frame: usize,
 }
 
 impl PageManager {
@@ -381,13 +760,19 @@ impl PageManager {
     ///
     /// * `frame_set` - a BitSet that stores which pages are available and which arent. should be created based on the system's memory map
     pub fn new(frame_set: BitSet, page_size: usize) -> Self {
-        Self { frame_set, page_size }
+        Self { frame_set, page_size, next_frame: 0 }
+    }
+
+    /// finds the index of the next free frame, starting at our cursor and wrapping around to the start of memory if need be
+    fn next_free_frame(&self) -> Option<usize> {
+        self.frame_set.first_unset_from(self.next_frame).or_else(|| self.frame_set.first_unset_from(0))
     }
 
     /// allocates a frame in memory, returning its physical address without assigning it to any page directories
     pub fn alloc_frame(&mut self) -> Result<u64, PagingError> {
-        if let Some(idx) = self.frame_set.first_unset() {
+        if let Some(idx) = self.next_free_frame() {
             self.frame_set.set(idx);
+            self.next_frame = idx + 1;
 
             Ok(idx as u64 * self.page_size as u64)
         } else {
@@ -395,8 +780,25 @@ impl PageManager {
         }
     }
 
+    /// allocates a frame whose physical address is below the given address, returning its physical address without assigning it to any page directories
+    ///
+    /// this is useful for things like real mode bootstrap code that can only run from low memory. the cursor used by `alloc_frame` isn't touched, since
+    /// low frames are scarce and we don't want regular allocations to start hunting for them
+    pub fn alloc_low_frame(&mut self, below: u64) -> Result<u64, PagingError> {
+        let limit: usize = (below / self.page_size as u64).try_into().map_err(|_| PagingError::BadAddress)?;
+
+        match self.frame_set.first_unset_from(0) {
+            Some(idx) if idx < limit => {
+                self.frame_set.set(idx);
+
+                Ok(idx as u64 * self.page_size as u64)
+            }
+            _ => Err(PagingError::NoAvailableFrames),
+        }
+    }
+
     pub fn first_available_frame(&self) -> Option<u64> {
-        self.frame_set.first_unset().map(|i| (i as u64) * (self.page_size as u64))
+        self.next_free_frame().map(|i| (i as u64) * (self.page_size as u64))
     }
 
     /// allocates a frame in the provided page directory at the given physical address, if available
@@ -415,7 +817,7 @@ impl PageManager {
         assert!(phys % self.page_size as u64 == 0, "physical address is not page aligned");
 
         if dir.is_unused(addr) {
-            let idx = phys / self.page_size as u64;
+            let idx: usize = (phys / self.page_size as u64).try_into().map_err(|_| PagingError::BadFrame)?;
 
             let frame = PageFrame {
                 addr: phys,
@@ -428,8 +830,17 @@ impl PageManager {
 
             trace!("allocating frame {:?} @ {:#x}", frame, addr);
 
-            self.frame_set.set(idx as usize);
-            dir.set_page(addr, Some(frame))?;
+            // mark the frame as used before mapping it so nothing else can grab it in the meantime, but put it back if it can't be mapped
+            let was_used = self.frame_set.test(idx);
+            self.frame_set.set(idx);
+
+            if let Err(err) = dir.set_page(addr, Some(frame)) {
+                if !was_used {
+                    self.frame_set.clear(idx);
+                }
+
+                return Err(err);
+            }
 
             Ok(())
         } else {
@@ -450,7 +861,7 @@ impl PageManager {
         debug!("setting {idx:#x} as used");
         self.frame_set.set(idx);
 
-        debug!("first_unset is now {:?}", self.frame_set.first_unset());
+        debug!("next free frame is now {:?}", self.next_free_frame());
     }
 
     /// sets a frame in our list of frames as free, allowing it to be allocated elsewhere
@@ -462,7 +873,59 @@ impl PageManager {
     pub fn set_frame_free(&mut self, addr: u64) {
         assert!(addr % self.page_size as u64 == 0, "frame address is not page aligned");
 
-        self.frame_set.clear((addr / self.page_size as u64).try_into().unwrap());
+        let idx = (addr / self.page_size as u64).try_into().unwrap();
+        self.frame_set.clear(idx);
+
+        // make sure holes below the cursor get found again
+        if idx < self.next_frame {
+            self.next_frame = idx;
+        }
+    }
+
+    /// converts a page aligned physical region into a range of frame indices, clamped to the frames we actually manage
+    fn region_frames(&self, phys_start: u64, len: u64) -> (usize, usize) {
+        let page_size = self.page_size as u64;
+
+        assert!(phys_start % page_size == 0, "region start is not page aligned");
+        assert!(len % page_size == 0, "region length is not page aligned");
+
+        let size = self.frame_set.size as u64;
+        let start = (phys_start / page_size).min(size);
+        let end = (phys_start.saturating_add(len) / page_size).min(size);
+
+        (start as usize, (end - start) as usize)
+    }
+
+    /// marks every frame in a physical region as used so it's never handed out, i.e. for MMIO holes like the local APIC or a framebuffer.
+    /// frames past the end of the memory we manage aren't tracked, so those parts of the region are ignored
+    ///
+    /// # Arguments
+    ///
+    /// * `phys_start` - the physical address of the start of the region. must be page aligned
+    /// * `len` - the length of the region in bytes. must be a multiple of the page size
+    pub fn reserve_region(&mut self, phys_start: u64, len: u64) {
+        let (start, count) = self.region_frames(phys_start, len);
+
+        debug!("reserving frames {start:#x} - {:#x}", start + count);
+        self.frame_set.set_range(start, count);
+    }
+
+    /// marks every frame in a physical region as free, undoing [Self::reserve_region]
+    ///
+    /// # Arguments
+    ///
+    /// * `phys_start` - the physical address of the start of the region. must be page aligned
+    /// * `len` - the length of the region in bytes. must be a multiple of the page size
+    pub fn release_region(&mut self, phys_start: u64, len: u64) {
+        let (start, count) = self.region_frames(phys_start, len);
+
+        debug!("releasing frames {start:#x} - {:#x}", start + count);
+        self.frame_set.clear_range(start, count);
+
+        // make sure holes below the cursor get found again
+        if count > 0 && start < self.next_frame {
+            self.next_frame = start;
+        }
     }
 
     /// frees a frame in the provided page directory, allowing that region of memory to be used by other things
@@ -479,9 +942,12 @@ impl PageManager {
         assert!(addr % self.page_size == 0, "frame address is not page aligned");
 
         if let Some(page) = dir.get_page(addr) {
-            trace!("freeing phys {:#x}", page.addr);
+            if page.present {
+                trace!("freeing phys {:#x}", page.addr);
+
+                self.set_frame_free(page.addr);
+            }
 
-            self.frame_set.clear((page.addr / self.page_size as u64) as usize);
             dir.set_page(addr, None)?;
 
             Ok(page.addr)
@@ -490,6 +956,36 @@ impl PageManager {
         }
     }
 
+    /// installs a guard page in the provided page directory at the given address, freeing whatever frame was mapped there beforehand
+    ///
+    /// guard pages are never present, and attempting to access one will cause a page fault that can be identified with `is_guard_page`
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - the page directory to install the guard page in
+    /// * `addr` - the virtual address of the guard page. must be page aligned
+    pub fn install_guard_page<T: PageDirectory>(&mut self, dir: &mut T, addr: usize) -> Result<(), PagingError> {
+        assert!(T::PAGE_SIZE == self.page_size);
+
+        assert!(addr % self.page_size == 0, "guard page address is not page aligned");
+
+        if let Some(page) = dir.get_page(addr) && page.present {
+            trace!("freeing phys {:#x} to make room for guard page", page.addr);
+
+            self.set_frame_free(page.addr);
+        }
+
+        trace!("installing guard page @ {addr:#x}");
+
+        dir.set_page(
+            addr,
+            Some(PageFrame {
+                guard: true,
+                ..Default::default()
+            }),
+        )
+    }
+
     /// prints out information about this page directory
     pub fn print_free(&self) {
         let bits_used = self.frame_set.bits_used;
@@ -497,6 +993,17 @@ impl PageManager {
         debug!("{}/{} mapped ({}% usage)", bits_used, size, (bits_used * 100) / size);
     }
 
+    /// copies the frame bitmap out so it can be saved and compared against later, see `BitSet::from_bytes()` for loading it back
+    pub fn snapshot(&self) -> Result<Vec<u8>, PagingError> {
+        let bytes = self.frame_set.as_bytes();
+
+        let mut snapshot = Vec::new();
+        snapshot.try_reserve_exact(bytes.len()).map_err(|_| PagingError::AllocError)?;
+        snapshot.extend_from_slice(bytes);
+
+        Ok(snapshot)
+    }
+
     /// sets all the pages mapped in the given page directory to used in this PageManager, so that no future allocations use the same memory
     ///
     /// note: this is slow! very slow! this should be done as infrequently as possible
@@ -513,36 +1020,241 @@ impl PageManager {
     }
 }
 
-/// our kernel-wide page manager instance
-static mut PAGE_MANAGER: Option<Mutex<PageManager>> = None;
+/// something a [FrameTransaction] did
+#[derive(Debug, Copy, Clone)]
+enum FrameOp {
+    /// a frame was allocated
+    Alloc(u64),
 
-/// gets the global page manager, locked with a spinlock
-pub fn get_page_manager() -> MutexGuard<'static, PageManager> {
-    unsafe {
-        let manager = PAGE_MANAGER.as_ref().expect("page manager not initialized");
+    /// a frame is to be freed once the transaction is committed
+    Free(u64),
 
-        let thread_id = crate::arch::get_thread_id();
-        let mut has_warned = false;
+    /// a page was mapped at this address, replacing what was there before
+    Map(usize, Option<PageFrame>),
+}
 
-        loop {
-            match manager.try_lock() {
-                Some(guard) => return guard,
-                None => {
-                    if !has_warned {
-                        debug!("warning (cpu {thread_id}): page manager is locked");
-                        has_warned = true;
-                    }
+/// keeps track of frames allocated and freed thru a PageManager and pages mapped with them so that an operation spanning multiple allocations
+/// can be undone if it fails partway thru
+///
+/// frames are allocated and pages are mapped immediately, but frames aren't freed until the transaction is committed. this way nothing else can
+/// grab a frame the transaction freed, so `rollback` never has to reclaim anything and can't fail
+#[derive(Default)]
+pub struct FrameTransaction {
+    /// everything done during this transaction, in the order it was done
+    log: Vec<FrameOp>,
+
+    /// physical addresses of frames allocated during this transaction that haven't been freed by it since
+    allocated: Vec<u64>,
+}
 
-                    // if the page manager is locked, we're likely waiting for a page update
-                    // we can try to process urgent messages here in case we're waiting for this cpu to handle a page update so we don't deadlock
-                    crate::task::get_cpus().expect("CPUs not initialized").get_thread(thread_id).unwrap().process_urgent_messages();
-                }
-            }
-        }
+impl FrameTransaction {
+    pub fn new() -> Self {
+        Self::default()
     }
-}
 
-/// sets the global page manager. can only be called once
+    /// records a newly allocated frame. space for it has to have been reserved already
+    fn record_alloc(&mut self, addr: u64) {
+        self.log.push(FrameOp::Alloc(addr));
+        self.allocated.push(addr);
+    }
+
+    /// allocates a frame with the given page manager and records it in this transaction
+    pub fn alloc_frame(&mut self, manager: &mut PageManager) -> Result<u64, PagingError> {
+        // reserve memory beforehand so we don't allocate a frame we can't keep track of
+        self.log.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+        self.allocated.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+
+        let addr = manager.alloc_frame()?;
+        self.record_alloc(addr);
+
+        Ok(addr)
+    }
+
+    /// allocates a frame from the global page manager with [alloc_frame_or_reclaim] and records it in this transaction
+    pub fn alloc_frame_or_reclaim(&mut self) -> Result<u64, PagingError> {
+        self.log.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+        self.allocated.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+
+        let addr = alloc_frame_or_reclaim()?;
+        self.record_alloc(addr);
+
+        Ok(addr)
+    }
+
+    /// marks a frame to be freed with the given page manager once this transaction is committed
+    pub fn free_frame(&mut self, manager: &mut PageManager, addr: u64) -> Result<(), PagingError> {
+        assert!(addr % manager.page_size as u64 == 0, "frame address is not page aligned");
+
+        if !manager.frame_set.test((addr / manager.page_size as u64).try_into().unwrap()) || self.log.iter().any(|op| matches!(op, FrameOp::Free(a) if *a == addr)) {
+            return Err(PagingError::FrameUnused);
+        }
+
+        self.log.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+
+        self.log.push(FrameOp::Free(addr));
+        self.allocated.retain(|a| *a != addr);
+
+        Ok(())
+    }
+
+    /// maps a page in the given page directory and records it in this transaction, along with whatever it replaced
+    pub fn map_page<D: PageDirectory>(&mut self, dir: &mut D, addr: usize, page: PageFrame) -> Result<(), PagingError> {
+        self.log.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+
+        let previous = dir.get_page(addr);
+        dir.set_page(addr, Some(page))?;
+        self.log.push(FrameOp::Map(addr, previous));
+
+        Ok(())
+    }
+
+    /// the physical addresses of all the frames allocated during this transaction that it hasn't freed since
+    pub fn allocated(&self) -> &[u64] {
+        &self.allocated
+    }
+
+    /// counts how many frames were allocated and freed and how many pages were mapped during this transaction
+    fn counts(&self) -> (usize, usize, usize) {
+        self.log.iter().fold((0, 0, 0), |(allocated, freed, mapped), op| match op {
+            FrameOp::Alloc(_) => (allocated + 1, freed, mapped),
+            FrameOp::Free(_) => (allocated, freed + 1, mapped),
+            FrameOp::Map(..) => (allocated, freed, mapped + 1),
+        })
+    }
+
+    /// keeps all the changes made during this transaction, freeing the frames it was asked to free
+    pub fn commit(self, manager: &mut PageManager) {
+        let (allocated, freed, mapped) = self.counts();
+        trace!("committing frame transaction ({allocated} allocated, {freed} freed, {mapped} mapped)");
+
+        for op in self.log.iter() {
+            if let FrameOp::Free(addr) = *op {
+                manager.set_frame_free(addr);
+            }
+        }
+    }
+
+    /// puts back whatever was mapped in the given page directory before this transaction mapped pages over it, in the opposite order they were mapped.
+    /// this has to be done before rolling it back, and is separate so the page directory doesn't have to be locked at the same time as the page manager
+    ///
+    /// every page being put back is in a page table this transaction mapped a page in, so it never has to allocate one and this can't fail
+    pub fn unmap_pages<D: PageDirectory>(&mut self, dir: &mut D) {
+        for op in self.log.iter().rev() {
+            if let FrameOp::Map(addr, previous) = *op
+                && let Err(err) = dir.set_page(addr, previous)
+            {
+                panic!("couldn't restore page @ {addr:#x} while rolling back frame transaction: {err:?}");
+            }
+        }
+
+        self.log.retain(|op| !matches!(op, FrameOp::Map(..)));
+    }
+
+    /// reverts all the changes made during this transaction in the opposite order they were made, freeing every frame it allocated.
+    /// frames it was asked to free were never freed, so they're left alone. any pages it mapped must have been unmapped with [Self::unmap_pages] first
+    pub fn rollback(self, manager: &mut PageManager) {
+        let (allocated, freed, mapped) = self.counts();
+        trace!("rolling back frame transaction ({allocated} allocated, {freed} freed)");
+
+        assert!(mapped == 0, "frame transaction rolled back with pages still mapped");
+
+        for op in self.log.iter().rev() {
+            if let FrameOp::Alloc(addr) = *op {
+                manager.set_frame_free(addr);
+            }
+        }
+    }
+}
+
+/// checks whether the page containing the given address is a guard page
+pub fn is_guard_page<D: PageDirectory>(dir: &D, addr: usize) -> bool {
+    let addr = (addr / D::PAGE_SIZE) * D::PAGE_SIZE;
+
+    dir.get_page(addr).map(|page| page.guard && !page.present).unwrap_or(false)
+}
+
+/// address of the guard page below the kernel's main stack, or 0 if it hasn't been set
+static STACK_GUARD: AtomicUsize = AtomicUsize::new(0);
+
+/// address of the guard page below the kernel's interrupt stack, or 0 if it hasn't been set
+static INT_STACK_GUARD: AtomicUsize = AtomicUsize::new(0);
+
+/// records where the guard pages below the kernel's stacks are, so faults on them can be reported as stack overflows
+pub fn set_stack_guards(stack_guard: usize, int_stack_guard: usize) {
+    STACK_GUARD.store(stack_guard, Ordering::Release);
+    INT_STACK_GUARD.store(int_stack_guard, Ordering::Release);
+}
+
+/// what a page fault was likely caused by
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    /// the guard page below the kernel's main stack was hit
+    StackOverflow,
+
+    /// the guard page below the kernel's interrupt stack was hit
+    InterruptStackOverflow,
+
+    /// some other guard page was hit
+    GuardPage,
+
+    /// a copy-on-write page was written to
+    CopyOnWrite,
+
+    /// an instruction was fetched from a page that isn't executable
+    NoExecute,
+
+    /// none of the above
+    Unknown,
+}
+
+/// works out what a page fault on the given address was likely caused by, given whether it happened while fetching an instruction
+pub fn classify_fault<D: PageDirectory>(dir: &D, addr: usize, instruction_fetch: bool) -> FaultKind {
+    let addr = (addr / D::PAGE_SIZE) * D::PAGE_SIZE;
+
+    if addr == STACK_GUARD.load(Ordering::Acquire) {
+        FaultKind::StackOverflow
+    } else if addr == INT_STACK_GUARD.load(Ordering::Acquire) {
+        FaultKind::InterruptStackOverflow
+    } else {
+        match dir.get_page(addr) {
+            Some(page) if page.guard && !page.present => FaultKind::GuardPage,
+            Some(page) if instruction_fetch && page.present && !page.executable => FaultKind::NoExecute,
+            Some(page) if page.present && page.copy_on_write => FaultKind::CopyOnWrite,
+            _ => FaultKind::Unknown,
+        }
+    }
+}
+
+/// our kernel-wide page manager instance
+static mut PAGE_MANAGER: Option<Mutex<PageManager>> = None;
+
+/// gets the global page manager, locked with a spinlock
+pub fn get_page_manager() -> MutexGuard<'static, PageManager> {
+    unsafe {
+        let manager = PAGE_MANAGER.as_ref().expect("page manager not initialized");
+
+        let thread_id = crate::arch::get_thread_id();
+        let mut has_warned = false;
+
+        loop {
+            match manager.try_lock() {
+                Some(guard) => return guard,
+                None => {
+                    if !has_warned {
+                        debug!("warning (cpu {thread_id}): page manager is locked");
+                        has_warned = true;
+                    }
+
+                    // if the page manager is locked, we're likely waiting for a page update
+                    // we can try to process urgent messages here in case we're waiting for this cpu to handle a page update so we don't deadlock
+                    crate::task::get_cpus().expect("CPUs not initialized").get_thread(thread_id).unwrap().process_urgent_messages();
+                }
+            }
+        }
+    }
+}
+
+/// sets the global page manager. can only be called once
 pub fn set_page_manager(manager: PageManager) {
     unsafe {
         if PAGE_MANAGER.is_some() {
@@ -553,18 +1265,73 @@ pub fn set_page_manager(manager: PageManager) {
     }
 }
 
+/// called when physical memory runs out, with how many frames are needed. should try to free some frames up, and returns whether the allocation
+/// should be retried
+pub type OOMHandler = fn(usize) -> bool;
+
+/// what to do when physical memory runs out, if anything
+static OOM_HANDLER: Mutex<Option<OOMHandler>> = Mutex::new(None);
+
+/// sets the function that gets called when physical memory runs out
+pub fn set_oom_handler(handler: OOMHandler) {
+    *OOM_HANDLER.lock() = Some(handler);
+}
+
+/// allocates a frame from the global page manager like `PageManager::alloc_frame`, but if there aren't any frames left a page gets swapped out
+/// to make room, and failing that the oom handler gets a chance to free some up before giving up
+///
+/// the page manager must not be locked when calling this, since the oom handler will need to lock it to free frames
+pub fn alloc_frame_or_reclaim() -> Result<u64, PagingError> {
+    loop {
+        let result = get_page_manager().alloc_frame();
+
+        if !matches!(result, Err(PagingError::NoAvailableFrames)) {
+            return result;
+        }
+
+        if super::swap::evict_page() {
+            continue;
+        }
+
+        // copy the handler out so it isn't locked while it runs
+        let handler = *OOM_HANDLER.lock();
+
+        match handler {
+            Some(handler) if handler(1) => debug!("oom handler freed memory, retrying allocation"),
+            _ => return result,
+        }
+    }
+}
+
 static mut KERNEL_PAGE_DIR: Option<Mutex<PageDirTracker<crate::arch::PageDirectory<'static>>>> = None;
 
+/// the kernel's page directory inside [KERNEL_PAGE_DIR], for switching to it without taking the lock
+static mut KERNEL_PAGE_DIR_UNLOCKED: *const PageDirTracker<crate::arch::PageDirectory<'static>> = core::ptr::null();
+
 pub fn get_kernel_page_dir() -> MutexedPageDir<'static, PageDirTracker<crate::arch::PageDirectory<'static>>> {
     unsafe { MutexedPageDir(KERNEL_PAGE_DIR.as_ref().expect("kernel page directory not set")) }
 }
 
+/// switches to the kernel's page directory without locking it, for places where waiting on whoever holds the lock could deadlock
+///
+/// # Safety
+///
+/// same as [PageDirectory::switch_to]. the kernel's page directory is never freed or replaced once it's set, so reading it without the lock is fine
+pub unsafe fn switch_to_kernel_page_dir() {
+    assert!(!KERNEL_PAGE_DIR_UNLOCKED.is_null(), "kernel page directory not set");
+
+    (*KERNEL_PAGE_DIR_UNLOCKED).switch_to();
+}
+
 pub fn set_kernel_page_dir(dir: crate::arch::PageDirectory<'static>) {
     unsafe {
         if KERNEL_PAGE_DIR.is_some() {
             panic!("can't set kernel page directory twice");
         } else {
             KERNEL_PAGE_DIR = Some(Mutex::new(PageDirTracker::new(dir, true)));
+
+            // the directory lives in the static from here on, so this pointer stays valid after the guard is dropped
+            KERNEL_PAGE_DIR_UNLOCKED = &*KERNEL_PAGE_DIR.as_ref().unwrap().lock() as *const _;
         }
     }
 }
@@ -577,6 +1344,7 @@ pub enum ProcessOrKernelPageDir {
 
 impl PageDirectory for ProcessOrKernelPageDir {
     const PAGE_SIZE: usize = crate::arch::PageDirectory::PAGE_SIZE;
+    const NO_EXECUTE: bool = crate::arch::PageDirectory::NO_EXECUTE;
 
     fn get_page(&self, addr: usize) -> Option<PageFrame> {
         match self {
@@ -599,6 +1367,13 @@ impl PageDirectory for ProcessOrKernelPageDir {
         }
     }
 
+    fn is_current(&self) -> bool {
+        match self {
+            Self::Process(id) => crate::task::get_process(*id).unwrap().page_directory.is_current(),
+            Self::Kernel => get_kernel_page_dir().is_current(),
+        }
+    }
+
     fn is_unused(&self, addr: usize) -> bool {
         match self {
             Self::Process(id) => crate::task::get_process(*id).unwrap().page_directory.is_unused(addr),
@@ -612,6 +1387,20 @@ impl PageDirectory for ProcessOrKernelPageDir {
             Self::Kernel => get_kernel_page_dir().virt_to_phys(virt),
         }
     }
+
+    fn set_page_batched(&mut self, addr: usize, page: Option<PageFrame>, batch: &mut PageUpdateBatch) -> Result<(), PagingError> {
+        match self {
+            Self::Process(id) => crate::task::get_process(*id).unwrap().page_directory.set_page_batched(addr, page, batch),
+            Self::Kernel => get_kernel_page_dir().set_page_batched(addr, page, batch),
+        }
+    }
+
+    fn flush_batch(&mut self, batch: &mut PageUpdateBatch) {
+        match self {
+            Self::Process(id) => crate::task::get_process(*id).unwrap().page_directory.flush_batch(batch),
+            Self::Kernel => get_kernel_page_dir().flush_batch(batch),
+        }
+    }
 }
 
 pub fn get_page_dir(thread_id: Option<crate::task::cpu::ThreadID>) -> ProcessOrKernelPageDir {
@@ -631,11 +1420,27 @@ pub fn get_page_dir(thread_id: Option<crate::task::cpu::ThreadID>) -> ProcessOrK
 /// allows for easy reference counting of copy-on-write pages and memory mappings
 pub struct PageRefCounter {
     references: BTreeMap<u64, PageReference>,
+
+    /// frames that are currently being copied on write
+    copying: BTreeSet<u64>,
 }
 
 impl PageRefCounter {
     pub fn new() -> Self {
-        Self { references: BTreeMap::default() }
+        Self {
+            references: BTreeMap::default(),
+            copying: BTreeSet::default(),
+        }
+    }
+
+    /// marks the given frame as being copied on write, returning false if something else is already copying it
+    pub fn try_start_copy(&mut self, phys: u64) -> bool {
+        self.copying.insert(phys)
+    }
+
+    /// marks the given frame as no longer being copied on write
+    pub fn finish_copy(&mut self, phys: u64) {
+        self.copying.remove(&phys);
     }
 
     pub fn add_reference(&mut self, phys: u64) {
@@ -715,6 +1520,7 @@ pub struct FreeablePageDir<D: PageDirectory>(D);
 
 impl<D: PageDirectory> PageDirectory for FreeablePageDir<D> {
     const PAGE_SIZE: usize = D::PAGE_SIZE;
+    const NO_EXECUTE: bool = D::NO_EXECUTE;
 
     fn get_page(&self, addr: usize) -> Option<PageFrame> {
         self.0.get_page(addr)
@@ -728,6 +1534,10 @@ impl<D: PageDirectory> PageDirectory for FreeablePageDir<D> {
         self.0.switch_to()
     }
 
+    fn is_current(&self) -> bool {
+        self.0.is_current()
+    }
+
     fn is_unused(&self, addr: usize) -> bool {
         self.0.is_unused(addr)
     }
@@ -760,6 +1570,15 @@ impl<D: PageDirectory> FreeablePageDir<D> {
 
 /// given a page frame, free its contents or otherwise clean them up with the proper method
 pub fn free_page(page: PageFrame) {
+    // non present pages (i.e. guard pages) don't have any memory behind them
+    if !page.present {
+        if page.swapped {
+            super::swap::free_swap_slot(page.addr as super::swap::SwapSlot);
+        }
+
+        return;
+    }
+
     if page.shared {
         if !super::shared::free_shared_reference(page.addr) {
             PAGE_REF_COUNTER.lock().remove_reference(page.addr);
@@ -780,11 +1599,76 @@ pub fn free_page_dir<D: PageDirectory>(dir: &D) {
     }
 }
 
+/// checks whether writing to the given page has to go thru copy on write first, either because it's referenced by multiple processes after a fork
+/// or because it's a copy on write mapping of shared memory
+pub fn needs_copy_on_write(page: &PageFrame) -> bool {
+    !page.writable && page.copy_on_write && (page.referenced || page.shared)
+}
+
+/// marks the given frame as being copied on write, waiting for anything else that's copying it to finish first
+fn start_copying_frame(phys: u64) {
+    let thread = crate::task::get_cpus().and_then(|cpus| cpus.get_thread(crate::arch::get_thread_id()));
+
+    while !PAGE_REF_COUNTER.lock().try_start_copy(phys) {
+        // whatever's copying the frame might be waiting on us to flush our TLB
+        match thread {
+            Some(thread) => thread.process_urgent_messages(),
+            None => crate::arch::spin(),
+        }
+    }
+}
+
 /// given a page directory, address, and the page frame at that address, copy its contents to a new page and replace the existing page with the new one, freeing the old page in the process
+///
+/// shared pages are always copied, since everything else sharing them has to keep seeing the original. the writer's reference to the shared area is
+/// dropped when the original page is freed
+///
+/// only one copy of a frame can be made at a time, so if multiple CPUs fault on the same page at once only the first one copies it,
+/// and the rest just get whatever it was replaced with
 pub fn copy_on_write(page_dir: &mut impl PageDirectory, addr: usize, mut page: PageFrame) -> Result<PageFrame, PagingError> {
+    loop {
+        start_copying_frame(page.addr);
+
+        // the page might have been dealt with while we were waiting, so check what's there now
+        match page_dir.get_page(addr) {
+            Some(current) if current.addr == page.addr && needs_copy_on_write(&current) => {
+                page = current;
+                break;
+            }
+            Some(current) if needs_copy_on_write(&current) => {
+                // it's been replaced with a different copy on write page, which is what has to be copied now
+                PAGE_REF_COUNTER.lock().finish_copy(page.addr);
+                page = current;
+            }
+            Some(current) => {
+                PAGE_REF_COUNTER.lock().finish_copy(page.addr);
+                debug!("page {addr:#x} has already been copied");
+
+                // whatever copied it might have had its copy swapped out since
+                return match current.swapped {
+                    true => resident_page(page_dir, addr)?.ok_or(PagingError::BadAddress),
+                    false => Ok(current),
+                };
+            }
+            None => {
+                PAGE_REF_COUNTER.lock().finish_copy(page.addr);
+                return Err(PagingError::BadAddress);
+            }
+        }
+    }
+
+    let phys = page.addr;
+    let result = copy_claimed_page(page_dir, addr, page);
+    PAGE_REF_COUNTER.lock().finish_copy(phys);
+
+    result
+}
+
+/// does the actual copying for [copy_on_write] once the page's frame has been claimed
+fn copy_claimed_page(page_dir: &mut impl PageDirectory, addr: usize, mut page: PageFrame) -> Result<PageFrame, PagingError> {
     let page_size = crate::arch::PageDirectory::PAGE_SIZE;
 
-    if PAGE_REF_COUNTER.lock().get_references_for(page.addr) > 1 {
+    if page.shared || PAGE_REF_COUNTER.lock().get_references_for(page.addr) > 1 {
         debug!("copying page {addr:#x} (phys {:#x})", page.addr);
 
         unsafe {
@@ -811,6 +1695,7 @@ pub fn copy_on_write(page_dir: &mut impl PageDirectory, addr: usize, mut page: P
                 page.writable = true;
                 page.copy_on_write = false;
                 page.referenced = false;
+                page.shared = false;
 
                 trace!("updating page");
                 if let Err(err) = page_dir.set_page(addr, Some(page)) {
@@ -824,7 +1709,7 @@ pub fn copy_on_write(page_dir: &mut impl PageDirectory, addr: usize, mut page: P
 
             // allocate a new page for the heap
             trace!("allocating new page");
-            let phys_addr = match get_page_manager().alloc_frame() {
+            let phys_addr = match alloc_frame_or_reclaim() {
                 Ok(addr) => addr,
                 Err(err) => {
                     page_dir.set_page(addr, Some(original_page)).expect("copy on write cleanup failed");
@@ -882,7 +1767,7 @@ pub fn try_copy_on_write(thread: &crate::task::cpu::CPUThread, addr: usize) -> R
     // round down to nearest multiple of page size
     let addr = (addr / page_size) * page_size;
 
-    if !page.writable && page.copy_on_write && page.referenced {
+    if needs_copy_on_write(&page) {
         copy_on_write(&mut ProcessOrKernelPageDir::Process(current_id.process), addr, page)?;
 
         Ok(true)
@@ -891,16 +1776,458 @@ pub fn try_copy_on_write(thread: &crate::task::cpu::CPUThread, addr: usize) -> R
     }
 }
 
-pub fn validate_region(page_dir: &impl PageDirectory, start: usize, len: usize) -> bool {
+/// maps a newly allocated page full of zeroes into the given page directory at the given address, for memory that's only allocated once it's touched
+pub fn map_zeroed_page(page_dir: &mut impl PageDirectory, addr: usize) -> Result<PageFrame, PagingError> {
+    let phys = alloc_frame_or_reclaim()?;
+
+    // clear the frame before it's mapped so nothing can see what was there before
+    if let Err(err) = unsafe { map_memory(&mut get_page_dir(None), &[phys], |s| s.fill(0)) } {
+        get_page_manager().set_frame_free(phys);
+        return Err(err);
+    }
+
+    let page = PageFrame {
+        addr: phys,
+        present: true,
+        user_mode: true,
+        writable: true,
+        executable: page_dir.executable_if(false),
+        ..Default::default()
+    };
+
+    if let Err(err) = page_dir.set_page(addr, Some(page)) {
+        get_page_manager().set_frame_free(phys);
+        return Err(err);
+    }
+
+    Ok(page)
+}
+
+/// used in page fault exception handlers to grow the stack of the current process down to the given address if it's just below it
+///
+/// returns true if the stack was grown and false if the address isn't somewhere the stack can grow to, or something else is mapped in the way
+pub fn try_grow_stack(thread: &crate::task::cpu::CPUThread, addr: usize) -> Result<bool, Errno> {
+    let current_id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+    let addr = (addr / page_size) * page_size;
+
+    let stack = match crate::task::get_process(current_id.process).ok_or(Errno::NoSuchProcess)?.stack {
+        Some(stack) if stack.can_grow_to(addr) => stack,
+        _ => return Ok(false),
+    };
+
+    debug!("growing stack of process {} down to {addr:#x}", current_id.process);
+
+    let mut page_dir = ProcessOrKernelPageDir::Process(current_id.process);
+
+    // map pages from the top down, so the stack stays contiguous if we run out of memory partway thru
+    for page_addr in (addr..stack.bottom).step_by(page_size).rev() {
+        let bottom = crate::task::get_process(current_id.process).ok_or(Errno::NoSuchProcess)?.stack.map(|stack| stack.bottom).unwrap_or(stack.bottom);
+
+        // another thread may have already grown the stack past here
+        if page_addr < bottom {
+            // nothing stops other mappings from being put where the stack could grow to, and growing past one would leave it in pieces
+            if !page_dir.is_unused(page_addr) {
+                debug!("stack of process {} ran into a mapping at {page_addr:#x}", current_id.process);
+                return Ok(false);
+            }
+
+            map_zeroed_page(&mut page_dir, page_addr)?;
+        }
+
+        if let Some(stack) = crate::task::get_process(current_id.process).ok_or(Errno::NoSuchProcess)?.stack.as_mut() {
+            stack.bottom = stack.bottom.min(page_addr);
+        }
+    }
+
+    Ok(true)
+}
+
+/// used in page fault exception handlers to map in a page of zeroes at the given address if it's been set up to be filled in when touched
+///
+/// returns true if a page was mapped in and false if the address isn't a zero fill page
+pub fn try_zero_fill(thread: &crate::task::cpu::CPUThread, addr: usize) -> Result<bool, Errno> {
+    let current_id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+    let addr = (addr / page_size) * page_size;
+
+    let mut page_dir = ProcessOrKernelPageDir::Process(current_id.process);
+
+    let placeholder = match page_dir.get_page(addr) {
+        Some(page) if page.zero_fill && !page.present => page,
+        // another thread may have already filled this page in
+        Some(page) if page.present => return Ok(true),
+        _ => return Ok(false),
+    };
+
+    trace!("filling in zeroed page @ {addr:#x} in process {}", current_id.process);
+
+    fill_zeroed_page(&mut page_dir, addr, placeholder)?;
+
+    Ok(true)
+}
+
+/// gives the given zero fill placeholder page at the given address memory, returning the page that replaced it
+fn fill_zeroed_page(page_dir: &mut impl PageDirectory, addr: usize, placeholder: PageFrame) -> Result<PageFrame, PagingError> {
+    let page = map_zeroed_page(page_dir, addr)?;
+
+    // zeroed pages are mapped writable and not executable where possible, which isn't always what the placeholder asked for
+    if placeholder.writable == page.writable && placeholder.executable == page.executable {
+        return Ok(page);
+    }
+
+    let page = PageFrame {
+        writable: placeholder.writable,
+        executable: placeholder.executable,
+        ..page
+    };
+
+    page_dir.set_page(addr, Some(page))?;
+
+    Ok(page)
+}
+
+/// checks whether every page in the range `start..start + len` is backed by memory, bringing in any that have been swapped out
+/// and filling in any zero fill pages that haven't been touched yet so the kernel can access the region directly afterwards
+pub fn validate_region(page_dir: &mut impl PageDirectory, start: usize, len: usize) -> bool {
     let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+    let Some(last) = start.checked_add(len.saturating_sub(1)) else {
+        return false;
+    };
     let start = (start / page_size) * page_size;
-    let end = ((start + len) / page_size) * page_size + page_size;
+    let end = (last / page_size) * page_size + page_size;
 
     for addr in (start..end).step_by(page_size) {
-        if page_dir.get_page(addr).is_none() {
-            return false;
+        match resident_page(page_dir, addr) {
+            Ok(Some(page)) if page.present => (),
+            Ok(_) => return false,
+            Err(err) => {
+                debug!("couldn't bring in page {addr:#x}: {err:?}");
+                return false;
+            }
         }
     }
 
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_PAGE_SIZE: usize = 0x1000;
+
+    /// a page directory that just keeps track of which pages are set, optionally refusing to map pages where nothing was like it couldn't allocate a page table
+    #[derive(Default)]
+    struct MockPageDir {
+        pages: BTreeMap<usize, PageFrame>,
+        fail_set_page: bool,
+    }
+
+    impl PageDirectory for MockPageDir {
+        const PAGE_SIZE: usize = TEST_PAGE_SIZE;
+        const NO_EXECUTE: bool = true;
+
+        fn get_page(&self, addr: usize) -> Option<PageFrame> {
+            self.pages.get(&(addr & !(TEST_PAGE_SIZE - 1))).copied()
+        }
+
+        fn set_page(&mut self, addr: usize, page: Option<PageFrame>) -> Result<(), PagingError> {
+            if self.fail_set_page && page.is_some() && !self.pages.contains_key(&addr) {
+                return Err(PagingError::AllocError);
+            }
+
+            match page {
+                Some(page) => self.pages.insert(addr, page),
+                None => self.pages.remove(&addr),
+            };
+
+            Ok(())
+        }
+
+        unsafe fn switch_to(&self) {
+            unimplemented!();
+        }
+    }
+
+    /// makes a page directory with every page in the given range used, except for the given holes
+    fn dir_with_holes(end: usize, holes: &[core::ops::Range<usize>]) -> MockPageDir {
+        let mut dir = MockPageDir::default();
+
+        for addr in (0..end).step_by(TEST_PAGE_SIZE) {
+            if !holes.iter().any(|hole| hole.contains(&addr)) {
+                dir.set_page(addr, Some(PageFrame { present: true, ..Default::default() })).unwrap();
+            }
+        }
+
+        dir
+    }
+
+    #[test_case]
+    fn frame_transaction_rollback_restores_bitset() {
+        let mut manager = PageManager::new(BitSet::new(64), TEST_PAGE_SIZE);
+
+        for frame in [0, 1, 5, 9] {
+            manager.set_frame_used((frame * TEST_PAGE_SIZE) as u64);
+        }
+
+        let before = manager.frame_set.as_bytes().to_vec();
+
+        let mut transaction = FrameTransaction::new();
+
+        let first = transaction.alloc_frame(&mut manager).unwrap();
+        let second = transaction.alloc_frame(&mut manager).unwrap();
+        transaction.alloc_frame(&mut manager).unwrap();
+
+        // a frame that's allocated and freed within the transaction, and one that was in use beforehand
+        transaction.free_frame(&mut manager, first).unwrap();
+        transaction.free_frame(&mut manager, (5 * TEST_PAGE_SIZE) as u64).unwrap();
+
+        // frames the transaction freed aren't handed out again until it's committed
+        transaction.alloc_frame(&mut manager).unwrap();
+        transaction.free_frame(&mut manager, second).unwrap();
+
+        transaction.rollback(&mut manager);
+
+        assert_eq!(manager.frame_set.as_bytes(), &before[..]);
+    }
+
+    #[test_case]
+    fn frame_transaction_only_frees_frames_on_commit() {
+        let mut manager = PageManager::new(BitSet::new(64), TEST_PAGE_SIZE);
+        manager.set_frame_used(0);
+
+        let mut transaction = FrameTransaction::new();
+        transaction.free_frame(&mut manager, 0).unwrap();
+
+        // nothing else can grab the frame in the meantime, so rolling back never has to take it back from anyone
+        assert!(manager.frame_set.test(0));
+        assert_ne!(manager.alloc_frame().unwrap(), 0);
+
+        // and it can't be freed twice
+        assert!(matches!(transaction.free_frame(&mut manager, 0), Err(PagingError::FrameUnused)));
+
+        transaction.commit(&mut manager);
+
+        assert!(!manager.frame_set.test(0));
+    }
+
+    #[test_case]
+    fn frame_transaction_rollback_restores_page_dir_and_frames() {
+        let mut manager = PageManager::new(BitSet::new(64), TEST_PAGE_SIZE);
+        manager.set_frame_used(0);
+
+        let mut dir = dir_with_holes(0x4000, &[]);
+
+        let mappings = |dir: &MockPageDir| dir.pages.iter().map(|(addr, page)| (*addr, page.addr)).collect::<Vec<_>>();
+        let frame = |addr| PageFrame { addr, present: true, ..Default::default() };
+
+        let mappings_before = mappings(&dir);
+        let used_before = manager.frame_set.bits_used;
+
+        let mut transaction = FrameTransaction::new();
+
+        for addr in (0x10000..0x13000).step_by(TEST_PAGE_SIZE) {
+            let phys = transaction.alloc_frame(&mut manager).unwrap();
+            transaction.map_page(&mut dir, addr, frame(phys)).unwrap();
+        }
+
+        // pages that are already there get put back
+        let phys = transaction.alloc_frame(&mut manager).unwrap();
+        transaction.map_page(&mut dir, 0x1000, frame(phys)).unwrap();
+
+        transaction.free_frame(&mut manager, 0).unwrap();
+
+        // fail partway thru
+        dir.fail_set_page = true;
+        assert!(transaction.map_page(&mut dir, 0x13000, frame(phys)).is_err());
+
+        transaction.unmap_pages(&mut dir);
+        transaction.rollback(&mut manager);
+
+        assert_eq!(mappings(&dir), mappings_before);
+        assert_eq!(manager.frame_set.bits_used, used_before);
+        assert!(manager.frame_set.test(0));
+    }
+
+    #[test_case]
+    fn find_hole_single_page_gap() {
+        let dir = dir_with_holes(0x10000, &[0x3000..0x4000]);
+
+        assert_eq!(find_hole(&dir, 0, 0x10000, TEST_PAGE_SIZE), Some(0x3000));
+        assert_eq!(find_hole(&dir, 0, 0x10000, 1), Some(0x3000));
+        assert_eq!(find_hole(&dir, 0, 0x10000, 0), Some(0x3000));
+    }
+
+    #[test_case]
+    fn find_hole_gap_of_exactly_the_requested_size() {
+        let dir = dir_with_holes(0x10000, &[0x2000..0x4000, 0x6000..0x9000]);
+
+        assert_eq!(find_hole(&dir, 0, 0x10000, 3 * TEST_PAGE_SIZE), Some(0x6000));
+        // sizes that aren't page aligned get rounded up
+        assert_eq!(find_hole(&dir, 0, 0x10000, 2 * TEST_PAGE_SIZE + 1), Some(0x6000));
+        assert_eq!(find_hole(&dir, 0, 0x10000, 2 * TEST_PAGE_SIZE), Some(0x2000));
+    }
+
+    #[test_case]
+    fn find_hole_gap_one_page_short() {
+        let dir = dir_with_holes(0x10000, &[0x2000..0x4000, 0x6000..0x9000]);
+
+        assert_eq!(find_hole(&dir, 0, 0x10000, 4 * TEST_PAGE_SIZE), None);
+        // the hole can't run past the end of the range either
+        assert_eq!(find_hole(&dir, 0, 0x8000, 3 * TEST_PAGE_SIZE), None);
+    }
+
+    #[test_case]
+    fn alloc_frame_at_frees_frame_when_set_page_fails() {
+        let mut manager = PageManager::new(BitSet::new(64), TEST_PAGE_SIZE);
+        let mut dir = MockPageDir {
+            fail_set_page: true,
+            ..Default::default()
+        };
+
+        let phys = (3 * TEST_PAGE_SIZE) as u64;
+
+        assert!(manager.alloc_frame_at(&mut dir, 0x1000, phys, true, true, false).is_err());
+        assert!(!manager.frame_set.test(3));
+
+        // a frame that was already in use has to stay that way
+        manager.set_frame_used(phys);
+        assert!(manager.alloc_frame_at(&mut dir, 0x1000, phys, true, true, false).is_err());
+        assert!(manager.frame_set.test(3));
+
+        dir.fail_set_page = false;
+        manager.set_frame_free(phys);
+
+        manager.alloc_frame_at(&mut dir, 0x1000, phys, true, true, false).unwrap();
+        assert!(manager.frame_set.test(3));
+        assert_eq!(dir.get_page(0x1000).map(|page| page.addr), Some(phys));
+    }
+
+    #[test_case]
+    fn alloc_frame_at_rejects_frames_past_the_bitset() {
+        let mut manager = PageManager::new(BitSet::new(64), TEST_PAGE_SIZE);
+        let mut dir = MockPageDir::default();
+
+        // the frame index doesn't fit in a usize, so it can't be truncated into one that does
+        let phys = (1 << 32) * TEST_PAGE_SIZE as u64;
+
+        assert!(matches!(manager.alloc_frame_at(&mut dir, 0x1000, phys, true, true, false), Err(PagingError::BadFrame)));
+        assert!(dir.get_page(0x1000).is_none());
+        assert!(!manager.frame_set.test(0));
+    }
+
+    #[test_case]
+    fn region_addresses_rejects_pages_without_memory() {
+        let mut dir = MockPageDir::default();
+        dir.set_page(0x1000, Some(PageFrame { guard: true, ..Default::default() })).unwrap();
+
+        assert!(matches!(region_addresses::<MockPageDir>(&mut dir, 0x1000, 0x10), Err(PagingError::BadAddress)));
+    }
+
+    #[test_case]
+    fn region_addresses_fills_in_zero_fill_pages() {
+        let mut dir = MockPageDir::default();
+        let placeholder = PageFrame {
+            user_mode: true,
+            zero_fill: true,
+            ..Default::default()
+        };
+        dir.set_page(0x1000, Some(placeholder)).unwrap();
+
+        let (addresses, offset) = region_addresses::<MockPageDir>(&mut dir, 0x1010, 0x10).unwrap();
+        let page = dir.get_page(0x1000).unwrap();
+
+        assert_eq!(offset, 0x10);
+        assert_eq!(addresses, [page.addr]);
+        assert!(page.present);
+
+        // it has to keep the permissions the placeholder asked for
+        assert!(!page.writable);
+
+        get_page_manager().set_frame_free(page.addr);
+    }
+
+    #[test_case]
+    fn validate_region_fills_in_zero_fill_pages() {
+        let mut dir = MockPageDir::default();
+        let placeholder = PageFrame {
+            user_mode: true,
+            writable: true,
+            zero_fill: true,
+            ..Default::default()
+        };
+        dir.set_page(0x1000, Some(PageFrame { present: true, ..placeholder })).unwrap();
+        dir.set_page(0x2000, Some(placeholder)).unwrap();
+
+        assert!(validate_region(&mut dir, 0x1ff0, 0x20));
+
+        let page = dir.get_page(0x2000).unwrap();
+        assert!(page.present);
+        assert!(page.writable);
+
+        get_page_manager().set_frame_free(page.addr);
+    }
+
+    #[test_case]
+    fn validate_region_rejects_pages_without_memory() {
+        let mut dir = MockPageDir::default();
+        dir.set_page(0x1000, Some(PageFrame { present: true, ..Default::default() })).unwrap();
+        dir.set_page(0x3000, Some(PageFrame { guard: true, ..Default::default() })).unwrap();
+
+        // a range that ends right at the hole doesn't touch it, but one more byte does
+        assert!(validate_region(&mut dir, 0x1000, 0x1000));
+        assert!(!validate_region(&mut dir, 0x1ff0, 0x11));
+        assert!(!validate_region(&mut dir, 0x3000, 0x10));
+        assert!(!validate_region(&mut dir, usize::MAX, 2));
+    }
+
+    #[test_case]
+    fn reserved_regions_are_clamped_to_managed_memory() {
+        let page = TEST_PAGE_SIZE as u64;
+        let mut manager = PageManager::new(BitSet::new(16), TEST_PAGE_SIZE);
+
+        manager.reserve_region(0, 4 * page);
+        assert_eq!(manager.frame_set.bits_used, 4);
+        assert_eq!(manager.alloc_frame().unwrap(), 4 * page);
+
+        // only the part of a region that's in memory we manage is tracked
+        manager.reserve_region(14 * page, 100 * page);
+        assert_eq!(manager.frame_set.bits_used, 7);
+        assert!(manager.frame_set.test(14) && manager.frame_set.test(15));
+
+        // and regions entirely past the end, even ones that would overflow, are ignored
+        manager.reserve_region(64 * page, page);
+        manager.reserve_region(u64::MAX - (page - 1), page);
+        manager.release_region(u64::MAX - (page - 1), page);
+        assert_eq!(manager.frame_set.bits_used, 7);
+
+        manager.release_region(14 * page, 100 * page);
+        assert_eq!(manager.frame_set.bits_used, 5);
+        assert!(!manager.frame_set.test(14) && !manager.frame_set.test(15));
+    }
+
+    #[test_case]
+    fn released_regions_rewind_the_allocation_cursor() {
+        let page = TEST_PAGE_SIZE as u64;
+        let mut manager = PageManager::new(BitSet::new(16), TEST_PAGE_SIZE);
+
+        manager.reserve_region(2 * page, 2 * page);
+
+        let allocated = (0..6).map(|_| manager.alloc_frame().unwrap() / page).collect::<Vec<_>>();
+        assert_eq!(allocated, [0, 1, 4, 5, 6, 7]);
+
+        // the released frames are below the cursor, so they have to be handed out next
+        manager.release_region(2 * page, 2 * page);
+        assert_eq!(manager.alloc_frame().unwrap(), 2 * page);
+        assert_eq!(manager.alloc_frame().unwrap(), 3 * page);
+        assert_eq!(manager.alloc_frame().unwrap(), 8 * page);
+
+        // releasing nothing doesn't move the cursor back
+        manager.release_region(16 * page, 4 * page);
+        manager.release_region(0, 0);
+        assert_eq!(manager.alloc_frame().unwrap(), 9 * page);
+    }
+}
@@ -1,6 +1,9 @@
 //! shared memory
 
-use super::paging::{get_page_manager, PageDirectory, PageFrame};
+use super::{
+    paging::{get_page_dir, get_page_manager, map_memory, FrameTransaction, PageDirectory, PageFrame},
+    sync::BatchedPageDir,
+};
 use crate::{task::get_process, util::array::ConsistentIndexArray};
 use alloc::{collections::BTreeMap, vec::Vec};
 use common::types::{Errno, MmapAccess, ProcessID, Result};
@@ -9,68 +12,294 @@ use spin::Mutex;
 
 pub const MAX_SHARED_IDS: u32 = u32::pow(2, 31) - 2;
 
+/// id of a shared memory area
+pub type SharedId = u32;
+
 pub static SHARED_MEMORY_AREAS: Mutex<ConsistentIndexArray<SharedMemoryArea>> = Mutex::new(ConsistentIndexArray::new());
 pub static PHYS_TO_SHARED: Mutex<BTreeMap<u64, u32>> = Mutex::new(BTreeMap::new());
 
 pub struct SharedMemoryArea {
     pub physical_addresses: Vec<u64>,
-    pub references: usize,
+
+    /// how many references there are to each page in `physical_addresses`. every mapping of a page holds one, as does whoever created the area
+    pub references: Vec<usize>,
     pub access: MmapAccess,
 }
 
-pub fn free_shared_reference(addr: u64) -> bool {
+impl SharedMemoryArea {
+    /// adds a reference to each of the first `num_pages` pages of this area, for when they've been mapped somewhere
+    pub fn add_references(&mut self, num_pages: usize) {
+        for references in self.references.iter_mut().take(num_pages) {
+            *references += 1;
+        }
+    }
+
+    /// drops a reference to the page at the given index in this area, returning whether there are no references left to any of its pages
+    fn remove_reference(&mut self, id: SharedId, index: usize) -> bool {
+        // every reference is only ever dropped once, so running out means something freed a page it didn't hold a reference to
+        assert!(self.references[index] > 0, "shared memory area {id} page {index} reference count underflow (double free?)");
+
+        self.references[index] -= 1;
+        self.references.iter().all(|references| *references == 0)
+    }
+}
+
+/// gets how many references there are to the given physical address in the shared memory area it's part of, or 0 if it isn't part of one
+///
+/// every page directory it's mapped into holds a reference, and so does whoever created the area until it's released
+pub fn shared_reference_count(addr: u64) -> usize {
+    let id = match PHYS_TO_SHARED.lock().get(&addr) {
+        Some(id) => *id,
+        None => return 0,
+    };
+
+    SHARED_MEMORY_AREAS
+        .lock()
+        .get(id as usize)
+        .and_then(|area| area.physical_addresses.iter().position(|phys| *phys == addr).map(|index| area.references[index]))
+        .unwrap_or(0)
+}
+
+/// removes the shared memory area with the given id, freeing its pages and its physical address to shared area id mappings
+/// so stale ids can't be looked up afterwards
+fn remove_area(shm_lock: &mut ConsistentIndexArray<SharedMemoryArea>, id: SharedId) {
+    trace!("no more references, freeing shared memory area {id}");
+
+    if let Some(area) = shm_lock.get(id as usize) {
+        for phys_addr in area.physical_addresses.iter() {
+            super::paging::PAGE_REF_COUNTER.lock().remove_reference(*phys_addr);
+        }
+
+        let mut mapping = PHYS_TO_SHARED.lock();
+        for phys_addr in area.physical_addresses.iter() {
+            mapping.remove(phys_addr);
+        }
+    }
+
+    shm_lock.remove(id as usize);
+}
+
+/// adds a reference to the given physical address in the shared memory area it's part of, returning whether it's part of one
+pub fn add_shared_reference(addr: u64) -> bool {
     let id = match PHYS_TO_SHARED.lock().get(&addr) {
         Some(id) => *id,
         None => return false,
     };
 
     let mut shm_lock = SHARED_MEMORY_AREAS.lock();
-    let area = match shm_lock.get_mut(id as usize) {
-        Some(area) => area,
+
+    match shm_lock.get_mut(id as usize).and_then(|area| area.physical_addresses.iter().position(|phys| *phys == addr).map(|index| (area, index))) {
+        Some((area, index)) => {
+            area.references[index] += 1;
+            true
+        }
+        None => false,
+    }
+}
+
+/// drops a reference to the given physical address in the shared memory area it's part of, freeing the area if nothing references it anymore.
+/// returns whether it's part of one
+pub fn free_shared_reference(addr: u64) -> bool {
+    let id = match PHYS_TO_SHARED.lock().get(&addr) {
+        Some(id) => *id,
         None => return false,
     };
 
-    if area.references <= 1 {
-        // remove the area and free pages if there aren't any remaining references to it
-
-        trace!("no more references, freeing shared memory area {id}");
+    let mut shm_lock = SHARED_MEMORY_AREAS.lock();
 
-        // free pages
-        for phys_addr in area.physical_addresses.iter() {
-            super::paging::PAGE_REF_COUNTER.lock().remove_reference(*phys_addr);
-        }
+    let is_unreferenced = match shm_lock.get_mut(id as usize).and_then(|area| area.physical_addresses.iter().position(|phys| *phys == addr).map(|index| (area, index))) {
+        Some((area, index)) => area.remove_reference(id, index),
+        None => return false,
+    };
 
-        // remove area
-        shm_lock.remove(id as usize);
-    } else {
-        //trace!("removing reference to shared memory area {id}");
-        area.references -= 1;
+    if is_unreferenced {
+        remove_area(&mut shm_lock, id);
     }
 
     true
 }
 
+/// drops the reference the creator of the shared memory area with the given id holds to each of its pages, freeing it if nothing else references it
 pub fn release_shared_area(id: u32) -> Result<()> {
     let mut shm_lock = SHARED_MEMORY_AREAS.lock();
     let area = shm_lock.get_mut(id as usize).ok_or(Errno::InvalidArgument)?;
 
-    if area.physical_addresses.len() >= area.references {
-        // remove the area and free pages if there aren't any remaining references to it
+    let mut is_unreferenced = false;
 
-        trace!("no more references, freeing shared memory area {id}");
+    for index in 0..area.physical_addresses.len() {
+        is_unreferenced = area.remove_reference(id, index);
+    }
 
-        // free pages
-        for phys_addr in area.physical_addresses.iter() {
-            super::paging::PAGE_REF_COUNTER.lock().remove_reference(*phys_addr);
+    if is_unreferenced {
+        remove_area(&mut shm_lock, id);
+    }
+
+    Ok(())
+}
+
+/// a shared memory region backed by its own memory, which can be mapped into any number of page directories at once
+///
+/// the region holds one reference to each of its pages for whoever created it, which is dropped with `release()`. every page mapped with `map_into()`
+/// holds another reference, which is dropped with `unmap_from()` or when the page directory is freed. the backing memory is freed once all of them are gone
+pub struct SharedRegion;
+
+impl SharedRegion {
+    /// allocates a new zeroed shared region at least `size` bytes long and returns its id
+    pub fn create(size: usize, access: MmapAccess) -> Result<SharedId> {
+        let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+        let num_pages = (size + page_size - 1) / page_size;
+
+        if num_pages == 0 {
+            return Err(Errno::InvalidArgument);
         }
 
-        // remove area
-        shm_lock.remove(id as usize);
-    } else {
-        area.references -= area.physical_addresses.len();
+        let mut transaction = FrameTransaction::new();
+
+        for _i in 0..num_pages {
+            let result = transaction.alloc_frame(&mut get_page_manager());
+
+            if let Err(err) = result {
+                transaction.rollback(&mut get_page_manager());
+                return Err(err.into());
+            }
+        }
+
+        let result = transaction
+            .allocated()
+            .iter()
+            .try_for_each(|phys_addr| unsafe { map_memory(&mut get_page_dir(None), &[*phys_addr], |s| s.fill(0)) });
+
+        if let Err(err) = result {
+            transaction.rollback(&mut get_page_manager());
+            return Err(err.into());
+        }
+
+        let mut share = match TempMemoryShare::new(Default::default(), 0, num_pages * page_size - 1) {
+            Ok(share) => share,
+            Err(err) => {
+                transaction.rollback(&mut get_page_manager());
+                return Err(err);
+            }
+        };
+
+        for phys_addr in transaction.allocated().iter() {
+            share.add_new(*phys_addr);
+        }
+
+        // the share frees the frames if anything goes wrong from here on
+        transaction.commit(&mut get_page_manager());
+
+        let id = share.share(access)?;
+
+        trace!("created shared region {id} ({num_pages} pages)");
+
+        Ok(id)
     }
 
-    Ok(())
+    /// maps the shared region with the given id into a page directory, starting at the given address
+    pub fn map_into(dir: &mut impl PageDirectory, id: SharedId, addr: usize) -> Result<()> {
+        let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+
+        if addr % page_size != 0 || addr >= crate::arch::KERNEL_PAGE_DIR_SPLIT {
+            return Err(Errno::InvalidArgument);
+        }
+
+        let mut shm_lock = SHARED_MEMORY_AREAS.lock();
+        let area = shm_lock.get_mut(id as usize).ok_or(Errno::InvalidArgument)?;
+
+        let end = addr.checked_add(area.physical_addresses.len() * page_size).ok_or(Errno::InvalidArgument)?;
+
+        if end > crate::arch::KERNEL_PAGE_DIR_SPLIT {
+            return Err(Errno::InvalidArgument);
+        }
+
+        for page_addr in (addr..end).step_by(page_size) {
+            if dir.get_page(page_addr).is_some() {
+                return Err(Errno::Exists);
+            }
+        }
+
+        for (index, phys_addr) in area.physical_addresses.iter().enumerate() {
+            let result = dir.set_page(
+                addr + index * page_size,
+                Some(PageFrame {
+                    addr: *phys_addr,
+                    present: true,
+                    user_mode: true,
+                    writable: (area.access & MmapAccess::Write).bits() > 0,
+                    executable: dir.executable_if((area.access & MmapAccess::Execute).bits() > 0),
+                    referenced: true,
+                    shared: true,
+                    ..Default::default()
+                }),
+            );
+
+            if let Err(err) = result {
+                // undo what we've mapped so far, none of it holds a reference yet
+                for page_addr in (addr..addr + index * page_size).step_by(page_size) {
+                    let _ = dir.set_page(page_addr, None);
+                }
+
+                return Err(err.into());
+            }
+        }
+
+        area.add_references(area.physical_addresses.len());
+
+        Ok(())
+    }
+
+    /// unmaps the shared region with the given id from a page directory, where it was mapped at the given address
+    pub fn unmap_from(dir: &mut impl PageDirectory, id: SharedId, addr: usize) -> Result<()> {
+        let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+
+        // copy the addresses out, since dropping references needs the lock
+        let physical_addresses = {
+            let shm_lock = SHARED_MEMORY_AREAS.lock();
+            let area = shm_lock.get(id as usize).ok_or(Errno::InvalidArgument)?;
+
+            let mut physical_addresses = Vec::new();
+            physical_addresses.try_reserve_exact(area.physical_addresses.len()).map_err(|_| Errno::OutOfMemory)?;
+            physical_addresses.extend_from_slice(&area.physical_addresses);
+            physical_addresses
+        };
+
+        // make sure the whole region is actually mapped there before touching anything
+        for (index, phys_addr) in physical_addresses.iter().enumerate() {
+            match dir.get_page(addr + index * page_size) {
+                Some(page) if page.present && page.shared && page.addr == *phys_addr => (),
+                _ => return Err(Errno::InvalidArgument),
+            }
+        }
+
+        // other CPUs could still have the pages cached until the batch is flushed, so the references can only be dropped after that
+        let mut batched = BatchedPageDir::new(dir);
+
+        let mut unmapped = 0;
+        let mut result = Ok(());
+
+        for index in 0..physical_addresses.len() {
+            if let Err(err) = batched.set_page(addr + index * page_size, None) {
+                result = Err(err.into());
+                break;
+            }
+
+            unmapped += 1;
+        }
+
+        drop(batched);
+
+        for phys_addr in physical_addresses.iter().take(unmapped) {
+            free_shared_reference(*phys_addr);
+        }
+
+        result
+    }
+
+    /// drops the reference held by the creator of the shared region with the given id
+    pub fn release(id: SharedId) -> Result<()> {
+        release_shared_area(id)
+    }
 }
 
 enum FreeMode {
@@ -180,12 +409,16 @@ impl TempMemoryShare {
             new_phys_addrs.push(entry.phys_addr);
         }
 
+        let mut references = Vec::new();
+        references.try_reserve_exact(self.phys_addresses.len()).map_err(|_| Errno::OutOfMemory)?;
+        references.resize(self.phys_addresses.len(), 1);
+
         let mut shm_lock = SHARED_MEMORY_AREAS.lock();
 
         let id = shm_lock
             .add(SharedMemoryArea {
                 physical_addresses: new_phys_addrs,
-                references: self.phys_addresses.len(),
+                references,
                 access,
             })
             .map_err(|_| Errno::OutOfMemory)?;
@@ -252,3 +485,97 @@ impl Drop for TempMemoryShare {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_ADDR: usize = 0x4000_0000;
+    const TEST_PAGES: usize = 3;
+    const TEST_DIRS: usize = 4;
+
+    fn physical_addresses(id: SharedId) -> Vec<u64> {
+        SHARED_MEMORY_AREAS.lock().get(id as usize).unwrap().physical_addresses.clone()
+    }
+
+    #[test_case]
+    fn references_are_counted_per_mapping() {
+        let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+        let id = SharedRegion::create(TEST_PAGES * page_size, MmapAccess::default()).unwrap();
+        let physical_addresses = physical_addresses(id);
+
+        // the creator holds a reference to every page
+        for phys_addr in physical_addresses.iter() {
+            assert_eq!(shared_reference_count(*phys_addr), 1);
+        }
+
+        let mut dirs = Vec::new();
+
+        for mapped in 1..=TEST_DIRS {
+            let mut dir = crate::arch::PageDirectory::new().unwrap();
+            SharedRegion::map_into(&mut dir, id, TEST_ADDR).unwrap();
+            dirs.push(dir);
+
+            for phys_addr in physical_addresses.iter() {
+                assert_eq!(shared_reference_count(*phys_addr), mapped + 1);
+            }
+        }
+
+        SharedRegion::release(id).unwrap();
+
+        for phys_addr in physical_addresses.iter() {
+            assert_eq!(shared_reference_count(*phys_addr), TEST_DIRS);
+        }
+
+        for (unmapped, dir) in dirs.iter_mut().enumerate() {
+            SharedRegion::unmap_from(dir, id, TEST_ADDR).unwrap();
+
+            for phys_addr in physical_addresses.iter() {
+                assert_eq!(shared_reference_count(*phys_addr), TEST_DIRS - unmapped - 1);
+            }
+        }
+
+        // the last unmap should've freed the area
+        assert!(SHARED_MEMORY_AREAS.lock().get(id as usize).is_none());
+    }
+
+    #[test_case]
+    fn freeing_a_single_page_keeps_the_area() {
+        let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+        let id = SharedRegion::create(TEST_PAGES * page_size, MmapAccess::default()).unwrap();
+        let physical_addresses = physical_addresses(id);
+
+        let mut dir = crate::arch::PageDirectory::new().unwrap();
+        SharedRegion::map_into(&mut dir, id, TEST_ADDR).unwrap();
+        SharedRegion::release(id).unwrap();
+
+        // a mapping of one page going away shouldn't take the rest of the area with it
+        dir.set_page(TEST_ADDR, None).unwrap();
+        assert!(free_shared_reference(physical_addresses[0]));
+
+        assert_eq!(shared_reference_count(physical_addresses[0]), 0);
+        assert_eq!(shared_reference_count(physical_addresses[1]), 1);
+        assert!(SHARED_MEMORY_AREAS.lock().get(id as usize).is_some());
+
+        for (index, phys_addr) in physical_addresses.iter().enumerate().skip(1) {
+            dir.set_page(TEST_ADDR + index * page_size, None).unwrap();
+            assert!(free_shared_reference(*phys_addr));
+        }
+
+        assert!(SHARED_MEMORY_AREAS.lock().get(id as usize).is_none());
+    }
+
+    #[test_case]
+    fn freeing_a_removed_area_is_caught() {
+        let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+        let id = SharedRegion::create(page_size, MmapAccess::default()).unwrap();
+        let phys_addr = physical_addresses(id)[0];
+
+        SharedRegion::release(id).unwrap();
+
+        // once the area's gone its pages aren't shared anymore, so extra frees and releases can't touch another area
+        assert_eq!(shared_reference_count(phys_addr), 0);
+        assert!(!free_shared_reference(phys_addr));
+        assert_eq!(SharedRegion::release(id), Err(Errno::InvalidArgument));
+    }
+}
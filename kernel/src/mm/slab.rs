@@ -0,0 +1,293 @@
+//! object caches for fixed size kernel objects
+//!
+//! each cache hands out slots carved out of page sized runs of memory (slabs), and keeps freed slots on an intrusive free list so
+//! they can be reused without going back to the heap. slabs are only ever allocated, never given back until the cache itself is dropped,
+//! so a cache that's churned thru lots of objects won't leave holes all over the heap
+
+use super::paging::PagingError;
+use crate::arch::PAGE_SIZE;
+use alloc::{
+    alloc::{alloc, dealloc, Layout},
+    vec::Vec,
+};
+use core::{
+    marker::PhantomData,
+    mem::{align_of, size_of},
+    ptr::NonNull,
+};
+use log::trace;
+
+/// how many pages are in each slab
+pub const SLAB_PAGES: usize = 1;
+
+/// a free slot in a slab, which stores a pointer to the next free slot in its own memory
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// a cache of objects of type T
+///
+/// dropping the cache frees all of its slabs, so any objects still allocated from it at that point are leaked without being dropped and become dangling
+pub struct ObjectCache<T> {
+    /// every slab this cache has allocated
+    slabs: Vec<NonNull<u8>>,
+
+    /// the first free slot, if there are any
+    free: Option<NonNull<FreeSlot>>,
+
+    /// how many objects are currently allocated from this cache
+    in_use: usize,
+
+    _marker: PhantomData<T>,
+}
+
+// the cache owns its slabs and the objects in them, so it can be sent wherever T can be
+unsafe impl<T: Send> Send for ObjectCache<T> {}
+
+impl<T> ObjectCache<T> {
+    /// the size of each slot, which has to be able to hold either an object or a free list entry
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<FreeSlot>() { size_of::<T>() } else { size_of::<FreeSlot>() };
+        let align = Self::SLOT_ALIGN;
+
+        (size + align - 1) / align * align
+    };
+
+    /// the alignment of each slot
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<FreeSlot>() { align_of::<T>() } else { align_of::<FreeSlot>() };
+
+    /// the size of each slab in bytes
+    const SLAB_SIZE: usize = PAGE_SIZE * SLAB_PAGES;
+
+    /// how many objects fit in a single slab
+    pub const OBJECTS_PER_SLAB: usize = Self::SLAB_SIZE / Self::SLOT_SIZE;
+
+    /// creates a new empty cache. no memory is allocated until the first object is
+    pub const fn new() -> Self {
+        Self {
+            slabs: Vec::new(),
+            free: None,
+            in_use: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(Self::SLAB_SIZE, PAGE_SIZE.max(Self::SLOT_ALIGN)).unwrap()
+    }
+
+    /// allocates a new slab and puts all of its slots on the free list
+    fn grow(&mut self) -> Result<(), PagingError> {
+        assert!(Self::OBJECTS_PER_SLAB > 0, "objects are too big to fit in a slab");
+
+        self.slabs.try_reserve(1).map_err(|_| PagingError::AllocError)?;
+
+        let slab = NonNull::new(unsafe { alloc(Self::slab_layout()) }).ok_or(PagingError::AllocError)?;
+        self.slabs.push(slab);
+
+        trace!("new slab @ {:#x} with {} slots of {} bytes", slab.as_ptr() as usize, Self::OBJECTS_PER_SLAB, Self::SLOT_SIZE);
+
+        // push the slots in reverse so they get handed out in address order
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            unsafe {
+                self.push_free(NonNull::new_unchecked(slab.as_ptr().add(i * Self::SLOT_SIZE)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// puts a slot on the free list
+    ///
+    /// # Safety
+    ///
+    /// the slot must be in one of this cache's slabs and must not be in use
+    unsafe fn push_free(&mut self, slot: NonNull<u8>) {
+        let slot = slot.cast::<FreeSlot>();
+        slot.as_ptr().write(FreeSlot { next: self.free });
+        self.free = Some(slot);
+    }
+
+    /// checks whether the given pointer is the start of a slot in one of this cache's slabs
+    fn owns(&self, ptr: *const u8) -> bool {
+        let addr = ptr as usize;
+
+        self.slabs.iter().any(|slab| {
+            let start = slab.as_ptr() as usize;
+            addr >= start && addr < start + Self::OBJECTS_PER_SLAB * Self::SLOT_SIZE && (addr - start) % Self::SLOT_SIZE == 0
+        })
+    }
+
+    /// moves the given value into a free slot, allocating a new slab if there aren't any
+    pub fn alloc(&mut self, value: T) -> Result<NonNull<T>, PagingError> {
+        if self.free.is_none() {
+            self.grow()?;
+        }
+
+        let slot = self.free.expect("free list is empty after growing");
+
+        unsafe {
+            self.free = slot.as_ref().next;
+
+            let object = slot.cast::<T>();
+            object.as_ptr().write(value);
+
+            self.in_use += 1;
+
+            Ok(object)
+        }
+    }
+
+    /// drops the given object and puts its slot back in the cache. this never touches the heap
+    ///
+    /// # Safety
+    ///
+    /// the object must have been allocated from this cache and not freed already, and nothing can use it after this
+    pub unsafe fn free(&mut self, object: NonNull<T>) {
+        debug_assert!(self.owns(object.as_ptr() as *const u8), "object wasn't allocated from this cache");
+
+        object.as_ptr().drop_in_place();
+        self.push_free(object.cast::<u8>());
+
+        self.in_use -= 1;
+    }
+
+    /// gets how many objects are currently allocated from this cache
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// gets how many objects this cache can hold without allocating another slab
+    pub fn capacity(&self) -> usize {
+        self.slabs.len() * Self::OBJECTS_PER_SLAB
+    }
+}
+
+impl<T> Default for ObjectCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for ObjectCache<T> {
+    fn drop(&mut self) {
+        for slab in self.slabs.iter() {
+            unsafe {
+                dealloc(slab.as_ptr(), Self::slab_layout());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// counts how many times it's been dropped
+    struct DropCounter<'a>(&'a AtomicUsize);
+
+    impl Drop for DropCounter<'_> {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test_case]
+    fn objects_are_stored_in_their_slots() {
+        let mut cache = ObjectCache::<u64>::new();
+        assert_eq!(cache.capacity(), 0);
+
+        let a = cache.alloc(0x1234).unwrap();
+        let b = cache.alloc(0x5678).unwrap();
+
+        assert_eq!(unsafe { *a.as_ptr() }, 0x1234);
+        assert_eq!(unsafe { *b.as_ptr() }, 0x5678);
+        assert_eq!(cache.in_use(), 2);
+        assert_eq!(cache.capacity(), ObjectCache::<u64>::OBJECTS_PER_SLAB);
+
+        // slots are handed out in address order
+        assert_eq!(b.as_ptr() as usize - a.as_ptr() as usize, ObjectCache::<u64>::SLOT_SIZE);
+
+        unsafe {
+            cache.free(a);
+            cache.free(b);
+        }
+
+        assert_eq!(cache.in_use(), 0);
+    }
+
+    #[test_case]
+    fn freed_slots_are_reused() {
+        let mut cache = ObjectCache::<u32>::new();
+
+        let a = cache.alloc(1).unwrap();
+        let _b = cache.alloc(2).unwrap();
+
+        unsafe {
+            cache.free(a);
+        }
+
+        assert_eq!(cache.alloc(3).unwrap(), a);
+        assert_eq!(cache.capacity(), ObjectCache::<u32>::OBJECTS_PER_SLAB);
+    }
+
+    #[test_case]
+    fn full_caches_grow() {
+        let mut cache = ObjectCache::<[u8; 256]>::new();
+        let per_slab = ObjectCache::<[u8; 256]>::OBJECTS_PER_SLAB;
+
+        let objects = (0..=per_slab).map(|i| cache.alloc([i as u8; 256]).unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(cache.capacity(), per_slab * 2);
+        assert_eq!(cache.in_use(), per_slab + 1);
+
+        for (i, object) in objects.iter().enumerate() {
+            assert!(cache.owns(object.as_ptr() as *const u8));
+            assert_eq!(unsafe { (*object.as_ptr())[255] }, i as u8);
+        }
+
+        for object in objects {
+            unsafe {
+                cache.free(object);
+            }
+        }
+
+        // slabs are kept around for later
+        assert_eq!(cache.capacity(), per_slab * 2);
+    }
+
+    #[test_case]
+    fn free_drops_objects() {
+        let drops = AtomicUsize::new(0);
+        let mut cache = ObjectCache::new();
+
+        let object = cache.alloc(DropCounter(&drops)).unwrap();
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+
+        unsafe {
+            cache.free(object);
+        }
+
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test_case]
+    fn slots_are_aligned() {
+        #[repr(align(64))]
+        struct Aligned(u8);
+
+        let mut cache = ObjectCache::new();
+        let a = cache.alloc(Aligned(1)).unwrap();
+        let b = cache.alloc(Aligned(2)).unwrap();
+
+        assert_eq!(a.as_ptr() as usize % 64, 0);
+        assert_eq!(b.as_ptr() as usize % 64, 0);
+        assert_eq!(unsafe { (*b.as_ptr()).0 }, 2);
+
+        unsafe {
+            cache.free(a);
+            cache.free(b);
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//! swapping pages out to a backing device and back in again
+
+use super::paging::{alloc_frame_or_reclaim, get_page_dir, get_page_manager, map_memory, PageDirectory, PageFrame, PagingError};
+use crate::{arch::KERNEL_PAGE_DIR_SPLIT, util::array::VecBitSet};
+use alloc::boxed::Box;
+use common::types::Errno;
+use log::{debug, error, trace};
+use spin::Mutex;
+
+/// index of a page-sized slot in a swap device
+pub type SwapSlot = u32;
+
+/// the largest number of slots a swap device can have, limited by how many bits we have to encode slots in a page table entry
+pub const MAX_SWAP_SLOTS: usize = 1 << 20;
+
+/// a backing store that pages can be evicted to when we're running low on memory
+pub trait SwapDevice: Send {
+    /// how many page-sized slots this device has
+    fn num_slots(&self) -> usize;
+
+    /// writes a page's worth of data into the given slot
+    fn write_page(&mut self, slot: SwapSlot, data: &[u8]) -> Result<(), Errno>;
+
+    /// reads a page's worth of data from the given slot
+    fn read_page(&mut self, slot: SwapSlot, data: &mut [u8]) -> Result<(), Errno>;
+}
+
+struct SwapState {
+    device: Box<dyn SwapDevice>,
+    used_slots: VecBitSet,
+
+    /// slots that are being written to or read from, which pages can't be swapped in from until that's done
+    busy_slots: VecBitSet,
+}
+
+static SWAP: Mutex<Option<SwapState>> = Mutex::new(None);
+
+/// registers a swap device, replacing the old one if there is one
+///
+/// pages swapped out to the old device can't be swapped back in, so this fails if any of them are still in use
+pub fn register_swap_device(device: Box<dyn SwapDevice>) -> Result<(), Errno> {
+    let mut swap = SWAP.lock();
+
+    if let Some(state) = swap.as_ref() && state.used_slots.bits_used > 0 {
+        return Err(Errno::Busy);
+    }
+
+    debug!("registering swap device with {} slots", device.num_slots());
+
+    *swap = Some(SwapState {
+        device,
+        used_slots: VecBitSet::new(),
+        busy_slots: VecBitSet::new(),
+    });
+
+    Ok(())
+}
+
+/// releases a swap slot so it can be reused. used when a swapped out page is freed without being swapped back in
+pub fn free_swap_slot(slot: SwapSlot) {
+    if let Some(state) = SWAP.lock().as_mut() {
+        state.used_slots.clear(slot as usize);
+    }
+}
+
+/// marks a slot as having been written to (or given up on), so pages swapped out to it can be swapped back in
+fn finish_writing_slot(slot: SwapSlot) {
+    if let Some(state) = SWAP.lock().as_mut() {
+        state.busy_slots.clear(slot as usize);
+    }
+}
+
+/// evicts the page at the given address in the given page directory to the swap device, freeing its frame and leaving a non present entry that remembers the slot it was written to
+///
+/// pages that are shared or reference counted can't be swapped out, since other page directories point to their frames
+pub fn swap_out<D: PageDirectory>(dir: &mut D, addr: usize) -> Result<SwapSlot, PagingError> {
+    let page = dir.get_page(addr).ok_or(PagingError::BadAddress)?;
+
+    if !page.present || page.shared || page.referenced || page.copy_on_write {
+        return Err(PagingError::BadFrame);
+    }
+
+    // claim a slot up front. the swap device isn't kept locked while the page is unmapped, since that waits on other CPUs which may be waiting on it too
+    let slot = {
+        let mut swap = SWAP.lock();
+        let state = swap.as_mut().ok_or(PagingError::NoAvailableFrames)?;
+
+        let slot = state.used_slots.first_unset();
+
+        if slot >= state.device.num_slots().min(MAX_SWAP_SLOTS) {
+            return Err(PagingError::NoAvailableFrames);
+        }
+
+        state.used_slots.set(slot);
+        state.busy_slots.set(slot);
+
+        slot as SwapSlot
+    };
+
+    trace!("swapping out {addr:#x} (phys {:#x}) to slot {slot}", page.addr);
+
+    // unmap the page before copying it out, so nothing can write to it after it's been copied
+    let result = dir.set_page(
+        addr,
+        Some(PageFrame {
+            addr: slot as u64,
+            user_mode: page.user_mode,
+            writable: page.writable,
+            executable: page.executable,
+            swapped: true,
+            ..Default::default()
+        }),
+    );
+
+    if let Err(err) = result {
+        finish_writing_slot(slot);
+        free_swap_slot(slot);
+        return Err(err);
+    }
+
+    let result = match SWAP.lock().as_mut() {
+        Some(state) => match unsafe { map_memory(&mut get_page_dir(None), &[page.addr], |s| state.device.write_page(slot, s)) } {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => {
+                error!("couldn't write to swap device: {err:?}");
+                Err(PagingError::BadFrame)
+            }
+            Err(err) => Err(err),
+        },
+        None => Err(PagingError::BadFrame),
+    };
+
+    finish_writing_slot(slot);
+
+    if let Err(err) = result {
+        // put the page back the way it was
+        dir.set_page(addr, Some(page))?;
+        free_swap_slot(slot);
+        return Err(err);
+    }
+
+    get_page_manager().set_frame_free(page.addr);
+
+    Ok(slot)
+}
+
+/// where [evict_page] left off, as a process ID and an address in it, so repeated evictions cycle thru every process' memory instead of
+/// hitting the same pages over and over
+static EVICT_CURSOR: Mutex<(u32, usize)> = Mutex::new((1, 0));
+
+/// swaps out a page from some process to free up its frame, for when physical memory has run out
+///
+/// processes are scanned round-robin starting from wherever the last eviction left off. the process running on this CPU is skipped, since whatever
+/// ran out of memory is likely working with its pages, and so are processes that are locked, since waiting on them could deadlock.
+/// neither the page manager nor the current process can be locked when calling this
+///
+/// returns true if a page was swapped out
+pub fn evict_page() -> bool {
+    // if the swap device is locked it might be by whatever ran out of memory, in which case it can't be used to free any up
+    if !SWAP.try_lock().map(|swap| swap.is_some()).unwrap_or(false) {
+        return false;
+    }
+
+    let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+
+    let Some(thread) = crate::task::get_cpus().and_then(|cpus| cpus.get_thread(crate::arch::get_thread_id())) else {
+        return false;
+    };
+
+    let current = match thread.task_queue.try_lock() {
+        Some(queue) => queue.current().map(|c| c.id().process),
+        None => return false,
+    };
+
+    let Some(mut cursor) = EVICT_CURSOR.try_lock() else {
+        // something else is already evicting pages, which will free up memory just as well
+        return false;
+    };
+
+    let highest = crate::task::highest_process_id();
+    let (start_id, start_addr) = *cursor;
+
+    // go around every process once, finishing off the start of the process we started partway thru
+    for i in 0..=highest {
+        let id = (start_id.saturating_sub(1) + i) % highest.max(1) + 1;
+
+        if Some(id) == current {
+            continue;
+        }
+
+        let Some(mut process) = crate::task::try_get_process(id) else {
+            continue;
+        };
+
+        let first_addr = if i == 0 { start_addr } else { 0 };
+        let addrs = (first_addr..KERNEL_PAGE_DIR_SPLIT).step_by(page_size).chain((0..if i == highest { start_addr } else { 0 }).step_by(page_size));
+
+        for addr in addrs {
+            let can_evict = match process.page_directory.get_page(addr) {
+                Some(page) => page.present && page.user_mode && !page.shared && !page.referenced && !page.copy_on_write,
+                None => false,
+            };
+
+            if can_evict && swap_out(&mut process.page_directory, addr).is_ok() {
+                debug!("evicted page {addr:#x} of process {id}");
+                *cursor = (id, addr + page_size);
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+/// brings the page at the given address in the given page directory back in from the swap device if it's been swapped out
+///
+/// returns true if the page was swapped in and false if it wasn't swapped out in the first place. if the page is still being swapped out or
+/// is being swapped in by something else, nothing is done and true is returned, since accessing it again will find it wherever it ends up
+pub fn swap_in<D: PageDirectory>(dir: &mut D, addr: usize) -> Result<bool, PagingError> {
+    let page = match dir.get_page(addr) {
+        Some(page) if page.swapped && !page.present => page,
+        _ => return Ok(false),
+    };
+
+    let slot = page.addr as SwapSlot;
+
+    // claim the slot. the swap device isn't kept locked for all of this, since allocating a frame can end up swapping other pages out
+    {
+        let mut swap = SWAP.lock();
+        let state = swap.as_mut().ok_or(PagingError::BadFrame)?;
+
+        if state.busy_slots.test(slot as usize) {
+            return Ok(true);
+        }
+
+        state.busy_slots.set(slot as usize);
+    }
+
+    trace!("swapping in {addr:#x} from slot {slot}");
+
+    let result = (|| {
+        let phys = alloc_frame_or_reclaim()?;
+
+        let read = match SWAP.lock().as_mut() {
+            Some(state) => match unsafe { map_memory(&mut get_page_dir(None), &[phys], |s| state.device.read_page(slot, s)) } {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(err)) => {
+                    error!("couldn't read from swap device: {err:?}");
+                    Err(PagingError::BadFrame)
+                }
+                Err(err) => Err(err),
+            },
+            None => Err(PagingError::BadFrame),
+        };
+
+        let new_page = PageFrame {
+            addr: phys,
+            present: true,
+            user_mode: page.user_mode,
+            writable: page.writable,
+            executable: page.executable,
+            ..Default::default()
+        };
+
+        if let Err(err) = read.and_then(|_| dir.set_page(addr, Some(new_page))) {
+            get_page_manager().set_frame_free(phys);
+            return Err(err);
+        }
+
+        Ok(())
+    })();
+
+    if let Some(state) = SWAP.lock().as_mut() {
+        state.busy_slots.clear(slot as usize);
+
+        if result.is_ok() {
+            state.used_slots.clear(slot as usize);
+        }
+    }
+
+    result.map(|_| true)
+}
+
+/// used in page fault exception handlers to check whether the faulting page was swapped out and bring it back in if so
+///
+/// returns true if a page was swapped in and false if it wasn't swapped out
+pub fn try_swap_in(thread: &crate::task::cpu::CPUThread, addr: usize) -> Result<bool, Errno> {
+    let current_id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    let page_size = crate::arch::PageDirectory::PAGE_SIZE;
+    let addr = (addr / page_size) * page_size;
+
+    Ok(swap_in(&mut super::paging::ProcessOrKernelPageDir::Process(current_id.process), addr)?)
+}
@@ -3,16 +3,106 @@ use crate::arch::KERNEL_PAGE_DIR_SPLIT;
 use log::{debug, trace};
 use spin::{Mutex, MutexGuard};
 
+/// coalesces page updates at contiguous addresses into a single range, so other CPUs can be told about all of them at once
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PageUpdateBatch {
+    start: usize,
+    len: usize,
+}
+
+impl PageUpdateBatch {
+    /// adds a page to the batch. if it doesn't directly follow the pages already in the batch, the batch is restarted at this page
+    /// and the range that was in it is returned so it can be sent
+    pub fn add(&mut self, addr: usize, page_size: usize) -> Option<(usize, usize)> {
+        if self.len > 0 && self.start.checked_add(self.len) == Some(addr) {
+            self.len += page_size;
+            None
+        } else {
+            let previous = self.take();
+            self.start = addr;
+            self.len = page_size;
+            previous
+        }
+    }
+
+    /// empties the batch, returning the start and length of the range that was in it
+    pub fn take(&mut self) -> Option<(usize, usize)> {
+        if self.len == 0 {
+            None
+        } else {
+            let range = (self.start, self.len);
+            self.len = 0;
+            Some(range)
+        }
+    }
+}
+
+/// holds back TLB shootdowns for pages set thru it, coalescing contiguous ones into ranges which are sent once it's dropped.
+/// the batch belongs to whoever made this, so updates made to the same page directory by anything else are never held back with it.
+/// frames unmapped thru this mustn't be freed until it's been dropped, since other CPUs could still have them cached
+pub struct BatchedPageDir<'a, D: PageDirectory> {
+    dir: &'a mut D,
+    batch: PageUpdateBatch,
+}
+
+impl<'a, D: PageDirectory> BatchedPageDir<'a, D> {
+    pub fn new(dir: &'a mut D) -> Self {
+        Self { dir, batch: PageUpdateBatch::default() }
+    }
+}
+
+impl<D: PageDirectory> PageDirectory for BatchedPageDir<'_, D> {
+    const PAGE_SIZE: usize = D::PAGE_SIZE;
+    const NO_EXECUTE: bool = D::NO_EXECUTE;
+
+    fn get_page(&self, addr: usize) -> Option<PageFrame> {
+        self.dir.get_page(addr)
+    }
+
+    fn set_page(&mut self, addr: usize, page: Option<PageFrame>) -> Result<(), PagingError> {
+        self.dir.set_page_batched(addr, page, &mut self.batch)
+    }
+
+    unsafe fn switch_to(&self) {
+        self.dir.switch_to()
+    }
+
+    fn is_current(&self) -> bool {
+        self.dir.is_current()
+    }
+
+    fn is_unused(&self, addr: usize) -> bool {
+        self.dir.is_unused(addr)
+    }
+
+    fn virt_to_phys(&self, virt: usize) -> Option<u64> {
+        self.dir.virt_to_phys(virt)
+    }
+}
+
+impl<D: PageDirectory> Drop for BatchedPageDir<'_, D> {
+    fn drop(&mut self) {
+        self.dir.flush_batch(&mut self.batch);
+    }
+}
+
 pub struct PageDirSync<'kernel, D: PageDirectory> {
     pub kernel: &'kernel Mutex<PageDirTracker<D>>,
     pub task: D,
     pub process_id: u32,
     pub kernel_space_updates: usize,
     pub should_update_pages: bool,
+
+    /// how many user pages are present in the task directory
+    pub resident_pages: usize,
+
+    /// how many user pages are mapped in the task directory at all, present or not
+    pub reserved_pages: usize,
 }
 
 impl<D: PageDirectory> PageDirectory for PageDirSync<'_, D> {
     const PAGE_SIZE: usize = D::PAGE_SIZE;
+    const NO_EXECUTE: bool = D::NO_EXECUTE;
 
     fn get_page(&self, addr: usize) -> Option<PageFrame> {
         if addr >= KERNEL_PAGE_DIR_SPLIT {
@@ -23,6 +113,44 @@ impl<D: PageDirectory> PageDirectory for PageDirSync<'_, D> {
     }
 
     fn set_page(&mut self, addr: usize, page: Option<PageFrame>) -> Result<(), PagingError> {
+        self.set_page_inner(addr, page, None)
+    }
+
+    unsafe fn switch_to(&self) {
+        self.task.switch_to()
+    }
+
+    fn set_page_batched(&mut self, addr: usize, page: Option<PageFrame>, batch: &mut PageUpdateBatch) -> Result<(), PagingError> {
+        self.set_page_inner(addr, page, Some(batch))
+    }
+
+    fn flush_batch(&mut self, batch: &mut PageUpdateBatch) {
+        if let Some((start, len)) = batch.take() && self.should_update_pages {
+            crate::task::update_task_range(self.process_id, self.task.physical_addr(), start, len);
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        self.task.is_current()
+    }
+
+    fn physical_addr(&self) -> Option<u32> {
+        self.task.physical_addr()
+    }
+
+    fn is_unused(&self, addr: usize) -> bool {
+        self.task.is_unused(addr)
+    }
+
+    fn virt_to_phys(&self, virt: usize) -> Option<u64> {
+        self.task.virt_to_phys(virt)
+    }
+}
+
+impl<D: PageDirectory> PageDirSync<'_, D> {
+    /// sets a page, adding the update for it to the given batch if there is one rather than sending it right away.
+    /// kernel pages are never batched, since they have to be synced to the kernel directory immediately anyway
+    fn set_page_inner(&mut self, addr: usize, page: Option<PageFrame>, batch: Option<&mut PageUpdateBatch>) -> Result<(), PagingError> {
         if addr >= KERNEL_PAGE_DIR_SPLIT {
             trace!("(process {}) setting page {addr:#x} in task directory", self.process_id);
             self.task.set_page(addr, page)?;
@@ -42,30 +170,40 @@ impl<D: PageDirectory> PageDirectory for PageDirSync<'_, D> {
             trace!("(process {}) sending page update", self.process_id);
             crate::task::update_kernel_page(addr);
         } else {
+            let old = self.task.get_page(addr);
             self.task.set_page(addr, page)?;
+            self.account(old, page);
 
             if self.should_update_pages {
-                crate::task::update_task_page(self.process_id, addr);
+                match batch {
+                    Some(batch) => {
+                        if let Some((start, len)) = batch.add(addr, Self::PAGE_SIZE) {
+                            crate::task::update_task_range(self.process_id, self.task.physical_addr(), start, len);
+                        }
+                    }
+                    None => crate::task::update_task_page(self.process_id, self.task.physical_addr(), addr),
+                }
             }
         }
 
         Ok(())
     }
 
-    unsafe fn switch_to(&self) {
-        self.task.switch_to()
-    }
+    /// updates the page counters after a user page changed from old to new. replacing a page with another one (i.e. copying a copy-on-write page)
+    /// doesn't change anything, so each page is only ever counted once no matter how many times it's been replaced
+    fn account(&mut self, old: Option<PageFrame>, new: Option<PageFrame>) {
+        let resident = |page: Option<PageFrame>| matches!(page, Some(page) if page.present) as usize;
+        let reserved = |page: Option<PageFrame>| page.is_some() as usize;
 
-    fn is_unused(&self, addr: usize) -> bool {
-        self.task.is_unused(addr)
+        self.resident_pages = (self.resident_pages + resident(new)).saturating_sub(resident(old));
+        self.reserved_pages = (self.reserved_pages + reserved(new)).saturating_sub(reserved(old));
     }
 
-    fn virt_to_phys(&self, virt: usize) -> Option<u64> {
-        self.task.virt_to_phys(virt)
+    /// recounts the page counters from scratch. has to be called whenever the task directory is replaced wholesale
+    pub fn recount(&mut self) {
+        (self.resident_pages, self.reserved_pages) = self.task.count_pages(KERNEL_PAGE_DIR_SPLIT);
     }
-}
 
-impl<D: PageDirectory> PageDirSync<'_, D> {
     /// synchronizes if we've fallen out of sync
     pub fn sync(&mut self) {
         if self.kernel_space_updates != self.kernel.lock().updates() {
@@ -111,26 +249,34 @@ pub struct PageDirTracker<D: PageDirectory> {
 
 impl<D: PageDirectory> PageDirectory for PageDirTracker<D> {
     const PAGE_SIZE: usize = D::PAGE_SIZE;
+    const NO_EXECUTE: bool = D::NO_EXECUTE;
 
     fn get_page(&self, addr: usize) -> Option<PageFrame> {
         self.page_dir.get_page(addr)
     }
 
     fn set_page(&mut self, addr: usize, page: Option<PageFrame>) -> Result<(), PagingError> {
-        self.updates = self.updates.wrapping_add(1);
-        self.page_dir.set_page(addr, page)?;
-
-        if self.is_kernel && addr > KERNEL_PAGE_DIR_SPLIT {
-            crate::task::update_kernel_page(addr);
-        }
-
-        Ok(())
+        self.set_page_inner(addr, page, None)
     }
 
     unsafe fn switch_to(&self) {
         self.page_dir.switch_to()
     }
 
+    fn set_page_batched(&mut self, addr: usize, page: Option<PageFrame>, batch: &mut PageUpdateBatch) -> Result<(), PagingError> {
+        self.set_page_inner(addr, page, Some(batch))
+    }
+
+    fn flush_batch(&mut self, batch: &mut PageUpdateBatch) {
+        if let Some((start, len)) = batch.take() {
+            crate::task::update_kernel_range(start, len);
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        self.page_dir.is_current()
+    }
+
     fn is_unused(&self, addr: usize) -> bool {
         self.page_dir.is_unused(addr)
     }
@@ -145,6 +291,25 @@ impl<D: PageDirectory> PageDirTracker<D> {
         Self { page_dir, updates: 0, is_kernel }
     }
 
+    /// sets a page, adding the update for it to the given batch if there is one rather than sending it right away
+    fn set_page_inner(&mut self, addr: usize, page: Option<PageFrame>, batch: Option<&mut PageUpdateBatch>) -> Result<(), PagingError> {
+        self.updates = self.updates.wrapping_add(1);
+        self.page_dir.set_page(addr, page)?;
+
+        if self.is_kernel && addr > KERNEL_PAGE_DIR_SPLIT {
+            match batch {
+                Some(batch) => {
+                    if let Some((start, len)) = batch.add(addr, Self::PAGE_SIZE) {
+                        crate::task::update_kernel_range(start, len);
+                    }
+                }
+                None => crate::task::update_kernel_page(addr),
+            }
+        }
+
+        Ok(())
+    }
+
     /// returns the update counter for this tracker
     pub fn updates(&self) -> usize {
         self.updates
@@ -171,6 +336,7 @@ pub struct GuardedPageDir<'a, D: PageDirectory>(pub MutexGuard<'a, D>);
 
 impl<D: PageDirectory> PageDirectory for GuardedPageDir<'_, D> {
     const PAGE_SIZE: usize = D::PAGE_SIZE;
+    const NO_EXECUTE: bool = D::NO_EXECUTE;
 
     fn get_page(&self, addr: usize) -> Option<PageFrame> {
         self.0.get_page(addr)
@@ -184,6 +350,10 @@ impl<D: PageDirectory> PageDirectory for GuardedPageDir<'_, D> {
         self.0.switch_to()
     }
 
+    fn is_current(&self) -> bool {
+        self.0.is_current()
+    }
+
     fn is_unused(&self, addr: usize) -> bool {
         self.0.is_unused(addr)
     }
@@ -191,6 +361,14 @@ impl<D: PageDirectory> PageDirectory for GuardedPageDir<'_, D> {
     fn virt_to_phys(&self, virt: usize) -> Option<u64> {
         self.0.virt_to_phys(virt)
     }
+
+    fn set_page_batched(&mut self, addr: usize, page: Option<PageFrame>, batch: &mut PageUpdateBatch) -> Result<(), PagingError> {
+        self.0.set_page_batched(addr, page, batch)
+    }
+
+    fn flush_batch(&mut self, batch: &mut PageUpdateBatch) {
+        self.0.flush_batch(batch)
+    }
 }
 
 #[repr(transparent)]
@@ -198,6 +376,7 @@ pub struct MutexedPageDir<'a, D: PageDirectory>(pub &'a Mutex<D>);
 
 impl<D: PageDirectory> PageDirectory for MutexedPageDir<'_, D> {
     const PAGE_SIZE: usize = D::PAGE_SIZE;
+    const NO_EXECUTE: bool = D::NO_EXECUTE;
 
     fn get_page(&self, addr: usize) -> Option<PageFrame> {
         self.lock().get_page(addr)
@@ -211,6 +390,10 @@ impl<D: PageDirectory> PageDirectory for MutexedPageDir<'_, D> {
         self.lock().switch_to()
     }
 
+    fn is_current(&self) -> bool {
+        self.lock().is_current()
+    }
+
     fn is_unused(&self, addr: usize) -> bool {
         self.lock().is_unused(addr)
     }
@@ -218,6 +401,14 @@ impl<D: PageDirectory> PageDirectory for MutexedPageDir<'_, D> {
     fn virt_to_phys(&self, virt: usize) -> Option<u64> {
         self.lock().virt_to_phys(virt)
     }
+
+    fn set_page_batched(&mut self, addr: usize, page: Option<PageFrame>, batch: &mut PageUpdateBatch) -> Result<(), PagingError> {
+        self.lock().set_page_batched(addr, page, batch)
+    }
+
+    fn flush_batch(&mut self, batch: &mut PageUpdateBatch) {
+        self.lock().flush_batch(batch)
+    }
 }
 
 impl<'a, D: PageDirectory> MutexedPageDir<'a, D> {
@@ -1,5 +1,7 @@
 //! bootloader specific code to be run during arch init
 
+pub mod multiboot2;
+
 use crate::{
     arch::PAGE_SIZE,
     mm::{
@@ -7,7 +9,7 @@ use crate::{
         paging::{PageDirectory, PageManager},
     },
     platform::LINKED_BASE,
-    util::{array::BitSet, debug::DebugArray},
+    util::debug::DebugArray,
 };
 use alloc::alloc::{alloc, Layout};
 use core::{ffi::CStr, fmt, slice};
@@ -21,6 +23,46 @@ extern "C" {
     pub static mboot_ptr: *mut MultibootInfo;
 }
 
+/// magic number passed to us in eax by a multiboot 1 compliant bootloader
+pub const MULTIBOOT_MAGIC: u32 = 0x2badb002;
+
+/// which boot protocol the bootloader used to load us
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BootProtocol {
+    Multiboot,
+    Multiboot2,
+}
+
+/// figures out which boot protocol was used from the magic number the bootloader passed in
+pub fn get_boot_protocol() -> Option<BootProtocol> {
+    match unsafe { mboot_sig } {
+        MULTIBOOT_MAGIC => Some(BootProtocol::Multiboot),
+        multiboot2::MULTIBOOT2_MAGIC => Some(BootProtocol::Multiboot2),
+        _ => None,
+    }
+}
+
+/// copies a string into memory allocated by the bump allocator
+fn bump_copy_str(s: &str) -> &'static str {
+    trace!("copy str {:?}", s);
+
+    let new = unsafe { bump_alloc_slice::<u8>(s.len()) };
+
+    new.copy_from_slice(s.as_bytes());
+
+    core::str::from_utf8(new).unwrap()
+}
+
+/// allocates an uninitialized slice with the given length with the bump allocator
+///
+/// # Safety
+///
+/// every element in the slice has to be initialized before it's read
+unsafe fn bump_alloc_slice<T>(len: usize) -> &'static mut [T] {
+    let layout = Layout::new::<T>();
+    slice::from_raw_parts_mut(bump_alloc::<T>(Layout::from_size_align(layout.size() * len, layout.align()).unwrap()).unwrap().pointer, len)
+}
+
 pub static mut MULTIBOOT_INFO: Option<MultibootInfoCopy> = None;
 
 /// multiboot info struct
@@ -154,10 +196,12 @@ impl MultibootModuleCopy {
             let layout = Layout::from_size_align(buf_size_aligned, PAGE_SIZE).unwrap();
             let ptr = unsafe { alloc(layout) };
 
+            let executable = dir.executable_if(false);
+
             // remap memory
             for i in (0..num_pages * PAGE_SIZE).step_by(PAGE_SIZE) {
                 manager.free_frame(dir, ptr as usize + i).unwrap();
-                manager.alloc_frame_at(dir, ptr as usize + i, data_start_aligned as u64 + i as u64, false, false, false).unwrap();
+                manager.alloc_frame_at(dir, ptr as usize + i, data_start_aligned as u64 + i as u64, false, false, executable).unwrap();
             }
 
             self.data = Some(unsafe { slice::from_raw_parts(ptr.offset(data_start_offset.try_into().unwrap()), buf_size) });
@@ -179,31 +223,11 @@ pub struct FlagOutOfBoundsError;
 
 impl MultibootInfo {
     pub fn copy(&self) -> MultibootInfoCopy {
-        let copy_str = |s: &str| {
-            trace!("copy str {:?}", s);
-
-            let chars = s.as_bytes();
-            let len = chars.len();
-            let new = unsafe { slice::from_raw_parts_mut(bump_alloc::<u8>(Layout::from_size_align(len, 1).unwrap()).unwrap().pointer, len) };
-
-            new.copy_from_slice(chars);
-
-            core::str::from_utf8(new).unwrap()
-        };
-
         let modules_copy = self.get_modules().and_then(|modules| {
             let len = modules.len();
 
             if len > 0 {
-                let new = unsafe {
-                    let layout = Layout::new::<MultibootModuleCopy>();
-                    slice::from_raw_parts_mut(
-                        bump_alloc::<MultibootModuleCopy>(Layout::from_size_align(layout.size() * len, layout.align()).unwrap())
-                            .unwrap()
-                            .pointer,
-                        len,
-                    )
-                };
+                let new = unsafe { bump_alloc_slice::<MultibootModuleCopy>(len) };
 
                 for i in 0..len {
                     let old_module = &modules[i];
@@ -212,7 +236,7 @@ impl MultibootInfo {
                     new_module.data = None;
                     new_module.data_start = old_module.start;
                     new_module.data_end = old_module.end;
-                    new_module.string = copy_str(unsafe { CStr::from_ptr((old_module.string as usize + LINKED_BASE) as *const _).to_str().unwrap_or("") });
+                    new_module.string = bump_copy_str(unsafe { CStr::from_ptr((old_module.string as usize + LINKED_BASE) as *const _).to_str().unwrap_or("") });
                 }
 
                 Some(new)
@@ -226,10 +250,7 @@ impl MultibootInfo {
             let len = iter.clone().filter(|r| r.length > 0).count();
 
             if len > 0 {
-                let new = unsafe {
-                    let layout = Layout::new::<MemMapEntry>();
-                    slice::from_raw_parts_mut(bump_alloc::<MemMapEntry>(Layout::from_size_align(layout.size() * len, layout.align()).unwrap()).unwrap().pointer, len)
-                };
+                let new = unsafe { bump_alloc_slice::<MemMapEntry>(len) };
 
                 for (i, region) in iter.filter(|r| r.length > 0).enumerate() {
                     new[i] = *region;
@@ -245,10 +266,10 @@ impl MultibootInfo {
             flags: self.flags,
             mem: self.get_mem(),
             boot_device: self.get_boot_device(),
-            cmdline: self.get_cmdline().map(copy_str),
+            cmdline: self.get_cmdline().map(bump_copy_str),
             mods: modules_copy,
             memory_map,
-            bootloader_name: self.get_bootloader_name().map(copy_str),
+            bootloader_name: self.get_bootloader_name().map(bump_copy_str),
             vbe: self.get_vbe().copied(),
             framebuffer: self.get_framebuffer().copied(),
         }
@@ -663,7 +684,7 @@ pub struct RGBColorInfo {
 /// won't be needed when boot code is moved into platform
 pub unsafe fn pre_init() {
     // check for proper multiboot signature. this is done as early as possible to prevent things from going wrong
-    if mboot_sig != 0x2badb002 {
+    if get_boot_protocol().is_none() {
         panic!("invalid multiboot signature!");
     }
 }
@@ -673,6 +694,11 @@ fn get_orig_multiboot_info() -> &'static MultibootInfo {
     unsafe { &*((mboot_ptr as usize + LINKED_BASE) as *const MultibootInfo) }
 }
 
+/// gets reference to multiboot2 boot information structure
+fn get_orig_multiboot2_info() -> &'static multiboot2::Multiboot2Info {
+    unsafe { &*((mboot_ptr as usize + LINKED_BASE) as *const multiboot2::Multiboot2Info) }
+}
+
 /// gets copy of multiboot info
 pub fn get_multiboot_info() -> &'static MultibootInfoCopy {
     unsafe { MULTIBOOT_INFO.as_ref().unwrap() }
@@ -686,13 +712,11 @@ fn get_multiboot_info_mut() -> &'static mut MultibootInfoCopy {
 /// saves me from typing a bit
 const PAGE_SIZE_U64: u64 = PAGE_SIZE as u64;
 
-/// given a bit set of available pages, set all the bits then clear only the ones that represent memory that is available for us to write to
+/// given a page manager, reserve every frame then release only the ones that represent memory that is available for us to write to
 /// this will prevent memory allocations from using reserved memory regions
-pub fn reserve_pages(set: &mut BitSet) {
-    let info: &MultibootInfo = get_orig_multiboot_info(); // we can just do this again for now
-
-    // set a region of memory in the bitset
-    fn set_region_used(set: &mut BitSet, start: u64, end: u64) {
+pub fn reserve_pages(manager: &mut PageManager) {
+    // set a region of memory as used
+    fn set_region_used(manager: &mut PageManager, start: u64, end: u64) {
         // when setting a region as used, we ensure that all memory in that region is used to avoid accidentally trampling on reserved memory
 
         // convert base address of region into page number we can use to index into the bitset
@@ -719,16 +743,12 @@ pub fn reserve_pages(set: &mut BitSet) {
         assert!(start_page * PAGE_SIZE_U64 >= start);
         assert!((end_page * PAGE_SIZE_U64) <= end + PAGE_SIZE_U64);
 
-        // free up memory covered by this region, allowing it to be used
-        for i in start_page..end_page {
-            if i < set.size as u64 {
-                set.set(i as usize);
-            }
-        }
+        // mark memory covered by this region as used
+        manager.reserve_region(start_page * PAGE_SIZE_U64, (end_page - start_page) * PAGE_SIZE_U64);
     }
 
-    // clear a region of memory in the bitset
-    fn set_region_free(set: &mut BitSet, start: u64, end: u64) {
+    // set a region of memory as free
+    fn set_region_free(manager: &mut PageManager, start: u64, end: u64) {
         // when setting a region as free, we ensure that as much memory inside the region as we can is set as free without setting anything outside it as free
 
         // convert base address of region into page number we can use to index into the bitset
@@ -756,57 +776,61 @@ pub fn reserve_pages(set: &mut BitSet) {
         assert!((end_page * PAGE_SIZE_U64) <= end);
 
         // free up memory covered by this region, allowing it to be used
-        for i in start_page..end_page {
-            if i < set.size as u64 {
-                set.clear(i as usize);
-            }
-        }
+        manager.release_region(start_page * PAGE_SIZE_U64, (end_page - start_page) * PAGE_SIZE_U64);
     }
 
-    // get memory map from bootloader info
-    let mut mmap = info.get_mmap();
-
-    if let Some(iter) = mmap.as_mut() {
-        // set entire bit set, faster to do it this way than to just call set.set()
-        for num in set.array.to_slice_mut().iter_mut() {
-            *num = 0xffffffff;
-        }
+    // mark everything that isn't available in the memory map as used, and mark the modules as used so we don't trample on them later
+    fn reserve_regions(manager: &mut PageManager, mmap: Option<impl Iterator<Item = MemMapEntry>>, modules: impl Iterator<Item = (u32, u32)>) {
+        if let Some(iter) = mmap {
+            // reserve all of memory
+            let frames = manager.frame_set.size as u64;
+            manager.reserve_region(0, frames * PAGE_SIZE_U64);
 
-        set.bits_used = set.size;
+            for region in iter {
+                if region.kind == MappingKind::Available {
+                    debug!("{:?}", region);
 
-        for region in iter {
-            if region.kind == MappingKind::Available {
-                debug!("{:?}", region);
-
-                set_region_free(set, region.base_addr, region.base_addr + region.length);
+                    set_region_free(manager, region.base_addr, region.base_addr + region.length);
+                }
             }
+        } else {
+            warn!("cannot get memory map from bootloader, assuming 640k-1mb only reserved");
+
+            // set the 640k-1mb area as reserved
+            set_region_used(manager, 0xa0000, 0x100000);
         }
-    } else {
-        warn!("cannot get memory map from bootloader, assuming 640k-1mb only reserved");
 
-        // set the 640k-1mb area as reserved
-        set_region_used(set, 0xa0000, 0x100000);
+        for (start, end) in modules {
+            debug!("module @ {start:#x} - {end:#x}");
+
+            set_region_used(manager, start as u64, end as u64);
+        }
     }
 
-    // mark modules provided by bootloader as reserved, so we don't trample on them later
-    let modules = info.get_modules();
+    // copy multiboot info since bump allocator is initialized here and we still have access to the old struct
+    match get_boot_protocol() {
+        Some(BootProtocol::Multiboot2) => {
+            let info = get_orig_multiboot2_info();
 
-    if let Some(modules) = modules {
-        for module in modules.iter() {
-            debug!("{:?}", module);
+            reserve_regions(manager, info.get_mmap(), info.get_modules().map(|module| (module.start, module.end)));
 
-            //let slice = unsafe { slice::from_raw_parts((module.start + LINKED_BASE as u32) as *const u8, (module.end - module.start) as usize) };
-            //debug!("{:?}: {:?}", slice, core::str::from_utf8(slice));
+            debug!("copying multiboot2 info");
 
-            set_region_used(set, module.start as u64, module.end as u64);
+            unsafe {
+                MULTIBOOT_INFO = Some(info.copy());
+            }
         }
-    }
+        _ => {
+            let info = get_orig_multiboot_info();
 
-    // copy multiboot info since bump allocator is initialized here and we still have access to the old struct
-    debug!("copying multiboot info");
+            reserve_regions(manager, info.get_mmap().map(|iter| iter.copied()), info.get_modules().unwrap_or(&[]).iter().map(|module| (module.start, module.end)));
+
+            debug!("copying multiboot info");
 
-    unsafe {
-        MULTIBOOT_INFO = Some(info.copy());
+            unsafe {
+                MULTIBOOT_INFO = Some(info.copy());
+            }
+        }
     }
 }
 
@@ -814,15 +838,30 @@ pub fn init() -> u64 {
     // basic multiboot setup
     debug!("mboot info ptr @ {:#x}", unsafe { mboot_ptr as usize });
 
-    // get reference to multiboot info struct
-    let info: &MultibootInfo = get_orig_multiboot_info();
-
-    debug!("bootloader info: {:?}", info);
+    let protocol = get_boot_protocol();
 
-    debug!("flags: {:#032b}", info.flags);
+    debug!("boot protocol: {protocol:?}");
 
     // get amount of available upper memory in kb
-    let (_, upper_mem) = info.get_mem().expect("couldn't get memory amount");
+    let (_, upper_mem) = match protocol {
+        Some(BootProtocol::Multiboot2) => {
+            let info = get_orig_multiboot2_info();
+
+            debug!("bootloader info: {:?}", info);
+
+            info.get_mem().expect("couldn't get memory amount")
+        }
+        _ => {
+            // get reference to multiboot info struct
+            let info: &MultibootInfo = get_orig_multiboot_info();
+
+            debug!("bootloader info: {:?}", info);
+
+            debug!("flags: {:#032b}", info.flags);
+
+            info.get_mem().expect("couldn't get memory amount")
+        }
+    };
 
     (upper_mem as u64 + 1024) * 1024 // upper memory + 1 mb
 }
@@ -0,0 +1,333 @@
+//! multiboot2 boot information parsing
+//!
+//! multiboot2 hands us a list of tags instead of a fixed struct, so this walks the tags and converts whatever we care about into the same MultibootInfoCopy the multiboot 1 code produces
+
+use super::{bump_alloc_slice, bump_copy_str, ColorInfo, FramebufferInfo, FramebufferKind, IndexedColorInfo, MappingKind, MemMapEntry, MultibootInfoCopy, MultibootModuleCopy, RGBColorInfo};
+use crate::platform::LINKED_BASE;
+use core::{ffi::CStr, fmt, mem::size_of};
+
+/// magic number passed to us in eax by a multiboot2 compliant bootloader
+pub const MULTIBOOT2_MAGIC: u32 = 0x36d76289;
+
+/// tag types we know about
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_BASIC_MEMINFO: u32 = 4;
+const TAG_BOOTDEV: u32 = 5;
+const TAG_MMAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+/// header common to every tag
+#[repr(C)]
+#[derive(Debug)]
+pub struct Tag {
+    /// what kind of tag this is
+    pub kind: u32,
+
+    /// size of the tag in bytes, including this header but not including any padding after it
+    pub size: u32,
+}
+
+impl Tag {
+    /// gets the contents of this tag that come after its header, as a pointer
+    fn body(&self) -> *const u8 {
+        (self as *const Self as usize + size_of::<Self>()) as *const u8
+    }
+
+    /// gets a value at the given offset into this tag's contents
+    ///
+    /// # Safety
+    ///
+    /// the offset must be inside this tag
+    unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        core::ptr::read_unaligned(self.body().add(offset) as *const T)
+    }
+
+    /// gets a null terminated string at the given offset into this tag's contents
+    ///
+    /// # Safety
+    ///
+    /// the offset must be inside this tag
+    unsafe fn read_str(&self, offset: usize) -> Option<&str> {
+        CStr::from_ptr(self.body().add(offset) as *const _).to_str().ok()
+    }
+}
+
+/// iterates over all the tags in a multiboot2 boot information structure
+#[derive(Clone)]
+pub struct TagIter<'a> {
+    current: *const Tag,
+    end: usize,
+    _info: &'a Multiboot2Info,
+}
+
+impl<'a> Iterator for TagIter<'a> {
+    type Item = &'a Tag;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.current as usize + size_of::<Tag>() > self.end {
+            return None;
+        }
+
+        let tag = unsafe { &*self.current };
+
+        // a zero sized tag would have us looping forever
+        if tag.kind == TAG_END || (tag.size as usize) < size_of::<Tag>() {
+            self.current = self.end as *const Tag;
+            return None;
+        }
+
+        // tags are padded to 8 byte boundaries
+        self.current = ((self.current as usize + tag.size as usize + 7) & !7) as *const Tag;
+
+        Some(tag)
+    }
+}
+
+/// a module, as described by a multiboot2 module tag
+#[derive(Debug)]
+pub struct Multiboot2Module<'a> {
+    /// start of module's contents (physical address)
+    pub start: u32,
+
+    /// end of module's contents (physical address)
+    pub end: u32,
+
+    /// string identifier of module
+    pub string: &'a str,
+}
+
+/// the boot information structure handed to us by a multiboot2 bootloader
+#[repr(C)]
+pub struct Multiboot2Info {
+    /// size of the whole structure including all of its tags
+    total_size: u32,
+
+    reserved: u32,
+}
+
+impl Multiboot2Info {
+    /// iterates over every tag in this structure
+    pub fn tags(&self) -> TagIter<'_> {
+        let start = self as *const Self as usize;
+
+        TagIter {
+            current: (start + size_of::<Self>()) as *const Tag,
+            end: start + self.total_size as usize,
+            _info: self,
+        }
+    }
+
+    /// finds the first tag of the given kind
+    fn find_tag(&self, kind: u32) -> Option<&Tag> {
+        self.tags().find(|tag| tag.kind == kind)
+    }
+
+    /// gets lower and upper memory amount, if available
+    pub fn get_mem(&self) -> Option<(u32, u32)> {
+        self.find_tag(TAG_BASIC_MEMINFO).map(|tag| unsafe { (tag.read(0), tag.read(4)) })
+    }
+
+    /// gets bios boot device, if available. this is converted to the same layout as multiboot 1 uses (part3, part2, part1, drive)
+    pub fn get_boot_device(&self) -> Option<[u8; 4]> {
+        self.find_tag(TAG_BOOTDEV).map(|tag| unsafe {
+            let drive: u32 = tag.read(0);
+            let part1: u32 = tag.read(4);
+            let part2: u32 = tag.read(8);
+
+            [0xff, part2 as u8, part1 as u8, drive as u8]
+        })
+    }
+
+    /// gets command line arguments for kernel if available
+    pub fn get_cmdline(&self) -> Option<&str> {
+        self.find_tag(TAG_CMDLINE).and_then(|tag| unsafe { tag.read_str(0) })
+    }
+
+    /// gets name of bootloader if available
+    pub fn get_bootloader_name(&self) -> Option<&str> {
+        self.find_tag(TAG_BOOTLOADER_NAME).and_then(|tag| unsafe { tag.read_str(0) })
+    }
+
+    /// iterates over all the modules passed by the bootloader
+    pub fn get_modules(&self) -> impl Iterator<Item = Multiboot2Module<'_>> + Clone {
+        self.tags().filter(|tag| tag.kind == TAG_MODULE).map(|tag| unsafe {
+            Multiboot2Module {
+                start: tag.read(0),
+                end: tag.read(4),
+                string: tag.read_str(8).unwrap_or(""),
+            }
+        })
+    }
+
+    /// gets iterator over memory map entries if available. entries are converted to the multiboot 1 format
+    pub fn get_mmap(&self) -> Option<impl Iterator<Item = MemMapEntry> + Clone + '_> {
+        let tag = self.find_tag(TAG_MMAP)?;
+
+        let entry_size = unsafe { tag.read::<u32>(0) } as usize;
+
+        // each entry has to at least have a base address, length, and type
+        if entry_size < 20 {
+            return None;
+        }
+
+        let entries_start = 8;
+        let num_entries = (tag.size as usize).saturating_sub(size_of::<Tag>() + entries_start) / entry_size;
+
+        Some((0..num_entries).map(move |i| unsafe {
+            let offset = entries_start + i * entry_size;
+
+            MemMapEntry {
+                size: 20,
+                base_addr: tag.read(offset),
+                length: tag.read(offset + 8),
+                kind: match tag.read::<u32>(offset + 16) {
+                    1 => MappingKind::Available,
+                    3 => MappingKind::AcpiReclaimable,
+                    4 => MappingKind::AcpiNVS,
+                    5 => MappingKind::BadRAM,
+                    _ => MappingKind::Reserved,
+                },
+            }
+        }))
+    }
+
+    /// gets framebuffer info if available
+    pub fn get_framebuffer(&self) -> Option<FramebufferInfo> {
+        let tag = self.find_tag(TAG_FRAMEBUFFER)?;
+
+        unsafe {
+            let kind = match tag.read::<u8>(21) {
+                0 => FramebufferKind::Indexed,
+                1 => FramebufferKind::RGB,
+                2 => FramebufferKind::EGAText,
+                _ => return None,
+            };
+
+            // color info starts after a 2 byte reserved field
+            let color_info = match kind {
+                FramebufferKind::Indexed => ColorInfo {
+                    indexed: IndexedColorInfo {
+                        // the palette immediately follows the number of colors, rather than being pointed to like in multiboot 1
+                        palette_addr: (tag.body().add(26) as usize - LINKED_BASE) as u32,
+                        num_colors: tag.read(24),
+                    },
+                },
+                FramebufferKind::RGB => ColorInfo {
+                    rgb: RGBColorInfo {
+                        red_field_pos: tag.read(24),
+                        red_mask_size: tag.read(25),
+                        green_field_pos: tag.read(26),
+                        green_mask_size: tag.read(27),
+                        blue_field_pos: tag.read(28),
+                        blue_mask_size: tag.read(29),
+                    },
+                },
+                FramebufferKind::EGAText => ColorInfo {
+                    indexed: IndexedColorInfo { palette_addr: 0, num_colors: 0 },
+                },
+            };
+
+            Some(FramebufferInfo {
+                addr: tag.read(0),
+                pitch: tag.read(8),
+                width: tag.read(12),
+                height: tag.read(16),
+                bpp: tag.read(20),
+                kind,
+                color_info,
+            })
+        }
+    }
+
+    /// copies everything we need out of this structure, since it isn't worth reserving the memory it uses
+    ///
+    /// the flags in the copy are set the same way a multiboot 1 bootloader would set them, based on which tags were present
+    pub fn copy(&self) -> MultibootInfoCopy {
+        let mods = {
+            let len = self.get_modules().count();
+
+            if len > 0 {
+                let new = unsafe { bump_alloc_slice::<MultibootModuleCopy>(len) };
+
+                for (new_module, old_module) in new.iter_mut().zip(self.get_modules()) {
+                    new_module.data = None;
+                    new_module.data_start = old_module.start;
+                    new_module.data_end = old_module.end;
+                    new_module.string = bump_copy_str(old_module.string);
+                }
+
+                Some(new)
+            } else {
+                None
+            }
+        };
+
+        let memory_map = self.get_mmap().and_then(|iter| {
+            // filter out bogus entries
+            let len = iter.clone().filter(|r| r.length > 0).count();
+
+            if len > 0 {
+                let new = unsafe { bump_alloc_slice::<MemMapEntry>(len) };
+
+                for (i, region) in iter.filter(|r| r.length > 0).enumerate() {
+                    new[i] = region;
+                }
+
+                Some(new)
+            } else {
+                None
+            }
+        });
+
+        let mem = self.get_mem();
+        let boot_device = self.get_boot_device();
+        let cmdline = self.get_cmdline().map(bump_copy_str);
+        let bootloader_name = self.get_bootloader_name().map(bump_copy_str);
+        let framebuffer = self.get_framebuffer();
+
+        let mut flags = 0;
+
+        for (bit, present) in [
+            (0, mem.is_some()),
+            (1, boot_device.is_some()),
+            (2, cmdline.is_some()),
+            (3, mods.is_some()),
+            (6, memory_map.is_some()),
+            (9, bootloader_name.is_some()),
+            (12, framebuffer.is_some()),
+        ] {
+            if present {
+                flags |= 1 << bit;
+            }
+        }
+
+        MultibootInfoCopy {
+            flags,
+            mem,
+            boot_device,
+            cmdline,
+            mods,
+            memory_map,
+            bootloader_name,
+            vbe: None,
+            framebuffer,
+        }
+    }
+}
+
+impl fmt::Debug for Multiboot2Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multiboot2Info")
+            .field("total_size", &self.total_size)
+            .field("mem", &self.get_mem())
+            .field("boot_device", &self.get_boot_device())
+            .field("cmdline", &self.get_cmdline())
+            .field("num_modules", &self.get_modules().count())
+            .field("bootloader_name", &self.get_bootloader_name())
+            .field("framebuffer", &self.get_framebuffer())
+            .finish()
+    }
+}
@@ -0,0 +1,154 @@
+//! kernel command line parsing
+
+use alloc::collections::BTreeMap;
+use core::fmt;
+
+/// a parsed kernel command line
+///
+/// arguments are separated by spaces and are either `key=value` or just `key`, in which case the key is present with an empty value.
+/// values can be surrounded in double quotes to allow for spaces in them
+#[derive(Default, Clone)]
+pub struct CmdLine<'a> {
+    args: BTreeMap<&'a str, &'a str>,
+}
+
+impl<'a> CmdLine<'a> {
+    /// parses the given command line
+    pub fn parse(cmdline: &'a str) -> Self {
+        let mut args = BTreeMap::new();
+        let mut rest = cmdline;
+
+        loop {
+            rest = rest.trim_start_matches(' ');
+
+            if rest.is_empty() {
+                break;
+            }
+
+            // find where this argument ends, skipping over spaces in quotes
+            let mut in_quotes = false;
+            let end = rest
+                .char_indices()
+                .find(|(_, c)| {
+                    if *c == '"' {
+                        in_quotes = !in_quotes;
+                    }
+
+                    *c == ' ' && !in_quotes
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+
+            let arg = &rest[..end];
+            rest = &rest[end..];
+
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, value.strip_prefix('"').map(|v| v.strip_suffix('"').unwrap_or(v)).unwrap_or(value)),
+                None => (arg, ""),
+            };
+
+            args.insert(key, value);
+        }
+
+        Self { args }
+    }
+
+    /// checks whether the given key is present
+    pub fn has(&self, key: &str) -> bool {
+        self.args.contains_key(key)
+    }
+
+    /// gets the value of the given key as a string
+    pub fn get_str(&self, key: &str) -> Option<&'a str> {
+        self.args.get(key).copied()
+    }
+
+    /// gets the value of the given key as a boolean. keys with no value are true
+    ///
+    /// returns None if the key isn't present or its value isn't a boolean
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get_str(key)? {
+            "" | "1" | "yes" | "true" | "on" => Some(true),
+            "0" | "no" | "false" | "off" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// gets the value of the given key as a number, which can be in decimal or hex with a leading `0x`
+    ///
+    /// returns None if the key isn't present or its value isn't a number
+    pub fn get_usize(&self, key: &str) -> Option<usize> {
+        let value = self.get_str(key)?;
+
+        match value.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        }
+    }
+
+    /// iterates over all the keys and values in this command line
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + '_ {
+        self.args.iter().map(|(k, v)| (*k, *v))
+    }
+}
+
+impl fmt::Debug for CmdLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.args.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bare_flags_are_present_and_true() {
+        let cmdline = CmdLine::parse("quiet  acpi");
+
+        assert!(cmdline.has("quiet"));
+        assert_eq!(cmdline.get_str("quiet"), Some(""));
+        assert_eq!(cmdline.get_bool("quiet"), Some(true));
+        assert_eq!(cmdline.get_str("acpi"), Some(""));
+        assert_eq!(cmdline.iter().count(), 2);
+    }
+
+    #[test_case]
+    fn key_value_pairs_are_parsed() {
+        let cmdline = CmdLine::parse("loglevel=debug verify_modules=off mem=0x1000 cpus=4 bad=12a");
+
+        assert_eq!(cmdline.get_str("loglevel"), Some("debug"));
+        assert_eq!(cmdline.get_bool("verify_modules"), Some(false));
+        assert_eq!(cmdline.get_bool("loglevel"), None);
+        assert_eq!(cmdline.get_usize("mem"), Some(0x1000));
+        assert_eq!(cmdline.get_usize("cpus"), Some(4));
+        assert_eq!(cmdline.get_usize("bad"), None);
+
+        // only the first = splits the key from the value
+        assert_eq!(CmdLine::parse("init=/bin/sh=1").get_str("init"), Some("/bin/sh=1"));
+    }
+
+    #[test_case]
+    fn quoted_values_can_have_spaces() {
+        let cmdline = CmdLine::parse(r#"init="/bin/init --single user" console=serial empty="" "#);
+
+        assert_eq!(cmdline.get_str("init"), Some("/bin/init --single user"));
+        assert_eq!(cmdline.get_str("console"), Some("serial"));
+        assert_eq!(cmdline.get_str("empty"), Some(""));
+        assert_eq!(cmdline.iter().count(), 3);
+
+        // an unterminated quote runs to the end of the command line
+        assert_eq!(CmdLine::parse(r#"a="b c"#).get_str("a"), Some("b c"));
+    }
+
+    #[test_case]
+    fn unknown_keys_are_missing() {
+        let cmdline = CmdLine::parse("a=1");
+
+        assert!(!cmdline.has("b"));
+        assert_eq!(cmdline.get_str("b"), None);
+        assert_eq!(cmdline.get_bool("b"), None);
+        assert_eq!(cmdline.get_usize("b"), None);
+        assert_eq!(CmdLine::parse("").iter().count(), 0);
+    }
+}
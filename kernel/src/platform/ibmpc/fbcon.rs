@@ -0,0 +1,564 @@
+//! text console drawn onto a linear framebuffer, for machines that don't have VGA text mode
+
+use super::bootloader::{FramebufferInfo, RGBColorInfo};
+use crate::{
+    arch::PAGE_SIZE,
+    mm::paging::{PageDirectory, PageFrame, PageManager, PagingError},
+};
+use alloc::alloc::{alloc, Layout};
+use core::{fmt, ops::Range, slice};
+use font8x8::legacy::BASIC_LEGACY;
+use log::debug;
+use spin::Mutex;
+
+/// width of a character cell in pixels
+const GLYPH_WIDTH: usize = 8;
+
+/// height of a character cell in pixels. the font is 8x8, so every row of it is drawn twice
+const GLYPH_HEIGHT: usize = 16;
+
+/// the standard 16 VGA text mode colors as RGB
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0xaa),
+    (0x00, 0xaa, 0x00),
+    (0x00, 0xaa, 0xaa),
+    (0xaa, 0x00, 0x00),
+    (0xaa, 0x00, 0xaa),
+    (0xaa, 0x55, 0x00),
+    (0xaa, 0xaa, 0xaa),
+    (0x55, 0x55, 0x55),
+    (0x55, 0x55, 0xff),
+    (0x55, 0xff, 0x55),
+    (0x55, 0xff, 0xff),
+    (0xff, 0x55, 0x55),
+    (0xff, 0x55, 0xff),
+    (0xff, 0xff, 0x55),
+    (0xff, 0xff, 0xff),
+];
+
+/// default foreground color, as an index into the palette (light gray)
+const DEFAULT_FOREGROUND: usize = 7;
+
+/// default background color, as an index into the palette (black)
+const DEFAULT_BACKGROUND: usize = 0;
+
+/// palette indexes of the 8 ANSI colors. ANSI numbers colors in a different order than VGA does (red is 1 in ANSI and 4 in VGA)
+const ANSI_COLORS: [usize; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+/// how many parameters of a control sequence are kept track of, any past this are ignored
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+/// how far thru an ANSI escape sequence the console is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum EscapeState {
+    /// not in an escape sequence
+    Normal,
+
+    /// got an escape character
+    Escape,
+
+    /// in a control sequence (`ESC [`), reading its parameters
+    Csi,
+}
+
+/// the framebuffer console, if there is one
+pub static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+/// errors that can occur while setting up a framebuffer console
+#[derive(Debug)]
+pub enum FramebufferError {
+    /// the framebuffer isn't a direct color framebuffer with 24 or 32 bits per pixel
+    Unsupported,
+
+    /// the framebuffer is too small to fit any text
+    TooSmall,
+
+    /// couldn't allocate address space for the framebuffer
+    OutOfMemory,
+
+    /// couldn't map the framebuffer into kernel memory
+    Paging(PagingError),
+}
+
+impl From<PagingError> for FramebufferError {
+    fn from(err: PagingError) -> Self {
+        Self::Paging(err)
+    }
+}
+
+/// a text console drawn onto a framebuffer with a bitmap font
+pub struct FramebufferConsole {
+    /// the framebuffer's memory, mapped into kernel memory
+    buffer: &'static mut [u8],
+
+    /// how many bytes are in each row of pixels
+    pitch: usize,
+
+    /// how many bytes are in each pixel, either 3 or 4
+    bytes_per_pixel: usize,
+
+    /// how the color channels are laid out in each pixel
+    layout: RGBColorInfo,
+
+    /// how many columns and rows of characters fit on the screen
+    columns: usize,
+    rows: usize,
+
+    /// where the next character will be drawn
+    cursor_x: usize,
+    cursor_y: usize,
+
+    /// current colors as pixel values
+    foreground: u32,
+    background: u32,
+
+    /// current colors as indexes into the palette
+    colors: (usize, usize),
+
+    /// how far thru an escape sequence the console is. this is kept between writes, so sequences split across them are picked up where they left off
+    escape: EscapeState,
+
+    /// parameters of the control sequence being read
+    params: [u16; MAX_ESCAPE_PARAMS],
+
+    /// how many parameters of the control sequence being read have been started
+    num_params: usize,
+}
+
+impl FramebufferConsole {
+    /// creates a new framebuffer console, mapping the framebuffer described by the bootloader into kernel memory and clearing it
+    pub fn new(info: &FramebufferInfo, manager: &mut PageManager, dir: &mut impl PageDirectory) -> Result<Self, FramebufferError> {
+        let layout = *info.get_rgb_color_info().ok_or(FramebufferError::Unsupported)?;
+
+        if info.bpp != 24 && info.bpp != 32 {
+            return Err(FramebufferError::Unsupported);
+        }
+
+        let pitch = info.pitch as usize;
+        let width = info.width as usize;
+        let height = info.height as usize;
+
+        let columns = width / GLYPH_WIDTH;
+        let rows = height / GLYPH_HEIGHT;
+
+        if columns == 0 || rows == 0 {
+            return Err(FramebufferError::TooSmall);
+        }
+
+        let buffer = map_framebuffer(info.addr, pitch * height, manager, dir)?;
+
+        let mut console = Self {
+            buffer,
+            pitch,
+            bytes_per_pixel: info.bpp as usize / 8,
+            layout,
+            columns,
+            rows,
+            cursor_x: 0,
+            cursor_y: 0,
+            foreground: 0,
+            background: 0,
+            colors: (DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+            escape: EscapeState::Normal,
+            params: [0; MAX_ESCAPE_PARAMS],
+            num_params: 0,
+        };
+
+        console.set_color(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+        console.clear();
+
+        Ok(console)
+    }
+
+    /// converts an RGB color into a pixel value for this framebuffer
+    fn pixel_value(&self, (red, green, blue): (u8, u8, u8)) -> u32 {
+        let channel = |value: u8, pos: u8, size: u8| ((value as u32) >> (8 - size.min(8))) << pos;
+
+        channel(red, self.layout.red_field_pos, self.layout.red_mask_size)
+            | channel(green, self.layout.green_field_pos, self.layout.green_mask_size)
+            | channel(blue, self.layout.blue_field_pos, self.layout.blue_mask_size)
+    }
+
+    /// sets the foreground and background colors of any text written after this, as indexes into the 16 color VGA palette
+    pub fn set_color(&mut self, foreground: usize, background: usize) {
+        self.foreground = self.pixel_value(PALETTE[foreground % PALETTE.len()]);
+        self.background = self.pixel_value(PALETTE[background % PALETTE.len()]);
+        self.colors = (foreground, background);
+    }
+
+    /// draws a single pixel
+    fn put_pixel(&mut self, x: usize, y: usize, value: u32) {
+        let offset = y * self.pitch + x * self.bytes_per_pixel;
+        let bytes = value.to_le_bytes();
+
+        self.buffer[offset..offset + self.bytes_per_pixel].copy_from_slice(&bytes[..self.bytes_per_pixel]);
+    }
+
+    /// fills the given rows of pixels with the background color
+    fn clear_rows(&mut self, start: usize, end: usize) {
+        let background = self.background;
+        let width = self.columns * GLYPH_WIDTH;
+
+        for y in start..end {
+            for x in 0..width {
+                self.put_pixel(x, y, background);
+            }
+        }
+    }
+
+    /// fills the given columns of a row of characters with the background color
+    fn clear_cells(&mut self, row: usize, columns: Range<usize>) {
+        let background = self.background;
+        let start = columns.start.min(self.columns) * GLYPH_WIDTH;
+        let end = columns.end.min(self.columns) * GLYPH_WIDTH;
+
+        for y in row * GLYPH_HEIGHT..(row + 1) * GLYPH_HEIGHT {
+            for x in start..end {
+                self.put_pixel(x, y, background);
+            }
+        }
+    }
+
+    /// clears the whole screen and moves the cursor to the top left
+    pub fn clear(&mut self) {
+        self.clear_rows(0, self.rows * GLYPH_HEIGHT);
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+    }
+
+    /// draws a character at the given column and row
+    fn draw_char(&mut self, column: usize, row: usize, c: char) {
+        let glyph = BASIC_LEGACY.get(c as usize).unwrap_or(&BASIC_LEGACY[b'?' as usize]);
+
+        let (foreground, background) = (self.foreground, self.background);
+
+        for y in 0..GLYPH_HEIGHT {
+            let bits = glyph[y * 8 / GLYPH_HEIGHT];
+
+            for x in 0..GLYPH_WIDTH {
+                // the least significant bit is the leftmost pixel
+                let value = if bits & (1 << x) != 0 { foreground } else { background };
+                self.put_pixel(column * GLYPH_WIDTH + x, row * GLYPH_HEIGHT + y, value);
+            }
+        }
+    }
+
+    /// moves everything on screen up by a row and clears the bottom row
+    fn scroll(&mut self) {
+        let row_size = self.pitch * GLYPH_HEIGHT;
+        let text_size = row_size * self.rows;
+
+        self.buffer.copy_within(row_size..text_size, 0);
+        self.clear_rows((self.rows - 1) * GLYPH_HEIGHT, self.rows * GLYPH_HEIGHT);
+    }
+
+    /// moves the cursor to the start of the next line, scrolling if needed
+    fn newline(&mut self) {
+        self.cursor_x = 0;
+
+        if self.cursor_y + 1 < self.rows {
+            self.cursor_y += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    /// writes a single character at the cursor, handling control characters
+    pub fn put_char(&mut self, c: char) {
+        if self.escape != EscapeState::Normal {
+            self.escape_char(c);
+            return;
+        }
+
+        match c {
+            '\x1b' => self.escape = EscapeState::Escape,
+            '\n' => self.newline(),
+            '\r' => self.cursor_x = 0,
+            '\t' => {
+                for _ in 0..(8 - self.cursor_x % 8) {
+                    self.put_char(' ');
+                }
+            }
+            '\x08' => {
+                if self.cursor_x > 0 {
+                    self.cursor_x -= 1;
+                    self.draw_char(self.cursor_x, self.cursor_y, ' ');
+                }
+            }
+            _ => {
+                if self.cursor_x >= self.columns {
+                    self.newline();
+                }
+
+                self.draw_char(self.cursor_x, self.cursor_y, c);
+                self.cursor_x += 1;
+            }
+        }
+    }
+
+    /// handles a character that's part of an escape sequence
+    fn escape_char(&mut self, c: char) {
+        match (self.escape, c) {
+            (EscapeState::Escape, '[') => {
+                self.escape = EscapeState::Csi;
+                self.params = [0; MAX_ESCAPE_PARAMS];
+                self.num_params = 0;
+            }
+            // only control sequences are supported, anything else is dropped
+            (EscapeState::Escape, _) => self.escape = EscapeState::Normal,
+            (EscapeState::Csi, '0'..='9') => {
+                if self.num_params == 0 {
+                    self.num_params = 1;
+                }
+
+                if let Some(param) = self.params.get_mut(self.num_params - 1) {
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+            }
+            // an empty parameter before the separator is still a parameter
+            (EscapeState::Csi, ';') => self.num_params = self.num_params.max(1).saturating_add(1),
+            (EscapeState::Csi, '\x40'..='\x7e') => {
+                self.escape = EscapeState::Normal;
+                self.control_sequence(c);
+            }
+            // private markers and intermediate bytes (i.e. the ? in `ESC [ ? 25 h`) aren't supported, but still have to be skipped over
+            (EscapeState::Csi, _) => (),
+            (EscapeState::Normal, _) => unreachable!(),
+        }
+    }
+
+    /// gets a parameter of the control sequence that was just read, or the given default if it's missing or 0
+    fn param(&self, index: usize, default: usize) -> usize {
+        match self.params.get(index) {
+            Some(&param) if index < self.num_params && param != 0 => param as usize,
+            _ => default,
+        }
+    }
+
+    /// runs a control sequence once its final character has been read
+    fn control_sequence(&mut self, command: char) {
+        match command {
+            // cursor position. rows and columns start at 1
+            'H' | 'f' => {
+                self.cursor_y = (self.param(0, 1) - 1).min(self.rows - 1);
+                self.cursor_x = (self.param(1, 1) - 1).min(self.columns - 1);
+            }
+            // erase in display
+            'J' => match self.param(0, 0) {
+                0 => {
+                    self.clear_cells(self.cursor_y, self.cursor_x..self.columns);
+                    self.clear_rows((self.cursor_y + 1) * GLYPH_HEIGHT, self.rows * GLYPH_HEIGHT);
+                }
+                1 => {
+                    self.clear_rows(0, self.cursor_y * GLYPH_HEIGHT);
+                    self.clear_cells(self.cursor_y, 0..self.cursor_x + 1);
+                }
+                _ => self.clear_rows(0, self.rows * GLYPH_HEIGHT),
+            },
+            // erase in line
+            'K' => {
+                let columns = match self.param(0, 0) {
+                    0 => self.cursor_x..self.columns,
+                    1 => 0..self.cursor_x + 1,
+                    _ => 0..self.columns,
+                };
+
+                self.clear_cells(self.cursor_y, columns);
+            }
+            'm' => self.select_graphic_rendition(),
+            _ => (),
+        }
+    }
+
+    /// handles an SGR control sequence, only colors are supported
+    fn select_graphic_rendition(&mut self) {
+        let (mut foreground, mut background) = self.colors;
+
+        // no parameters at all is the same as a single 0
+        for &param in &self.params[..self.num_params.clamp(1, MAX_ESCAPE_PARAMS)] {
+            let param = param as usize;
+
+            match param {
+                0 => (foreground, background) = (DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+                30..=37 => foreground = ANSI_COLORS[param - 30],
+                39 => foreground = DEFAULT_FOREGROUND,
+                40..=47 => background = ANSI_COLORS[param - 40],
+                49 => background = DEFAULT_BACKGROUND,
+                // bright colors are the second half of the palette
+                90..=97 => foreground = ANSI_COLORS[param - 90] + 8,
+                100..=107 => background = ANSI_COLORS[param - 100] + 8,
+                _ => (),
+            }
+        }
+
+        self.set_color(foreground, background);
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.put_char(c);
+        }
+
+        Ok(())
+    }
+}
+
+/// maps the given physical memory into kernel memory, the same way bootloader modules are mapped
+fn map_framebuffer(phys: u64, size: usize, manager: &mut PageManager, dir: &mut impl PageDirectory) -> Result<&'static mut [u8], FramebufferError> {
+    let phys_aligned = phys / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+    let offset = (phys - phys_aligned) as usize;
+    let size_aligned = (size + offset + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+    debug!("mapping framebuffer @ {phys:#x} ({size:#x} bytes)");
+
+    let layout = Layout::from_size_align(size_aligned, PAGE_SIZE).map_err(|_| FramebufferError::OutOfMemory)?;
+    let ptr = unsafe { alloc(layout) };
+
+    if ptr.is_null() {
+        return Err(FramebufferError::OutOfMemory);
+    }
+
+    for i in (0..size_aligned).step_by(PAGE_SIZE) {
+        manager.free_frame(dir, ptr as usize + i)?;
+
+        // the framebuffer isn't ram, so it isn't tracked by the page manager
+        dir.set_page(
+            ptr as usize + i,
+            Some(PageFrame {
+                addr: phys_aligned + i as u64,
+                present: true,
+                writable: true,
+                ..Default::default()
+            }),
+        )?;
+    }
+
+    Ok(unsafe { slice::from_raw_parts_mut(ptr.add(offset), size) })
+}
+
+/// sets up the framebuffer console with the framebuffer the bootloader gave us, if it's usable
+pub fn init(info: &FramebufferInfo, manager: &mut PageManager, dir: &mut impl PageDirectory) -> Result<(), FramebufferError> {
+    let console = FramebufferConsole::new(info, manager, dir)?;
+
+    *CONSOLE.lock() = Some(console);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::fmt::Write;
+
+    /// makes a console that draws into a buffer on the heap, with 32 bits per pixel and 8 bits per color channel
+    fn test_console(columns: usize, rows: usize) -> FramebufferConsole {
+        let pitch = columns * GLYPH_WIDTH * 4;
+        let buffer = vec![0xff; pitch * rows * GLYPH_HEIGHT].leak();
+
+        let mut console = FramebufferConsole {
+            buffer,
+            pitch,
+            bytes_per_pixel: 4,
+            layout: RGBColorInfo {
+                red_field_pos: 16,
+                red_mask_size: 8,
+                green_field_pos: 8,
+                green_mask_size: 8,
+                blue_field_pos: 0,
+                blue_mask_size: 8,
+            },
+            columns,
+            rows,
+            cursor_x: 0,
+            cursor_y: 0,
+            foreground: 0,
+            background: 0,
+            colors: (DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+            escape: EscapeState::Normal,
+            params: [0; MAX_ESCAPE_PARAMS],
+            num_params: 0,
+        };
+
+        console.set_color(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+        console.clear();
+        console
+    }
+
+    /// checks whether every pixel of a character cell is the given color
+    fn cell_is(console: &FramebufferConsole, column: usize, row: usize, color: usize) -> bool {
+        let value = console.pixel_value(PALETTE[color]).to_le_bytes();
+
+        (row * GLYPH_HEIGHT..(row + 1) * GLYPH_HEIGHT).all(|y| {
+            (column * GLYPH_WIDTH..(column + 1) * GLYPH_WIDTH).all(|x| {
+                let offset = y * console.pitch + x * console.bytes_per_pixel;
+                console.buffer[offset..offset + 4] == value
+            })
+        })
+    }
+
+    #[test_case]
+    fn sgr_sets_colors() {
+        let mut console = test_console(8, 4);
+
+        write!(console, "\x1b[31;44m").unwrap();
+        assert_eq!(console.colors, (4, 1));
+
+        write!(console, "\x1b[93;39m").unwrap();
+        assert_eq!(console.colors, (DEFAULT_FOREGROUND, 1));
+
+        write!(console, "\x1b[m").unwrap();
+        assert_eq!(console.colors, (DEFAULT_FOREGROUND, DEFAULT_BACKGROUND));
+
+        write!(console, "\x1b[102m ").unwrap();
+        assert!(cell_is(&console, 0, 0, 10));
+    }
+
+    #[test_case]
+    fn split_escape_sequences_are_resumed() {
+        let mut console = test_console(8, 4);
+
+        write!(console, "\x1b").unwrap();
+        write!(console, "[3").unwrap();
+        write!(console, ";5H").unwrap();
+        assert_eq!((console.cursor_x, console.cursor_y), (4, 2));
+
+        // nothing in the sequence should have been drawn
+        assert!((0..8).all(|column| (0..4).all(|row| cell_is(&console, column, row, DEFAULT_BACKGROUND))));
+    }
+
+    #[test_case]
+    fn cursor_position_is_clamped() {
+        let mut console = test_console(8, 4);
+
+        write!(console, "\x1b[99;99H").unwrap();
+        assert_eq!((console.cursor_x, console.cursor_y), (7, 3));
+
+        write!(console, "\x1b[H").unwrap();
+        assert_eq!((console.cursor_x, console.cursor_y), (0, 0));
+
+        write!(console, "\x1b[;3H").unwrap();
+        assert_eq!((console.cursor_x, console.cursor_y), (2, 0));
+    }
+
+    #[test_case]
+    fn erase_clears_the_right_cells() {
+        let mut console = test_console(8, 4);
+
+        write!(console, "\x1b[41m        \n        \x1b[m").unwrap();
+
+        // erase from the cursor to the end of the line
+        write!(console, "\x1b[1;3H\x1b[K").unwrap();
+        assert!(cell_is(&console, 1, 0, 4));
+        assert!((2..8).all(|column| cell_is(&console, column, 0, DEFAULT_BACKGROUND)));
+
+        // erase from the start of the screen to the cursor
+        write!(console, "\x1b[2;2H\x1b[1J").unwrap();
+        assert!(cell_is(&console, 0, 0, DEFAULT_BACKGROUND) && cell_is(&console, 1, 1, DEFAULT_BACKGROUND));
+        assert!(cell_is(&console, 2, 1, 4));
+        assert_eq!((console.cursor_x, console.cursor_y), (1, 1));
+    }
+}
@@ -1,12 +1,88 @@
+use alloc::{string::String, vec::Vec};
+use common::types::Errno;
 use core::{
+    cell::UnsafeCell,
     fmt,
     fmt::Write,
-    sync::atomic::{AtomicU32, Ordering},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize, Ordering},
 };
 use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use spin::RwLock;
 
 use x86::io::{inb, outb};
 
+/// base I/O port of COM1
+const COM1: u16 = 0x3f8;
+
+/// how many times to poll the UART before giving up on it, so a missing or broken UART can't hang us
+const SERIAL_TIMEOUT: usize = 0x10000;
+
+/// how many times to try to take the logger lock before giving up and buffering the line instead
+const LOCK_SPINS: usize = 0x10000;
+
+/// how many per-CPU log buffers there are. CPUs with APIC IDs past this share buffers
+const LOG_BUFFERS: usize = 16;
+
+/// how many bytes of log output each per-CPU buffer can hold before the oldest output starts getting dropped
+const LOG_BUFFER_SIZE: usize = 4096;
+
+/// where log output goes
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Backend {
+    /// COM1 and the bochs/qemu 0xe9 port (the default)
+    All = 0,
+
+    /// only COM1
+    Serial = 1,
+
+    /// only the bochs/qemu 0xe9 port
+    Debugcon = 2,
+}
+
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::All as u8);
+
+/// whether the UART has been initialized yet
+static SERIAL_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// sets where log output goes
+pub fn set_backend(backend: Backend) {
+    if backend != Backend::Debugcon {
+        init_serial();
+    }
+
+    BACKEND.store(backend as u8, Ordering::Release);
+}
+
+/// sets the backend based on the value of the `console` command line key
+pub fn set_backend_from_cmdline(console: &str) {
+    match console {
+        "serial" | "ttyS0" => set_backend(Backend::Serial),
+        "debugcon" => set_backend(Backend::Debugcon),
+        "all" => set_backend(Backend::All),
+        _ => log::warn!("unknown console {console:?}, ignoring"),
+    }
+}
+
+/// initializes COM1 as a 16550 UART at 115200 baud, 8 data bits, no parity, 1 stop bit
+///
+/// this only does anything the first time it's called, so it's safe to call from anywhere (like a panic handler)
+pub fn init_serial() {
+    if SERIAL_INITIALIZED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    unsafe {
+        outb(COM1 + 1, 0x00); // disable interrupts
+        outb(COM1 + 3, 0x80); // enable DLAB to set the baud rate divisor
+        outb(COM1, 0x01); // divisor low byte (115200 baud)
+        outb(COM1 + 1, 0x00); // divisor high byte
+        outb(COM1 + 3, 0x03); // 8 bits, no parity, 1 stop bit, disable DLAB
+        outb(COM1 + 2, 0xc7); // enable and clear FIFOs with a 14 byte threshold
+        outb(COM1 + 4, 0x03); // assert DTR and RTS
+    }
+}
+
 /// Write a string to the output channel
 ///
 /// # Safety
@@ -14,8 +90,21 @@ use x86::io::{inb, outb};
 /// This method is unsafe because it does port accesses without synchronisation
 pub unsafe fn serial_puts(s: &str) {
     for b in s.bytes() {
-        serial_putb(b);
+        serial_putb_crlf(b);
+    }
+}
+
+/// writes a single byte to the output channel, turning line feeds into CR+LF since that's what terminals expect
+///
+/// # Safety
+///
+/// This method is unsafe because it does port accesses without synchronisation
+unsafe fn serial_putb_crlf(b: u8) {
+    if b == b'\n' {
+        serial_putb(b'\r');
     }
+
+    serial_putb(b);
 }
 
 /// Write a single byte to the output channel
@@ -24,15 +113,23 @@ pub unsafe fn serial_puts(s: &str) {
 ///
 /// This method is unsafe because it does port accesses without synchronisation
 pub unsafe fn serial_putb(b: u8) {
-    // Wait for the serial port's fifo to not be empty
-    while (inb(0x3F8 + 5) & 0x20) == 0 {
-        // Do nothing
+    let backend = BACKEND.load(Ordering::Acquire);
+
+    if backend != Backend::Debugcon as u8 {
+        // Wait for the serial port's transmit buffer to be empty
+        for _i in 0..SERIAL_TIMEOUT {
+            if (inb(COM1 + 5) & 0x20) != 0 {
+                break;
+            }
+        }
+        // Send the byte out the serial port
+        outb(COM1, b);
     }
-    // Send the byte out the serial port
-    outb(0x3F8, b);
 
-    // Also send to the bochs 0xe9 hack
-    outb(0xe9, b);
+    if backend != Backend::Serial as u8 {
+        // Also send to the bochs 0xe9 hack
+        outb(0xe9, b);
+    }
 }
 
 /// wrapper struct to allow us to "safely" write!() to the serial port
@@ -47,15 +144,217 @@ impl Write for SerialWriter {
     }
 }
 
+/// fixed size ring buffer of log output that couldn't be written right away, since whoever had the logger locked might never release it.
+/// this doesn't allocate or wait on anything, so it's safe to use from anywhere
+struct LogBuffer {
+    /// whether something is currently reading or writing this buffer
+    busy: AtomicBool,
+    data: UnsafeCell<[u8; LOG_BUFFER_SIZE]>,
+    start: UnsafeCell<usize>,
+    len: UnsafeCell<usize>,
+
+    /// how many lines were dropped because the buffer was busy
+    dropped: AtomicUsize,
+}
+
+// access to the data is synchronized by the busy flag
+unsafe impl Sync for LogBuffer {}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            busy: AtomicBool::new(false),
+            data: UnsafeCell::new([0; LOG_BUFFER_SIZE]),
+            start: UnsafeCell::new(0),
+            len: UnsafeCell::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// runs the given function with exclusive access to this buffer if nothing else is using it
+    fn try_with<F: FnOnce(&mut LogBufferWriter)>(&self, f: F) -> bool {
+        if self.busy.swap(true, Ordering::Acquire) {
+            return false;
+        }
+
+        unsafe {
+            f(&mut LogBufferWriter {
+                data: &mut *self.data.get(),
+                start: &mut *self.start.get(),
+                len: &mut *self.len.get(),
+            });
+        }
+
+        self.busy.store(false, Ordering::Release);
+
+        true
+    }
+}
+
+/// exclusive access to the contents of a [LogBuffer]
+struct LogBufferWriter<'a> {
+    data: &'a mut [u8; LOG_BUFFER_SIZE],
+    start: &'a mut usize,
+    len: &'a mut usize,
+}
+
+impl LogBufferWriter<'_> {
+    /// writes everything in the buffer out to the serial port and empties it
+    fn drain(&mut self) {
+        while *self.len > 0 {
+            unsafe {
+                serial_putb_crlf(self.data[*self.start]);
+            }
+
+            *self.start = (*self.start + 1) % LOG_BUFFER_SIZE;
+            *self.len -= 1;
+        }
+    }
+}
+
+impl Write for LogBufferWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            // drop the oldest output if we're out of room
+            if *self.len == LOG_BUFFER_SIZE {
+                *self.start = (*self.start + 1) % LOG_BUFFER_SIZE;
+                *self.len -= 1;
+            }
+
+            self.data[(*self.start + *self.len) % LOG_BUFFER_SIZE] = b;
+            *self.len += 1;
+        }
+
+        Ok(())
+    }
+}
+
+const LOG_BUFFER_INIT: LogBuffer = LogBuffer::new();
+
+/// log output that's waiting to be written, one buffer per CPU
+static PENDING_OUTPUT: [LogBuffer; LOG_BUFFERS] = [LOG_BUFFER_INIT; LOG_BUFFERS];
+
+/// writes out everything waiting in the per-CPU log buffers. the logger lock must be held while calling this
+fn flush_log_buffers() {
+    for (i, buffer) in PENDING_OUTPUT.iter().enumerate() {
+        // whatever's using the buffer will get it next time
+        buffer.try_with(|writer| writer.drain());
+
+        let dropped = buffer.dropped.swap(0, Ordering::AcqRel);
+
+        if dropped > 0 {
+            let _ = writeln!(&mut SerialWriter, "(dropped {dropped} buffered log lines from buffer {i})");
+        }
+    }
+}
+
+/// every level filter, indexed by its numeric value
+const LEVEL_FILTERS: [LevelFilter; 6] = [LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn, LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace];
+
+/// log levels of specific modules, overriding the global log level
+static MODULE_LEVELS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(Vec::new());
+
+/// whether MODULE_LEVELS has anything in it, so the common case doesn't have to touch the lock
+static HAS_MODULE_LEVELS: AtomicBool = AtomicBool::new(false);
+
+/// checks whether the given filter names the given module or one of its parents, i.e. `paging` and `mm::paging` both match `kernel::mm::paging`
+fn module_matches(path: &str, filter: &str) -> bool {
+    path.match_indices(filter)
+        .any(|(i, _)| (i == 0 || path[..i].ends_with("::")) && (i + filter.len() == path.len() || path[i + filter.len()..].starts_with("::")))
+}
+
+/// updates the log crate's max level to the most verbose level any module can log at, so its macros only call into the logger when something might be logged
+fn update_max_level() {
+    let mut max_level = LOGGER.max_level();
+
+    if let Some(levels) = MODULE_LEVELS.try_read() {
+        for (_, level) in levels.iter() {
+            max_level = max_level.max(*level);
+        }
+    }
+
+    log::set_max_level(max_level);
+}
+
+/// sets the global log level. modules that have their own log level aren't affected
+pub fn set_log_level(level: LevelFilter) {
+    LOGGER.max_level.store(level as usize, Ordering::Release);
+    update_max_level();
+}
+
+/// sets the log level of the given module and all its children, overriding the global log level
+pub fn set_module_log_level(module: &str, level: LevelFilter) -> common::types::Result<()> {
+    {
+        let mut levels = MODULE_LEVELS.write();
+
+        if let Some(entry) = levels.iter_mut().find(|(name, _)| name == module) {
+            entry.1 = level;
+        } else {
+            let mut name = String::new();
+            name.try_reserve_exact(module.len()).map_err(|_| Errno::OutOfMemory)?;
+            name.push_str(module);
+
+            levels.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+            levels.push((name, level));
+        }
+
+        HAS_MODULE_LEVELS.store(true, Ordering::Release);
+    }
+
+    update_max_level();
+
+    Ok(())
+}
+
+/// sets log levels based on the value of the `loglevel` command line key, which is a comma separated list of either a global level (`debug`)
+/// or per-module levels (`paging=trace`)
+pub fn set_log_level_from_cmdline(loglevel: &str) {
+    for item in loglevel.split(',').filter(|item| !item.is_empty()) {
+        let (module, level) = match item.split_once('=') {
+            Some((module, level)) => (Some(module), level),
+            None => (None, item),
+        };
+
+        let Ok(level) = level.parse::<LevelFilter>() else {
+            log::warn!("unknown log level {level:?}, ignoring");
+            continue;
+        };
+
+        match module {
+            Some(module) => {
+                if let Err(err) = set_module_log_level(module, level) {
+                    log::warn!("couldn't set log level of {module:?}: {err:?}");
+                }
+            }
+            None => set_log_level(level),
+        }
+    }
+}
+
 /// simple logger implementation over serial
 struct Logger {
-    max_level: LevelFilter,
+    max_level: AtomicUsize,
     lock: AtomicU32,
 }
 
+impl Logger {
+    fn max_level(&self) -> LevelFilter {
+        LEVEL_FILTERS[self.max_level.load(Ordering::Relaxed) % LEVEL_FILTERS.len()]
+    }
+}
+
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.max_level
+        // the most specific matching module level wins. if the levels are being changed right now, just fall back on the global level
+        if HAS_MODULE_LEVELS.load(Ordering::Relaxed) && let Some(levels) = MODULE_LEVELS.try_read() {
+            let found = levels.iter().filter(|(name, _)| module_matches(metadata.target(), name)).max_by_key(|(name, _)| name.len());
+
+            if let Some((_, level)) = found {
+                return metadata.level() <= *level;
+            }
+        }
+
+        metadata.level() <= self.max_level()
     }
 
     #[allow(unused_must_use)]
@@ -63,10 +362,37 @@ impl Log for Logger {
         if self.enabled(record.metadata()) {
             let apic_id = crate::arch::apic::get_local_apic().map(|apic| apic.id() as u32 + 1).unwrap_or(1);
 
+            let level = record.level();
+            let width = 5;
+            let args = record.args();
+
             // acquire lock if this cpu doesn't have it already
             let has_lock = if self.lock.load(Ordering::Acquire) != apic_id {
                 // how the fuck does ordering work
+                let mut spins = 0;
                 while self.lock.compare_exchange(0, apic_id, Ordering::SeqCst, Ordering::Acquire).is_err() {
+                    spins += 1;
+
+                    if spins >= LOCK_SPINS {
+                        // whoever has the lock might be waiting on us (i.e. we interrupted something it's waiting for),
+                        // so buffer this line for whoever gets the lock next instead of waiting forever
+                        let buffer = &PENDING_OUTPUT[(apic_id as usize - 1) % LOG_BUFFERS];
+
+                        let buffered = buffer.try_with(|writer| {
+                            if let Some(path) = record.module_path() {
+                                writeln!(writer, "{level:width$} [{path}] {args}");
+                            } else {
+                                writeln!(writer, "{level:width$} [unknown] {args}");
+                            }
+                        });
+
+                        if !buffered {
+                            buffer.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+
+                        return;
+                    }
+
                     crate::arch::spin();
                 }
                 true
@@ -74,9 +400,7 @@ impl Log for Logger {
                 false
             };
 
-            let level = record.level();
-            let width = 5;
-            let args = record.args();
+            flush_log_buffers();
 
             if let Some(path) = record.module_path() {
                 writeln!(&mut SerialWriter, "{level:width$} [{path}] {args}");
@@ -84,6 +408,11 @@ impl Log for Logger {
                 writeln!(&mut SerialWriter, "{level:width$} [unknown] {args}");
             }
 
+            // don't wait on the framebuffer console, since whoever has it locked might be the one logging
+            if let Some(console) = super::fbcon::CONSOLE.try_lock().as_mut().and_then(|c| c.as_mut()) {
+                writeln!(console, "{level:width$} {args}");
+            }
+
             if has_lock {
                 // release lock
                 self.lock.store(0, Ordering::Release);
@@ -91,16 +420,23 @@ impl Log for Logger {
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if self.lock.compare_exchange(0, u32::MAX, Ordering::SeqCst, Ordering::Acquire).is_ok() {
+            flush_log_buffers();
+            self.lock.store(0, Ordering::Release);
+        }
+    }
 }
 
 /// our logger that we will log things with
 static LOGGER: Logger = Logger {
-    max_level: LevelFilter::Info,
+    max_level: AtomicUsize::new(LevelFilter::Info as usize),
     lock: AtomicU32::new(0),
 };
 
 /// initialize the logger, setting the max level in the process
 pub fn init() -> Result<(), SetLoggerError> {
-    log::set_logger(&LOGGER).map(|_| log::set_max_level(LOGGER.max_level))
+    init_serial();
+
+    log::set_logger(&LOGGER).map(|_| log::set_max_level(LOGGER.max_level()))
 }
@@ -1,5 +1,8 @@
 pub mod bootloader;
+pub mod cmdline;
+pub mod fbcon;
 pub mod logger;
+pub mod signature;
 
 use crate::{
     arch::{
@@ -14,7 +17,9 @@ use crate::{
     util::{
         abi::ABI,
         array::BitSet,
+        cpio::CpioIterator,
         debug::DebugArray,
+        hash::fnv1a,
         tar::{EntryKind, TarIterator},
     },
 };
@@ -28,7 +33,7 @@ use alloc::{
 };
 use compression::prelude::*;
 use core::{arch::asm, mem::size_of};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
 pub const LINKED_BASE: usize = 0xe0000000;
 pub const HEAP_START: usize = LINKED_BASE + 0x01000000;
@@ -97,7 +102,13 @@ pub fn kmain() {
 
     // === multiboot pre-init ===
 
-    let mem_size = bootloader::init();
+    let mut mem_size = bootloader::init();
+
+    // frames the page directories can't map would only ever fail to be mapped, so don't hand them out in the first place
+    if mem_size > crate::arch::PHYS_ADDR_LIMIT {
+        warn!("ignoring {} MB of memory above {:#x}", (mem_size - crate::arch::PHYS_ADDR_LIMIT) / 1024 / 1024, crate::arch::PHYS_ADDR_LIMIT);
+        mem_size = crate::arch::PHYS_ADDR_LIMIT;
+    }
     let mem_size_pages: usize = (mem_size / PAGE_SIZE as u64).try_into().unwrap();
 
     // === paging init ===
@@ -108,21 +119,23 @@ pub fn kmain() {
     }
 
     // initialize the pagemanager to manage our page allocations
-    set_page_manager(PageManager::new(
+    let mut manager = PageManager::new(
         {
             let layout = Layout::new::<u32>();
+            let words = mem_size_pages.div_ceil(32); // round up so the last few pages get bits too
             let ptr = unsafe {
-                bump_alloc::<u32>(Layout::from_size_align(mem_size_pages / 32 * layout.size(), layout.align()).unwrap())
+                bump_alloc::<u32>(Layout::from_size_align(words * layout.size(), layout.align()).unwrap())
                     .unwrap()
                     .pointer
             };
-            let mut bitset = BitSet::place_at(ptr, mem_size_pages);
+            let mut bitset = BitSet::place_at(ptr, words, mem_size_pages).expect("frame bitmap storage is too small");
             bitset.clear_all();
-            bootloader::reserve_pages(&mut bitset);
             bitset
         },
         PAGE_SIZE,
-    ));
+    );
+    bootloader::reserve_pages(&mut manager);
+    set_page_manager(manager);
 
     // page directory for kernel
     let mut page_dir = PageDir::bump_allocate();
@@ -140,23 +153,26 @@ pub fn kmain() {
             if !page_dir.has_page_table(addr.try_into().unwrap()) {
                 debug!("allocating new page table");
                 let ptr = unsafe { bump_alloc::<PageTable>(Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap()).unwrap() };
-                page_dir.add_page_table(addr.try_into().unwrap(), unsafe { &mut *ptr.pointer }, ptr.phys_addr.try_into().unwrap(), false);
+                page_dir.add_page_table(addr.try_into().unwrap(), unsafe { &mut *ptr.pointer }, ptr.phys_addr.try_into().unwrap(), false).unwrap();
             }
 
             manager.alloc_frame_at(&mut page_dir, addr, (addr - LINKED_BASE) as u64, false, true, true).unwrap();
         }
 
-        // free the page below the stack, to catch stack overflow
+        // put guard pages below the stacks, to catch stack overflow
         debug!("stack @ {stack_base_pos:#x} - {stack_end_pos:#x}");
-        manager.free_frame(&mut page_dir, stack_base_pos - PAGE_SIZE).unwrap();
+        manager.install_guard_page(&mut page_dir, stack_base_pos - PAGE_SIZE).unwrap();
 
         debug!("interrupt stack @ {int_stack_base_pos:#x} - {int_stack_end_pos:#x}");
-        manager.free_frame(&mut page_dir, int_stack_base_pos - PAGE_SIZE).unwrap();
+        manager.install_guard_page(&mut page_dir, int_stack_base_pos - PAGE_SIZE).unwrap();
+
+        crate::mm::paging::set_stack_guards(stack_base_pos - PAGE_SIZE, int_stack_base_pos - PAGE_SIZE);
+
+        // the real mode IVT and BIOS data area live in the first frame, and we still need the latter to find things like the EBDA
+        manager.set_frame_used(0);
 
-        // set aside some memory for bootstrapping other CPUs
-        //let bootstrap_addr = manager.first_available_frame().unwrap();
-        let bootstrap_addr = 0x1000;
-        manager.set_frame_used(bootstrap_addr);
+        // set aside some memory for bootstrapping other CPUs. the SIPI vector can only point below 1mb
+        let bootstrap_addr = manager.alloc_low_frame(0x100000).expect("couldn't find low memory for CPU bootstrap code");
 
         debug!("bootstrap code @ {bootstrap_addr:#x}");
 
@@ -171,7 +187,7 @@ pub fn kmain() {
             if !page_dir.has_page_table(addr.try_into().unwrap()) {
                 debug!("allocating new page table");
                 let ptr = unsafe { bump_alloc::<PageTable>(Layout::from_size_align(size_of::<PageTable>(), PAGE_SIZE).unwrap()).unwrap() };
-                page_dir.add_page_table(addr.try_into().unwrap(), unsafe { &mut *ptr.pointer }, ptr.phys_addr.try_into().unwrap(), false);
+                page_dir.add_page_table(addr.try_into().unwrap(), unsafe { &mut *ptr.pointer }, ptr.phys_addr.try_into().unwrap(), false).unwrap();
             }
 
             let phys_addr = manager.alloc_frame().unwrap();
@@ -227,6 +243,24 @@ pub fn kmain() {
 
     debug!("{info:?}");
 
+    // === framebuffer console ===
+
+    if let Some(framebuffer) = info.framebuffer.as_ref() {
+        match fbcon::init(framebuffer, &mut get_page_manager(), unsafe { PAGE_DIR.as_mut().unwrap() }) {
+            Ok(_) => info!("using {}x{}x{} framebuffer console", framebuffer.width, framebuffer.height, framebuffer.bpp),
+            Err(err) => warn!("couldn't set up framebuffer console: {err:?}"),
+        }
+    }
+
+    // === parse command line ===
+    let cmdline = bootloader::get_multiboot_info().cmdline.map(cmdline::CmdLine::parse).unwrap_or_default();
+
+    if let Some(loglevel) = cmdline.get_str("loglevel") {
+        logger::set_log_level_from_cmdline(loglevel);
+    }
+
+    debug!("{:?}", cmdline);
+
     // === discover modules ===
 
     if info.mods.is_none() || info.mods.as_ref().unwrap().is_empty() {
@@ -237,16 +271,164 @@ pub fn kmain() {
 
     let mut modules: BTreeMap<String, &'static [u8]> = BTreeMap::new();
 
-    fn discover_module(modules: &mut BTreeMap<String, &'static [u8]>, name: String, data: &'static [u8]) {
+    /// the least a decompression buffer grows by at once when the decompressed size isn't known up front
+    const DECOMPRESS_CHUNK_SIZE: usize = 0x10000;
+
+    /// how deeply archives and compressed files can be nested in modules before we give up on them
+    const MAX_MODULE_DEPTH: usize = 8;
+
+    /// writes the output of one of the compression crate's decoders straight into a buffer, so there's never a second copy of the decompressed data around.
+    /// if the decompressed size is known the buffer is allocated up front, otherwise it's doubled in size whenever it fills up so big modules don't
+    /// take quadratic time to copy around.
+    /// decompression is aborted if more than the given limit would be produced
+    fn decompress_stream<E: core::fmt::Debug>(decoded: impl Iterator<Item = Result<u8, E>>, size_hint: Option<usize>, limit: usize) -> Result<Vec<u8>, String> {
+        let mut decompressed = Vec::new();
+
+        // the size hint is only a hint, so if it's bogus we can still fall back to growing the buffer as we go
+        if let Some(size) = size_hint
+            && size <= limit
+            && decompressed.try_reserve_exact(size).is_err()
+        {
+            warn!("couldn't allocate {size} bytes up front for decompressed data");
+        }
+
+        for byte in decoded {
+            if decompressed.len() >= limit {
+                return Err(format!("decompressed data exceeds limit of {limit} bytes"));
+            }
+
+            if decompressed.len() == decompressed.capacity() {
+                let additional = decompressed.len().max(DECOMPRESS_CHUNK_SIZE).min(limit - decompressed.len());
+                decompressed.try_reserve_exact(additional).map_err(|_| "out of memory".to_string())?;
+            }
+
+            decompressed.push(byte.map_err(|err| format!("{err:?}"))?);
+        }
+
+        Ok(decompressed)
+    }
+
+    /// gets the decompressed size of a gzip stream from its trailer. this is only the size modulo 2^32 and only covers the last member of the stream,
+    /// so it can't be trusted to be exact
+    fn gzip_size(data: &[u8]) -> Option<usize> {
+        // 10 byte header and 8 byte trailer
+        if data.len() < 18 {
+            return None;
+        }
+
+        u32::from_le_bytes(data[data.len() - 4..].try_into().ok()?).try_into().ok()
+    }
+
+    /// decompresses an entire xz stream, since unlike the compression crate xz4rust doesn't give us an iterator adapter
+    fn decompress_xz(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+        let mut decoder = xz4rust::XzDecoder::in_heap();
+        let mut decompressed = Vec::new();
+        let mut buf = [0_u8; 4096];
+        let mut position = 0;
+
+        loop {
+            match decoder.decode(&data[position..], &mut buf).map_err(|err| format!("{err:?}"))? {
+                xz4rust::XzNextBlockResult::NeedMoreData(consumed, produced) => {
+                    position += consumed;
+
+                    if decompressed.len() + produced > limit {
+                        return Err(format!("decompressed data exceeds limit of {limit} bytes"));
+                    }
+
+                    decompressed.extend_from_slice(&buf[..produced]);
+
+                    // if the decoder is stuck, the stream must be truncated
+                    if consumed == 0 && produced == 0 {
+                        return Err("truncated stream".to_string());
+                    }
+                }
+                xz4rust::XzNextBlockResult::EndOfStream(_, produced) => {
+                    if decompressed.len() + produced > limit {
+                        return Err(format!("decompressed data exceeds limit of {limit} bytes"));
+                    }
+
+                    decompressed.extend_from_slice(&buf[..produced]);
+                    return Ok(decompressed);
+                }
+            }
+        }
+    }
+
+    /// decompresses an entire zstd stream
+    fn decompress_zstd(mut data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+        use ruzstd::io::Read;
+
+        let mut decoder = ruzstd::StreamingDecoder::new(&mut data).map_err(|err| format!("{err:?}"))?;
+        let mut decompressed = Vec::new();
+        let mut buf = [0_u8; 4096];
+
+        loop {
+            match decoder.read(&mut buf).map_err(|err| format!("{err:?}"))? {
+                0 => return Ok(decompressed),
+                produced if decompressed.len() + produced > limit => return Err(format!("decompressed data exceeds limit of {limit} bytes")),
+                produced => decompressed.extend_from_slice(&buf[..produced]),
+            }
+        }
+    }
+
+    /// gives decompressed data the 'static lifetime, reusing an identical buffer that's already been leaked if there is one
+    ///
+    /// Box::leak() prevents the decompressed data from being dropped, giving it the 'static lifetime since it doesn't contain any references to
+    /// anything else. since it's never freed, sharing buffers between identical modules saves that memory for good
+    fn leak_module(leaked: &mut BTreeMap<u64, Vec<&'static [u8]>>, decompressed: Vec<u8>) -> &'static [u8] {
+        let hash = fnv1a(&decompressed);
+        let bucket = leaked.entry(hash).or_default();
+
+        if let Some(existing) = bucket.iter().find(|existing| **existing == decompressed.as_slice()) {
+            debug!("reusing identical decompressed module @ {:#x}", existing.as_ptr() as usize);
+            return *existing;
+        }
+
+        let data: &'static [u8] = Box::leak(decompressed.into_boxed_slice());
+        bucket.push(data);
+
+        data
+    }
+
+    /// finds modules in the given file, unpacking any archives and decompressing any compressed files inside it
+    ///
+    /// depth is how many archives or compressed files this one is nested in, and budget is how many more bytes can be decompressed in total,
+    /// so a maliciously nested or highly compressible module can't use up all the memory
+    fn discover_module(
+        modules: &mut BTreeMap<String, &'static [u8]>,
+        leaked: &mut BTreeMap<u64, Vec<&'static [u8]>>,
+        name: String,
+        data: &'static [u8],
+        depth: usize,
+        budget: &mut usize,
+    ) {
         debug!("found module {name:?}: {:?}", DebugArray(data));
 
+        if depth > MAX_MODULE_DEPTH {
+            error!("{name:?} is nested more than {MAX_MODULE_DEPTH} archives deep, skipping it");
+            return;
+        }
+
         match name.split('.').last() {
             Some("tar") => {
                 info!("discovering all files in {name:?} as modules");
 
                 for entry in TarIterator::new(data) {
-                    if entry.header.kind() == EntryKind::NormalFile {
-                        discover_module(modules, entry.header.name().to_string(), entry.contents);
+                    match entry {
+                        Ok(entry) if entry.header.kind() == EntryKind::NormalFile => discover_module(modules, leaked, entry.name().to_string(), entry.contents, depth + 1, budget),
+                        Ok(_) => (),
+                        Err(err) => error!("error reading {name:?}, skipping the rest of it: {err:?}"),
+                    }
+                }
+            }
+            Some("cpio") => {
+                info!("discovering all files in {name:?} as modules");
+
+                for entry in CpioIterator::new(data) {
+                    match entry {
+                        Ok(entry) if entry.header.is_file() => discover_module(modules, leaked, entry.name.to_string(), entry.contents, depth + 1, budget),
+                        Ok(_) => (),
+                        Err(err) => error!("error reading {name:?}, skipping the rest of it: {err:?}"),
                     }
                 }
             }
@@ -260,10 +442,12 @@ pub fn kmain() {
 
                 info!("decompressing {name:?} as {new_name:?}");
 
-                match data.iter().cloned().decode(&mut BZip2Decoder::new()).collect::<Result<Vec<_>, _>>() {
-                    // Box::leak() prevents the decompressed data from being dropped, giving it the 'static lifetime since it doesn't
-                    // contain any references to anything else
-                    Ok(decompressed) => discover_module(modules, new_name, Box::leak(decompressed.into_boxed_slice())),
+                match decompress_stream(data.iter().cloned().decode(&mut BZip2Decoder::new()), None, *budget) {
+                    Ok(decompressed) => {
+                        *budget -= decompressed.len();
+                        let data = leak_module(leaked, decompressed);
+                        discover_module(modules, leaked, new_name, data, depth + 1, budget);
+                    }
                     Err(err) => error!("error decompressing {name}: {err:?}"),
                 }
             }
@@ -276,8 +460,48 @@ pub fn kmain() {
 
                 info!("decompressing {name:?} as {new_name:?}");
 
-                match data.iter().cloned().decode(&mut GZipDecoder::new()).collect::<Result<Vec<_>, _>>() {
-                    Ok(decompressed) => discover_module(modules, new_name, Box::leak(decompressed.into_boxed_slice())),
+                match decompress_stream(data.iter().cloned().decode(&mut GZipDecoder::new()), gzip_size(data), *budget) {
+                    Ok(decompressed) => {
+                        *budget -= decompressed.len();
+                        let data = leak_module(leaked, decompressed);
+                        discover_module(modules, leaked, new_name, data, depth + 1, budget);
+                    }
+                    Err(err) => error!("error decompressing {name}: {err:?}"),
+                }
+            }
+            Some("xz") => {
+                let new_name = {
+                    let mut split: Vec<&str> = name.split('.').collect();
+                    split.pop();
+                    split.join(".")
+                };
+
+                info!("decompressing {name:?} as {new_name:?}");
+
+                match decompress_xz(data, *budget) {
+                    Ok(decompressed) => {
+                        *budget -= decompressed.len();
+                        let data = leak_module(leaked, decompressed);
+                        discover_module(modules, leaked, new_name, data, depth + 1, budget);
+                    }
+                    Err(err) => error!("error decompressing {name}: {err:?}"),
+                }
+            }
+            Some("zst") => {
+                let new_name = {
+                    let mut split: Vec<&str> = name.split('.').collect();
+                    split.pop();
+                    split.join(".")
+                };
+
+                info!("decompressing {name:?} as {new_name:?}");
+
+                match decompress_zstd(data, *budget) {
+                    Ok(decompressed) => {
+                        *budget -= decompressed.len();
+                        let data = leak_module(leaked, decompressed);
+                        discover_module(modules, leaked, new_name, data, depth + 1, budget);
+                    }
                     Err(err) => error!("error decompressing {name}: {err:?}"),
                 }
             }
@@ -288,8 +512,25 @@ pub fn kmain() {
         }
     }
 
+    let mut leaked = BTreeMap::new();
+
+    // leave half of the free memory for everything else that has to happen during boot
+    let mut budget = {
+        let manager = get_page_manager();
+        (manager.frame_set.size - manager.frame_set.bits_used) / 2 * PAGE_SIZE
+    };
+
+    debug!("decompression budget is {budget} bytes");
+
     for module in bootloader_modules.iter() {
-        discover_module(&mut modules, module.string().to_string(), module.data());
+        discover_module(&mut modules, &mut leaked, module.string().to_string(), module.data(), 0, &mut budget);
+    }
+
+    // === verify modules ===
+
+    if cmdline.get_bool("verify_modules").unwrap_or(false) {
+        info!("verifying module signatures");
+        signature::verify_modules(&mut modules);
     }
 
     // === print module info ===
@@ -322,21 +563,20 @@ pub fn kmain() {
 
     get_page_manager().print_free();
 
-    // === parse command line ===
-    let cmdline = bootloader::get_multiboot_info().cmdline.filter(|s| !s.is_empty()).map(|cmdline| {
-        let mut map = BTreeMap::new();
+    if let Some(console) = cmdline.get_str("console") {
+        logger::set_backend_from_cmdline(console);
+    }
 
-        for arg in cmdline.split(' ') {
-            if !arg.is_empty() {
-                let arg = arg.split('=').collect::<Vec<_>>();
-                map.insert(arg[0], arg.get(1).copied().unwrap_or(""));
-            }
+    // set up what to do if we panic, so unattended runs don't hang forever
+    if let Some(value) = cmdline.get_str("panic") {
+        match crate::PanicAction::from_cmdline(value) {
+            Some(action) => crate::set_panic_action(action),
+            None => warn!("unknown panic action {value:?}, ignoring"),
         }
+    }
 
-        map
-    });
-
-    debug!("{:?}", cmdline);
+    // kill processes to free up memory instead of failing allocations outright
+    crate::mm::paging::set_oom_handler(crate::task::kill_largest_process);
 
     // set the global kernel page directory
     crate::mm::paging::set_kernel_page_dir(unsafe { PAGE_DIR.take().unwrap() });
@@ -345,6 +585,17 @@ pub fn kmain() {
     let start = 0xb8000;
     let end = start + 32 * 1024;
     let mut temp = crate::mm::shared::TempMemoryShare::new(Default::default(), start, end - 1).unwrap();
+    {
+        // make sure nothing else can allocate video memory out from under us
+        let mut manager = get_page_manager();
+        let (start_frame, num_frames) = (start / PAGE_SIZE, (end - start) / PAGE_SIZE);
+        let already_used = manager.frame_set.count_set_in_range(start_frame, num_frames);
+
+        if already_used != num_frames {
+            debug!("{} video memory frames weren't reserved, reserving them", num_frames - already_used);
+            manager.frame_set.set_range(start_frame, num_frames);
+        }
+    }
     for i in (start..end).step_by(PAGE_SIZE) {
         temp.add_reserved(i as u64);
     }
@@ -353,7 +604,8 @@ pub fn kmain() {
     // arch code takes over here
     crate::arch::init(cmdline, modules);
 
-    loop {
-        crate::arch::halt_until_interrupt();
-    }
+    // we only get here if init couldn't be started
+    warn!("no init process is running, halting");
+
+    crate::arch::idle_loop();
 }
@@ -0,0 +1,95 @@
+//! detached module signature verification
+//!
+//! every module `name` can have a `name.sig` module next to it containing a raw 64 byte Ed25519 signature over its contents, which gets checked
+//! against the public key baked into the kernel at build time thru the `MODULE_SIGNING_KEY` environment variable (64 hex digits)
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use ed25519_compact::{PublicKey, Signature};
+use log::{debug, error, info};
+
+/// file extension of detached signatures
+const SIGNATURE_EXTENSION: &str = ".sig";
+
+/// the public key modules are signed with, as hex, if one was provided at build time
+const MODULE_SIGNING_KEY: Option<&str> = option_env!("MODULE_SIGNING_KEY");
+
+/// errors that can occur while verifying a module
+#[derive(Debug)]
+pub enum VerifyError {
+    /// there's no public key to check signatures against
+    NoKey,
+
+    /// the public key baked into the kernel isn't valid
+    BadKey,
+
+    /// the module doesn't have a signature
+    Unsigned,
+
+    /// the signature isn't a valid Ed25519 signature
+    BadSignature,
+
+    /// the signature doesn't match the module's contents
+    Mismatch,
+}
+
+/// parses the public key baked into the kernel
+fn get_public_key() -> Result<PublicKey, VerifyError> {
+    let hex = MODULE_SIGNING_KEY.ok_or(VerifyError::NoKey)?.trim();
+
+    if hex.len() != PublicKey::BYTES * 2 {
+        return Err(VerifyError::BadKey);
+    }
+
+    let mut bytes = [0_u8; PublicKey::BYTES];
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or(VerifyError::BadKey)?, 16).map_err(|_| VerifyError::BadKey)?;
+    }
+
+    Ok(PublicKey::new(bytes))
+}
+
+/// checks the given module's contents against its detached signature
+fn verify(key: &PublicKey, data: &[u8], signature: Option<&[u8]>) -> Result<(), VerifyError> {
+    let signature = Signature::from_slice(signature.ok_or(VerifyError::Unsigned)?).map_err(|_| VerifyError::BadSignature)?;
+
+    key.verify(data, &signature).map_err(|_| VerifyError::Mismatch)
+}
+
+/// verifies every module against its detached signature, removing any that fail verification along with all the signatures themselves
+pub fn verify_modules(modules: &mut BTreeMap<String, &'static [u8]>) {
+    let key = match get_public_key() {
+        Ok(key) => Some(key),
+        Err(err) => {
+            error!("can't verify modules ({err:?}), refusing all of them");
+            None
+        }
+    };
+
+    let signatures: Vec<String> = modules.keys().filter(|name| name.ends_with(SIGNATURE_EXTENSION)).cloned().collect();
+    let mut signatures: BTreeMap<String, &'static [u8]> = signatures.into_iter().filter_map(|name| modules.remove_entry(&name)).collect();
+
+    modules.retain(|name, data| {
+        let signature = signatures.remove(&(name.clone() + SIGNATURE_EXTENSION));
+
+        let result = match key.as_ref() {
+            Some(key) => verify(key, *data, signature),
+            None => Err(VerifyError::NoKey),
+        };
+
+        match result {
+            Ok(_) => {
+                debug!("module {name:?} verified");
+                true
+            }
+            Err(err) => {
+                error!("module {name:?} failed verification ({err:?}), refusing it");
+                false
+            }
+        }
+    });
+
+    for name in signatures.keys() {
+        info!("ignoring signature {name:?} with no matching module");
+    }
+}
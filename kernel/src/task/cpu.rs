@@ -1,14 +1,31 @@
-use super::{queue::TaskQueue, ProcessID};
-use crate::arch::ThreadInfo;
+use super::{
+    queue::{TaskQueue, TaskQueueEntry},
+    ProcessID,
+};
+use crate::{arch::ThreadInfo, mm::paging::ScratchWindow};
 use alloc::{collections::VecDeque, vec::Vec};
 use common::types::{Errno, Result};
 use core::{
     fmt,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
-use log::{trace, warn};
+use log::{debug, trace, warn};
 use spin::Mutex;
 
+/// how many urgent messages each thread has space for up front, so they can be broadcast without allocating
+const URGENT_QUEUE_CAPACITY: usize = 16;
+
+/// how many timer ticks of history idle time is measured over
+pub const IDLE_WINDOW_TICKS: usize = 256;
+
+/// how many more tasks than the emptiest thread a task's previous thread can have and still be picked for it,
+/// and how big the difference between two threads' queues has to be before tasks are stolen between them.
+/// moving a task away from a CPU that still has its data in the cache costs more than a slightly longer wait for its turn
+pub const MIGRATION_STICKINESS: usize = 1;
+
+/// how many messages from processes each thread's message queue can hold by default
+pub const DEFAULT_MESSAGE_QUEUE_CAPACITY: usize = 256;
+
 /// describes a CPU and its layout of cores and threads
 ///
 /// this kind of knowledge of the CPU's topology is required for more intelligent load balancing
@@ -37,10 +54,111 @@ impl CPU {
         self.cores.get_mut(id.core)?.threads.get_mut(id.thread)
     }
 
-    /// searches through threads in this CPU in hierarchical order to find a thread with at least one extra task
+    /// gets the index of a thread in this CPU, counting threads in the order they appear in each core. used to refer to threads in affinity masks
+    pub fn thread_index(&self, id: ThreadID) -> Option<usize> {
+        let core = self.cores.get(id.core)?;
+
+        if id.thread >= core.threads.len() {
+            return None;
+        }
+
+        Some(self.cores[..id.core].iter().map(|c| c.threads.len()).sum::<usize>() + id.thread)
+    }
+
+    /// gets the ID of the thread with the given index in this CPU, the reverse of [CPU::thread_index]
+    pub fn thread_from_index(&self, mut index: usize) -> Option<ThreadID> {
+        for (core_num, core) in self.cores.iter().enumerate() {
+            if index < core.threads.len() {
+                return Some(ThreadID { core: core_num, thread: index });
+            }
+
+            index -= core.threads.len();
+        }
+
+        None
+    }
+
+    /// checks whether a task with the given affinity is allowed to run on the given thread
+    pub fn is_allowed_on(&self, affinity: Option<AffinityMask>, id: ThreadID) -> bool {
+        match affinity {
+            Some(mask) => self.thread_index(id).map(|index| mask.contains(index)).unwrap_or(false),
+            None => true,
+        }
+    }
+
+    /// checks whether a task with the given affinity is allowed to run on any thread in this CPU
+    pub fn is_allowed_anywhere(&self, affinity: Option<AffinityMask>) -> bool {
+        self.cores
+            .iter()
+            .enumerate()
+            .any(|(core, c)| (0..c.threads.len()).any(|thread| self.is_allowed_on(affinity, ThreadID { core, thread })))
+    }
+
+    /// sends an urgent message to every other started thread in this CPU, interrupts them to make them process it, and waits until they all have
+    ///
+    /// this doesn't allocate any memory (as long as the urgent message queues haven't been filled past their initial capacity),
+    /// so it's safe to call while the page manager or heap is locked. if a thread's queue is full, this waits for it to drain instead of growing it
+    pub fn broadcast_urgent(&self, message: UrgentMessage) {
+        let local_id = crate::arch::get_thread_id();
+        let local_thread = self.get_thread(local_id);
+
+        // keep processing our own urgent messages while we wait, since another thread might be waiting on us to do the same
+        let wait = || match local_thread {
+            Some(thread) => thread.process_urgent_messages(),
+            None => crate::arch::spin(),
+        };
+
+        super::take_page_update_lock(local_id);
+
+        for (core_num, core) in self.cores.iter().enumerate() {
+            for (thread_num, thread) in core.threads.iter().enumerate() {
+                let id = ThreadID { core: core_num, thread: thread_num };
+
+                if id == local_id || !thread.has_started() {
+                    continue;
+                }
+
+                loop {
+                    let mut queue = thread.urgent_message_queue.lock();
+
+                    if queue.len() < queue.capacity() {
+                        queue.push_back(message);
+                        break;
+                    }
+
+                    drop(queue);
+                    wait();
+                }
+
+                assert!(crate::arch::send_interrupt_to_cpu(id, crate::arch::MESSAGE_INT), "failed to send interrupt");
+            }
+        }
+
+        for (core_num, core) in self.cores.iter().enumerate() {
+            for (thread_num, thread) in core.threads.iter().enumerate() {
+                let id = ThreadID { core: core_num, thread: thread_num };
+
+                if id == local_id || !thread.has_started() {
+                    continue;
+                }
+
+                trace!("waiting for {id}");
+                while !thread.urgent_message_queue.lock().is_empty() {
+                    wait();
+                }
+            }
+        }
+
+        super::release_page_update_lock();
+    }
+
+    /// searches through threads in this CPU in hierarchical order to find a thread with at least one extra task that's allowed to run on the given thread
     pub fn find_thread_to_steal_from(&self, id: ThreadID) -> Option<ThreadID> {
+        // pinned tasks can't be migrated anywhere they're not allowed to run
+        let can_steal = |entry: &TaskQueueEntry| self.is_allowed_on(entry.affinity(), id);
+
         // search threads in the same core as the provided ID
-        if let Some(thread_id) = self.cores.get(id.core)?.find_busiest_thread() {
+        if let Some(thread_id) = self.cores.get(id.core)?.find_busiest_thread(can_steal) {
             return Some(ThreadID { core: id.core, thread: thread_id });
         }
 
@@ -53,7 +171,7 @@ impl CPU {
                 continue;
             }
 
-            if let Some(thread_id) = core.find_busiest_thread() {
+            if let Some(thread_id) = core.find_busiest_thread(can_steal) {
                 return Some(ThreadID { core: core_id, thread: thread_id });
             }
         }
@@ -62,29 +180,128 @@ impl CPU {
         None
     }
 
-    /// searches through cores and threads in this CPU to find the one with the least amount of tasks
-    pub fn find_thread_to_add_to(&self) -> Option<ThreadID> {
-        let mut possible_threads = Vec::new();
+    /// moves a batch of tasks from the busiest thread that has tasks allowed to run on the given idle thread to the idle thread, returning the IDs of the tasks that were moved
+    ///
+    /// up to half of the difference between the two threads' queues is moved, rounding up (and never more than max), as long as the busy thread isn't left with fewer tasks than the idle one.
+    /// tasks that are currently running count towards how many tasks a thread has, but can't be moved.
+    /// nothing is moved unless the difference is bigger than [MIGRATION_STICKINESS], so tasks don't get bounced between threads with nearly the same load.
+    /// the lowest priority tasks are moved first so the busy thread keeps its most important work
+    pub fn steal_batch(&self, idle: ThreadID, max: usize) -> Vec<ProcessID> {
+        let Some(victim_id) = self.find_thread_to_steal_from(idle) else {
+            return Vec::new();
+        };
+
+        if victim_id == idle {
+            return Vec::new();
+        }
+
+        let (Some(victim), Some(thief)) = (self.get_thread(victim_id), self.get_thread(idle)) else {
+            return Vec::new();
+        };
+
+        // how many tasks are waiting in a queue, and how many tasks the thread has in total including the one it's running
+        let load = |queue: &TaskQueue| (queue.len(), queue.len() + queue.current().is_some() as usize);
+
+        // only one queue is locked at a time, so two threads stealing from each other can't deadlock
+        let (thief_len, thief_load) = load(&thief.task_queue.lock());
+
+        let taken = {
+            let mut victim_queue = victim.task_queue.lock();
+            let (victim_len, victim_load) = load(&victim_queue);
+
+            if victim_load <= thief_load + MIGRATION_STICKINESS {
+                return Vec::new();
+            }
+
+            // a single extra waiting task is still worth moving if the busy thread is also running something
+            let count = victim_len.saturating_sub(thief_len).div_ceil(2).min((victim_load - thief_load) / 2).min(max);
+
+            match victim_queue.take_lowest(count, |entry| self.is_allowed_on(entry.affinity(), idle)) {
+                Ok(taken) => taken,
+                Err(err) => {
+                    warn!("couldn't steal tasks from CPU {victim_id}: {err:?}");
+                    return Vec::new();
+                }
+            }
+        };
+
+        let mut moved = Vec::new();
+        let mut put_back = Vec::new();
+
+        {
+            let mut thief_queue = thief.task_queue.lock();
+
+            for entry in taken.into_iter() {
+                if moved.try_reserve(1).is_ok() && thief_queue.insert(entry).is_ok() {
+                    moved.push(entry.id());
+                } else {
+                    put_back.push(entry);
+                }
+            }
+        }
+
+        if !put_back.is_empty() {
+            let mut victim_queue = victim.task_queue.lock();
+
+            for entry in put_back.into_iter() {
+                if let Err(err) = victim_queue.insert(entry) {
+                    warn!("couldn't put task {} back after failing to migrate it: {err:?}", entry.id());
+                }
+            }
+        }
+
+        // messages for these tasks should be sent to where they are now
+        for id in moved.iter() {
+            if let Some(mut process) = super::get_process(id.process) && let Some(thread) = process.threads.get_mut(id.thread as usize) {
+                thread.cpu = Some(idle);
+            }
+        }
+
+        trace!("stole {} tasks from CPU {victim_id} for CPU {idle}", moved.len());
+
+        moved
+    }
+
+    /// searches through cores and threads in this CPU to find the one with the least amount of tasks that a task with the given affinity is allowed to run on
+    ///
+    /// when threads have the same amount of tasks, threads on idle cores are preferred over threads whose siblings are busy,
+    /// since hyperthreads on the same core compete with each other for its resources. any remaining ties go to whichever thread has been idle the most recently
+    ///
+    /// if the task last ran on the given preferred thread, that thread is picked as long as it doesn't have more than [MIGRATION_STICKINESS] more tasks than the best one
+    pub fn find_thread_to_add_to(&self, affinity: Option<AffinityMask>, preferred: Option<ThreadID>) -> Option<ThreadID> {
+        let mut thread_id = None;
+        let mut weight = (usize::MAX, true, usize::MAX);
 
         for (core_id, core) in self.cores.iter().enumerate() {
-            if let Some((thread_num, num_tasks)) = core.find_emptiest_thread() {
+            if let Some((thread_num, num_tasks)) = core.find_emptiest_thread(|thread| self.is_allowed_on(affinity, ThreadID { core: core_id, thread })) {
                 let id = ThreadID { core: core_id, thread: thread_num };
+                let busy_percent = 100 - core.threads[thread_num].idle_percent();
+                let cur_weight = (num_tasks, core.is_busy(), busy_percent);
 
-                if num_tasks == 0 {
+                // can't do any better than an empty, completely idle thread on an idle core
+                if cur_weight == (0, false, 0) {
                     return Some(id);
-                } else {
-                    possible_threads.push((id, num_tasks));
+                }
+
+                if cur_weight < weight {
+                    thread_id = Some(id);
+                    weight = cur_weight;
                 }
             }
         }
 
-        let mut thread_id = None;
-        let mut num_tasks = usize::MAX;
+        if let Some(preferred) = preferred
+            && thread_id != Some(preferred)
+            && self.is_allowed_on(affinity, preferred)
+            && let Some(thread) = self.get_thread(preferred)
+        {
+            let num_tasks = {
+                let queue = thread.task_queue.lock();
+                queue.len() + usize::from(queue.current().is_some())
+            };
 
-        for (id, cur_num_tasks) in possible_threads.iter() {
-            if *cur_num_tasks < num_tasks {
-                thread_id = Some(*id);
-                num_tasks = *cur_num_tasks;
+            if num_tasks <= weight.0.saturating_add(MIGRATION_STICKINESS) {
+                return Some(preferred);
             }
         }
 
@@ -110,13 +327,18 @@ impl CPUCore {
         self.threads.push(CPUThread::new(info, timer));
     }
 
-    /// finds the thread in this core with the most tasks waiting in its queue
-    pub fn find_busiest_thread(&self) -> Option<usize> {
+    /// checks whether any thread in this core is currently running a task
+    pub fn is_busy(&self) -> bool {
+        self.threads.iter().any(|thread| thread.task_queue.lock().current().is_some())
+    }
+
+    /// finds the thread in this core with the most tasks waiting in its queue, only counting tasks that match the given filter
+    pub fn find_busiest_thread<F: Fn(&TaskQueueEntry) -> bool>(&self, filter: F) -> Option<usize> {
         let mut thread_id = None;
         let mut num_tasks = 0;
 
         for (id, thread) in self.threads.iter().enumerate() {
-            let cur_num_tasks = thread.task_queue.lock().len();
+            let cur_num_tasks = thread.task_queue.lock().iter().filter(|e| filter(e)).count();
             if cur_num_tasks > num_tasks {
                 thread_id = Some(id);
                 num_tasks = cur_num_tasks;
@@ -126,14 +348,18 @@ impl CPUCore {
         thread_id
     }
 
-    /// finds the thread in this core with the least tasks waiting in its queue
+    /// finds the thread in this core with the least tasks waiting in its queue, skipping any threads that aren't allowed
     ///
     /// when successful, returns the ID of the thread and how many tasks it has
-    pub fn find_emptiest_thread(&self) -> Option<(usize, usize)> {
+    pub fn find_emptiest_thread<F: Fn(usize) -> bool>(&self, allowed: F) -> Option<(usize, usize)> {
         let mut thread_id = None;
         let mut num_tasks = usize::MAX;
 
         for (id, thread) in self.threads.iter().enumerate() {
+            if !allowed(id) {
+                continue;
+            }
+
             let cur_num_tasks = {
                 let queue = thread.task_queue.lock();
                 queue.len() + usize::from(queue.current().is_some())
@@ -150,11 +376,21 @@ impl CPUCore {
 
 #[derive(Debug, Copy, Clone)]
 pub enum UrgentMessage {
-    /// update a page in the current task's address space
-    TaskPageUpdate { process_id: u32, addr: usize },
+    /// update a page in the address space of the page directory with the given physical address, if it's loaded.
+    /// if the physical address isn't known the page is always updated
+    TaskPageUpdate { page_dir: Option<u32>, addr: usize },
 
     /// update a page in the kernel's address space
     KernelPageUpdate { addr: usize },
+
+    /// update a range of pages in the address space of the page directory with the given physical address, same as TaskPageUpdate
+    TaskPageRange { page_dir: Option<u32>, start: usize, len: usize },
+
+    /// update a range of pages in the kernel's address space
+    KernelPageRange { start: usize, len: usize },
+
+    /// stop using the page directory with the given physical address since it's about to be freed
+    ReleaseAddressSpace { page_dir: u32 },
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -169,7 +405,156 @@ pub enum Message {
         process: u32,
         message: u32,
         data: Option<(u64, usize)>,
+        reply_to: Option<(u32, u32)>,
+    },
+
+    /// deliver a reply to a thread waiting in send_and_wait
+    Reply {
+        to: u32,
+        token: u32,
+        data: Option<(u64, usize)>,
     },
+
+    /// a task was queued on this thread, so pick it up right away if there's nothing running instead of waiting for the next context switch
+    Reschedule,
+}
+
+impl Message {
+    /// whether this message was sent on behalf of a process, rather than by the kernel itself
+    pub fn from_process(&self) -> bool {
+        matches!(self, Self::SendMessage { .. } | Self::Reply { .. })
+    }
+}
+
+/// statistics on how much time a CPU thread spends idle, both in how long it stays halted waiting for interrupts and how many of its recent timer ticks
+/// it wasn't running anything at
+#[derive(Debug, Default, Copy, Clone)]
+pub struct IdleStats {
+    /// shortest time spent asleep, in microseconds
+    pub min: u64,
+
+    /// longest time spent asleep, in microseconds
+    pub max: u64,
+
+    /// total time spent asleep, in microseconds
+    pub total: u64,
+
+    /// how many times we've woken up
+    pub wakeups: u64,
+
+    /// whether we were idle at each of the most recent timer ticks
+    pub window: IdleWindow,
+}
+
+impl IdleStats {
+    /// records a single sleep that lasted the given amount of timer ticks
+    pub fn record(&mut self, ticks: u64, hz: u64) {
+        // the timer hasn't been calibrated yet, so we can't tell how long we slept for
+        if hz == 0 {
+            return;
+        }
+
+        let micros = ticks.saturating_mul(1000000) / hz;
+
+        if self.wakeups == 0 || micros < self.min {
+            self.min = micros;
+        }
+
+        if micros > self.max {
+            self.max = micros;
+        }
+
+        self.total = self.total.saturating_add(micros);
+        self.wakeups += 1;
+    }
+
+    /// the average time spent asleep, in microseconds
+    pub fn average(&self) -> u64 {
+        if self.wakeups == 0 {
+            0
+        } else {
+            self.total / self.wakeups
+        }
+    }
+}
+
+impl fmt::Display for IdleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} wakeups, min {} us, max {} us, avg {} us, {}% idle",
+            self.wakeups,
+            self.min,
+            self.max,
+            self.average(),
+            self.window.idle_percent()
+        )
+    }
+}
+
+/// a fixed size ring of samples of whether a CPU thread was idle at each of its most recent timer ticks.
+/// this doesn't allocate so it's safe to update from the timer interrupt handler
+#[derive(Debug, Copy, Clone)]
+pub struct IdleWindow {
+    /// one bit per sample, set if the thread was idle
+    samples: [u32; IDLE_WINDOW_TICKS / 32],
+
+    /// index of the next sample to overwrite
+    next: usize,
+
+    /// how many samples have been taken, up to the size of the window
+    len: usize,
+
+    /// how many samples in the window are idle
+    num_idle: usize,
+}
+
+impl IdleWindow {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0; IDLE_WINDOW_TICKS / 32],
+            next: 0,
+            len: 0,
+            num_idle: 0,
+        }
+    }
+
+    /// records whether the thread was idle at a timer tick, replacing the oldest sample if the window is full
+    pub fn record(&mut self, idle: bool) {
+        let (index, bit) = (self.next / 32, 1 << (self.next % 32));
+
+        if self.len == IDLE_WINDOW_TICKS {
+            if self.samples[index] & bit != 0 {
+                self.num_idle -= 1;
+            }
+        } else {
+            self.len += 1;
+        }
+
+        if idle {
+            self.samples[index] |= bit;
+            self.num_idle += 1;
+        } else {
+            self.samples[index] &= !bit;
+        }
+
+        self.next = (self.next + 1) % IDLE_WINDOW_TICKS;
+    }
+
+    /// what percentage of the samples in the window were idle. a thread that hasn't been sampled yet is considered completely idle
+    pub fn idle_percent(&self) -> usize {
+        if self.len == 0 {
+            100
+        } else {
+            self.num_idle * 100 / self.len
+        }
+    }
+}
+
+impl Default for IdleWindow {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug)]
@@ -179,6 +564,13 @@ pub struct CPUThread {
     pub message_queue: Mutex<VecDeque<Message>>,
     pub timer: usize,
     pub info: ThreadInfo,
+    pub idle_stats: Mutex<IdleStats>,
+
+    /// the idle percentage from the last time [CPUThread::sample_idle] was called, kept outside the stats so it can be read without locking anything
+    idle_percent: AtomicUsize,
+    pub scratch_window: Mutex<Option<ScratchWindow>>,
+    message_queue_capacity: AtomicUsize,
+    quantum: AtomicUsize,
     in_kernel: AtomicBool,
     has_started: AtomicBool,
 }
@@ -187,10 +579,15 @@ impl CPUThread {
     pub fn new(info: ThreadInfo, timer: usize) -> Self {
         Self {
             task_queue: Mutex::new(TaskQueue::new()),
-            urgent_message_queue: Mutex::new(VecDeque::new()),
+            urgent_message_queue: Mutex::new(VecDeque::with_capacity(URGENT_QUEUE_CAPACITY)),
             message_queue: Mutex::new(VecDeque::new()),
             timer,
             info,
+            idle_stats: Mutex::new(IdleStats::default()),
+            idle_percent: AtomicUsize::new(100),
+            scratch_window: Mutex::new(None),
+            message_queue_capacity: AtomicUsize::new(DEFAULT_MESSAGE_QUEUE_CAPACITY),
+            quantum: AtomicUsize::new(0),
             in_kernel: AtomicBool::new(true),
             has_started: AtomicBool::new(false),
         }
@@ -207,21 +604,42 @@ impl CPUThread {
         while let Some(entry) = self.urgent_message_queue.lock().pop_front() {
             trace!("processing {entry:?}");
             match entry {
-                UrgentMessage::TaskPageUpdate { process_id: id, addr } => {
-                    let queue_lock = self.task_queue.lock(); // hold queue lock for as long as this TLB flush takes, not sure whether a race condition here would be bad or not
-
-                    let process_id = queue_lock.current().map(|c| c.id());
-                    if let Some(pid) = process_id && id == pid.process {
+                // these can interrupt code that's holding the task queue lock too, so they work the same way as ReleaseAddressSpace below
+                UrgentMessage::TaskPageUpdate { page_dir, addr } => {
+                    if page_dir.is_none_or(crate::arch::paging::is_page_dir_loaded) {
                         crate::arch::refresh_page(addr);
                     }
                 }
                 UrgentMessage::KernelPageUpdate { addr } => crate::arch::refresh_page(addr),
+                UrgentMessage::TaskPageRange { page_dir, start, len } => {
+                    if page_dir.is_none_or(crate::arch::paging::is_page_dir_loaded) {
+                        crate::arch::refresh_range(start, len);
+                    }
+                }
+                UrgentMessage::KernelPageRange { start, len } => crate::arch::refresh_range(start, len),
+                UrgentMessage::ReleaseAddressSpace { page_dir } => {
+                    // this can interrupt code that's holding the task queue lock, so check what's actually loaded rather than what's running
+                    if crate::arch::paging::is_page_dir_loaded(page_dir) {
+                        unsafe {
+                            crate::mm::paging::switch_to_kernel_page_dir();
+                        }
+                    }
+                }
             }
         }
     }
 
+    /// queues a message for this thread to process
+    ///
+    /// messages sent by processes are refused with OperationWouldBlock once the queue is full, so a process spamming messages can't use up all the memory.
+    /// messages the kernel relies on (i.e. killing threads and processes) are always queued
     pub fn send_message(&self, message: Message) -> Result<()> {
         let mut queue = self.message_queue.lock();
+
+        if message.from_process() && queue.len() >= self.message_queue_capacity() {
+            return Err(Errno::OperationWouldBlock);
+        }
+
         queue.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
         queue.push_back(message);
         Ok(())
@@ -233,6 +651,8 @@ impl CPUThread {
             match entry {
                 Message::KillThread(id) => {
                     self.task_queue.lock().remove_thread(id);
+                    super::ipc::cancel_pending_replies(id.process, Some(id.thread));
+                    super::ipc::fail_replies_from(id.process, Some(id.thread));
                     if let Some(current_id) = self.task_queue.lock().current().map(|c| c.id()) && current_id == id {
                         super::switch::manual_context_switch(self.timer, Some(cpu), regs, super::switch::ContextSwitchMode::Remove);
                     }
@@ -243,17 +663,113 @@ impl CPUThread {
                         super::switch::manual_context_switch(self.timer, Some(cpu), regs, super::switch::ContextSwitchMode::Remove);
                     }
                 }
-                Message::SendMessage { process, message, data } => {
-                    match super::ipc::send_message(cpu, self, regs, process, message, data) {
-                        Ok(_) => (),
-                        Err(Errno::NoSuchProcess) => (), // process doesn't exist, do nothing here since it probably exited
-                        Err(err) => warn!("(CPU {cpu}) couldn't send_message: {err:?}"),
+                Message::SendMessage { process, message, data, reply_to } => {
+                    if let Err(err) = super::ipc::send_message(cpu, self, regs, process, message, data, reply_to) {
+                        // process not existing isn't worth warning about since it probably exited
+                        if err != Errno::NoSuchProcess {
+                            warn!("(CPU {cpu}) couldn't send_message: {err:?}");
+                        }
+
+                        // the sender has already moved on, so nothing else is going to free the data or tell it the message went nowhere
+                        if let Some((addr, _)) = data {
+                            crate::mm::paging::get_page_manager().set_frame_free(addr);
+                        }
+
+                        if let Some((from, token)) = reply_to && let Err(err) = super::ipc::fail_reply(from, token, err) {
+                            debug!("(CPU {cpu}) couldn't fail reply {token} for process {from}: {err:?}");
+                        }
+                    }
+                }
+                Message::Reply { to, token, data } => {
+                    if let Err(err) = super::ipc::deliver_reply(to, token, data) {
+                        // same as above
+                        if err != Errno::NoSuchProcess {
+                            warn!("(CPU {cpu}) couldn't deliver reply: {err:?}");
+                        }
+
+                        if let Some((addr, _)) = data {
+                            crate::mm::paging::get_page_manager().set_frame_free(addr);
+                        }
                     }
                 }
+                Message::Reschedule => {
+                    // if something's already running, the new task will get its turn at the next context switch like normal
+                    if self.task_queue.lock().current().is_none() {
+                        super::switch::manual_context_switch(self.timer, Some(cpu), regs, super::switch::ContextSwitchMode::Normal);
+                    }
+                }
+            }
+        }
+    }
+
+    /// wakes up any tasks sleeping on this thread whose deadlines have passed
+    ///
+    /// this is called from the timer interrupt handler, so if the task queue is locked it'll just try again on the next tick
+    pub fn tick_timers(&self, now: u64) {
+        let Some(mut task_queue) = self.task_queue.try_lock() else {
+            return;
+        };
+
+        while let Some(id) = task_queue.next_due_sleeper(now) {
+            // this is called from the timer interrupt, which could've interrupted whatever's holding the process list or the process itself.
+            // spinning on it would never end, so the task is left asleep until a later tick instead
+            let process = super::try_get_process(id.process);
+
+            if process.is_none() && task_queue.is_blocked(id) {
+                break;
+            }
+
+            if let Some(Some(entry)) = task_queue.take_due_sleeper(now) && let Some(mut process) = process {
+                match super::unblock_task_locked(&mut task_queue, entry, &mut process) {
+                    Ok(_) | Err(Errno::NoSuchProcess) => (),
+                    Err(err) => warn!("couldn't wake sleeping task {}: {err:?}", entry.id()),
+                }
             }
         }
     }
 
+    /// records whether this thread is running a task at this timer tick, for working out how much of its time it spends idle
+    ///
+    /// this is called from the timer interrupt handler, so if the task queue is locked the thread is counted as busy since it's clearly doing something with it
+    pub fn sample_idle(&self) {
+        let idle = self.task_queue.try_lock().map(|queue| queue.current().is_none()).unwrap_or(false);
+
+        if let Some(mut stats) = self.idle_stats.try_lock() {
+            stats.window.record(idle);
+            self.idle_percent.store(stats.window.idle_percent(), Ordering::Relaxed);
+        }
+    }
+
+    /// gets what percentage of its recent timer ticks this thread has spent idle.
+    /// this doesn't lock anything, so it's safe to call while picking a thread to queue a task on from an interrupt handler
+    pub fn idle_percent(&self) -> usize {
+        self.idle_percent.load(Ordering::Relaxed)
+    }
+
+    /// gets how many messages from processes this thread's message queue can hold
+    pub fn message_queue_capacity(&self) -> usize {
+        self.message_queue_capacity.load(Ordering::Relaxed)
+    }
+
+    /// sets how many messages from processes this thread's message queue can hold. messages already in the queue aren't affected
+    pub fn set_message_queue_capacity(&self, capacity: usize) {
+        self.message_queue_capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    /// gets how many ticks of this thread's timer (which ticks at the given rate) each task gets to run for before it's preempted
+    pub fn quantum(&self, hz: u64) -> u64 {
+        match self.quantum.load(Ordering::Relaxed) {
+            0 => (hz / super::switch::CPU_TIME_SLICE).max(1),
+            ticks => ticks as u64,
+        }
+    }
+
+    /// sets how many ticks of this thread's timer each task gets to run for before it's preempted, or goes back to the default if it's 0.
+    /// this takes effect the next time a context switch is scheduled, see [super::switch::set_quantum] to make it take effect right away
+    pub fn set_quantum(&self, ticks: usize) {
+        self.quantum.store(ticks, Ordering::Relaxed);
+    }
+
     pub fn check_enter_kernel(&self) {
         if self.enter_kernel() {
             panic!("already in kernel");
@@ -279,6 +795,71 @@ impl CPUThread {
     pub fn has_started(&self) -> bool {
         self.has_started.load(Ordering::Relaxed)
     }
+
+    /// halts this thread until an interrupt occurs, keeping track of how long it was asleep for
+    pub fn idle(&self) {
+        let start = crate::timer::get_timer(self.timer).map(|timer| timer.jiffies());
+
+        crate::arch::halt_until_interrupt();
+
+        if let Some(start) = start && let Some(timer) = crate::timer::get_timer(self.timer) {
+            // an interrupt could switch to a task while the lock is held, so keep them off until the next halt turns them back on
+            crate::arch::cli();
+
+            // don't wait on the lock if someone's reading our stats, it's not worth it
+            if let Some(mut stats) = self.idle_stats.try_lock() {
+                stats.record(timer.jiffies().saturating_sub(start), timer.hz());
+            }
+        }
+    }
+}
+
+/// a set of CPU threads a task is allowed to run on, indexed by [CPU::thread_index]
+///
+/// only the first 64 threads can be represented
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AffinityMask(u64);
+
+impl AffinityMask {
+    /// creates an empty affinity mask
+    pub fn new() -> Self {
+        Self(0)
+    }
+
+    /// creates an affinity mask from its raw bits, where bit n allows the thread with index n
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// creates an affinity mask only containing the given thread
+    pub fn only(cpu: &CPU, id: ThreadID) -> Option<Self> {
+        let mut mask = Self::new();
+        mask.allow(cpu, id)?;
+        Some(mask)
+    }
+
+    /// allows the given thread in this affinity mask, returning None if it doesn't exist or can't be represented
+    pub fn allow(&mut self, cpu: &CPU, id: ThreadID) -> Option<()> {
+        let index = cpu.thread_index(id)?;
+
+        if index >= 64 {
+            return None;
+        }
+
+        self.0 |= 1 << index;
+
+        Some(())
+    }
+
+    /// checks whether the thread with the given index is in this affinity mask
+    pub fn contains(&self, index: usize) -> bool {
+        index < 64 && self.0 & (1 << index) != 0
+    }
+
+    /// checks whether this affinity mask doesn't allow any threads
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
 }
 
 /// an ID of a CPU thread
@@ -293,3 +874,141 @@ impl fmt::Display for ThreadID {
         write!(f, "{}:{}", self.core, self.thread)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn idle_stats_min_max_average() {
+        let mut stats = IdleStats::default();
+
+        // samples from before the timer's calibrated are skipped
+        stats.record(50, 0);
+        assert_eq!(stats.wakeups, 0);
+
+        // 1000 Hz, so every tick is 1000 us
+        for ticks in [4, 1, 7, 4] {
+            stats.record(ticks, 1000);
+        }
+
+        assert_eq!(stats.wakeups, 4);
+        assert_eq!(stats.min, 1000);
+        assert_eq!(stats.max, 7000);
+        assert_eq!(stats.average(), 4000);
+    }
+
+    #[test_case]
+    fn idle_window_only_counts_recent_ticks() {
+        let mut stats = IdleStats::default();
+        assert_eq!(stats.window.idle_percent(), 100);
+
+        stats.window.record(true);
+        stats.window.record(false);
+        assert_eq!(stats.window.idle_percent(), 50);
+
+        // once the window is full of busy ticks, the idle ones have fallen out of it
+        for _i in 0..IDLE_WINDOW_TICKS {
+            stats.window.record(false);
+        }
+
+        assert_eq!(stats.window.idle_percent(), 0);
+    }
+
+    #[test_case]
+    fn thread_indices_round_trip() {
+        let cpus = crate::task::get_cpus().unwrap();
+
+        for (core_num, core) in cpus.cores.iter().enumerate() {
+            for thread_num in 0..core.threads.len() {
+                let id = ThreadID { core: core_num, thread: thread_num };
+                assert_eq!(cpus.thread_from_index(cpus.thread_index(id).unwrap()), Some(id));
+            }
+        }
+
+        assert_eq!(cpus.thread_from_index(usize::MAX), None);
+    }
+
+    /// builds a CPU with a single core with the given number of threads, all with empty queues
+    fn test_cpu(num_threads: usize) -> CPU {
+        let info = crate::task::get_cpus().unwrap().cores[0].threads[0].info;
+
+        let mut cpu = CPU::new();
+        cpu.add_core();
+
+        for _i in 0..num_threads {
+            cpu.cores[0].add_thread(info, 0);
+        }
+
+        cpu
+    }
+
+    /// queues tasks for processes that don't exist on the given thread, making the first one the running task if asked to
+    fn queue_tasks(thread: &CPUThread, num: u32, running: bool) {
+        let mut queue = thread.task_queue.lock();
+
+        for process in 0..num {
+            queue.insert(TaskQueueEntry::new(ProcessID { process: u32::MAX - process, thread: 0 }, 0)).unwrap();
+        }
+
+        if running {
+            queue.consume();
+        }
+    }
+
+    /// how many tasks are waiting in each thread's queue
+    fn queue_lengths(cpu: &CPU) -> Vec<usize> {
+        cpu.cores[0].threads.iter().map(|thread| thread.task_queue.lock().len()).collect()
+    }
+
+    #[test_case]
+    fn stealing_counts_running_tasks() {
+        let (busy, idle) = (ThreadID { core: 0, thread: 0 }, ThreadID { core: 0, thread: 1 });
+
+        // one running and one waiting task is enough to give the waiting one away
+        let cpu = test_cpu(2);
+        queue_tasks(cpu.get_thread(busy).unwrap(), 2, true);
+        assert_eq!(cpu.steal_batch(idle, usize::MAX).len(), 1);
+        assert_eq!(queue_lengths(&cpu), [0, 1]);
+
+        // an odd difference is rounded up when the busy thread's running task makes up for it
+        let cpu = test_cpu(2);
+        queue_tasks(cpu.get_thread(busy).unwrap(), 6, true);
+        assert_eq!(cpu.steal_batch(idle, usize::MAX).len(), 3);
+        assert_eq!(queue_lengths(&cpu), [2, 3]);
+
+        // but not when it would leave the busy thread with fewer tasks
+        let cpu = test_cpu(2);
+        queue_tasks(cpu.get_thread(busy).unwrap(), 3, false);
+        assert_eq!(cpu.steal_batch(idle, usize::MAX).len(), 1);
+        assert_eq!(queue_lengths(&cpu), [2, 1]);
+
+        // and a lone waiting task isn't worth moving
+        let cpu = test_cpu(2);
+        queue_tasks(cpu.get_thread(busy).unwrap(), 1, false);
+        assert!(cpu.steal_batch(idle, usize::MAX).is_empty());
+        assert_eq!(queue_lengths(&cpu), [1, 0]);
+    }
+
+    #[test_case]
+    fn stealing_is_limited_to_max() {
+        let cpu = test_cpu(2);
+        queue_tasks(cpu.get_thread(ThreadID { core: 0, thread: 0 }).unwrap(), 8, true);
+
+        assert_eq!(cpu.steal_batch(ThreadID { core: 0, thread: 1 }, 2).len(), 2);
+        assert_eq!(queue_lengths(&cpu), [5, 2]);
+    }
+
+    #[test_case]
+    fn affinity_has_to_allow_an_existing_thread() {
+        let cpus = crate::task::get_cpus().unwrap();
+
+        assert!(cpus.is_allowed_anywhere(None));
+        assert!(cpus.is_allowed_anywhere(Some(AffinityMask::from_bits(1))));
+        assert!(cpus.is_allowed_on(Some(AffinityMask::from_bits(1)), ThreadID { core: 0, thread: 0 }));
+
+        // nothing's ever going to have this many CPU threads
+        assert!(!cpus.is_allowed_anywhere(Some(AffinityMask::from_bits(1 << 63))));
+        assert!(!cpus.is_allowed_anywhere(Some(AffinityMask::new())));
+    }
+}
@@ -2,16 +2,24 @@
 
 use crate::{
     arch::{KERNEL_PAGE_DIR_SPLIT, STACK_SIZE},
-    mm::paging::{free_page_dir, get_page_dir, get_page_manager, map_memory, map_memory_from, FreeablePageDir, PageDirectory, PageFrame},
+    mm::paging::{free_page_dir, get_page_dir, get_page_manager, map_memory, map_memory_from, FrameTransaction, FreeablePageDir, PageDirectory, PageFrame, PagingError},
 };
-use common::types::{Errno, Result};
-use core::mem::size_of;
+use alloc::vec::Vec;
+use common::types::{Errno, ProcessID, Result};
+use core::{mem::size_of, ops::Range};
 use goblin::elf::{
+    header::{et_to_str, machine_to_str, ET_DYN, ET_EXEC},
     program_header::{PT_INTERP, PT_LOAD},
     Elf,
 };
 use log::{debug, info};
 
+/// size of the thread-local storage area reserved for the initial thread of a process
+pub const TLS_SIZE: usize = 0x1000;
+
+/// how big the stack of a process can grow to
+pub const MAX_STACK_SIZE: usize = 0x800000;
+
 /*
 /// spawn a process from the given path
 pub fn exec(path: &str, args: &[String], env: &[String]) -> Result<usize, Errno> {
@@ -88,14 +96,101 @@ fn poke_str_slice_into_mem(task: &mut Task, slice: &[String]) -> Result<usize, E
 }
 */
 
+/// creates a new process and loads the given executable into it without running it, returning the ID of the new process.
+/// its first thread can be queued with [super::queue_process] once it's ready to go
+pub fn load(data: &[u8]) -> Result<u32> {
+    let process = super::create_process(crate::arch::PageDirectory::new()?)?;
+
+    let result = super::get_process(process)
+        .ok_or(Errno::NoSuchProcess)
+        .and_then(|mut p| exec_as::<crate::arch::PageDirectory>(None, &mut p, data));
+
+    if let Err(err) = result {
+        super::remove_process(process);
+        return Err(err);
+    }
+
+    Ok(process)
+}
+
+/// creates a new process, loads the given executable into it, and queues it to run, returning the ID of the new process
+pub fn spawn(data: &[u8]) -> Result<u32> {
+    let process = load(data)?;
+
+    if let Err(err) = super::queue_process(ProcessID { process, thread: 1 }) {
+        super::remove_process(process);
+        return Err(err);
+    }
+
+    Ok(process)
+}
+
+/// maps freshly allocated, zeroed memory at every page in the given range of a process's page directory. if any of it can't be mapped, whatever was
+/// mapped is unmapped again and all the memory is freed
+fn map_zeroed_region<D: PageDirectory>(kernel_page_dir: &mut Option<&mut D>, process_page_dir: &mut impl PageDirectory, range: Range<usize>, executable: bool) -> Result<()> {
+    let thread_id = crate::arch::get_thread_id();
+    let mut transaction = FrameTransaction::new();
+
+    let mut map = || -> core::result::Result<(), PagingError> {
+        for addr in range.clone().step_by(D::PAGE_SIZE) {
+            let phys = transaction.alloc_frame_or_reclaim()?;
+            let executable = process_page_dir.executable_if(executable);
+
+            transaction.map_page(
+                process_page_dir,
+                addr,
+                PageFrame {
+                    addr: phys,
+                    user_mode: true,
+                    writable: true,
+                    executable,
+                    present: true,
+                    ..Default::default()
+                },
+            )?;
+        }
+
+        // clear the memory so we don't leak any information
+        unsafe {
+            if let Some(dir) = kernel_page_dir.as_mut() {
+                map_memory(*dir, transaction.allocated(), |s| s.fill(0))
+            } else {
+                map_memory(&mut get_page_dir(Some(thread_id)), transaction.allocated(), |s| s.fill(0))
+            }
+        }
+    };
+
+    match map() {
+        Ok(()) => {
+            transaction.commit(&mut get_page_manager());
+            Ok(())
+        }
+        Err(_) => {
+            // unmap whatever did get mapped so the page directory doesn't free the frames again when it's freed
+            transaction.unmap_pages(process_page_dir);
+            transaction.rollback(&mut get_page_manager());
+
+            Err(Errno::OutOfMemory)
+        }
+    }
+}
+
+/// replaces the contents of the given process with the given ELF executable, leaving it with a single thread at the executable's entry point
+///
+/// every loadable segment is mapped with the permissions it asks for, except that segments can't be both writable and executable.
+/// any part of a segment that isn't backed by the file (i.e. bss) is only given memory once it's touched
 #[allow(clippy::vec_init_then_push)]
 pub fn exec_as<D: PageDirectory>(mut kernel_page_dir: Option<&mut D>, process: &mut super::Process, data: &[u8]) -> Result<()> {
     let elf = Elf::parse(data).map_err(|_| Errno::ExecutableFormatErr)?;
 
-    if (elf.is_64 && size_of::<usize>() != 64 / 8) || (!elf.is_64 && size_of::<usize>() != 32 / 8) {
+    if elf.header.e_type != ET_EXEC && elf.header.e_type != ET_DYN {
+        info!("can't load ELF file of type {}", et_to_str(elf.header.e_type));
+        Err(Errno::ExecutableFormatErr)
+    } else if (elf.is_64 && size_of::<usize>() != 64 / 8) || (!elf.is_64 && size_of::<usize>() != 32 / 8) || elf.header.e_machine != crate::arch::ELF_MACHINE {
+        info!("can't load ELF file for {} ({} bit)", machine_to_str(elf.header.e_machine), if elf.is_64 { 64 } else { 32 });
         Err(Errno::ExecutableFormatErr)
     } else {
-        let mut process_page_dir = FreeablePageDir::new(crate::arch::PageDirectory::new());
+        let mut process_page_dir = FreeablePageDir::new(crate::arch::PageDirectory::new()?);
 
         let thread_id = crate::arch::get_thread_id();
 
@@ -116,46 +211,50 @@ pub fn exec_as<D: PageDirectory>(mut kernel_page_dir: Option<&mut D>, process: &
                         return Err(Errno::ValueOverflow);
                     }
 
+                    if filesz > memsz || file_end > data.len() || vaddr.checked_add(memsz).map(|end| end > KERNEL_PAGE_DIR_SPLIT).unwrap_or(true) {
+                        info!("malformed program header {ph:?}");
+                        return Err(Errno::ExecutableFormatErr);
+                    }
+
+                    // W^X
+                    if ph.is_write() && ph.is_executable() {
+                        info!("refusing to map segment @ {vaddr:#x} as both writable and executable");
+                        return Err(Errno::ExecutableFormatErr);
+                    }
+
                     debug!("data @ {:#x} - {:#x} (filesz {:#x})", ph.p_vaddr, ph.p_vaddr + memsz as u64, filesz);
 
                     let addr_start = (vaddr / D::PAGE_SIZE) * D::PAGE_SIZE;
                     let addr_end = ((vaddr + memsz) / D::PAGE_SIZE) * D::PAGE_SIZE + (D::PAGE_SIZE - 1);
 
+                    // pages past the end of the segment's data in the file are only given memory once they're touched
+                    let data_end = (vaddr + filesz).div_ceil(D::PAGE_SIZE) * D::PAGE_SIZE;
+
                     for addr in (addr_start..=addr_end).step_by(D::PAGE_SIZE) {
-                        if process_page_dir.get_page(addr).is_none() {
-                            let phys = get_page_manager().alloc_frame().map_err(|_| Errno::OutOfMemory)?;
-
-                            process_page_dir
-                                .set_page(
-                                    addr,
-                                    Some(PageFrame {
-                                        addr: phys,
-                                        user_mode: true,
-                                        writable: true,
-                                        executable: ph.is_executable(),
-                                        present: true,
-                                        ..Default::default()
-                                    }),
-                                )
-                                .map_err(|_| {
-                                    get_page_manager().set_frame_free(phys);
-                                    Errno::OutOfMemory
-                                })?;
-
-                            // clear page so we don't leak any information
-                            unsafe {
-                                let op = |s: &mut [u8]| {
-                                    for i in s.iter_mut() {
-                                        *i = 0;
-                                    }
-                                };
-
-                                if let Some(dir) = kernel_page_dir.as_mut() {
-                                    map_memory(*dir, &[phys], op).map_err(|_| Errno::OutOfMemory)?;
-                                } else {
-                                    map_memory(&mut get_page_dir(Some(thread_id)), &[phys], op).map_err(|_| Errno::OutOfMemory)?;
-                                }
+                        let existing = process_page_dir.get_page(addr);
+
+                        if addr >= data_end {
+                            if existing.is_none() {
+                                process_page_dir
+                                    .set_page(
+                                        addr,
+                                        Some(PageFrame {
+                                            user_mode: true,
+                                            writable: ph.is_write(),
+                                            executable: process_page_dir.executable_if(ph.is_executable()),
+                                            zero_fill: true,
+                                            ..Default::default()
+                                        }),
+                                    )
+                                    .map_err(|_| Errno::OutOfMemory)?;
                             }
+
+                            continue;
+                        }
+
+                        // another segment may have left a zero fill page here that this one needs filled in now
+                        if existing.map(|page| page.zero_fill && !page.present).unwrap_or(true) {
+                            map_zeroed_region(&mut kernel_page_dir, &mut process_page_dir, addr..addr + D::PAGE_SIZE, ph.is_executable())?;
                         }
                     }
 
@@ -190,26 +289,25 @@ pub fn exec_as<D: PageDirectory>(mut kernel_page_dir: Option<&mut D>, process: &
             }
         }
 
-        for addr in (KERNEL_PAGE_DIR_SPLIT - STACK_SIZE..KERNEL_PAGE_DIR_SPLIT).step_by(D::PAGE_SIZE) {
-            let phys = get_page_manager().alloc_frame().map_err(|_| Errno::OutOfMemory)?;
-
-            process_page_dir
-                .set_page(
-                    addr,
-                    Some(PageFrame {
-                        addr: phys,
-                        user_mode: true,
-                        writable: true,
-                        present: true,
-                        ..Default::default()
-                    }),
-                )
-                .map_err(|_| {
-                    get_page_manager().set_frame_free(phys);
-                    Errno::OutOfMemory
-                })?;
+        map_zeroed_region(&mut kernel_page_dir, &mut process_page_dir, KERNEL_PAGE_DIR_SPLIT - STACK_SIZE..KERNEL_PAGE_DIR_SPLIT, false)?;
+
+        // only the top of the stack is mapped to start with, the rest is mapped in as it's touched. put a guard page below where it can grow to
+        // so it can't grow into anything else
+        get_page_manager()
+            .install_guard_page(&mut process_page_dir, KERNEL_PAGE_DIR_SPLIT - MAX_STACK_SIZE - D::PAGE_SIZE)
+            .map_err(|_| Errno::OutOfMemory)?;
+
+        // reserve a thread-local storage area below the guard page. userspace can point the TLS segment somewhere else with the set_tls syscall if it wants more
+        let tls_base = KERNEL_PAGE_DIR_SPLIT - MAX_STACK_SIZE - D::PAGE_SIZE - TLS_SIZE;
+
+        // a segment could've been loaded here, which mapping over would leak
+        if !(tls_base..tls_base + TLS_SIZE).step_by(D::PAGE_SIZE).all(|addr| process_page_dir.is_unused(addr)) {
+            info!("executable overlaps the thread-local storage area at {tls_base:#x}");
+            return Err(Errno::ExecutableFormatErr);
         }
 
+        map_zeroed_region(&mut kernel_page_dir, &mut process_page_dir, tls_base..tls_base + TLS_SIZE, false)?;
+
         let entry_point = elf.entry.try_into().map_err(|_| Errno::ValueOverflow)?;
 
         /*debug!("lowest @ {:#x}", lowest_addr);
@@ -314,13 +412,24 @@ pub fn exec_as<D: PageDirectory>(mut kernel_page_dir: Option<&mut D>, process: &
                 return Err(err);
             }
         }
+        process.stack = Some(super::StackRegion {
+            base: KERNEL_PAGE_DIR_SPLIT,
+            max_size: MAX_STACK_SIZE,
+            bottom: KERNEL_PAGE_DIR_SPLIT - STACK_SIZE,
+        });
         process.remove_all_threads();
         process
             .add_thread(crate::task::Thread {
                 register_queue: super::RegisterQueue::new(super::RegisterQueueEntry::from_registers(crate::arch::Registers::new_task(entry_point, stack_end))),
                 priority: 0,
+                priority_class: Default::default(),
+                affinity: None,
                 cpu: None,
+                last_ran_on: None,
+                migrations: 0,
                 is_blocked: false,
+                tls_base,
+                donated_priorities: Vec::new(),
             })
             .map_err(|_| Errno::OutOfMemory)?;
 
@@ -0,0 +1,171 @@
+//! fast userspace mutexes
+//!
+//! threads wait on a 32 bit word in their memory. futexes in private memory are keyed by the process and virtual address of that word, since the frame
+//! behind it can change while something's waiting (i.e. when it's copied on write or swapped out). futexes in shared memory are keyed by the physical
+//! address of the word instead, so that waiting and waking works across processes that share memory
+
+use super::{
+    cpu::{CPUThread, ThreadID},
+    queue::WaitReason,
+    switch::{manual_context_switch, ContextSwitchMode},
+};
+use crate::mm::paging::{get_page_dir, validate_region, PageDirectory};
+use common::types::{Errno, Result};
+use core::{mem::size_of, sync::atomic::AtomicU32};
+use log::{trace, warn};
+
+/// what waiters on a futex are keyed by
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FutexKey {
+    /// a futex in memory private to the given process, at the given virtual address
+    Private { process: u32, addr: usize },
+
+    /// a futex in shared memory, at the given physical address
+    Shared(u64),
+}
+
+/// works out the key for the futex at the given address in the given process's page directory
+fn futex_key(page_dir: &mut impl PageDirectory, process: u32, addr: usize) -> Result<FutexKey> {
+    if !validate_region(page_dir, addr, size_of::<u32>()) {
+        return Err(Errno::BadAddress);
+    }
+
+    let page = page_dir.get_page(addr).ok_or(Errno::BadAddress)?;
+
+    // a shared page that's copied on write turns into a private one the first time it's written to, so only pages that stay shared are keyed by frame
+    if page.shared && !page.copy_on_write {
+        page_dir.virt_to_phys(addr).map(FutexKey::Shared).ok_or(Errno::BadAddress)
+    } else {
+        Ok(FutexKey::Private { process, addr })
+    }
+}
+
+/// checks that the given address is a valid, aligned futex word in the current process and returns a reference to it along with its key
+fn get_futex(thread_id: ThreadID, cpu_thread: &CPUThread, addr: usize) -> Result<(&'static AtomicU32, FutexKey)> {
+    if addr % size_of::<u32>() != 0 {
+        return Err(Errno::InvalidArgument);
+    }
+
+    let process = cpu_thread.task_queue.lock().current().map(|c| c.id().process).ok_or(Errno::NoSuchProcess)?;
+    let key = futex_key(&mut get_page_dir(Some(thread_id)), process, addr)?;
+
+    // the current process's memory is mapped in while it's making a syscall
+    Ok((unsafe { &*(addr as *const AtomicU32) }, key))
+}
+
+/// blocks the current thread until the futex at the given address is woken up, as long as it holds the expected value.
+/// if it doesn't, TryAgain is returned right away
+///
+/// on success the syscall's return value is set here, since the registers belong to whatever's running next once this returns
+pub fn wait(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, addr: usize, expected: u32) -> Result<()> {
+    let caller = cpu_thread.task_queue.lock().current().map(|c| c.id()).ok_or(Errno::NoSuchProcess)?;
+
+    let (word, key) = get_futex(thread_id, cpu_thread, addr)?;
+
+    trace!("{caller} waiting on futex @ {addr:#x} ({key:x?})");
+
+    // block before checking the value, so a wakeup can't slip in between the check and the block. anything that changes the value after it's checked
+    // will find this thread waiting when it goes to wake it up
+    super::block_task(caller, WaitReason::Futex(key))?;
+
+    if word.load(core::sync::atomic::Ordering::SeqCst) != expected {
+        // whatever changed the value may have already woken us up
+        match super::wake_task(caller) {
+            Ok(()) | Err(Errno::NoSuchProcess) => (),
+            Err(err) => warn!("couldn't wake {caller} after its futex changed: {err:?}"),
+        }
+
+        return Err(Errno::TryAgain);
+    }
+
+    regs.syscall_return(Ok(0));
+
+    manual_context_switch(cpu_thread.timer, Some(thread_id), regs, ContextSwitchMode::Normal);
+
+    Ok(())
+}
+
+/// wakes up to the given number of threads waiting on the futex at the given address, returning how many were woken
+pub fn wake(thread_id: ThreadID, cpu_thread: &CPUThread, addr: usize, count: usize) -> Result<usize> {
+    let (_, key) = get_futex(thread_id, cpu_thread, addr)?;
+
+    trace!("waking up to {count} waiters on futex @ {addr:#x} ({key:x?})");
+
+    Ok(super::wake_some(WaitReason::Futex(key), count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mm::paging::PageFrame;
+    use crate::task::queue::{TaskQueue, TaskQueueEntry};
+    use common::types::ProcessID;
+
+    /// an address in the user half of the address space that won't share a page table with anything else in a new page directory
+    const TEST_ADDR: usize = 0x4000_0000;
+
+    #[test_case]
+    fn private_futexes_survive_copy_on_write() {
+        let mut dir = crate::arch::PageDirectory::new().unwrap();
+        let original = PageFrame {
+            addr: 0x1000,
+            present: true,
+            user_mode: true,
+            copy_on_write: true,
+            referenced: true,
+            ..Default::default()
+        };
+        dir.set_page(TEST_ADDR, Some(original)).unwrap();
+
+        let id = ProcessID { process: u32::MAX, thread: 1 };
+        let mut queue = TaskQueue::new();
+        queue.insert(TaskQueueEntry::new(id, 0)).unwrap();
+
+        let key = futex_key(&mut dir, id.process, TEST_ADDR + 4).unwrap();
+        assert!(queue.block(id, WaitReason::Futex(key)).unwrap());
+
+        // copying the page on write moves it to another frame after the waiter went to sleep, which is what the waker sees
+        let copied = PageFrame {
+            addr: 0x2000,
+            writable: true,
+            copy_on_write: false,
+            referenced: false,
+            ..original
+        };
+        dir.set_page(TEST_ADDR, Some(copied)).unwrap();
+
+        let key = futex_key(&mut dir, id.process, TEST_ADDR + 4).unwrap();
+        assert_eq!(queue.take_blocked_by_up_to(WaitReason::Futex(key), 1).len(), 1);
+
+        // the same address in another process is a different futex though
+        assert_ne!(futex_key(&mut dir, id.process - 1, TEST_ADDR + 4).unwrap(), key);
+
+        dir.set_page(TEST_ADDR, None).unwrap();
+    }
+
+    #[test_case]
+    fn shared_futexes_are_keyed_by_frame() {
+        let mut dir = crate::arch::PageDirectory::new().unwrap();
+        let shared = PageFrame {
+            addr: 0x1000,
+            present: true,
+            user_mode: true,
+            writable: true,
+            shared: true,
+            ..Default::default()
+        };
+
+        // the same shared memory mapped at different addresses in different processes
+        dir.set_page(TEST_ADDR, Some(shared)).unwrap();
+        dir.set_page(TEST_ADDR + crate::arch::PAGE_SIZE, Some(shared)).unwrap();
+
+        let key = futex_key(&mut dir, 1, TEST_ADDR + 4).unwrap();
+        assert_eq!(key, FutexKey::Shared(0x1004));
+        assert_eq!(futex_key(&mut dir, 2, TEST_ADDR + crate::arch::PAGE_SIZE + 4).unwrap(), key);
+
+        dir.set_page(TEST_ADDR, None).unwrap();
+        dir.set_page(TEST_ADDR + crate::arch::PAGE_SIZE, None).unwrap();
+
+        assert!(matches!(futex_key(&mut dir, 1, TEST_ADDR + 4), Err(Errno::BadAddress)));
+    }
+}
@@ -8,13 +8,52 @@ use crate::{
     arch::KERNEL_PAGE_DIR_SPLIT,
     mm::paging::{find_hole, get_page_dir, map_memory_from, validate_region, PageDirectory, PageFrame, ProcessOrKernelPageDir},
 };
+use alloc::vec::Vec;
 use common::types::{Errno, ProcessID, Result};
+use core::sync::atomic::{AtomicU32, Ordering};
 use log::{debug, trace, warn};
 
 pub const HIGHEST_MESSAGE_NUM: u32 = u32::pow(2, 20) - 1; // 20 bits, inclusive
 
+/// the most data that can be sent along with a message. it's copied into a single fresh page which is then mapped into the receiving process
+pub const MAX_MESSAGE_DATA: usize = crate::arch::PageDirectory::PAGE_SIZE;
+
+/// the token to give to the next reply that's waited for.
+/// this is global rather than per-process so that a reply meant for a process that's since exited can't be mistaken for one meant for a new process with the same ID
+static NEXT_REPLY_TOKEN: AtomicU32 = AtomicU32::new(1);
+
+/// a reply that a thread is waiting for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PendingReply {
+    /// the token the reply will be sent with
+    pub token: u32,
+
+    /// the thread that's waiting for this reply
+    pub thread: u32,
+
+    /// the thread the message was sent to, which has the waiting thread's priority donated to it until it replies
+    pub server: ProcessID,
+
+    /// the data sent with the reply, or None if it hasn't arrived yet. if the message couldn't be handled, this is the error that stopped it
+    pub reply: Option<Result<Option<(u64, usize)>>>,
+}
+
 /// message passing internals- used by the send message syscall and other cpus to send a message if the receiving process is on the same cpu
-pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, process_num: u32, message: u32, data: Option<(u64, usize)>) -> Result<()> {
+///
+/// if data is provided, it's the physical address of a page containing the message's data and how many bytes of it are used. that page is mapped
+/// into the receiving process at an address of the kernel's choosing, which is what gets passed to the message handler, and is freed when the handler exits.
+/// if the handler doesn't take any data, the page is freed immediately
+///
+/// if reply_to is provided, the ID of the process to reply to and the token to reply with are passed to the message handler after its other arguments
+pub fn send_message(
+    thread_id: ThreadID,
+    cpu_thread: &CPUThread,
+    regs: &mut crate::arch::Registers,
+    process_num: u32,
+    message: u32,
+    data: Option<(u64, usize)>,
+    reply_to: Option<(u32, u32)>,
+) -> Result<()> {
     // TODO: find available thread
 
     let process_id = ProcessID { process: process_num, thread: 1 };
@@ -22,12 +61,14 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
     let handler;
     let current_cpu;
     let stack_pointer;
+    let pending_handlers;
     {
         let process = get_process(process_id.process).ok_or(Errno::NoSuchProcess)?;
         handler = *process.message_handlers.get(&message).ok_or(Errno::InvalidArgument)?;
         let thread = process.threads.get(process_id.thread as usize).ok_or(Errno::NoSuchProcess)?;
         current_cpu = thread.cpu;
         stack_pointer = thread.register_queue.current().registers.stack_pointer();
+        pending_handlers = thread.register_queue.pending();
     }
 
     debug!(
@@ -36,8 +77,15 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
     );
 
     if current_cpu.is_none() || current_cpu == Some(thread_id) {
+        // messages delivered on this CPU skip the message queue, so the same limit is applied to how many handlers can be stacked up on the receiver instead
+        if pending_handlers >= cpu_thread.message_queue_capacity() {
+            return Err(Errno::OperationWouldBlock);
+        }
+
         let mut process_page_dir = ProcessOrKernelPageDir::Process(process_num);
 
+        let data_page = data.map(|(addr, _)| addr);
+
         // map data into process's memory if we can
         let (data, data_len) = if handler.has_data && let Some((data, data_len)) = data {
             trace!("mapping data in");
@@ -66,15 +114,17 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
         };
 
         let mut finish_sending_message = || -> Result<()> {
-            let arguments = if handler.has_data {
-                crate::util::abi::CallBuilder::new(crate::platform::PLATFORM_ABI)?
-                    .argument(&message)?
-                    .argument(&data.unwrap_or(0))?
-                    .argument(&data_len)?
-                    .finish()?
-            } else {
-                crate::util::abi::CallBuilder::new(crate::platform::PLATFORM_ABI)?.argument(&message)?.finish()?
-            };
+            let mut builder = crate::util::abi::CallBuilder::new(crate::platform::PLATFORM_ABI)?.argument(&message)?;
+
+            if handler.has_data {
+                builder = builder.argument(&data.unwrap_or(0))?.argument(&data_len)?;
+            }
+
+            if let Some((from, token)) = reply_to.as_ref() {
+                builder = builder.argument(from)?.argument(token)?;
+            }
+
+            let arguments = builder.finish()?;
 
             let stack_pointer = (stack_pointer - arguments.stack.len()) & !15; // align to 16 bytes
 
@@ -84,7 +134,7 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
             if arguments.should_write_stack {
                 // make sure our stack is valid
                 trace!("validating stack");
-                if !validate_region(&process_page_dir, stack_pointer, arguments.stack.len()) {
+                if !validate_region(&mut process_page_dir, stack_pointer, arguments.stack.len()) {
                     return Err(Errno::BadAddress);
                 }
 
@@ -127,9 +177,23 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
                 // queue this process for execution
                 trace!("queueing process");
                 let mut task_queue = cpu_thread.task_queue.lock();
+
+                if task_queue.is_blocked(process_id) {
+                    // the handler's registers have already been pushed, so if the thread is woken up now it'll definitely run the handler.
+                    // if it's blocked on something other than a message, the handler will run whenever that wakes it up
+                    if task_queue.take_blocked_for(process_id, super::queue::WaitReason::Message).is_none() {
+                        return Ok(());
+                    }
+
+                    trace!("waking up {process_id} to receive message");
+                    thread.is_blocked = false;
+                }
+
                 task_queue.remove_thread(process_id);
 
-                let mut entry = super::queue::TaskQueueEntry::new(process_id, thread.priority);
+                let mut entry = super::queue::TaskQueueEntry::new(process_id, thread.effective_priority());
+                entry.set_class(thread.priority_class);
+                entry.set_affinity(thread.affinity);
                 entry.set_sub_priority(handler.priority);
                 task_queue.insert(entry)?;
             }
@@ -150,6 +214,11 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
             return Err(err);
         }
 
+        if data.is_none() && let Some(addr) = data_page {
+            // the handler doesn't take any data, so nothing will ever see this page
+            crate::mm::paging::get_page_manager().set_frame_free(addr);
+        }
+
         debug!("message sent");
     } else {
         // ask other CPU to handle this message
@@ -160,10 +229,308 @@ pub fn send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crat
         let thread = cpus.get_thread(current_cpu).expect("couldn't get CPU thread");
 
         debug!("forwarding message to CPU {current_cpu}");
-        thread.send_message(super::cpu::Message::SendMessage { process: process_num, message, data })?;
+        thread.send_message(super::cpu::Message::SendMessage { process: process_num, message, data, reply_to })?;
 
         assert!(crate::arch::send_interrupt_to_cpu(current_cpu, crate::arch::MESSAGE_INT), "failed to send interrupt");
     }
 
     Ok(())
 }
+
+/// sends a message to the given process on behalf of the currently running thread and blocks the thread until a reply to it arrives.
+/// the token the reply will be sent with is both returned and set as the thread's syscall return value, so it can be passed to [take_reply] once the thread is running again
+pub fn send_and_wait(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, process_num: u32, message: u32, data: Option<(u64, usize)>) -> Result<u32> {
+    let caller = cpu_thread.task_queue.lock().current().map(|c| c.id()).ok_or(Errno::NoSuchProcess)?;
+
+    // waiting on ourselves would never finish
+    if caller.process == process_num {
+        return Err(Errno::InvalidArgument);
+    }
+
+    let token = NEXT_REPLY_TOKEN.fetch_add(1, Ordering::Relaxed);
+
+    // messages are always handled by the first thread of a process
+    let server = ProcessID { process: process_num, thread: 1 };
+
+    let priority = {
+        let mut process = get_process(caller.process).ok_or(Errno::NoSuchProcess)?;
+        process.pending_replies.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+        process.pending_replies.push(PendingReply { token, thread: caller.thread, server, reply: None });
+        process.threads.get(caller.thread as usize).ok_or(Errno::NoSuchProcess)?.effective_priority()
+    };
+
+    let cancel = |err| {
+        if let Some(mut process) = get_process(caller.process) {
+            process.pending_replies.retain(|pending| pending.token != token);
+        }
+
+        revoke_priority(server, token);
+
+        err
+    };
+
+    // keep the server from being preempted by anything of lower priority than us while we're waiting on it.
+    // this has to happen before the message is sent, since the server could otherwise start handling it at its old priority
+    donate_priority(server, token, priority);
+
+    // block before sending so the reply can't arrive before we're waiting for it
+    super::block_task(caller, super::queue::WaitReason::Reply(token)).map_err(cancel)?;
+
+    if let Err(err) = send_message(thread_id, cpu_thread, regs, process_num, message, data, Some((caller.process, token))) {
+        if let Err(err) = super::wake_task(caller) {
+            warn!("couldn't wake {caller} after failing to send message: {err:?}");
+        }
+
+        return Err(cancel(err));
+    }
+
+    // the token is what the syscall returns once this thread runs again, and it has to be set before switching away
+    regs.syscall_return(Ok(token as usize));
+
+    // if the reply already arrived, this just puts us back in the queue
+    super::switch::manual_context_switch(cpu_thread.timer, Some(thread_id), regs, super::switch::ContextSwitchMode::Normal);
+
+    Ok(token)
+}
+
+/// blocks the currently running thread until a message is sent to it, at which point its message handler is run.
+/// once the handler exits, this returns
+///
+/// messages are always handled by the first thread of a process, so no other thread can wait for them
+pub fn receive_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers) -> Result<()> {
+    let caller = cpu_thread.task_queue.lock().current().map(|c| c.id()).ok_or(Errno::NoSuchProcess)?;
+
+    if caller.thread != 1 {
+        return Err(Errno::InvalidArgument);
+    }
+
+    {
+        let mut process = get_process(caller.process).ok_or(Errno::NoSuchProcess)?;
+        let thread = process.threads.get_mut(caller.thread as usize).ok_or(Errno::NoSuchProcess)?;
+
+        // send_message() pushes the handler and checks whether the thread is blocked while holding both of these locks in this order,
+        // so holding them both here means a message can't slip in between checking for pending handlers and blocking
+        let mut task_queue = cpu_thread.task_queue.lock();
+
+        // a message that arrived while this thread was running has already pushed its handler, so blocking now would wait for another one
+        let pending_handlers = thread.register_queue.pending();
+
+        // these registers are what the thread returns to once its message handler exits
+        regs.syscall_return(Ok(0));
+
+        if pending_handlers > 0 {
+            trace!("{caller} already has {pending_handlers} message handler(s) pending");
+            return Ok(());
+        }
+
+        trace!("{caller} waiting for a message");
+
+        if !task_queue.block(caller, super::queue::WaitReason::Message)? {
+            return Err(Errno::NoSuchProcess);
+        }
+
+        thread.is_blocked = true;
+    }
+
+    super::switch::manual_context_switch(cpu_thread.timer, Some(thread_id), regs, super::switch::ContextSwitchMode::Normal);
+
+    Ok(())
+}
+
+/// donates the given priority to the given thread until the reply with the given token is sent, raising its effective priority if it's higher
+fn donate_priority(to: ProcessID, token: u32, priority: i8) {
+    let new_priority = {
+        let Some(mut process) = get_process(to.process) else { return };
+        let Some(thread) = process.threads.get_mut(to.thread as usize) else { return };
+
+        let old_priority = thread.effective_priority();
+
+        if thread.donated_priorities.try_reserve(1).is_err() {
+            warn!("couldn't donate priority to {to}, it may be preempted while {token} is waiting on it");
+            return;
+        }
+
+        thread.donated_priorities.push((token, priority));
+
+        let new_priority = thread.effective_priority();
+
+        if new_priority == old_priority {
+            return;
+        }
+
+        new_priority
+    };
+
+    trace!("{to} inherited priority {new_priority}");
+
+    super::update_task_priority(to, new_priority);
+}
+
+/// removes the priority donated to the given thread for the reply with the given token, lowering its effective priority back down if needed
+fn revoke_priority(from: ProcessID, token: u32) {
+    let new_priority = {
+        let Some(mut process) = get_process(from.process) else { return };
+        let Some(thread) = process.threads.get_mut(from.thread as usize) else { return };
+
+        let old_priority = thread.effective_priority();
+
+        thread.donated_priorities.retain(|(t, _)| *t != token);
+
+        let new_priority = thread.effective_priority();
+
+        if new_priority == old_priority {
+            return;
+        }
+
+        new_priority
+    };
+
+    trace!("{from} reverted to priority {new_priority}");
+
+    super::update_task_priority(from, new_priority);
+}
+
+/// forgets about every reply the given process (or only the given thread of it) is waiting for, taking back the priority it donated while waiting.
+/// this has to be done before a waiting thread goes away, since nothing would ever revoke its donations otherwise
+pub fn cancel_pending_replies(process_num: u32, thread: Option<u32>) {
+    let mut cancelled = Vec::new();
+
+    {
+        let Some(mut process) = get_process(process_num) else { return };
+
+        // leaving the replies in place if this fails is fine, they're dropped along with the process anyway
+        if cancelled.try_reserve_exact(process.pending_replies.len()).is_err() {
+            warn!("couldn't revoke priorities donated by process {process_num}");
+            return;
+        }
+
+        process.pending_replies.retain(|pending| {
+            if thread.is_none_or(|thread| thread == pending.thread) {
+                cancelled.push((pending.server, pending.token));
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    // the servers are other processes, so they can only be locked once the waiting process is unlocked
+    for (server, token) in cancelled.into_iter() {
+        revoke_priority(server, token);
+    }
+}
+
+/// wakes up every thread waiting for a reply from the given process (or only the given thread of it) with an error, since it's going away and won't be replying.
+/// this has to be done before a server goes away, since its waiters would never be woken up otherwise
+pub fn fail_replies_from(server_num: u32, thread: Option<u32>) {
+    let mut failed = Vec::new();
+
+    for process_num in 1..=super::highest_process_id() {
+        if process_num == server_num {
+            continue;
+        }
+
+        let Some(process) = get_process(process_num) else { continue };
+
+        for pending in process.pending_replies.iter() {
+            if pending.server.process == server_num && thread.is_none_or(|thread| thread == pending.server.thread) && pending.reply.is_none() {
+                if failed.try_reserve(1).is_err() {
+                    warn!("couldn't wake up all threads waiting on a reply from process {server_num}");
+                    break;
+                }
+
+                failed.push((process_num, pending.token));
+            }
+        }
+    }
+
+    // each waiting process has to be unlocked again before its reply can be failed
+    for (process_num, token) in failed.into_iter() {
+        if let Err(err) = fail_reply(process_num, token, Errno::NoSuchProcess) {
+            debug!("couldn't fail reply {token} for process {process_num}: {err:?}");
+        }
+    }
+}
+
+/// sends a reply with the given token to the given process, waking up the thread waiting for it
+pub fn send_reply(thread_id: ThreadID, to: u32, token: u32, data: Option<(u64, usize)>) -> Result<()> {
+    let (waiting_cpu, server) = {
+        let process = get_process(to).ok_or(Errno::NoSuchProcess)?;
+        let pending = process.pending_replies.iter().find(|pending| pending.token == token).ok_or(Errno::InvalidArgument)?;
+        (process.threads.get(pending.thread as usize).and_then(|t| t.cpu), pending.server)
+    };
+
+    revoke_priority(server, token);
+
+    match waiting_cpu {
+        Some(cpu) if cpu != thread_id => {
+            // ask the CPU the waiting thread is on to deliver the reply
+            debug!("forwarding reply to CPU {cpu}");
+
+            let cpus = get_cpus().expect("CPUs not initialized");
+            let thread = cpus.get_thread(cpu).expect("couldn't get CPU thread");
+
+            thread.send_message(super::cpu::Message::Reply { to, token, data })?;
+
+            assert!(crate::arch::send_interrupt_to_cpu(cpu, crate::arch::MESSAGE_INT), "failed to send interrupt");
+
+            Ok(())
+        }
+        _ => deliver_reply(to, token, data),
+    }
+}
+
+/// stores a reply in the given process's pending reply table and wakes up the thread waiting for it
+///
+/// replies with tokens that aren't in the table are refused, since whatever was waiting for them is gone
+pub fn deliver_reply(to: u32, token: u32, data: Option<(u64, usize)>) -> Result<()> {
+    store_reply(to, token, Ok(data))
+}
+
+/// wakes up the thread waiting for the reply with the given token, handing it the given error instead of a reply.
+/// this is for when the message it's waiting on will never be replied to, so it doesn't wait forever
+pub fn fail_reply(to: u32, token: u32, err: Errno) -> Result<()> {
+    let server = {
+        let process = get_process(to).ok_or(Errno::NoSuchProcess)?;
+        process.pending_replies.iter().find(|pending| pending.token == token).ok_or(Errno::InvalidArgument)?.server
+    };
+
+    revoke_priority(server, token);
+
+    store_reply(to, token, Err(err))
+}
+
+fn store_reply(to: u32, token: u32, reply: Result<Option<(u64, usize)>>) -> Result<()> {
+    let waiting = {
+        let mut process = get_process(to).ok_or(Errno::NoSuchProcess)?;
+        let pending = process.pending_replies.iter_mut().find(|pending| pending.token == token).ok_or(Errno::InvalidArgument)?;
+
+        if pending.reply.is_some() {
+            return Err(Errno::Exists);
+        }
+
+        pending.reply = Some(reply);
+
+        ProcessID { process: to, thread: pending.thread }
+    };
+
+    trace!("delivering reply {token} to {waiting}");
+
+    super::wake_task(waiting)
+}
+
+/// takes the reply with the given token out of the given process's pending reply table, if it's arrived.
+/// if the message couldn't be handled, the error that stopped it is returned instead
+pub fn take_reply(process_num: u32, token: u32) -> Result<Option<(u64, usize)>> {
+    let mut process = get_process(process_num).ok_or(Errno::NoSuchProcess)?;
+
+    let index = process.pending_replies.iter().position(|pending| pending.token == token).ok_or(Errno::InvalidArgument)?;
+
+    match process.pending_replies[index].reply {
+        Some(reply) => {
+            process.pending_replies.swap_remove(index);
+            reply
+        }
+        None => Err(Errno::TryAgain),
+    }
+}
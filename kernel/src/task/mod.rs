@@ -2,15 +2,19 @@
 
 pub mod cpu;
 pub mod exec;
+pub mod futex;
 pub mod ipc;
 pub mod queue;
 pub mod switch;
 pub mod syscalls;
 
 use crate::{arch::{Registers, get_thread_id}, mm::sync::PageDirSync, util::array::ConsistentIndexArray};
-use alloc::{collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use common::types::{Errno, ProcessID, Result};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
 use log::{debug, error, trace, warn};
 use spin::Mutex;
 
@@ -35,6 +39,30 @@ pub struct MessageHandler {
     pub has_data: bool,
 }
 
+/// how far below the bottom of a growable stack a fault can be and still count as the stack growing. anything further down is more likely to be a bad
+/// pointer than a big stack frame
+pub const STACK_GROWTH_WINDOW: usize = 0x10000;
+
+/// a stack that's mapped in as it grows down, rather than all at once
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StackRegion {
+    /// the top of the stack, which it grows down from
+    pub base: usize,
+
+    /// the most the stack is allowed to grow to
+    pub max_size: usize,
+
+    /// the lowest address of the stack that's currently mapped
+    pub bottom: usize,
+}
+
+impl StackRegion {
+    /// checks whether a fault at the given address should grow the stack down to it
+    pub fn can_grow_to(&self, addr: usize) -> bool {
+        addr < self.bottom && self.bottom - addr <= STACK_GROWTH_WINDOW && addr >= self.base - self.max_size
+    }
+}
+
 pub struct Process {
     /// the page directory of this process
     pub page_directory: PageDirSync<'static, crate::arch::PageDirectory<'static>>,
@@ -44,6 +72,12 @@ pub struct Process {
 
     /// all the message handlers associated with this process
     pub message_handlers: BTreeMap<u32, MessageHandler>,
+
+    /// replies that threads in this process are waiting for. there's at most one per waiting thread, so this is kept small enough to search linearly
+    pub pending_replies: Vec<ipc::PendingReply>,
+
+    /// the stack of this process's main thread, if it grows on demand
+    pub stack: Option<StackRegion>,
 }
 
 impl Process {
@@ -51,6 +85,7 @@ impl Process {
         let old_page_dir = core::mem::replace(&mut self.page_directory.task, page_dir);
         match self.page_directory.force_sync() {
             Ok(_) => {
+                self.page_directory.recount();
                 crate::mm::paging::free_page_dir(&old_page_dir);
                 Ok(())
             }
@@ -62,6 +97,16 @@ impl Process {
         }
     }
 
+    /// how many pages of user memory this process has present
+    pub fn resident_pages(&self) -> usize {
+        self.page_directory.resident_pages
+    }
+
+    /// how many pages of user memory this process has mapped, whether they're present or not
+    pub fn virtual_pages(&self) -> usize {
+        self.page_directory.reserved_pages
+    }
+
     pub fn remove_all_threads(&mut self) {
         self.threads.clear();
         self.page_directory.should_update_pages = false;
@@ -124,6 +169,11 @@ impl RegisterQueue {
         }
     }
 
+    /// how many entries are queued up beneath the current one
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
     pub fn pop(&mut self) -> Option<RegisterQueueEntry> {
         if let Some(next) = self.queue.pop() {
             Some(core::mem::replace(&mut self.current, next))
@@ -161,11 +211,59 @@ pub struct Thread {
     /// this thread's priority
     pub priority: i8,
 
+    /// this thread's priority class
+    pub priority_class: queue::PriorityClass,
+
+    /// which CPU threads this thread is allowed to run on, if it's pinned
+    pub affinity: Option<cpu::AffinityMask>,
+
     /// the CPU this thread was last on
     pub cpu: Option<cpu::ThreadID>,
 
+    /// the CPU this thread last actually ran on, which it likely still has data in the cache of
+    pub last_ran_on: Option<cpu::ThreadID>,
+
+    /// how many times this thread has started running on a different CPU than the one it last ran on
+    pub migrations: usize,
+
     /// whether this thread is blocked or not
     pub is_blocked: bool,
+
+    /// base address of this thread's thread-local storage area, which the TLS segment is pointed at whenever this thread is switched to
+    pub tls_base: usize,
+
+    /// priorities donated to this thread by threads waiting for it to reply to a message, keyed by reply token
+    pub donated_priorities: Vec<(u32, i8)>,
+}
+
+impl Thread {
+    /// gets the priority this thread should be scheduled with, which is the highest of its own priority and any priority donated to it.
+    /// donations don't stack, so any number of waiters can only raise it as high as the highest of them
+    pub fn effective_priority(&self) -> i8 {
+        self.donated_priorities.iter().map(|(_, priority)| *priority).fold(self.priority, i8::max)
+    }
+
+    /// records that this thread is about to run on the given CPU thread, counting it as a migration if it last ran on a different one
+    pub fn record_run_on(&mut self, cpu: cpu::ThreadID) {
+        if self.last_ran_on.is_some_and(|last| last != cpu) {
+            self.migrations = self.migrations.saturating_add(1);
+        }
+
+        self.last_ran_on = Some(cpu);
+    }
+}
+
+/// the ID of the init process, which is the only process allowed to do privileged things like raising priorities
+static INIT_PROCESS: AtomicU32 = AtomicU32::new(0);
+
+/// sets which process is the init process
+pub fn set_init_process(id: u32) {
+    INIT_PROCESS.store(id, Ordering::Release);
+}
+
+/// checks whether the given process is allowed to do privileged things
+pub fn is_privileged(id: u32) -> bool {
+    id != 0 && INIT_PROCESS.load(Ordering::Acquire) == id
 }
 
 // this is all very jank but it seems to work? wonder whether the overhead of locking individual processes is at all worth it
@@ -210,6 +308,20 @@ pub fn get_process(id: u32) -> Option<spin::MutexGuard<'static, Process>> {
     res
 }
 
+/// like [get_process], but gives up instead of spinning if the process list or the process is locked. useful when whatever's holding the lock
+/// might never release it, like when panicking
+pub fn try_get_process(id: u32) -> Option<spin::MutexGuard<'static, Process>> {
+    if PROCESSES_LOCK.swap(true, Ordering::Acquire) {
+        return None;
+    }
+
+    let res = unsafe { PROCESSES.get(id as usize).and_then(|p| p.try_lock()) };
+
+    release_processes_lock();
+
+    res
+}
+
 pub fn create_process(page_dir: crate::arch::PageDirectory<'static>) -> Result<u32> {
     take_processes_lock();
 
@@ -227,9 +339,13 @@ pub fn create_process(page_dir: crate::arch::PageDirectory<'static>) -> Result<u
                     process_id: 0,
                     kernel_space_updates: 0,
                     should_update_pages: false,
+                    resident_pages: 0,
+                    reserved_pages: 0,
                 },
                 threads: ConsistentIndexArray::new(),
                 message_handlers: BTreeMap::default(),
+                pending_replies: Vec::new(),
+                stack: None,
             }))
         } {
             Ok(index) => index,
@@ -255,6 +371,8 @@ pub fn create_process(page_dir: crate::arch::PageDirectory<'static>) -> Result<u
             let mut process = get_process(pid).ok_or(Errno::TryAgain)?;
 
             process.page_directory.process_id = pid;
+            process.page_directory.recount();
+
             match process.page_directory.force_sync() {
                 Ok(_) => (),
                 Err(err) => {
@@ -279,6 +397,17 @@ pub fn remove_process(id: u32) {
     release_processes_lock();
 }
 
+/// gets an upper bound on the IDs of every process that currently exists
+pub fn highest_process_id() -> u32 {
+    take_processes_lock();
+
+    let res = unsafe { PROCESSES.highest_index() };
+
+    release_processes_lock();
+
+    res as u32
+}
+
 pub fn num_processes() -> usize {
     take_processes_lock();
 
@@ -289,38 +418,397 @@ pub fn num_processes() -> usize {
     res
 }
 
+/// the default oom handler, kills whichever process has the most memory resident to free up its frames.
+/// the process currently running on this CPU is left alone, since whatever ran out of memory is likely working on its behalf
+pub fn kill_largest_process(_needed: usize) -> bool {
+    let thread_id = get_thread_id();
+
+    let Some(thread) = get_cpus().and_then(|cpus| cpus.get_thread(thread_id)) else {
+        return false;
+    };
+
+    // if whatever ran out of memory is holding our task queue we can't tell which process is current, so bail instead of risking a deadlock
+    let current = match thread.task_queue.try_lock() {
+        Some(queue) => queue.current().map(|c| c.id().process),
+        None => return false,
+    };
+
+    let mut largest: Option<(u32, usize)> = None;
+
+    for id in 1..=highest_process_id() {
+        if Some(id) == current {
+            continue;
+        }
+
+        // whatever ran out of memory could be holding a process lock too, and a process that's locked is in use anyway so it's skipped
+        if let Some(process) = try_get_process(id) {
+            let pages = process.resident_pages();
+
+            if pages > largest.map(|(_, p)| p).unwrap_or(0) {
+                largest = Some((id, pages));
+            }
+        }
+    }
+
+    match largest {
+        Some((id, pages)) => {
+            error!("out of memory, killing process {id} ({pages} pages resident)");
+            syscalls::kill_process(thread_id, id);
+            true
+        }
+        None => false,
+    }
+}
+
 pub fn queue_process(id: ProcessID) -> Result<()> {
     let cpus = get_cpus().expect("CPUs not initialized");
 
-    let to_queue_on = cpus.find_thread_to_add_to().unwrap_or_default();
+    let (priority, class, affinity, last_ran_on) = get_process(id.process)
+        .and_then(|process| {
+            process
+                .threads
+                .get(id.thread as usize)
+                .map(|thread| (thread.effective_priority(), thread.priority_class, thread.affinity, thread.last_ran_on))
+        })
+        .unwrap_or_default();
+
+    // a pinned task can't fall back to running on just any thread
+    let to_queue_on = match affinity {
+        Some(_) => cpus.find_thread_to_add_to(affinity, last_ran_on).ok_or(Errno::InvalidArgument)?,
+        None => cpus.find_thread_to_add_to(None, last_ran_on).unwrap_or_default(),
+    };
 
     debug!("queueing process {id} on CPU {to_queue_on}");
 
-    match cpus.get_thread(to_queue_on) {
-        Some(thread) => match thread.task_queue.lock().insert(crate::task::queue::TaskQueueEntry::new(id, 0)) {
-            Ok(_) => match get_process(id.process) {
-                Some(mut process) => match process.threads.get_mut(id.thread as usize) {
-                    Some(thread) => {
-                        thread.cpu = Some(to_queue_on);
-                        Ok(())
+    let mut entry = crate::task::queue::TaskQueueEntry::new(id, priority);
+    entry.set_class(class);
+    entry.set_affinity(affinity);
+
+    let cpu_thread = cpus.get_thread(to_queue_on).ok_or(Errno::NoSuchProcess)?;
+
+    // the queue's guard has to be dropped here, since it's locked again below
+    let inserted = cpu_thread.task_queue.lock().insert(entry);
+    inserted?;
+
+    let found = match get_process(id.process) {
+        Some(mut process) => match process.threads.get_mut(id.thread as usize) {
+            Some(thread) => {
+                thread.cpu = Some(to_queue_on);
+                true
+            }
+            None => false,
+        },
+        None => false,
+    };
+
+    if !found {
+        cpu_thread.task_queue.lock().remove_thread(id);
+        return Err(Errno::NoSuchProcess);
+    }
+
+    wake_idle_thread(to_queue_on, cpu_thread);
+
+    Ok(())
+}
+
+static mut CPUS: Option<cpu::CPU> = None;
+
+/// blocks the given task until it's woken up by [wake_task] or [wake_all]
+///
+/// if the task is currently running, it'll keep running until the next context switch
+pub fn block_task(id: ProcessID, reason: queue::WaitReason) -> Result<()> {
+    let cpus = get_cpus().expect("CPUs not initialized");
+
+    for core in cpus.cores.iter() {
+        for cpu_thread in core.threads.iter() {
+            let mut task_queue = cpu_thread.task_queue.lock();
+
+            if task_queue.block(id, reason)? {
+                let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
+                let thread = process.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?;
+                thread.is_blocked = true;
+
+                return Ok(());
+            }
+        }
+    }
+
+    Err(Errno::NoSuchProcess)
+}
+
+/// updates the priority of the given task wherever it is in the task queues, so a change in its effective priority takes effect immediately
+pub fn update_task_priority(id: ProcessID, priority: i8) {
+    let cpus = get_cpus().expect("CPUs not initialized");
+
+    for core in cpus.cores.iter() {
+        for cpu_thread in core.threads.iter() {
+            if cpu_thread.task_queue.lock().set_priority(id, priority) {
+                return;
+            }
+        }
+    }
+}
+
+/// updates the CPU affinity of the given task in whichever queue it's in.
+/// if it's waiting to run on a CPU thread it isn't allowed on anymore it's moved to one it is, and if it's running on one it's moved the next time it's switched away from
+pub fn update_task_affinity(id: ProcessID, affinity: Option<cpu::AffinityMask>) -> Result<()> {
+    let cpus = get_cpus().expect("CPUs not initialized");
+
+    for (core_num, core) in cpus.cores.iter().enumerate() {
+        for (thread_num, cpu_thread) in core.threads.iter().enumerate() {
+            let mut task_queue = cpu_thread.task_queue.lock();
+
+            if !task_queue.set_affinity(id, affinity) {
+                continue;
+            }
+
+            let is_waiting = task_queue.iter().any(|entry| entry.id() == id);
+
+            if is_waiting && !cpus.is_allowed_on(affinity, cpu::ThreadID { core: core_num, thread: thread_num }) {
+                task_queue.remove_thread(id);
+
+                // the queue has to be unlocked before queueing it elsewhere, since that'll lock another queue
+                drop(task_queue);
+
+                return queue_process(id);
+            }
+
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// blocks the given task until the given amount of ticks of the timer of the CPU thread it's on have passed.
+/// the task can still be woken up early by [wake_task]
+pub fn sleep_for(id: ProcessID, ticks: u64) -> Result<()> {
+    let cpus = get_cpus().expect("CPUs not initialized");
+
+    for core in cpus.cores.iter() {
+        for cpu_thread in core.threads.iter() {
+            let mut task_queue = cpu_thread.task_queue.lock();
+
+            // the deadline is in terms of the timer of whichever CPU thread the task is on, so look for it first
+            if !task_queue.contains(id) {
+                continue;
+            }
+
+            let now = crate::timer::get_timer(cpu_thread.timer).map(|timer| timer.jiffies()).ok_or(Errno::NoSuchDevice)?;
+            let deadline = now.saturating_add(ticks);
+
+            if task_queue.block(id, queue::WaitReason::Timer(deadline))? {
+                if let Err(err) = task_queue.add_sleeper(deadline, id) {
+                    // nothing would ever wake it up, so don't leave it blocked. if it's running it'll be put back in the queue when it's switched away from
+                    let entry = task_queue.take_blocked(id).unwrap();
+
+                    if task_queue.current().map(|c| c.id()) != Some(id) {
+                        task_queue.insert(entry)?;
+                    }
+
+                    return Err(err);
+                }
+
+                let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
+                let thread = process.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?;
+                thread.is_blocked = true;
+
+                return Ok(());
+            }
+        }
+    }
+
+    Err(Errno::NoSuchProcess)
+}
+
+/// marks a task that was taken out of the blocked list of the given queue as runnable. if it isn't still running there, its entry is returned so it can be
+/// queued again with [requeue_woken_task] once the queue has been unlocked
+fn mark_woken(task_queue: &queue::TaskQueue, entry: queue::TaskQueueEntry) -> Result<Option<queue::TaskQueueEntry>> {
+    let id = entry.id();
+
+    // the task may have exited while it was blocked
+    let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
+    let thread = process.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?;
+    thread.is_blocked = false;
+
+    // if the task was woken up before it got switched away from, it'll be put back in the queue by the next context switch
+    if task_queue.current().map(|c| c.id()) == Some(id) {
+        Ok(None)
+    } else {
+        Ok(Some(entry))
+    }
+}
+
+/// queues a task that was just woken up on whichever CPU thread suits it best. the thread it was blocked on is preferred, since that's where it last ran,
+/// and it's used as a fallback if the task can't be queued anywhere else
+///
+/// this locks task queues to compare them, so none can be held when it's called
+fn requeue_woken_task(cpus: &cpu::CPU, blocked_on: cpu::ThreadID, entry: queue::TaskQueueEntry) -> Result<()> {
+    let id = entry.id();
+    let preferred = cpus.find_thread_to_add_to(entry.affinity(), Some(blocked_on)).unwrap_or(blocked_on);
+
+    let insert_on = |thread_id| {
+        let cpu_thread = cpus.get_thread(thread_id).ok_or(Errno::NoSuchProcess)?;
+        let inserted = cpu_thread.task_queue.lock().insert(entry);
+        inserted.map(|_| cpu_thread)
+    };
+
+    let (to_queue_on, cpu_thread) = match insert_on(preferred) {
+        Ok(cpu_thread) => (preferred, cpu_thread),
+        // the task has already been marked as runnable, so it can't just be dropped
+        Err(err) if preferred != blocked_on => {
+            debug!("couldn't wake up {id} on CPU {preferred} ({err:?}), putting it back on CPU {blocked_on}");
+            (blocked_on, insert_on(blocked_on)?)
+        }
+        Err(err) => return Err(err),
+    };
+
+    if to_queue_on != blocked_on {
+        trace!("woke up {id} on CPU {to_queue_on} instead of CPU {blocked_on}");
+
+        // messages for it should be sent to where it is now
+        if let Some(mut process) = get_process(id.process) && let Some(thread) = process.threads.get_mut(id.thread as usize) {
+            thread.cpu = Some(to_queue_on);
+        }
+    }
+
+    wake_idle_thread(to_queue_on, cpu_thread);
+
+    Ok(())
+}
+
+/// marks a task that was taken out of the blocked list as runnable and inserts it back into the given queue.
+/// unlike [mark_woken], this can be used while the process is already locked, and doesn't move the task anywhere else
+fn unblock_task_locked(task_queue: &mut queue::TaskQueue, entry: queue::TaskQueueEntry, process: &mut Process) -> Result<()> {
+    let id = entry.id();
+
+    let thread = process.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?;
+    thread.is_blocked = false;
+
+    // if the task was woken up before it got switched away from, it'll be put back in the queue by the next context switch
+    if task_queue.current().map(|c| c.id()) == Some(id) {
+        return Ok(());
+    }
+
+    task_queue.insert(entry)
+}
+
+/// if the given CPU thread isn't running anything, interrupts it so it picks up whatever was just queued on it. otherwise a halted thread
+/// wouldn't notice its new task until its next context switch
+fn wake_idle_thread(id: cpu::ThreadID, cpu_thread: &cpu::CPUThread) {
+    if id == get_thread_id() || !cpu_thread.has_started() || cpu_thread.task_queue.lock().current().is_some() {
+        return;
+    }
+
+    match cpu_thread.send_message(cpu::Message::Reschedule) {
+        Ok(_) => assert!(crate::arch::send_interrupt_to_cpu(id, crate::arch::MESSAGE_INT), "failed to send interrupt"),
+        Err(err) => warn!("couldn't wake CPU {id} for new task: {err:?}"),
+    }
+}
+
+/// wakes up the given blocked task, no matter what it's waiting for
+pub fn wake_task(id: ProcessID) -> Result<()> {
+    let cpus = get_cpus().expect("CPUs not initialized");
+
+    for (core_num, core) in cpus.cores.iter().enumerate() {
+        for (thread_num, cpu_thread) in core.threads.iter().enumerate() {
+            let woken = {
+                let mut task_queue = cpu_thread.task_queue.lock();
+
+                match task_queue.take_blocked(id) {
+                    Some(entry) => mark_woken(&task_queue, entry)?,
+                    None => continue,
+                }
+            };
+
+            if let Some(entry) = woken {
+                requeue_woken_task(cpus, cpu::ThreadID { core: core_num, thread: thread_num }, entry)?;
+            }
+
+            return Ok(());
+        }
+    }
+
+    Err(Errno::NoSuchProcess)
+}
+
+/// wakes up all tasks waiting for the given reason, returning how many were woken
+pub fn wake_all(reason: queue::WaitReason) -> usize {
+    wake_some(reason, usize::MAX)
+}
+
+/// wakes up to the given number of tasks waiting for the given reason, returning how many were woken
+pub fn wake_some(reason: queue::WaitReason, count: usize) -> usize {
+    let cpus = get_cpus().expect("CPUs not initialized");
+    let mut num_woken = 0;
+
+    for (core_num, core) in cpus.cores.iter().enumerate() {
+        for (thread_num, cpu_thread) in core.threads.iter().enumerate() {
+            if num_woken >= count {
+                return num_woken;
+            }
+
+            // the woken tasks can only be queued elsewhere once this queue has been unlocked
+            let mut woken = {
+                let mut task_queue = cpu_thread.task_queue.lock();
+                let mut woken = task_queue.take_blocked_by_up_to(reason, count - num_woken);
+
+                woken.retain(|entry| match mark_woken(&task_queue, *entry) {
+                    Ok(Some(_)) => true,
+                    Ok(None) => {
+                        num_woken += 1;
+                        false
                     }
-                    None => {
-                        thread.task_queue.lock().remove_thread(id);
-                        Err(Errno::NoSuchProcess)
+                    Err(Errno::NoSuchProcess) => false,
+                    Err(err) => {
+                        error!("couldn't wake task {}: {err:?}", entry.id());
+                        false
                     }
-                },
-                None => {
-                    thread.task_queue.lock().remove_thread(id);
-                    Err(Errno::NoSuchProcess)
+                });
+
+                woken
+            };
+
+            for entry in woken.drain(..) {
+                match requeue_woken_task(cpus, cpu::ThreadID { core: core_num, thread: thread_num }, entry) {
+                    Ok(_) => num_woken += 1,
+                    Err(err) => error!("couldn't wake task {}: {err:?}", entry.id()),
                 }
-            },
-            Err(err) => Err(err),
-        },
-        None => Err(Errno::NoSuchProcess),
+            }
+        }
     }
+
+    num_woken
 }
 
-static mut CPUS: Option<cpu::CPU> = None;
+/// gets how many times the given task has been migrated between CPUs
+pub fn migrations(id: ProcessID) -> Option<usize> {
+    get_process(id.process)?.threads.get(id.thread as usize).map(|thread| thread.migrations)
+}
+
+/// gets what percentage of its recent timer ticks the given CPU thread has spent idle
+pub fn idle_percent(id: cpu::ThreadID) -> Option<usize> {
+    get_cpus()?.get_thread(id).map(|thread| thread.idle_percent())
+}
+
+/// generates the contents of /proc/sched, with a line for every CPU thread describing how long it sleeps for whenever it's idle and how much of its
+/// recent time it's been idle for
+pub fn sched_info() -> String {
+    let mut info = String::new();
+
+    if let Some(cpus) = get_cpus() {
+        for (core_num, core) in cpus.cores.iter().enumerate() {
+            for (thread_num, thread) in core.threads.iter().enumerate() {
+                let id = cpu::ThreadID { core: core_num, thread: thread_num };
+                let stats = *thread.idle_stats.lock();
+
+                let _ = writeln!(info, "cpu {id}: {stats}");
+            }
+        }
+    }
+
+    info
+}
 
 pub fn get_cpus() -> Option<&'static cpu::CPU> {
     unsafe { CPUS.as_ref() }
@@ -357,40 +845,41 @@ fn release_page_update_lock() {
 }
 
 pub fn update_kernel_page(addr: usize) {
-    let thread_id = crate::arch::get_thread_id();
-
-    debug!("(CPU {thread_id}) updating page @ {addr:#x}");
+    debug!("(CPU {}) updating page @ {addr:#x}", crate::arch::get_thread_id());
 
     if let Some(cpus) = get_cpus() {
-        for (core_num, core) in cpus.cores.iter().enumerate() {
-            for (thread_num, thread) in core.threads.iter().enumerate() {
-                if (thread_id.core != core_num || thread_id.thread != thread_num) && thread.has_started() {
-                    take_page_update_lock(thread_id);
+        cpus.broadcast_urgent(cpu::UrgentMessage::KernelPageUpdate { addr });
+    }
+}
 
-                    thread.send_urgent_message(cpu::UrgentMessage::KernelPageUpdate { addr }).unwrap();
+/// tells every other CPU to flush the given range of kernel memory from its TLB, all at once
+pub fn update_kernel_range(start: usize, len: usize) {
+    debug!("(CPU {}) updating pages @ {start:#x} - {:#x}", crate::arch::get_thread_id(), start + (len - 1));
 
-                    let id = cpu::ThreadID { core: core_num, thread: thread_num };
+    if let Some(cpus) = get_cpus() {
+        cpus.broadcast_urgent(cpu::UrgentMessage::KernelPageRange { start, len });
+    }
+}
 
-                    assert!(crate::arch::send_interrupt_to_cpu(id, crate::arch::MESSAGE_INT), "failed to send interrupt");
+/// tells every other CPU running the given process to flush the given page from its TLB. `page_dir` is the physical address of the process's
+/// page directory (see [crate::mm::paging::PageDirectory::physical_addr]), which CPUs check against what they have loaded
+pub fn update_task_page(process_id: u32, page_dir: Option<u32>, addr: usize) {
+    debug!("(CPU {}) updating page in process {process_id} @ {addr:?}", crate::arch::get_thread_id());
 
-                    // inefficient and slow :(
-                    trace!("waiting for {id}");
-                    while !thread.urgent_message_queue.lock().is_empty() {
-                        crate::arch::spin();
-                    }
+    send_task_page_update(process_id, cpu::UrgentMessage::TaskPageUpdate { page_dir, addr });
+}
 
-                    release_page_update_lock();
-                }
-            }
-        }
-    }
+/// tells every other CPU running the given process to flush the given range of its memory from its TLB, all at once
+pub fn update_task_range(process_id: u32, page_dir: Option<u32>, start: usize, len: usize) {
+    debug!("(CPU {}) updating pages in process {process_id} @ {start:#x} - {:#x}", crate::arch::get_thread_id(), start + (len - 1));
+
+    send_task_page_update(process_id, cpu::UrgentMessage::TaskPageRange { page_dir, start, len });
 }
 
-pub fn update_task_page(process_id: u32, addr: usize) {
+/// sends the given page update message to every other CPU running the given process, and waits for each of them to process it
+fn send_task_page_update(process_id: u32, message: cpu::UrgentMessage) {
     let thread_id = crate::arch::get_thread_id();
 
-    debug!("(CPU {thread_id}) updating page in process {process_id} @ {addr:?}");
-
     if let Some(cpus) = get_cpus() {
         for (core_num, core) in cpus.cores.iter().enumerate() {
             for (thread_num, thread) in core.threads.iter().enumerate() {
@@ -398,7 +887,7 @@ pub fn update_task_page(process_id: u32, addr: usize) {
                     && let Some(current_id) = thread.task_queue.lock().current().map(|c| c.id()) && current_id.process == process_id {
                     take_page_update_lock(thread_id);
 
-                    thread.send_urgent_message(cpu::UrgentMessage::TaskPageUpdate { process_id, addr }).unwrap();
+                    thread.send_urgent_message(message).unwrap();
 
                     let id = cpu::ThreadID { core: core_num, thread: thread_num };
 
@@ -435,3 +924,118 @@ pub fn nmi_all_other_cpus() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// builds a CPU with a single core with the given number of threads, all with empty queues
+    fn test_cpu(num_threads: usize) -> cpu::CPU {
+        let info = get_cpus().unwrap().cores[0].threads[0].info;
+
+        let mut cpu = cpu::CPU::new();
+        cpu.add_core();
+
+        for _i in 0..num_threads {
+            cpu.cores[0].add_thread(info, 0);
+        }
+
+        cpu
+    }
+
+    #[test_case]
+    fn woken_tasks_fall_back_on_the_thread_they_blocked_on() {
+        let cpus = test_cpu(2);
+        let (blocked_on, emptiest) = (cpu::ThreadID { core: 0, thread: 0 }, cpu::ThreadID { core: 0, thread: 1 });
+
+        // a process that doesn't exist, so nothing tries to update it
+        let id = ProcessID { process: u32::MAX, thread: 0 };
+        let entry = queue::TaskQueueEntry::new(id, 0);
+
+        // make the emptiest thread refuse the task by already having it
+        for process in 1..=2 {
+            cpus.get_thread(blocked_on).unwrap().task_queue.lock().insert(queue::TaskQueueEntry::new(ProcessID { process: u32::MAX - process, thread: 0 }, 0)).unwrap();
+        }
+        cpus.get_thread(emptiest).unwrap().task_queue.lock().insert(entry).unwrap();
+
+        requeue_woken_task(&cpus, blocked_on, entry).unwrap();
+
+        assert!(cpus.get_thread(blocked_on).unwrap().task_queue.lock().contains(id));
+        assert_eq!(cpus.get_thread(emptiest).unwrap().task_queue.lock().len(), 1);
+    }
+
+    #[test_case]
+    fn running_somewhere_else_counts_as_a_migration() {
+        let process = exec::load(&crate::arch::spinning_executable()).unwrap();
+        let id = ProcessID { process, thread: 1 };
+        let (first, second) = (cpu::ThreadID { core: 0, thread: 0 }, cpu::ThreadID { core: 1, thread: 0 });
+
+        {
+            let mut process = get_process(process).unwrap();
+            let thread = process.threads.get_mut(1).unwrap();
+
+            assert_eq!(thread.last_ran_on, None);
+
+            // the first time a thread runs anywhere isn't a migration
+            thread.record_run_on(first);
+            thread.record_run_on(first);
+            assert_eq!((thread.migrations, thread.last_ran_on), (0, Some(first)));
+
+            thread.record_run_on(second);
+            thread.record_run_on(first);
+            assert_eq!((thread.migrations, thread.last_ran_on), (2, Some(first)));
+        }
+
+        assert_eq!(migrations(id), Some(2));
+
+        remove_process(process);
+        assert_eq!(migrations(id), None);
+    }
+
+    #[test_case]
+    fn killed_processes_free_everything() {
+        let executable = crate::arch::spinning_executable();
+        let spawn_and_kill = || {
+            let process = exec::spawn(&executable).unwrap();
+            syscalls::kill_process(get_thread_id(), process);
+            assert!(get_process(process).is_none());
+        };
+
+        // the first run may grow the heap or fill in kernel page tables, which stick around
+        spawn_and_kill();
+
+        let frames_used = crate::mm::paging::get_page_manager().frame_set.bits_used;
+        let processes = num_processes();
+
+        for _i in 0..4 {
+            spawn_and_kill();
+        }
+
+        assert_eq!(crate::mm::paging::get_page_manager().frame_set.bits_used, frames_used);
+        assert_eq!(num_processes(), processes);
+    }
+
+    #[test_case]
+    fn killed_servers_fail_replies_waiting_on_them() {
+        let executable = crate::arch::spinning_executable();
+        let server = exec::load(&executable).unwrap();
+        let waiter = exec::load(&executable).unwrap();
+        let token = u32::MAX;
+
+        get_process(waiter).unwrap().pending_replies.push(ipc::PendingReply {
+            token,
+            thread: 1,
+            server: ProcessID { process: server, thread: 1 },
+            reply: None,
+        });
+
+        assert!(matches!(ipc::take_reply(waiter, token), Err(Errno::TryAgain)));
+
+        syscalls::kill_process(get_thread_id(), server);
+
+        assert!(matches!(ipc::take_reply(waiter, token), Err(Errno::NoSuchProcess)));
+        assert!(get_process(waiter).unwrap().pending_replies.is_empty());
+
+        remove_process(waiter);
+    }
+}
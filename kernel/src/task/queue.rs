@@ -1,5 +1,14 @@
+use super::cpu::AffinityMask;
 use alloc::{collections::VecDeque, vec::Vec};
+use core::cmp::Reverse;
 use common::types::{Errno, Result};
+use log::warn;
+
+/// how many times in a row realtime tasks can be picked over waiting normal tasks before we complain about it
+const REALTIME_STARVATION_WARN_THRESHOLD: usize = 1000;
+
+/// how many times a normal task can be passed over in a row before its effective priority is raised
+const AGING_THRESHOLD: u16 = 16;
 
 /// a per-CPU task queue
 #[derive(Debug)]
@@ -11,6 +20,18 @@ pub struct TaskQueue {
     queue: VecDeque<TaskQueueEntry>,
 
     pub timer: Option<u64>,
+
+    /// how many times in a row a realtime task has been picked while normal tasks were waiting
+    realtime_streak: usize,
+
+    /// the turn number to give to the next task inserted into the queue
+    next_turn: u64,
+
+    /// tasks that aren't runnable until they're woken up, along with what they're waiting for
+    blocked: Vec<(TaskQueueEntry, WaitReason)>,
+
+    /// deadlines of tasks that are sleeping, sorted so the soonest deadline is first
+    sleepers: VecDeque<(u64, super::ProcessID)>,
 }
 
 impl TaskQueue {
@@ -19,6 +40,10 @@ impl TaskQueue {
             current: None,
             queue: VecDeque::new(),
             timer: None,
+            realtime_streak: 0,
+            next_turn: 0,
+            blocked: Vec::new(),
+            sleepers: VecDeque::new(),
         }
     }
 
@@ -26,24 +51,62 @@ impl TaskQueue {
     pub fn consume(&mut self) -> Option<&TaskQueueEntry> {
         self.current = self.queue.pop_front();
 
+        // whatever's running now doesn't need any help getting picked anymore
+        if let Some(entry) = self.current.as_mut() {
+            entry.reset_age();
+        }
+
+        self.age_waiting();
+
+        // realtime tasks are allowed to starve normal tasks, but it's worth knowing when that's happening
+        match self.current {
+            Some(entry) if entry.class() == PriorityClass::Realtime && self.queue.iter().any(|e| e.class() == PriorityClass::Normal) => {
+                self.realtime_streak += 1;
+
+                if self.realtime_streak == REALTIME_STARVATION_WARN_THRESHOLD {
+                    warn!("realtime task {:?} has been monopolizing the CPU, normal priority tasks are starving", entry.id());
+                }
+            }
+            _ => self.realtime_streak = 0,
+        }
+
         self.current.as_ref()
     }
 
+    /// ages every normal task that was passed over, re-sorting the queue if any of their effective priorities changed
+    fn age_waiting(&mut self) {
+        let mut changed = false;
+
+        for entry in self.queue.iter_mut() {
+            changed |= entry.age();
+        }
+
+        if changed {
+            // sort is stable, so tasks with equal effective priority keep their order
+            self.queue.make_contiguous().sort_by(|a, b| b.sort_key().cmp(&a.sort_key()));
+        }
+    }
+
     /// wrapper around try_reserve for the internal queue structure
     pub fn try_reserve(&mut self, amt: usize) -> Result<()> {
         self.queue.try_reserve(amt).map_err(|_| Errno::OutOfMemory)
     }
 
     /// inserts a task into the queue
-    pub fn insert(&mut self, entry: TaskQueueEntry) -> Result<()> {
+    ///
+    /// tasks with the same priority take turns, so a task will always be placed behind every other task of the same priority that's already waiting,
+    /// no matter how quickly it's re-inserted after running
+    pub fn insert(&mut self, mut entry: TaskQueueEntry) -> Result<()> {
         self.try_reserve(1)?;
 
+        entry.turn = self.next_turn;
+
         let mut insert_position = None;
 
         for (idx, item) in self.queue.iter().enumerate() {
             if item.id == entry.id {
                 return Err(Errno::Exists);
-            } else if insert_position.is_none() && entry.full_priority() > item.full_priority() {
+            } else if insert_position.is_none() && entry.sort_key() > item.sort_key() {
                 insert_position = Some(idx);
             }
         }
@@ -53,6 +116,8 @@ impl TaskQueue {
             None => self.queue.push_back(entry),
         }
 
+        self.next_turn = self.next_turn.wrapping_add(1);
+
         Ok(())
     }
 
@@ -66,6 +131,19 @@ impl TaskQueue {
         self.queue.len()
     }
 
+    /// iterates over all the tasks waiting in this queue, in the order they'll be run
+    pub fn iter(&self) -> impl Iterator<Item = &TaskQueueEntry> {
+        self.queue.iter()
+    }
+
+    /// checks whether anything waiting in this queue would be picked over or alongside the current task, i.e. it has the same or a higher priority
+    pub fn has_peer_for_current(&self) -> bool {
+        match self.current {
+            Some(current) => self.queue.iter().any(|e| (e.class(), e.effective_priority()) >= (current.class(), current.effective_priority())),
+            None => !self.queue.is_empty(),
+        }
+    }
+
     /// gets the current task being processed in the queue
     pub fn current(&self) -> Option<TaskQueueEntry> {
         self.current
@@ -76,16 +154,195 @@ impl TaskQueue {
         if let Some(index) = self.queue.iter().position(|e| e.id() == id) {
             self.queue.remove(index);
         }
+
+        self.blocked.retain(|(e, _)| e.id() != id);
+        self.sleepers.retain(|(_, i)| *i != id);
     }
 
     /// given a process id, remove all threads corresponding to it from the queue
     pub fn remove_process(&mut self, id: u32) {
-        let to_remove = self.queue.iter().enumerate().filter(|(_, e)| e.id().process == id).map(|(i, _)| i).collect::<Vec<usize>>();
+        self.queue.retain(|e| e.id().process != id);
+        self.blocked.retain(|(e, _)| e.id().process != id);
+        self.sleepers.retain(|(_, i)| i.process != id);
+    }
+
+    /// removes up to the given number of the lowest priority tasks that match the given filter from the queue, for migrating them to another queue
+    pub fn take_lowest<F: Fn(&TaskQueueEntry) -> bool>(&mut self, max: usize, filter: F) -> Result<Vec<TaskQueueEntry>> {
+        let mut taken = Vec::new();
+        taken.try_reserve(max.min(self.queue.len())).map_err(|_| Errno::OutOfMemory)?;
+
+        // the queue is sorted highest priority first, so search from the back
+        let mut index = self.queue.len();
+
+        while index > 0 && taken.len() < max {
+            index -= 1;
+
+            if filter(&self.queue[index]) {
+                let mut entry = self.queue.remove(index).unwrap();
+                entry.reset_age();
+                taken.push(entry);
+            }
+        }
+
+        Ok(taken)
+    }
+
+    /// moves the given task out of the runnable queue and into the blocked list until it's woken up.
+    /// if the task is the one currently running, it'll be blocked once it's switched away from
+    ///
+    /// returns false if the task isn't in this queue
+    pub fn block(&mut self, id: super::ProcessID, reason: WaitReason) -> Result<bool> {
+        if self.is_blocked(id) {
+            return Err(Errno::Exists);
+        }
+
+        let mut entry = match self.queue.iter().position(|e| e.id() == id) {
+            Some(index) => {
+                self.blocked.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+                self.queue.remove(index).unwrap()
+            }
+            None => match self.current {
+                Some(current) if current.id() == id => {
+                    self.blocked.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+                    current
+                }
+                _ => return Ok(false),
+            },
+        };
+
+        entry.reset_age();
+        self.blocked.push((entry, reason));
+
+        Ok(true)
+    }
+
+    /// removes the given task from the blocked list, returning its entry so it can be inserted back into the queue
+    pub fn take_blocked(&mut self, id: super::ProcessID) -> Option<TaskQueueEntry> {
+        let index = self.blocked.iter().position(|(e, _)| e.id() == id)?;
+        Some(self.blocked.remove(index).0)
+    }
+
+    /// removes the given task from the blocked list if it's waiting for the given reason, returning its entry so it can be inserted back into the queue
+    pub fn take_blocked_for(&mut self, id: super::ProcessID, reason: WaitReason) -> Option<TaskQueueEntry> {
+        let index = self.blocked.iter().position(|(e, r)| e.id() == id && *r == reason)?;
+        Some(self.blocked.remove(index).0)
+    }
+
+    /// removes all tasks waiting for the given reason from the blocked list, returning their entries so they can be inserted back into the queue
+    pub fn take_blocked_by(&mut self, reason: WaitReason) -> Vec<TaskQueueEntry> {
+        self.take_blocked_by_up_to(reason, usize::MAX)
+    }
+
+    /// removes up to the given number of tasks waiting for the given reason from the blocked list, in the order they blocked
+    pub fn take_blocked_by_up_to(&mut self, reason: WaitReason, max: usize) -> Vec<TaskQueueEntry> {
+        let mut woken = Vec::new();
+
+        self.blocked.retain(|(e, r)| {
+            if *r == reason && woken.len() < max && woken.try_reserve(1).is_ok() {
+                woken.push(*e);
+                false
+            } else {
+                true
+            }
+        });
+
+        woken
+    }
+
+    /// changes the priority of the given task, whether it's running, waiting, or blocked, re-sorting the queue if needed.
+    /// tasks with the same priority keep their relative order
+    ///
+    /// returns false if the task isn't in this queue
+    pub fn set_priority(&mut self, id: super::ProcessID, priority: i8) -> bool {
+        let mut found = false;
+
+        if let Some(current) = self.current.as_mut() && current.id() == id {
+            current.set_priority(priority);
+            found = true;
+        }
+
+        if let Some(entry) = self.queue.iter_mut().find(|e| e.id() == id) {
+            entry.set_priority(priority);
+            self.queue.make_contiguous().sort_by(|a, b| b.sort_key().cmp(&a.sort_key()));
+            found = true;
+        }
+
+        if let Some((entry, _)) = self.blocked.iter_mut().find(|(e, _)| e.id() == id) {
+            entry.set_priority(priority);
+            found = true;
+        }
+
+        found
+    }
+
+    /// changes the CPU affinity of the given task, whether it's running, waiting, or blocked
+    ///
+    /// returns false if the task isn't in this queue
+    pub fn set_affinity(&mut self, id: super::ProcessID, affinity: Option<AffinityMask>) -> bool {
+        let mut found = false;
+
+        if let Some(current) = self.current.as_mut() && current.id() == id {
+            current.set_affinity(affinity);
+            found = true;
+        }
+
+        if let Some(entry) = self.queue.iter_mut().find(|e| e.id() == id) {
+            entry.set_affinity(affinity);
+            found = true;
+        }
+
+        if let Some((entry, _)) = self.blocked.iter_mut().find(|(e, _)| e.id() == id) {
+            entry.set_affinity(affinity);
+            found = true;
+        }
+
+        found
+    }
+
+    /// adds a deadline for the given task, which should be blocked with [WaitReason::Timer]
+    pub fn add_sleeper(&mut self, deadline: u64, id: super::ProcessID) -> Result<()> {
+        self.sleepers.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+
+        let index = self.sleepers.iter().position(|(d, _)| *d > deadline).unwrap_or(self.sleepers.len());
+        self.sleepers.insert(index, (deadline, id));
+
+        Ok(())
+    }
+
+    /// gets the next sleeping task whose deadline has passed without removing it
+    pub fn next_due_sleeper(&self, now: u64) -> Option<super::ProcessID> {
+        self.sleepers.front().filter(|(deadline, _)| *deadline <= now).map(|(_, id)| *id)
+    }
+
+    /// checks whether the given task is either running or waiting to run in this queue
+    pub fn contains(&self, id: super::ProcessID) -> bool {
+        self.current.is_some_and(|current| current.id() == id) || self.queue.iter().any(|e| e.id() == id)
+    }
 
-        for index in to_remove.iter() {
-            self.queue.remove(*index);
+    /// removes the next sleeping task whose deadline has passed, returning its entry if it's still blocked waiting for it
+    pub fn take_due_sleeper(&mut self, now: u64) -> Option<Option<TaskQueueEntry>> {
+        if self.sleepers.front()?.0 > now {
+            return None;
+        }
+
+        let (deadline, id) = self.sleepers.pop_front().unwrap();
+
+        // the task may have been woken up some other way in the meantime
+        match self.blocked.iter().position(|(e, r)| e.id() == id && *r == WaitReason::Timer(deadline)) {
+            Some(index) => Some(Some(self.blocked.remove(index).0)),
+            None => Some(None),
         }
     }
+
+    /// checks whether the given task is in the blocked list
+    pub fn is_blocked(&self, id: super::ProcessID) -> bool {
+        self.blocked.iter().any(|(e, _)| e.id() == id)
+    }
+
+    /// gets how many tasks are blocked on this queue
+    pub fn num_blocked(&self) -> usize {
+        self.blocked.len()
+    }
 }
 
 impl Default for TaskQueue {
@@ -94,6 +351,39 @@ impl Default for TaskQueue {
     }
 }
 
+/// what a blocked task is waiting for
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WaitReason {
+    /// waiting for a message to be sent to it
+    Message,
+
+    /// waiting for the reply with the given token
+    Reply(u32),
+
+    /// waiting for the process with the given ID to exit
+    Pid(u32),
+
+    /// waiting for the timer to reach the given tick
+    Timer(u64),
+
+    /// waiting on the futex with the given key
+    Futex(super::futex::FutexKey),
+}
+
+/// which class of priority a task is in
+///
+/// realtime tasks are always scheduled ahead of normal tasks and are first in, first out with respect to other realtime tasks of the same priority.
+/// this means a realtime task that never blocks will monopolize its CPU, which is intentional- the task queue will log a warning when it happens though
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum PriorityClass {
+    /// priority can be adjusted by the scheduler
+    #[default]
+    Normal,
+
+    /// fixed priority, always ahead of normal tasks
+    Realtime,
+}
+
 /// an entry in a task queue
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct TaskQueueEntry {
@@ -102,6 +392,21 @@ pub struct TaskQueueEntry {
 
     /// the priority of this task
     priority: u8,
+
+    /// the priority class of this task
+    class: PriorityClass,
+
+    /// which CPU threads this task is allowed to run on. if None, it can run anywhere
+    affinity: Option<AffinityMask>,
+
+    /// how many times this task has been passed over since its effective priority was last raised
+    skipped: u16,
+
+    /// how much this task's effective priority has been temporarily raised by waiting
+    boost: u8,
+
+    /// when this task was inserted into its queue relative to other tasks, used to rotate between tasks of the same priority
+    turn: u64,
 }
 
 impl TaskQueueEntry {
@@ -110,9 +415,44 @@ impl TaskQueueEntry {
         Self {
             priority: (((priority + 7) as u8) << 4) | 7,
             id,
+            class: PriorityClass::Normal,
+            affinity: None,
+            skipped: 0,
+            boost: 0,
+            turn: 0,
+        }
+    }
+
+    /// gets the CPU affinity of this task queue entry
+    pub fn affinity(&self) -> Option<AffinityMask> {
+        self.affinity
+    }
+
+    /// sets the CPU affinity of this task queue entry
+    pub fn set_affinity(&mut self, affinity: Option<AffinityMask>) {
+        self.affinity = affinity;
+    }
+
+    /// gets the priority class of this task queue entry
+    pub fn class(&self) -> PriorityClass {
+        self.class
+    }
+
+    /// sets the priority class of this task queue entry
+    pub fn set_class(&mut self, class: PriorityClass) {
+        self.class = class;
+
+        if class == PriorityClass::Realtime {
+            self.priority = (self.priority & 0xf0) | 7;
         }
     }
 
+    /// the lowest priority a task can have
+    pub const MIN_PRIORITY: i8 = -7;
+
+    /// the highest priority a task can have
+    pub const MAX_PRIORITY: i8 = 8;
+
     /// gets the priority of this task queue entry
     pub fn priority(&self) -> i8 {
         (self.priority >> 4) as i8 - 7
@@ -129,8 +469,12 @@ impl TaskQueueEntry {
     }
 
     /// sets the sub-priority of this task queue entry
+    ///
+    /// realtime tasks have a fixed priority, so this does nothing for them
     pub fn set_sub_priority(&mut self, sub_priority: i8) {
-        self.priority = (self.priority & 0xf0) | (sub_priority + 7) as u8;
+        if self.class == PriorityClass::Normal {
+            self.priority = (self.priority & 0xf0) | (sub_priority + 7) as u8;
+        }
     }
 
     /// gets the full priority index of this task queue entry
@@ -138,8 +482,148 @@ impl TaskQueueEntry {
         self.priority
     }
 
+    /// gets the priority this task queue entry is actually scheduled with, including any boost it's gotten from waiting
+    ///
+    /// the boost is added to the full priority, so it raises the sub-priority first and only outranks higher priorities after waiting for a long time.
+    /// it's reset as soon as the task runs, so a low priority task can't permanently get ahead of a higher priority one
+    pub fn effective_priority(&self) -> u8 {
+        self.priority.saturating_add(self.boost)
+    }
+
+    /// records that this task was passed over, raising its effective priority if it's been waiting long enough.
+    /// returns whether its effective priority changed
+    ///
+    /// realtime tasks have a fixed priority, so they're never aged
+    fn age(&mut self) -> bool {
+        if self.class != PriorityClass::Normal {
+            return false;
+        }
+
+        self.skipped = self.skipped.saturating_add(1);
+
+        if self.skipped >= AGING_THRESHOLD && self.effective_priority() < u8::MAX {
+            self.skipped = 0;
+            self.boost += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// removes any boost this task has gotten from waiting
+    fn reset_age(&mut self) {
+        self.skipped = 0;
+        self.boost = 0;
+    }
+
+    /// gets the key this task queue entry is ordered by in a task queue, where greater keys are run first.
+    /// tasks that have been waiting longer win ties
+    fn sort_key(&self) -> (PriorityClass, u8, Reverse<u64>) {
+        (self.class, self.effective_priority(), Reverse(self.turn))
+    }
+
     /// gets the task id that this task queue entry represents
     pub fn id(&self) -> super::ProcessID {
         self.id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::ProcessID;
+
+    #[test_case]
+    fn realtime_tasks_stay_ahead_of_aged_normal_tasks() {
+        let mut queue = TaskQueue::new();
+
+        let mut realtime = TaskQueueEntry::new(ProcessID { process: 1, thread: 0 }, TaskQueueEntry::MIN_PRIORITY);
+        realtime.set_class(PriorityClass::Realtime);
+
+        let normal = TaskQueueEntry::new(ProcessID { process: 2, thread: 0 }, TaskQueueEntry::MAX_PRIORITY);
+
+        queue.insert(normal).unwrap();
+        queue.insert(realtime).unwrap();
+
+        // plenty of turns for the normal task to age all the way up
+        for _i in 0..AGING_THRESHOLD as usize * 16 {
+            let current = *queue.consume().unwrap();
+            assert_eq!(current.id(), realtime.id());
+
+            queue.insert(current).unwrap();
+        }
+
+        let waiting = queue.iter().last().unwrap();
+        assert_eq!(waiting.id(), normal.id());
+        assert_eq!(waiting.effective_priority(), u8::MAX);
+    }
+
+    #[test_case]
+    fn equal_priority_tasks_take_turns() {
+        let mut queue = TaskQueue::new();
+
+        let tasks = [1, 2, 3].map(|process| TaskQueueEntry::new(ProcessID { process, thread: 0 }, 0));
+
+        for task in tasks.iter() {
+            queue.insert(*task).unwrap();
+        }
+
+        // every task goes straight back in after its quantum, so none of them should get to run twice in a row
+        for quantum in 0..tasks.len() * 4 {
+            let current = *queue.consume().unwrap();
+            assert_eq!(current.id(), tasks[quantum % tasks.len()].id());
+
+            queue.insert(current).unwrap();
+        }
+    }
+
+    #[test_case]
+    fn set_affinity_updates_waiting_and_running_tasks() {
+        let mut queue = TaskQueue::new();
+
+        let running = TaskQueueEntry::new(ProcessID { process: 1, thread: 0 }, 0);
+        let waiting = TaskQueueEntry::new(ProcessID { process: 2, thread: 0 }, 0);
+        let affinity = Some(AffinityMask::from_bits(0b10));
+
+        queue.insert(running).unwrap();
+        queue.insert(waiting).unwrap();
+        queue.consume();
+
+        assert!(queue.set_affinity(running.id(), affinity));
+        assert!(queue.set_affinity(waiting.id(), affinity));
+        assert!(!queue.set_affinity(ProcessID { process: 3, thread: 0 }, affinity));
+
+        assert_eq!(queue.current().unwrap().affinity(), affinity);
+        assert_eq!(queue.iter().next().unwrap().affinity(), affinity);
+    }
+
+    #[test_case]
+    fn sleepers_are_due_in_deadline_order() {
+        let mut queue = TaskQueue::new();
+
+        let early = TaskQueueEntry::new(ProcessID { process: 1, thread: 0 }, 0);
+        let late = TaskQueueEntry::new(ProcessID { process: 2, thread: 0 }, 0);
+
+        for (entry, deadline) in [(late, 20), (early, 10)] {
+            queue.insert(entry).unwrap();
+            assert!(queue.contains(entry.id()));
+
+            assert!(queue.block(entry.id(), WaitReason::Timer(deadline)).unwrap());
+            queue.add_sleeper(deadline, entry.id()).unwrap();
+            assert!(!queue.contains(entry.id()));
+        }
+
+        assert_eq!(queue.next_due_sleeper(9), None);
+        assert_eq!(queue.next_due_sleeper(15), Some(early.id()));
+
+        // peeking doesn't take it
+        assert_eq!(queue.next_due_sleeper(15), Some(early.id()));
+        assert_eq!(queue.take_due_sleeper(15).unwrap().map(|e| e.id()), Some(early.id()));
+        assert_eq!(queue.next_due_sleeper(15), None);
+
+        // a sleeper that's been woken up some other way doesn't give its entry back
+        queue.take_blocked(late.id()).unwrap();
+        assert_eq!(queue.next_due_sleeper(20), Some(late.id()));
+        assert!(queue.take_due_sleeper(20).unwrap().is_none());
+    }
+}
@@ -1,13 +1,17 @@
 use super::{cpu::ThreadID, get_cpus, get_process, queue::TaskQueueEntry};
 use crate::{
     arch::{get_thread_id, Registers},
-    mm::paging::PageDirectory,
+    mm::paging::{switch_to_kernel_page_dir, PageDirectory},
 };
-use log::{error, trace};
+use common::types::{Errno, Result};
+use log::{debug, error, trace};
 
-/// how much time each process gets before it's forcefully preempted
+/// how much time each process gets before it's forcefully preempted, unless the CPU thread's quantum has been changed
 pub const CPU_TIME_SLICE: u64 = 200; // 5 ms quantum
 
+/// the most tasks a CPU thread with nothing left to run will take from a busier one at once
+pub const STEAL_BATCH_SIZE: usize = 4;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ContextSwitchMode {
     /// normal context switch, places the current task back onto the queue
@@ -18,17 +22,33 @@ pub enum ContextSwitchMode {
 
     /// removes the current task and obviously doesn't place it back on the queue
     Remove,
+
+    /// gives up the rest of the current task's quantum, placing it back onto the queue behind every other task of the same priority.
+    /// if nothing else of the same or higher priority is waiting, the current task just keeps running
+    Yield,
 }
 
 /// performs a context switch
 fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers, manual: bool, was_in_kernel: bool, mode: ContextSwitchMode) {
     let cpu = cpu.unwrap_or_else(get_thread_id);
 
-    let thread = get_cpus().expect("CPUs not initialized").get_thread(cpu).expect("couldn't get CPU thread object");
+    let cpus = get_cpus().expect("CPUs not initialized");
+    let thread = cpus.get_thread(cpu).expect("couldn't get CPU thread object");
+
+    // if there's nothing waiting to run here, take some work off of a busier thread first.
+    // stealing locks this thread's queue itself, so it has to happen before it's locked below
+    if !was_in_kernel && thread.task_queue.lock().is_empty() {
+        cpus.steal_batch(cpu, STEAL_BATCH_SIZE);
+    }
 
     // get the task queue for this CPU
     let mut queue = thread.task_queue.lock();
 
+    // there's nothing to yield to, so leave everything (including the pending timer) as it is
+    if mode == ContextSwitchMode::Yield && !queue.has_peer_for_current() {
+        return;
+    }
+
     if manual {
         // remove the pending timer if there is one
         if let Some(expires) = queue.timer {
@@ -39,7 +59,13 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
         }
     }
 
-    if !was_in_kernel && (queue.len() > 0 || mode != ContextSwitchMode::Normal) {
+    // if the current task has been blocked, it has to be switched away from even if there's nothing else to run
+    let current_blocked = queue.current().is_some_and(|c| queue.is_blocked(c.id()));
+
+    // a task that isn't allowed to run here anymore, which has to be queued somewhere else once this queue is unlocked
+    let mut to_migrate = None;
+
+    if !was_in_kernel && (queue.len() > 0 || mode != ContextSwitchMode::Normal || current_blocked) {
         let mut remove_id = None;
         let mut can_load_task = true;
 
@@ -48,7 +74,7 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
             let current = queue.current()?;
 
             // make sure we'll be able to reinsert the process back into the queue if we need to
-            if mode == ContextSwitchMode::Normal && queue.try_reserve(1).is_err() {
+            if matches!(mode, ContextSwitchMode::Normal | ContextSwitchMode::Yield) && queue.try_reserve(1).is_err() {
                 can_load_task = false;
                 return None;
             }
@@ -65,9 +91,9 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
 
             if !thread.is_blocked {
                 match mode {
-                    ContextSwitchMode::Normal => {
+                    ContextSwitchMode::Normal | ContextSwitchMode::Yield => {
                         thread.cpu = Some(cpu);
-                        return Some((current.id(), thread.priority));
+                        return Some((current.id(), thread.effective_priority(), thread.priority_class, thread.affinity));
                     }
                     ContextSwitchMode::Block => {
                         thread.cpu = None;
@@ -103,11 +129,15 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
 
                         regs.transfer(&thread.register_queue.current().registers);
 
+                        thread.record_run_on(cpu);
+
+                        crate::arch::set_tls_base(thread.tls_base);
+
                         // todo: loading of other registers (x87, MMX, SSE, etc.)
 
                         process.page_directory.sync();
 
-                        if let Some((last_process_id, _)) = last_id.as_ref() {
+                        if let Some((last_process_id, _, _, _)) = last_id.as_ref() {
                             // is the process different? (i.e. not the same thread)
                             if last_process_id.process != id.process {
                                 // yes, switch the page directory
@@ -133,6 +163,11 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
             }
 
             if !has_task {
+                // don't sit on the page directory of whatever was running last, since it could be freed while we're idle
+                unsafe {
+                    switch_to_kernel_page_dir();
+                }
+
                 // this'll set the registers into a safe state so the cpu will return from the interrupt handler and just wait for an interrupt there,
                 // since for whatever reason just waiting here really messes things up
                 crate::arch::safely_halt_cpu(regs);
@@ -140,9 +175,16 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
 
             // put previous task back into queue if necessary
             match mode {
-                ContextSwitchMode::Normal => {
-                    if let Some((id, priority)) = last_id {
-                        queue.insert(TaskQueueEntry::new(id, priority)).unwrap();
+                ContextSwitchMode::Normal | ContextSwitchMode::Yield => {
+                    if let Some((id, priority, class, affinity)) = last_id {
+                        if get_cpus().expect("CPUs not initialized").is_allowed_on(affinity, cpu) {
+                            let mut entry = TaskQueueEntry::new(id, priority);
+                            entry.set_class(class);
+                            entry.set_affinity(affinity);
+                            queue.insert(entry).unwrap();
+                        } else {
+                            to_migrate = Some(id);
+                        }
                     }
                 }
                 ContextSwitchMode::Block => (),
@@ -166,9 +208,28 @@ fn _context_switch(timer_num: usize, cpu: Option<ThreadID>, regs: &mut Registers
     // requeue timer
     let timer = crate::timer::get_timer(timer_num).expect("unable to get timer for next context switch");
     let expires = timer
-        .add_timer_in(timer.hz() / CPU_TIME_SLICE, context_switch_timer)
+        .add_timer_in(thread.quantum(timer.hz()), context_switch_timer)
         .expect("unable to add timer callback for next context switch");
     queue.timer = Some(expires);
+
+    if let Some(id) = to_migrate {
+        // queueing it elsewhere locks another queue, so ours has to be unlocked first
+        drop(queue);
+
+        if let Err(err) = super::queue_process(id) {
+            error!("couldn't move task {id} off of CPU {cpu} after its affinity changed: {err:?}");
+
+            let mut entry = TaskQueueEntry::new(id, 0);
+            if let Some(mut process) = get_process(id.process) && let Some(thread) = process.threads.get_mut(id.thread as usize) {
+                entry.set_priority(thread.effective_priority());
+                entry.set_class(thread.priority_class);
+                entry.set_affinity(thread.affinity);
+            }
+
+            // leaving it here is better than losing it
+            let _ = thread.task_queue.lock().insert(entry);
+        }
+    }
 }
 
 /// timer callback run every time we want to perform a context switch
@@ -192,7 +253,7 @@ pub fn wait_for_context_switch(timer_num: usize, cpu: ThreadID) {
         // queue timer
         let timer = crate::timer::get_timer(timer_num).expect("unable to get timer for next context switch");
         let expires = timer
-            .add_timer_in(timer.hz() / CPU_TIME_SLICE, context_switch_timer)
+            .add_timer_in(thread.quantum(timer.hz()), context_switch_timer)
             .expect("unable to add timer callback for next context switch");
         queue.timer = Some(expires);
     }
@@ -200,8 +261,40 @@ pub fn wait_for_context_switch(timer_num: usize, cpu: ThreadID) {
     thread.leave_kernel();
 
     loop {
-        crate::arch::halt_until_interrupt();
+        thread.idle();
+    }
+}
+
+/// sets how many timer ticks each task gets to run for on the given CPU thread before it's preempted, or goes back to the default if it's 0
+///
+/// if the next context switch is further away than the new quantum, it's moved closer so the new quantum takes effect right away
+pub fn set_quantum(cpu: ThreadID, ticks: usize) -> Result<()> {
+    let thread = get_cpus().expect("CPUs not initialized").get_thread(cpu).ok_or(Errno::InvalidArgument)?;
+
+    thread.set_quantum(ticks);
+
+    let mut queue = thread.task_queue.lock();
+
+    if let Some(expires) = queue.timer {
+        let timer = crate::timer::get_timer(thread.timer).expect("unable to get timer for next context switch");
+        let new_expires = timer.jiffies() + thread.quantum(timer.hz());
+
+        if new_expires < expires {
+            timer.remove_timer(expires);
+
+            match timer.add_timer_at(new_expires, context_switch_timer) {
+                Ok(_) => queue.timer = Some(new_expires),
+                Err(_) => {
+                    // removing the old timer freed up space for this one, so this shouldn't ever fail
+                    timer.add_timer_at(expires, context_switch_timer).expect("unable to add timer callback for next context switch");
+                }
+            }
+        }
     }
+
+    debug!("set quantum of CPU {cpu} to {ticks} ticks");
+
+    Ok(())
 }
 
 /// cancels the next context switch timer
@@ -7,10 +7,11 @@ use super::{
 use crate::{
     arch::KERNEL_PAGE_DIR_SPLIT,
     mm::{
-        paging::{find_hole, get_kernel_page_dir, get_page_dir, get_page_manager, validate_region, PageDirectory},
+        paging::{find_hole, get_kernel_page_dir, get_page_dir, get_page_manager, validate_region, FrameTransaction, PageDirectory, ProcessOrKernelPageDir},
         shared::TempMemoryShare,
+        sync::BatchedPageDir,
     },
-    task::RegisterQueueEntry,
+    task::{queue::TaskQueueEntry, RegisterQueueEntry},
 };
 use alloc::vec::Vec;
 use common::types::{Errno, MmapAccess, MmapFlags, Result, Syscalls};
@@ -19,30 +20,41 @@ use log::{debug, error, trace};
 
 /// exits the current process, cleans up memory, and performs a context switch to the next process if applicable
 pub fn exit_current_process(thread_id: ThreadID, thread: &super::cpu::CPUThread, regs: &mut crate::arch::Registers) {
-    let cpus = get_cpus().expect("CPUs not initialized");
-
     // make sure we're not on the process' page directory
     unsafe {
         crate::mm::paging::get_kernel_page_dir().switch_to();
     }
 
     let id = thread.task_queue.lock().current().unwrap().id();
-    let num_threads = get_process(id.process).unwrap().threads.num_entries();
 
     debug!("exiting process {}", id.process);
 
     // perform context switch so we're not on this thread anymore
     manual_context_switch(thread.timer, Some(thread_id), regs, ContextSwitchMode::Remove);
 
-    // remove any more threads of the process
-    thread.task_queue.lock().remove_process(id.process);
+    kill_process(thread_id, id.process);
+}
 
-    if num_threads > 1 && cpus.cores.len() > 1 {
+/// kills every thread of the given process on every CPU, then frees the process and its address space once no CPU is using it anymore
+///
+/// the process can't be running on the current CPU, so [exit_current_process] should be used if it is
+pub fn kill_process(thread_id: ThreadID, process_id: u32) {
+    let cpus = get_cpus().expect("CPUs not initialized");
+
+    // remove any more threads of the process from our queue
+    if let Some(thread) = cpus.get_thread(thread_id) {
+        thread.task_queue.lock().remove_process(process_id);
+    }
+
+    // if there aren't any threads left, nothing else can be running the process
+    let has_threads = get_process(process_id).map(|p| p.threads.num_entries() > 0).unwrap_or(false);
+
+    if has_threads {
         // tell all other CPUs to kill this process
         for (core_num, core) in cpus.cores.iter().enumerate() {
             for (thread_num, thread) in core.threads.iter().enumerate() {
                 if (thread_id.core != core_num || thread_id.thread != thread_num) && thread.has_started() {
-                    thread.send_message(super::cpu::Message::KillProcess(id.process)).unwrap();
+                    thread.send_message(super::cpu::Message::KillProcess(process_id)).unwrap();
 
                     let id = ThreadID { core: core_num, thread: thread_num };
 
@@ -56,9 +68,21 @@ pub fn exit_current_process(thread_id: ThreadID, thread: &super::cpu::CPUThread,
                 }
             }
         }
+
+        // every CPU has switched away from the process by now, but make sure none of them still have its page directory loaded before it's freed
+        if let Some(page_dir) = get_process(process_id).map(|p| p.page_directory.task.tables_physical_addr) {
+            cpus.broadcast_urgent(super::cpu::UrgentMessage::ReleaseAddressSpace { page_dir });
+        }
     }
 
-    remove_process(id.process);
+    // anything this process was waiting on a reply from doesn't need its priority anymore
+    super::ipc::cancel_pending_replies(process_id, None);
+
+    // and anything waiting on a reply from this process won't be getting one
+    super::ipc::fail_replies_from(process_id, None);
+
+    // dropping the process frees all of its pages and its page directory
+    remove_process(process_id);
 }
 
 /// exits current thread, calls exit_current_process if it's the last remaining thread
@@ -69,6 +93,7 @@ pub fn exit_current_thread(thread_id: ThreadID, thread: &CPUThread, regs: &mut c
 
     if num_threads > 1 {
         manual_context_switch(thread.timer, Some(thread_id), regs, ContextSwitchMode::Remove);
+        super::ipc::fail_replies_from(id.process, Some(id.thread));
     } else {
         exit_current_process(thread_id, thread, regs);
     }
@@ -81,25 +106,34 @@ pub fn fork_current_process(thread: &CPUThread, regs: &mut crate::arch::Register
     let id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
 
     let priority;
+    let priority_class;
+    let affinity;
     let is_blocked;
+    let tls_base;
     let message_handlers_clone;
+    let stack;
 
     {
         let process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
         let thread = process.threads.get(id.thread as usize).ok_or(Errno::NoSuchProcess)?;
 
         priority = thread.priority;
+        priority_class = thread.priority_class;
+        affinity = thread.affinity;
         is_blocked = thread.is_blocked;
+        tls_base = thread.tls_base;
 
         // TODO: allow clone() to fail gracefully here
         message_handlers_clone = process.message_handlers.clone();
+        stack = process.stack;
     }
 
     // copy page directory
     trace!("copying page directory");
-    let mut new_orig_page_dir = crate::arch::PageDirectory::new();
-    let mut new_fork_page_dir = crate::arch::PageDirectory::new();
+    let mut new_orig_page_dir = crate::arch::PageDirectory::new()?;
+    let mut new_fork_page_dir = crate::arch::PageDirectory::new()?;
     let mut referenced_pages = Vec::new();
+    let mut shared_pages = Vec::new();
 
     debug!(
         "new_orig_page_dir @ {:#x}, new_fork_page_dir @ {:#x}",
@@ -109,11 +143,18 @@ pub fn fork_current_process(thread: &CPUThread, regs: &mut crate::arch::Register
     let page_size = crate::arch::PageDirectory::PAGE_SIZE;
 
     for addr in (0..KERNEL_PAGE_DIR_SPLIT).step_by(page_size) {
+        // swapped out pages can't be shared between processes, so bring them back in before copying them
+        crate::mm::swap::swap_in(&mut ProcessOrKernelPageDir::Process(id.process), addr)?;
+
         let mut page = get_process(id.process).ok_or(Errno::NoSuchProcess)?.page_directory.get_page(addr);
 
         // does this page exist?
         if let Some(page) = page.as_mut() {
-            if !page.copy_on_write && !page.shared {
+            if page.present && page.referenced && !page.shared {
+                // this page is already reference counted (i.e. it's left over from an earlier fork), so the new process just adds another reference to it
+                referenced_pages.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+                referenced_pages.push((page.addr, 1));
+            } else if page.present && !page.copy_on_write && !page.shared {
                 trace!("modifying page {addr:#x} (phys {:#x})", page.addr);
 
                 // if this page is writable, set it as non-writable and set it to copy on write
@@ -123,12 +164,18 @@ pub fn fork_current_process(thread: &CPUThread, regs: &mut crate::arch::Register
                 if page.writable {
                     page.writable = false;
                     page.copy_on_write = true;
-                    page.referenced = true;
                 }
 
+                // both processes now reference this page, so it can't be freed until they've both let go of it
+                page.referenced = true;
+
                 // add this page's address to our list of referenced pages
                 referenced_pages.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
-                referenced_pages.push(page.addr);
+                referenced_pages.push((page.addr, 2));
+            } else if page.present && page.shared {
+                // shared pages are mapped as-is into both processes, so the new process needs its own reference to the shared area
+                shared_pages.try_reserve(1).map_err(|_| Errno::OutOfMemory)?;
+                shared_pages.push(page.addr);
             }
 
             // set page in page directories
@@ -156,6 +203,7 @@ pub fn fork_current_process(thread: &CPUThread, regs: &mut crate::arch::Register
             }
         }
 
+        process.page_directory.recount();
         process.page_directory.switch_to();
     }
 
@@ -174,18 +222,29 @@ pub fn fork_current_process(thread: &CPUThread, regs: &mut crate::arch::Register
             .add(crate::task::Thread {
                 register_queue: super::RegisterQueue::new(RegisterQueueEntry::from_registers(*regs)),
                 priority,
+                priority_class,
+                affinity,
                 cpu: None,
+                last_ran_on: None,
+                migrations: 0,
                 is_blocked,
+                tls_base,
+                donated_priorities: Vec::new(),
             })
             .map_err(|_| Errno::OutOfMemory)?;
 
         process.message_handlers = message_handlers_clone;
+        process.stack = stack;
     }
 
     // update the page reference counter with our new pages
-    for addr in referenced_pages.iter() {
+    for (addr, references) in referenced_pages.iter() {
         // FIXME: BTreeMap used in the page ref counter doesn't expect alloc to fail, this can probably crash the kernel if we run out of memory!
-        crate::mm::paging::PAGE_REF_COUNTER.lock().add_references(*addr, 2);
+        crate::mm::paging::PAGE_REF_COUNTER.lock().add_references(*addr, *references);
+    }
+
+    for addr in shared_pages.iter() {
+        crate::mm::shared::add_shared_reference(*addr);
     }
 
     // queue new process for execution
@@ -285,28 +344,30 @@ fn syscall_mmap(thread: &CPUThread, shm_id: usize, mut start_addr: usize, mut le
         }
     }
 
+    let page_at = |phys: u64, executable: bool, shared: bool| crate::mm::paging::PageFrame {
+        addr: phys,
+        present: true,
+        user_mode: true,
+        writable: (protection & MmapAccess::Write).bits() > 0,
+        copy_on_write: (flags & MmapFlags::CopyOnWrite).bits() > 0,
+        executable,
+        referenced: shared,
+        shared,
+        ..Default::default()
+    };
+
     let map_memory = |physical_addresses: &[u64], shared: bool| -> Result<()> {
         for (index, addr) in (start_addr..=end_addr).step_by(page_size).enumerate() {
-            if index > physical_addresses.len() {
+            if index >= physical_addresses.len() {
                 break;
             }
 
-            get_process(id.process)
-                .ok_or(Errno::NoSuchProcess)?
+            let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
+            let executable = process.page_directory.executable_if((protection & MmapAccess::Execute).bits() > 0);
+
+            process
                 .page_directory
-                .set_page(
-                    addr,
-                    Some(crate::mm::paging::PageFrame {
-                        addr: physical_addresses[index],
-                        present: true,
-                        user_mode: true,
-                        writable: (protection & MmapAccess::Write).bits() > 0,
-                        copy_on_write: (flags & MmapFlags::CopyOnWrite).bits() > 0,
-                        executable: (protection & MmapAccess::Execute).bits() > 0,
-                        referenced: shared,
-                        shared,
-                    }),
-                )
+                .set_page(addr, Some(page_at(physical_addresses[index], executable, shared)))
                 .map_err(|_| Errno::OutOfMemory)?;
         }
 
@@ -318,25 +379,33 @@ fn syscall_mmap(thread: &CPUThread, shm_id: usize, mut start_addr: usize, mut le
     if (flags & MmapFlags::Anonymous).bits() > 0 {
         // anonymous flag is set, map in new memory
 
-        let mut physical_addresses = Vec::new();
-        physical_addresses.try_reserve(num_pages).map_err(|_| Errno::OutOfMemory)?;
-
-        // allocate new memory
-        for _i in 0..num_pages {
-            match get_page_manager().alloc_frame() {
-                Ok(addr) => physical_addresses.push(addr),
-                Err(_) => {
-                    // free memory we're not gonna be using
-                    for addr in physical_addresses.iter() {
-                        get_page_manager().set_frame_free(*addr);
-                    }
+        let mut transaction = FrameTransaction::new();
 
-                    return Err(Errno::OutOfMemory);
-                }
+        let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
+        let executable = process.page_directory.executable_if((protection & MmapAccess::Execute).bits() > 0);
+
+        // allocate new memory and map it in
+        let mut map = || -> Result<()> {
+            for addr in (start_addr..=end_addr).step_by(page_size) {
+                let phys = transaction.alloc_frame(&mut get_page_manager())?;
+                transaction.map_page(&mut process.page_directory, addr, page_at(phys, executable, false))?;
             }
+
+            Ok(())
+        };
+
+        if let Err(err) = map() {
+            // put back whatever was there so nothing is left pointing at the frames we're about to free
+            transaction.unmap_pages(&mut process.page_directory);
+            drop(process);
+
+            transaction.rollback(&mut get_page_manager());
+
+            return Err(err);
         }
 
-        map_memory(&physical_addresses, false)?;
+        drop(process);
+        transaction.commit(&mut get_page_manager());
 
         // zero out new mapping
         let slice = unsafe { core::slice::from_raw_parts_mut(start_addr as *mut u8, end_addr - start_addr) };
@@ -369,7 +438,7 @@ fn syscall_mmap(thread: &CPUThread, shm_id: usize, mut start_addr: usize, mut le
 
         map_memory(&shm.physical_addresses, true)?;
 
-        shm.references += num_pages;
+        shm.add_references(num_pages);
 
         for page in original_pages {
             crate::mm::paging::free_page(page);
@@ -385,12 +454,15 @@ fn syscall_mmap(thread: &CPUThread, shm_id: usize, mut start_addr: usize, mut le
 
         map_memory(&shm.physical_addresses, true)?;
 
-        shm.references += num_pages;
+        shm.add_references(num_pages);
     }
 
     Ok(start_addr)
 }
 
+/// how many pages syscall_unmap unmaps before telling other CPUs about them
+const UNMAP_CHUNK_PAGES: usize = 64;
+
 fn syscall_unmap(thread: &CPUThread, address: usize, length: usize) -> Result<usize> {
     let page_size = crate::arch::PageDirectory::PAGE_SIZE;
 
@@ -415,20 +487,41 @@ fn syscall_unmap(thread: &CPUThread, address: usize, length: usize) -> Result<us
     let id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
     let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
 
-    // unmap memory
-    for addr in (start_addr..=end_addr).step_by(page_size) {
-        if let Some(page) = process.page_directory.get_page(addr) {
-            process.page_directory.set_page(addr, None).map_err(|_| Errno::OutOfMemory)?;
+    // unmap memory in chunks, so other CPUs only have to be told about each chunk once. pages can't be freed until they have been
+    let mut addrs = (start_addr..=end_addr).step_by(page_size).peekable();
+
+    while addrs.peek().is_some() {
+        let mut unmapped: [Option<crate::mm::paging::PageFrame>; UNMAP_CHUNK_PAGES] = [None; UNMAP_CHUNK_PAGES];
+        let mut result = Ok(0);
+
+        let mut batched = BatchedPageDir::new(&mut process.page_directory);
+
+        for (slot, addr) in unmapped.iter_mut().zip(&mut addrs) {
+            if let Some(page) = batched.get_page(addr) {
+                if batched.set_page(addr, None).is_err() {
+                    result = Err(Errno::OutOfMemory);
+                    break;
+                }
 
+                *slot = Some(page);
+            }
+        }
+
+        // the shootdowns for this chunk are sent here
+        drop(batched);
+
+        for page in unmapped.into_iter().flatten() {
             crate::mm::paging::free_page(page);
         }
+
+        result?;
     }
 
     Ok(0)
 }
 
 fn syscall_getpid(thread_id: ThreadID, thread: &CPUThread, addr: usize) -> Result<usize> {
-    if !validate_region(&get_page_dir(Some(thread_id)), addr, size_of::<ProcessID>()) {
+    if !validate_region(&mut get_page_dir(Some(thread_id)), addr, size_of::<ProcessID>()) {
         Err(Errno::BadAddress)
     } else {
         let id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
@@ -476,56 +569,73 @@ fn syscall_share_memory(thread: &CPUThread, address: usize, length: usize, acces
     entries.share(access).map(|id| id as usize)
 }
 
-fn syscall_send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, target: usize, message: usize, data_start: usize, data_len: usize) -> Result<()> {
-    // validate arguments
-    if target > super::HIGHEST_PROCESS_NUM as usize {
-        return Err(Errno::ValueOverflow);
+/// copies the given message data out of the calling process's memory into a freshly allocated page, returning its physical address and length
+fn copy_message_data(thread_id: ThreadID, data_start: usize, data_len: usize) -> Result<Option<(u64, usize)>> {
+    if data_start == 0 && data_len > 0 {
+        return Err(Errno::BadAddress);
     }
 
-    if message > super::ipc::HIGHEST_MESSAGE_NUM as usize {
+    if data_start == 0 || data_len == 0 {
+        return Ok(None);
+    }
+
+    if usize::MAX - data_start < data_len {
         return Err(Errno::ValueOverflow);
     }
 
-    let message = message as u32;
+    if data_start >= KERNEL_PAGE_DIR_SPLIT || (data_start + data_len) >= KERNEL_PAGE_DIR_SPLIT {
+        return Err(Errno::InvalidArgument);
+    }
 
-    if data_start == 0 && data_len > 0 {
+    if data_len > super::ipc::MAX_MESSAGE_DATA {
+        return Err(Errno::InvalidArgument);
+    }
+
+    let mut page_dir = get_page_dir(Some(thread_id));
+
+    // the data is copied straight out of the sender's memory below, so make sure all of it is actually there first
+    if !validate_region(&mut page_dir, data_start, data_len) {
         return Err(Errno::BadAddress);
     }
 
-    // allocate memory for and copy data
-    let data = if data_start > 0 && data_len > 0 {
-        if usize::MAX - data_start < data_len {
-            return Err(Errno::ValueOverflow);
-        }
+    let addr = crate::mm::paging::alloc_frame_or_reclaim().map_err(|_| Errno::OutOfMemory)?;
 
-        if data_start >= KERNEL_PAGE_DIR_SPLIT || (data_start + data_len) >= KERNEL_PAGE_DIR_SPLIT {
-            return Err(Errno::InvalidArgument);
-        }
+    let res = unsafe {
+        crate::mm::paging::map_memory(&mut page_dir, &[addr], |s| {
+            (&mut s[..data_len]).copy_from_slice(core::slice::from_raw_parts(data_start as *const u8, data_len));
+            for i in (&mut s[data_len..]).iter_mut() {
+                *i = 0;
+            }
+        })
+    };
 
-        if data_len > crate::arch::PageDirectory::PAGE_SIZE {
-            return Err(Errno::InvalidArgument);
-        }
+    if let Err(err) = res {
+        get_page_manager().set_frame_free(addr);
+        return Err(err.into());
+    }
 
-        let mut page_dir = get_page_dir(Some(thread_id));
+    Ok(Some((addr, data_len)))
+}
 
-        let addr = get_page_manager().alloc_frame().map_err(|_| Errno::OutOfMemory)?;
+/// validates the target process and message number passed to one of the message sending syscalls
+fn check_message_target(target: usize, message: usize) -> Result<(u32, u32)> {
+    if target > super::HIGHEST_PROCESS_NUM as usize {
+        return Err(Errno::ValueOverflow);
+    }
 
-        unsafe {
-            crate::mm::paging::map_memory(&mut page_dir, &[addr], |s| {
-                (&mut s[..data_len]).copy_from_slice(core::slice::from_raw_parts(data_start as *const u8, data_len));
-                for i in (&mut s[data_len..]).iter_mut() {
-                    *i = 0;
-                }
-            })?;
-        }
+    if message > super::ipc::HIGHEST_MESSAGE_NUM as usize {
+        return Err(Errno::ValueOverflow);
+    }
 
-        Some((addr, data_len))
-    } else {
-        None
-    };
+    Ok((target as u32, message as u32))
+}
+
+fn syscall_send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, target: usize, message: usize, data_start: usize, data_len: usize) -> Result<()> {
+    let (target, message) = check_message_target(target, message)?;
+    let data = copy_message_data(thread_id, data_start, data_len)?;
 
     // send message
-    match super::ipc::send_message(thread_id, cpu_thread, regs, target as u32, message, data) {
+    match super::ipc::send_message(thread_id, cpu_thread, regs, target, message, data, None) {
         Ok(_) => Ok(()),
         Err(err) => {
             if let Some((addr, _)) = data {
@@ -536,6 +646,64 @@ fn syscall_send_message(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut
     }
 }
 
+/// sends a message and blocks the calling thread until it's replied to. the token the reply will arrive with is returned, and the reply itself can be
+/// collected with the take reply syscall
+fn syscall_send_and_wait(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, target: usize, message: usize, data_start: usize, data_len: usize) -> Result<()> {
+    let (target, message) = check_message_target(target, message)?;
+    let data = copy_message_data(thread_id, data_start, data_len)?;
+
+    if let Err(err) = super::ipc::send_and_wait(thread_id, cpu_thread, regs, target, message, data) {
+        if let Some((addr, _)) = data {
+            get_page_manager().set_frame_free(addr);
+        }
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// replies to a message that was sent with the send and wait syscall, waking up the thread waiting for it
+fn syscall_reply(thread_id: ThreadID, to: usize, token: usize, data_start: usize, data_len: usize) -> Result<()> {
+    let to: u32 = to.try_into().map_err(|_| Errno::NoSuchProcess)?;
+    let token: u32 = token.try_into().map_err(|_| Errno::InvalidArgument)?;
+    let data = copy_message_data(thread_id, data_start, data_len)?;
+
+    if let Err(err) = super::ipc::send_reply(thread_id, to, token, data) {
+        if let Some((addr, _)) = data {
+            get_page_manager().set_frame_free(addr);
+        }
+
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// copies the data sent with the reply with the given token into the given buffer, returning how many bytes were sent with it.
+/// if more data was sent than fits in the buffer, the rest is discarded
+fn syscall_take_reply(thread_id: ThreadID, cpu_thread: &CPUThread, token: usize, buf: usize, len: usize) -> Result<usize> {
+    let token: u32 = token.try_into().map_err(|_| Errno::InvalidArgument)?;
+
+    if len > 0 && (buf.checked_add(len).map(|end| end > KERNEL_PAGE_DIR_SPLIT).unwrap_or(true) || !validate_region(&mut get_page_dir(Some(thread_id)), buf, len)) {
+        return Err(Errno::BadAddress);
+    }
+
+    let caller = cpu_thread.task_queue.lock().current().map(|c| c.id()).ok_or(Errno::NoSuchProcess)?;
+
+    let Some((addr, data_len)) = super::ipc::take_reply(caller.process, token)? else {
+        return Ok(0);
+    };
+
+    let size = len.min(data_len);
+
+    let res = unsafe { crate::mm::paging::map_memory(&mut get_page_dir(Some(thread_id)), &[addr], |s| core::slice::from_raw_parts_mut(buf as *mut u8, size).copy_from_slice(&s[..size])) };
+
+    get_page_manager().set_frame_free(addr);
+
+    res.map(|_| data_len).map_err(|err| err.into())
+}
+
 fn syscall_set_message_handler(thread_id: ThreadID, thread: &CPUThread, message: usize, priority: isize, function_ptr: usize, has_data: bool) -> Result<()> {
     if message > super::ipc::HIGHEST_MESSAGE_NUM as usize {
         return Err(Errno::ValueOverflow);
@@ -545,7 +713,7 @@ fn syscall_set_message_handler(thread_id: ThreadID, thread: &CPUThread, message:
 
     let priority = priority.try_into().map_err(|_| Errno::ValueOverflow)?;
 
-    if !validate_region(&get_page_dir(Some(thread_id)), function_ptr, 1) {
+    if !validate_region(&mut get_page_dir(Some(thread_id)), function_ptr, 1) {
         return Err(Errno::BadAddress);
     }
 
@@ -591,6 +759,154 @@ fn syscall_exit_message_handler(thread: &CPUThread, regs: &mut crate::arch::Regi
     Ok(())
 }
 
+/// points the current thread's thread-local storage segment at the given address
+fn syscall_set_tls(thread: &CPUThread, base: usize) -> Result<usize> {
+    if base >= KERNEL_PAGE_DIR_SPLIT {
+        return Err(Errno::BadAddress);
+    }
+
+    let id = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    get_process(id.process).ok_or(Errno::NoSuchProcess)?.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?.tls_base = base;
+
+    // the context switch code only updates the segment when switching to a thread, so it has to be updated here too
+    crate::arch::set_tls_base(base);
+
+    Ok(0)
+}
+
+/// sets the priority of the given thread. processes that aren't privileged can only lower the priority of their own threads
+fn syscall_set_priority(thread: &CPUThread, process_id: usize, thread_id: usize, priority: usize) -> Result<usize> {
+    // the priority is sign extended to fill the whole register, so anything that doesn't fit is an error rather than being truncated
+    let priority = i8::try_from(priority as isize).map_err(|_| Errno::InvalidArgument)?;
+
+    if !(TaskQueueEntry::MIN_PRIORITY..=TaskQueueEntry::MAX_PRIORITY).contains(&priority) {
+        return Err(Errno::InvalidArgument);
+    }
+
+    let caller = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    let id = ProcessID {
+        process: process_id.try_into().map_err(|_| Errno::NoSuchProcess)?,
+        thread: thread_id.try_into().map_err(|_| Errno::NoSuchProcess)?,
+    };
+
+    if id.process != caller.process && !super::is_privileged(caller.process) {
+        return Err(Errno::PermissionDenied);
+    }
+
+    let effective_priority = {
+        let mut process = get_process(id.process).ok_or(Errno::NoSuchProcess)?;
+        let target = process.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?;
+
+        if priority > target.priority && !super::is_privileged(caller.process) {
+            return Err(Errno::PermissionDenied);
+        }
+
+        target.priority = priority;
+        target.effective_priority()
+    };
+
+    // the process has to be unlocked before touching the task queues, and the queue it's in gets re-sorted so this takes effect right away
+    super::update_task_priority(id, effective_priority);
+
+    Ok(0)
+}
+
+/// sets which CPU threads the given thread is allowed to run on. bit n of the mask allows the CPU thread with index n (see [super::cpu::CPU::thread_index]),
+/// and a mask of 0 lets it run anywhere
+///
+/// processes that aren't privileged can only change the affinity of their own threads
+fn syscall_set_affinity(thread: &CPUThread, process_id: usize, thread_id: usize, mask: usize) -> Result<usize> {
+    let caller = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    let id = ProcessID {
+        process: process_id.try_into().map_err(|_| Errno::NoSuchProcess)?,
+        thread: thread_id.try_into().map_err(|_| Errno::NoSuchProcess)?,
+    };
+
+    if id.process != caller.process && !super::is_privileged(caller.process) {
+        return Err(Errno::PermissionDenied);
+    }
+
+    let affinity = if mask == 0 { None } else { Some(super::cpu::AffinityMask::from_bits(mask as u64)) };
+
+    // a thread that can't run anywhere would never run again
+    if !get_cpus().expect("CPUs not initialized").is_allowed_anywhere(affinity) {
+        return Err(Errno::InvalidArgument);
+    }
+
+    get_process(id.process).ok_or(Errno::NoSuchProcess)?.threads.get_mut(id.thread as usize).ok_or(Errno::NoSuchProcess)?.affinity = affinity;
+
+    // the process has to be unlocked before touching the task queues
+    super::update_task_affinity(id, affinity)?;
+
+    Ok(0)
+}
+
+/// blocks the calling thread for at least the given number of milliseconds, rounded up to the next tick of the timer of the CPU thread it's on
+fn syscall_sleep(thread_id: ThreadID, cpu_thread: &CPUThread, regs: &mut crate::arch::Registers, ms: usize) -> Result<()> {
+    let caller = cpu_thread.task_queue.lock().current().map(|c| c.id()).ok_or(Errno::NoSuchProcess)?;
+
+    let hz = crate::timer::get_timer(cpu_thread.timer).map(|timer| timer.hz()).ok_or(Errno::NoSuchDevice)?;
+    let ticks = (ms as u64).saturating_mul(hz).div_ceil(1000);
+
+    super::sleep_for(caller, ticks)?;
+
+    regs.syscall_return(Ok(0));
+
+    manual_context_switch(cpu_thread.timer, Some(thread_id), regs, ContextSwitchMode::Normal);
+
+    Ok(())
+}
+
+/// sets how many timer ticks each task gets to run for on the CPU thread with the given index (see [super::cpu::CPU::thread_index]) before it's preempted,
+/// or goes back to the default if it's 0. only privileged processes can do this, since it affects everything running on that thread
+fn syscall_set_quantum(thread: &CPUThread, index: usize, ticks: usize) -> Result<usize> {
+    let caller = thread.task_queue.lock().current().ok_or(Errno::NoSuchProcess)?.id();
+
+    if !super::is_privileged(caller.process) {
+        return Err(Errno::PermissionDenied);
+    }
+
+    let cpu = get_cpus().expect("CPUs not initialized").thread_from_index(index).ok_or(Errno::InvalidArgument)?;
+
+    super::switch::set_quantum(cpu, ticks)?;
+
+    Ok(0)
+}
+
+/// gets the priority of the given thread, not including any priority donated to it
+fn syscall_get_priority(process_id: usize, thread_id: usize) -> Result<usize> {
+    let process_id: u32 = process_id.try_into().map_err(|_| Errno::NoSuchProcess)?;
+
+    let priority = get_process(process_id).ok_or(Errno::NoSuchProcess)?.threads.get(thread_id).ok_or(Errno::NoSuchProcess)?.priority;
+
+    Ok(priority as isize as usize)
+}
+
+/// copies the contents of /proc/sched (see [super::sched_info]) starting at the given offset into the given buffer, returning how many bytes were copied.
+/// 0 is returned once the end has been reached
+fn syscall_read_sched_info(thread_id: ThreadID, buf: usize, len: usize, offset: usize) -> Result<usize> {
+    if len == 0 {
+        return Ok(0);
+    }
+
+    if buf.checked_add(len).map(|end| end > KERNEL_PAGE_DIR_SPLIT).unwrap_or(true) || !validate_region(&mut get_page_dir(Some(thread_id)), buf, len) {
+        return Err(Errno::BadAddress);
+    }
+
+    let info = super::sched_info();
+    let contents = info.as_bytes().get(offset..).unwrap_or(&[]);
+    let size = len.min(contents.len());
+
+    unsafe {
+        core::slice::from_raw_parts_mut(buf as *mut u8, size).copy_from_slice(&contents[..size]);
+    }
+
+    Ok(size)
+}
+
 /// low-level syscall handler. handles the parsing, execution, and error handling of syscalls
 pub fn syscall_handler(regs: &mut crate::arch::Registers, num: u32, arg0: usize, arg1: usize, arg2: usize, arg3: usize) {
     let thread_id = crate::arch::get_thread_id();
@@ -639,6 +955,44 @@ pub fn syscall_handler(regs: &mut crate::arch::Registers, num: u32, arg0: usize,
                 regs.syscall_return(res);
             }
         }
+        Ok(Syscalls::SetTLS) => regs.syscall_return(syscall_set_tls(thread, arg0)),
+        Ok(Syscalls::SetPriority) => regs.syscall_return(syscall_set_priority(thread, arg0, arg1, arg2)),
+        Ok(Syscalls::GetPriority) => regs.syscall_return(syscall_get_priority(arg0, arg1)),
+        Ok(Syscalls::FutexWait) => {
+            // the return value is only set here on error, since on success the thread has been blocked and the registers belong to whatever's running now
+            if let Err(err) = super::futex::wait(thread_id, thread, regs, arg0, arg1 as u32) {
+                regs.syscall_return(Err(err));
+            }
+        }
+        Ok(Syscalls::FutexWake) => regs.syscall_return(super::futex::wake(thread_id, thread, arg0, arg1)),
+        Ok(Syscalls::ReceiveMessage) => {
+            // same as above
+            if let Err(err) = super::ipc::receive_message(thread_id, thread, regs) {
+                regs.syscall_return(Err(err));
+            }
+        }
+        Ok(Syscalls::Yield) => {
+            // the return value has to be set before switching, since the registers could belong to another thread afterwards
+            regs.syscall_return(Ok(0));
+            manual_context_switch(thread.timer, Some(thread_id), regs, ContextSwitchMode::Yield);
+        }
+        Ok(Syscalls::ReadSchedInfo) => regs.syscall_return(syscall_read_sched_info(thread_id, arg0, arg1, arg2)),
+        Ok(Syscalls::SetAffinity) => regs.syscall_return(syscall_set_affinity(thread, arg0, arg1, arg2)),
+        Ok(Syscalls::Sleep) => {
+            // same as FutexWait
+            if let Err(err) = syscall_sleep(thread_id, thread, regs, arg0) {
+                regs.syscall_return(Err(err));
+            }
+        }
+        Ok(Syscalls::SendAndWait) => {
+            // same as FutexWait
+            if let Err(err) = syscall_send_and_wait(thread_id, thread, regs, arg0, arg1, arg2, arg3) {
+                regs.syscall_return(Err(err));
+            }
+        }
+        Ok(Syscalls::Reply) => regs.syscall_return(syscall_reply(thread_id, arg0, arg1, arg2, arg3).map(|_| 0)),
+        Ok(Syscalls::TakeReply) => regs.syscall_return(syscall_take_reply(thread_id, thread, arg0, arg1, arg2)),
+        Ok(Syscalls::SetQuantum) => regs.syscall_return(syscall_set_quantum(thread, arg0, arg1)),
         Err(err) => {
             // invalid syscall, yoink the thread
             let pid = thread.task_queue.lock().current().unwrap().id();
@@ -0,0 +1,40 @@
+//! tests
+//!
+//! these run in the kernel itself once all the CPUs are up, in place of init. each test is a plain function marked with `#[test_case]`,
+//! usually in a `tests` module at the bottom of whatever it's testing
+
+use log::info;
+
+/// exit code passed to emulators once every test has passed, distinct from the ones `halt` and panics use
+pub const TEST_SUCCESS_EXIT_CODE: u8 = 0x10;
+
+/// custom test runner to run all tests
+pub fn test_runner(tests: &[&dyn Testable]) {
+    // a failed test panics, which should end the run with a distinct exit code instead of leaving the machine hanging
+    crate::set_panic_action(crate::PanicAction::Exit);
+
+    info!("=== Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    info!("=== Done");
+
+    unsafe {
+        crate::arch::exit_emulator(TEST_SUCCESS_EXIT_CODE);
+    }
+}
+
+/// custom testable trait
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T> Testable for T
+where T: Fn()
+{
+    fn run(&self) {
+        info!("--- {}...", core::any::type_name::<T>());
+        self();
+        info!("--- ok");
+    }
+}
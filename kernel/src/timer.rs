@@ -80,6 +80,7 @@ impl TimerState {
     /// ticks the timer without running any callbacks (may be useful if things are locked? idk)
     pub fn tick_no_callbacks(&mut self) {
         self.jiffies += 1;
+        crate::clock::tick(self.num);
     }
 
     /// ticks the timer, calling callbacks if it's not locked
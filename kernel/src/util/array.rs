@@ -125,19 +125,58 @@ impl BitSet {
     /// create a bitset and allocate memory for it
     pub fn new(size: usize) -> Self {
         Self {
-            array: RawPtrArray::new((size + 31) / 32), // always round up
+            array: RawPtrArray::new(size.div_ceil(32)),
             size,
             bits_used: 0,
         }
     }
 
     /// place a bitset at an existing location in memory
-    pub fn place_at(addr: *mut u32, size: usize) -> Self {
-        Self {
-            array: RawPtrArray::place_at(addr, (size + 31) / 32),
+    ///
+    /// `words` is how many u32s are available at `addr`. if that isn't enough to hold `size` bits, None is returned
+    pub fn place_at(addr: *mut u32, words: usize, size: usize) -> Option<Self> {
+        if words < size.div_ceil(32) {
+            return None;
+        }
+
+        Some(Self {
+            array: RawPtrArray::place_at(addr, size.div_ceil(32)),
             size,
             bits_used: 0,
+        })
+    }
+
+    /// creates a bitset holding `num_bits` bits from a copy of the given bytes, like the ones returned by `as_bytes()`
+    ///
+    /// bits past `num_bits` are ignored and missing bytes are treated as unset, and the number of set bits is recounted from the data
+    pub fn from_bytes(bytes: &[u8], num_bits: usize) -> Self {
+        let mut set = Self::new(num_bits);
+
+        let words = set.array.to_slice_mut();
+
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(size_of::<u32>())) {
+            let mut buf = [0; size_of::<u32>()];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            *word = u32::from_ne_bytes(buf);
         }
+
+        // clear anything past the end of the set so it doesn't get counted
+        if num_bits % 32 != 0 {
+            if let Some(last) = words.last_mut() {
+                *last &= (1 << (num_bits % 32)) - 1;
+            }
+        }
+
+        set.bits_used = words.iter().map(|word| word.count_ones() as usize).sum();
+
+        set
+    }
+
+    /// returns the raw contents of this bitset, suitable for `from_bytes()`
+    pub fn as_bytes(&self) -> &[u8] {
+        let words = self.array.to_slice();
+
+        unsafe { slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * size_of::<u32>()) }
     }
 
     /// set a bit in the set
@@ -180,6 +219,35 @@ impl BitSet {
         self.bits_used = 0;
     }
 
+    /// returns an iterator over the index and mask of every word covering the range `start..start + len`, clamped to the size of the set
+    fn words_in_range(&self, start: usize, len: usize) -> WordRange {
+        WordRange {
+            pos: start,
+            end: start.saturating_add(len).min(self.size),
+        }
+    }
+
+    /// set all the bits in the range `start..start + len`
+    pub fn set_range(&mut self, start: usize, len: usize) {
+        for (idx, mask) in self.words_in_range(start, len) {
+            self.bits_used += (mask & !self.array[idx]).count_ones() as usize;
+            self.array[idx] |= mask;
+        }
+    }
+
+    /// clear all the bits in the range `start..start + len`
+    pub fn clear_range(&mut self, start: usize, len: usize) {
+        for (idx, mask) in self.words_in_range(start, len) {
+            self.bits_used -= (mask & self.array[idx]).count_ones() as usize;
+            self.array[idx] &= !mask;
+        }
+    }
+
+    /// counts how many bits are set in the range `start..start + len`
+    pub fn count_set_in_range(&self, start: usize, len: usize) -> usize {
+        self.words_in_range(start, len).map(|(idx, mask)| (self.array[idx] & mask).count_ones() as usize).sum()
+    }
+
     /// check if bit is set
     pub fn test(&self, addr: usize) -> bool {
         if addr < self.size {
@@ -200,13 +268,67 @@ impl BitSet {
                 for j in 0..32 {
                     let bit = 1 << j;
                     if f & bit == 0 {
-                        return Some(i * 32 + j);
+                        // bits past the end of the set aren't ours to hand out
+                        return if i * 32 + j < self.size { Some(i * 32 + j) } else { None };
                     }
                 }
             }
         }
         None
     }
+
+    /// gets first unset bit at or after the given index
+    pub fn first_unset_from(&self, start: usize) -> Option<usize> {
+        if start >= self.size {
+            return None;
+        }
+
+        let mut i = start / 32;
+
+        // pretend the bits below our starting index are set so they get skipped over
+        let mut f = self.array[i] | ((1 << (start % 32)) - 1);
+
+        loop {
+            if f != 0xffffffff {
+                let bit = i * 32 + (!f).trailing_zeros() as usize;
+
+                return if bit < self.size { Some(bit) } else { None };
+            }
+
+            i += 1;
+
+            if i >= self.array.size {
+                return None;
+            }
+
+            f = self.array[i];
+        }
+    }
+}
+
+/// iterates over the words in a range of bits in a BitSet, giving the index of each word and a mask of the bits in the range
+struct WordRange {
+    pos: usize,
+    end: usize,
+}
+
+impl Iterator for WordRange {
+    type Item = (usize, u32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.end {
+            return None;
+        }
+
+        let off = self.pos % 32;
+        let bits = (32 - off).min(self.end - self.pos);
+        let mask = if bits == 32 { 0xffffffff } else { ((1 << bits) - 1) << off };
+        let idx = self.pos / 32;
+
+        self.pos += bits;
+
+        Some((idx, mask))
+    }
 }
 
 impl fmt::Debug for BitSet {
@@ -374,6 +496,11 @@ impl<T> ConsistentIndexArray<T> {
     pub fn num_entries(&self) -> usize {
         self.bit_set.bits_used
     }
+
+    /// the highest index that could have an item in it, useful for iterating over every item
+    pub fn highest_index(&self) -> usize {
+        self.array.len()
+    }
 }
 
 impl<T> Default for ConsistentIndexArray<T> {
@@ -381,3 +508,53 @@ impl<T> Default for ConsistentIndexArray<T> {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bitset_bytes_round_trip() {
+        let mut set = BitSet::new(70);
+        set.set(0);
+        set.set_range(30, 5);
+        set.set(69);
+
+        let copy = BitSet::from_bytes(set.as_bytes(), 70);
+
+        assert_eq!(copy.size, 70);
+        assert_eq!(copy.bits_used, 7);
+        assert!((0..70).all(|i| copy.test(i) == set.test(i)));
+    }
+
+    #[test_case]
+    fn bitset_from_bytes_ignores_extra_bits_and_fills_missing_ones() {
+        // bits past the end of the set shouldn't be counted
+        let set = BitSet::from_bytes(&[0xff, 0xff], 12);
+        assert_eq!(set.bits_used, 12);
+        assert!(!set.test(12));
+
+        // and a short buffer leaves the rest of the set clear
+        let set = BitSet::from_bytes(&[0x81], 64);
+        assert_eq!(set.bits_used, 2);
+        assert!(set.test(0) && set.test(7));
+        assert_eq!(set.first_unset_from(8), Some(8));
+    }
+
+    #[test_case]
+    fn bitset_place_at_checks_storage_size() {
+        let mut storage = [0xffffffff_u32; 3];
+
+        assert!(BitSet::place_at(storage.as_mut_ptr(), 2, 65).is_none());
+        assert_eq!(storage, [0xffffffff; 3], "storage was touched even though it's too small");
+
+        // the last word only needs room for a single bit
+        let set = BitSet::place_at(storage.as_mut_ptr(), 3, 65).unwrap();
+        assert_eq!(set.array.size, 3);
+        assert_eq!(set.bits_used, 0);
+        assert_eq!(set.first_unset(), Some(0));
+        drop(set);
+
+        assert_eq!(storage, [0; 3]);
+    }
+}
@@ -0,0 +1,258 @@
+//! simple newc cpio parser
+
+use core::str;
+
+/// size of a newc header, not including the name that follows it
+const HEADER_SIZE: usize = 110;
+
+/// magic number at the start of every newc header
+const MAGIC: &[u8] = b"070701";
+
+/// magic number at the start of every newc header with checksums, which is laid out the same way
+const MAGIC_CRC: &[u8] = b"070702";
+
+/// name of the entry that marks the end of an archive
+const TRAILER: &str = "TRAILER!!!";
+
+/// mask for the file type bits in a mode
+const MODE_TYPE_MASK: usize = 0o170000;
+
+/// file type bits for a regular file
+const MODE_REGULAR: usize = 0o100000;
+
+/// file type bits for a directory
+const MODE_DIRECTORY: usize = 0o040000;
+
+/// file type bits for a symbolic link
+const MODE_SYMLINK: usize = 0o120000;
+
+/// header of a file in a cpio archive
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Header {
+    pub inode: usize,
+    pub mode: usize,
+    pub uid: usize,
+    pub gid: usize,
+    pub num_links: usize,
+    pub mod_time: usize,
+    pub file_size: usize,
+    pub device_major: usize,
+    pub device_minor: usize,
+    pub rdevice_major: usize,
+    pub rdevice_minor: usize,
+    pub name_size: usize,
+    pub checksum: usize,
+}
+
+impl Header {
+    /// parses a header from the given bytes, returning None if it's malformed
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || (&data[..6] != MAGIC && &data[..6] != MAGIC_CRC) {
+            return None;
+        }
+
+        let field = |index: usize| {
+            let start = 6 + index * 8;
+            usize::from_str_radix(str::from_utf8(&data[start..start + 8]).ok()?, 16).ok()
+        };
+
+        Some(Self {
+            inode: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            num_links: field(4)?,
+            mod_time: field(5)?,
+            file_size: field(6)?,
+            device_major: field(7)?,
+            device_minor: field(8)?,
+            rdevice_major: field(9)?,
+            rdevice_minor: field(10)?,
+            name_size: field(11)?,
+            checksum: field(12)?,
+        })
+    }
+
+    /// checks whether this header describes a regular file
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_REGULAR
+    }
+
+    /// checks whether this header describes a directory
+    pub fn is_directory(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    /// checks whether this header describes a symbolic link
+    pub fn is_symlink(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_SYMLINK
+    }
+
+    /// gets the permission bits of this header's mode
+    pub fn permissions(&self) -> usize {
+        self.mode & !MODE_TYPE_MASK
+    }
+}
+
+/// errors that can occur while iterating over a cpio archive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CpioError {
+    /// the header at the given offset has a bad magic number or isn't a valid header
+    BadHeader { offset: usize },
+
+    /// the name or contents of the entry at the given offset go past the end of the archive
+    Truncated { offset: usize },
+
+    /// the name of the entry at the given offset isn't valid UTF-8
+    BadName { offset: usize },
+}
+
+/// entry in a cpio archive, as returned by CpioIterator
+#[derive(Debug)]
+pub struct CpioEntry<'a> {
+    pub header: Header,
+    pub name: &'a str,
+    pub contents: &'a [u8],
+}
+
+/// rounds the given offset up to the next multiple of 4, since headers and contents are padded to 4 byte boundaries.
+/// returns None if that would overflow
+fn align_4(offset: usize) -> Option<usize> {
+    Some(offset.checked_add(3)? & !3)
+}
+
+/// struct to enable iterating over a cpio archive
+#[derive(Debug)]
+pub struct CpioIterator<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> CpioIterator<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    pub fn recreate(&self) -> Self {
+        Self::new(self.data)
+    }
+
+    /// stops iteration and returns the given error
+    fn fail(&mut self, err: CpioError) -> Option<Result<CpioEntry<'a>, CpioError>> {
+        self.offset = self.data.len();
+        Some(Err(err))
+    }
+}
+
+impl<'a> Iterator for CpioIterator<'a> {
+    type Item = Result<CpioEntry<'a>, CpioError>;
+
+    /// gets the next entry in the archive, stopping at the trailer entry
+    ///
+    /// if an entry is bad, an error is returned and iteration stops, since the header can't be trusted to tell us where the next one is
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+
+        if offset >= self.data.len() {
+            return None;
+        }
+
+        let header = match Header::parse(&self.data[offset..]) {
+            Some(header) => header,
+            None => return self.fail(CpioError::BadHeader { offset }),
+        };
+
+        // the name includes its null terminator. Header::parse made sure the header fits, so only the size fields from the header can overflow
+        let name_start = offset + HEADER_SIZE;
+        let name_end = match name_start.checked_add(header.name_size) {
+            Some(end) if header.name_size > 0 && end <= self.data.len() => end,
+            _ => return self.fail(CpioError::Truncated { offset }),
+        };
+
+        let name = match str::from_utf8(&self.data[name_start..name_end - 1]) {
+            Ok(name) => name,
+            Err(_) => return self.fail(CpioError::BadName { offset }),
+        };
+
+        if name == TRAILER {
+            self.offset = self.data.len();
+            return None;
+        }
+
+        let contents_start = match align_4(name_end) {
+            Some(start) => start,
+            None => return self.fail(CpioError::Truncated { offset }),
+        };
+        let contents_end = match contents_start.checked_add(header.file_size) {
+            Some(end) if end <= self.data.len() => end,
+            _ => return self.fail(CpioError::Truncated { offset }),
+        };
+
+        // an entry that ends right at the end of the buffer can't have another one after it, so it doesn't matter where we say the next one is
+        self.offset = align_4(contents_end).unwrap_or(self.data.len());
+
+        Some(Ok(CpioEntry {
+            header,
+            name,
+            contents: &self.data[contents_start..contents_end],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{format, vec::Vec};
+
+    /// builds a newc archive entry with the given name, contents, and file size field
+    fn entry(name: &str, contents: &[u8], file_size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+
+        data.extend_from_slice(MAGIC);
+        for field in [1, MODE_REGULAR | 0o644, 0, 0, 1, 0, file_size, 0, 0, 0, 0, name.len() + 1, 0] {
+            data.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+
+        data.extend_from_slice(name.as_bytes());
+        data.push(0);
+        data.resize(align_4(data.len()).unwrap(), 0);
+
+        data.extend_from_slice(contents);
+        data.resize(align_4(data.len()).unwrap(), 0);
+
+        data
+    }
+
+    #[test_case]
+    fn parses_entries() {
+        let mut data = entry("hello", b"world", 5);
+        data.extend(entry(TRAILER, &[], 0));
+
+        let entries = CpioIterator::new(&data).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "hello");
+        assert_eq!(entries[0].contents, b"world");
+    }
+
+    #[test_case]
+    fn huge_file_size_is_truncated() {
+        // big enough to wrap around when added to the offset of the contents
+        let data = entry("hello", b"world", u32::MAX as usize);
+
+        let mut iter = CpioIterator::new(&data);
+        assert!(matches!(iter.next(), Some(Err(CpioError::Truncated { offset: 0 }))));
+        assert!(iter.next().is_none());
+    }
+
+    #[test_case]
+    fn huge_name_size_is_truncated() {
+        let mut data = entry("hello", b"world", 5);
+
+        // the name size is the 12th field in the header
+        data[6 + 11 * 8..6 + 12 * 8].copy_from_slice(b"ffffffff");
+
+        let mut iter = CpioIterator::new(&data);
+        assert!(matches!(iter.next(), Some(Err(CpioError::Truncated { offset: 0 }))));
+    }
+}
@@ -0,0 +1,12 @@
+//! simple non-cryptographic hashing
+
+/// FNV-1a offset basis for 64 bit hashes
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a prime for 64 bit hashes
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// hashes the given bytes with 64 bit FNV-1a
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
@@ -1,4 +1,6 @@
 pub mod abi;
 pub mod array;
+pub mod cpio;
 pub mod debug;
+pub mod hash;
 pub mod tar;
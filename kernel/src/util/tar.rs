@@ -5,6 +5,7 @@ use generic_array::{
     typenum::{U12, U8},
     ArrayLength, GenericArray,
 };
+use num_enum::TryFromPrimitive;
 
 pub type UserID = usize;
 pub type GroupID = usize;
@@ -12,6 +13,12 @@ pub type Permissions = usize;
 
 const BLOCK_SIZE: usize = 512;
 
+/// maximum size of an extended attribute's value, the same as Linux's limit. bigger values in PAX extended headers are ignored
+pub const MAX_XATTR_SIZE: usize = 0x10000;
+
+/// prefix of PAX keys that hold extended attributes, as written by GNU tar and star
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
 /// header of a file in a tar archive. contains many kinds of information about the file
 #[repr(C)]
 pub struct Header {
@@ -22,7 +29,9 @@ pub struct Header {
     file_size: TarNumber<U12>,
     mod_time: TarNumber<U12>,
     checksum: TarNumber<U8>,
-    kind: EntryKind,
+
+    /// raw type flag, which isn't stored as an EntryKind since archives can contain anything here
+    kind: u8,
     link_name: [u8; 100],
     ustar_indicator: [u8; 6],
     ustar_version: [u8; 2],
@@ -33,10 +42,16 @@ pub struct Header {
     filename_prefix: [u8; 155],
 }
 
+/// gets a string from a field that's either null terminated or takes up the whole field. if it isn't valid UTF-8, only the part before the first invalid byte is returned
 fn from_c_str(c: &[u8]) -> &str {
-    match CStr::from_bytes_until_nul(c) {
-        Ok(string) => string.to_str().unwrap(),
-        Err(_) => core::str::from_utf8(c).unwrap(),
+    let bytes = match CStr::from_bytes_until_nul(c) {
+        Ok(string) => string.to_bytes(),
+        Err(_) => c,
+    };
+
+    match str::from_utf8(bytes) {
+        Ok(string) => string,
+        Err(err) => str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default(),
     }
 }
 
@@ -61,6 +76,11 @@ impl Header {
         usize::from(&self.file_size)
     }
 
+    /// gets the size of this entry's contents, or None if the size field isn't a valid octal number
+    pub fn try_file_size(&self) -> Option<usize> {
+        self.file_size.parse()
+    }
+
     pub fn mod_time(&self) -> usize {
         usize::from(&self.mod_time)
     }
@@ -69,8 +89,9 @@ impl Header {
         usize::from(&self.checksum)
     }
 
+    /// gets what kind of entry this is. old archives use a null byte for normal files, and unknown kinds are treated as normal files as well
     pub fn kind(&self) -> EntryKind {
-        self.kind
+        EntryKind::try_from_primitive(self.kind).unwrap_or(EntryKind::NormalFile)
     }
 
     pub fn link_name(&self) -> &str {
@@ -136,23 +157,34 @@ struct TarNumber<N: ArrayLength<u8>> {
 
 impl<N: ArrayLength<u8>> TarNumber<N> {
     fn to_str(&self) -> &str {
+        // some implementations pad numbers with leading spaces
+        let start = self.data.iter().position(|c| *c != b' ').unwrap_or(self.data.len());
+
         // get length of string. numeric values are supposed to end in either a null byte or a space and we don't want rust tripping over those values
-        let length = self.data.iter().position(|c| *c == 0 || *c == 32).unwrap_or(self.data.len());
+        let length = self.data[start..].iter().position(|c| *c == 0 || *c == b' ').unwrap_or(self.data.len() - start);
 
-        // convert the raw bytes into a string
-        core::str::from_utf8(&self.data[0..length]).unwrap()
+        // convert the raw bytes into a string, anything that isn't valid UTF-8 won't parse as a number anyway
+        str::from_utf8(&self.data[start..start + length]).unwrap_or("?")
+    }
+
+    /// parses this number, returning None if it isn't valid octal. an empty field is treated as 0
+    fn parse<T: num_traits::Num>(&self) -> Option<T> {
+        match self.to_str() {
+            "" => Some(T::zero()),
+            string => T::from_str_radix(string, 8).ok(),
+        }
     }
 }
 
 impl<N: ArrayLength<u8>> From<&TarNumber<N>> for usize {
     fn from(num: &TarNumber<N>) -> Self {
-        Self::from_str_radix(num.to_str(), 8).unwrap_or(0) //.expect("couldn't parse numeric value")
+        num.parse().unwrap_or(0)
     }
 }
 
 impl<N: ArrayLength<u8>> From<&TarNumber<N>> for u32 {
     fn from(num: &TarNumber<N>) -> Self {
-        Self::from_str_radix(num.to_str(), 8).unwrap_or(0) //.expect("couldn't parse numeric value")
+        num.parse().unwrap_or(0)
     }
 }
 
@@ -165,7 +197,7 @@ impl<N: ArrayLength<u8>> fmt::Debug for TarNumber<N> {
 
 /// type of file that can be stored in a tar archive
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive)]
 pub enum EntryKind {
     NormalFile = 48,
     HardLink = 49,
@@ -205,11 +237,197 @@ pub enum EntryKind {
     ExtendedHeaderNext = 120,
 }
 
+impl EntryKind {
+    /// GNU pseudo-entry whose contents are the name of the next entry
+    pub const GNU_LONG_NAME: Self = Self::VendorSpecificL;
+
+    /// GNU pseudo-entry whose contents are the link name of the next entry
+    pub const GNU_LONG_LINK_NAME: Self = Self::VendorSpecificK;
+
+    /// whether this kind of entry describes other entries instead of being a file itself
+    pub fn is_metadata(self) -> bool {
+        matches!(self, Self::GNU_LONG_NAME | Self::GNU_LONG_LINK_NAME | Self::GlobalExtendedHeader | Self::ExtendedHeaderNext)
+    }
+}
+
+/// offset of the checksum field in a header
+const CHECKSUM_OFFSET: usize = 148;
+
+/// length of the checksum field in a header
+const CHECKSUM_LENGTH: usize = 8;
+
+/// checks whether the checksum stored in a header block matches the sum of all the bytes in it, with the checksum field itself counted as spaces
+///
+/// some old implementations summed the bytes as signed chars, so either sum is accepted
+fn verify_checksum(block: &[u8], stored: usize) -> bool {
+    let mut unsigned_sum: usize = 0;
+    let mut signed_sum: isize = 0;
+
+    for (i, byte) in block.iter().enumerate() {
+        let byte = if (CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LENGTH).contains(&i) { b' ' } else { *byte };
+
+        unsigned_sum += byte as usize;
+        signed_sum += byte as i8 as isize;
+    }
+
+    stored == unsigned_sum || stored as isize == signed_sum
+}
+
+/// errors that can occur while iterating over a tar file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TarError {
+    /// the header at the given offset has a bad checksum, so the archive is probably truncated or corrupt
+    BadChecksum { offset: usize },
+
+    /// the contents of the entry at the given offset go past the end of the archive
+    Truncated { offset: usize },
+
+    /// the GNU long name entry at the given offset isn't valid UTF-8 or isn't followed by an entry
+    BadLongName { offset: usize },
+
+    /// the PAX extended header at the given offset has a malformed record or isn't followed by an entry
+    BadExtendedHeader { offset: usize },
+}
+
 /// entry in a tar file, as returned by TarIterator
 #[derive(Debug)]
 pub struct TarEntry<'a> {
     pub header: &'a Header,
     pub contents: &'a [u8],
+
+    /// name of this entry from a preceding PAX extended header or GNU long name entry, if there was one
+    pub long_name: Option<&'a str>,
+
+    /// link name of this entry from a preceding PAX extended header or GNU long link name entry, if there was one
+    pub long_link_name: Option<&'a str>,
+
+    /// contents of the PAX extended header this entry's extended attributes come from, if there was one
+    xattr_records: Option<&'a [u8]>,
+}
+
+impl<'a> TarEntry<'a> {
+    /// gets the full name of this entry, which may be longer than what fits in the header
+    pub fn name(&self) -> &'a str {
+        self.long_name.unwrap_or_else(|| self.header.name())
+    }
+
+    /// gets the full link name of this entry, which may be longer than what fits in the header
+    pub fn link_name(&self) -> &'a str {
+        self.long_link_name.unwrap_or_else(|| self.header.link_name())
+    }
+
+    /// gets the extended attributes of this entry from a preceding PAX extended header as name and value pairs.
+    /// attributes with values bigger than MAX_XATTR_SIZE are left out
+    pub fn xattrs(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> {
+        PaxRecords { rest: self.xattr_records.unwrap_or_default() }
+            .map_while(|record| record)
+            .filter_map(|(key, value)| Some((key.strip_prefix(XATTR_PREFIX)?, value)))
+            .filter(|(_, value)| value.len() <= MAX_XATTR_SIZE)
+    }
+}
+
+/// gets the name stored in the contents of a GNU long name entry, which may or may not be null terminated
+fn long_name_from(contents: &[u8]) -> Option<&str> {
+    let length = contents.iter().position(|c| *c == 0).unwrap_or(contents.len());
+
+    str::from_utf8(&contents[..length]).ok()
+}
+
+/// iterator over the `length key=value\n` records in the contents of a PAX extended header.
+/// a malformed record is returned as None, after which iteration stops
+struct PaxRecords<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> PaxRecords<'a> {
+    /// parses the record at the start of what's left, moving past it if it's valid
+    fn parse_record(&mut self) -> Option<(&'a str, &'a [u8])> {
+        let rest = self.rest;
+
+        // the length is in decimal and counts the entire record, including itself and the trailing newline
+        let space = rest.iter().position(|c| *c == b' ')?;
+        let length: usize = str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+
+        if length <= space + 1 || length > rest.len() || rest[length - 1] != b'\n' {
+            return None;
+        }
+
+        let record = &rest[space + 1..length - 1];
+        let equals = record.iter().position(|c| *c == b'=')?;
+
+        // values of extended attributes are raw bytes, so only the key has to be valid UTF-8
+        let key = str::from_utf8(&record[..equals]).ok()?;
+
+        self.rest = &rest[length..];
+
+        Some((key, &record[equals + 1..]))
+    }
+}
+
+impl<'a> Iterator for PaxRecords<'a> {
+    type Item = Option<(&'a str, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the contents may be padded with nulls
+        if self.rest.first().map(|c| *c == 0).unwrap_or(true) {
+            return None;
+        }
+
+        let record = self.parse_record();
+
+        if record.is_none() {
+            self.rest = &[];
+        }
+
+        Some(record)
+    }
+}
+
+/// values from PAX extended headers that override what's in the header of an entry
+#[derive(Debug, Default, Copy, Clone)]
+struct PaxOverrides<'a> {
+    path: Option<&'a str>,
+    link_path: Option<&'a str>,
+
+    /// PAX sizes are decimal strings with no length limit, so they can be bigger than the octal size field can hold
+    size: Option<usize>,
+
+    /// contents of the extended header that set extended attributes, if any did.
+    /// attributes aren't merged, so a local extended header with attributes replaces all the global ones
+    xattr_records: Option<&'a [u8]>,
+}
+
+impl<'a> PaxOverrides<'a> {
+    /// combines these overrides with another set, with ours taking precedence
+    fn or(self, other: Self) -> Self {
+        Self {
+            path: self.path.or(other.path),
+            link_path: self.link_path.or(other.link_path),
+            size: self.size.or(other.size),
+            xattr_records: self.xattr_records.or(other.xattr_records),
+        }
+    }
+
+    /// parses the `length key=value\n` records in the contents of a PAX extended header, applying the ones we understand on top of these overrides
+    fn parse(mut self, contents: &'a [u8]) -> Option<Self> {
+        for record in (PaxRecords { rest: contents }) {
+            let (key, value) = record?;
+
+            match key {
+                "path" => self.path = Some(str::from_utf8(value).ok()?),
+                "linkpath" => self.link_path = Some(str::from_utf8(value).ok()?),
+                "size" => self.size = Some(str::from_utf8(value).ok()?.parse().ok()?),
+                _ if key.starts_with(XATTR_PREFIX) => self.xattr_records = Some(contents),
+                _ => (),
+            }
+        }
+
+        Some(self)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.path.is_none() && self.link_path.is_none() && self.size.is_none() && self.xattr_records.is_none()
+    }
 }
 
 /// struct to enable iterating over a tar file
@@ -217,11 +435,14 @@ pub struct TarEntry<'a> {
 pub struct TarIterator<'a> {
     data: &'a [u8],
     offset: usize,
+
+    /// overrides from PAX global extended headers, which apply to every entry after them
+    global: PaxOverrides<'a>,
 }
 
 impl<'a> TarIterator<'a> {
     pub fn new(data: &'a [u8]) -> Self {
-        Self { data, offset: 0 }
+        Self { data, offset: 0, global: PaxOverrides::default() }
     }
 
     pub fn recreate(&self) -> Self {
@@ -229,12 +450,14 @@ impl<'a> TarIterator<'a> {
     }
 }
 
-impl<'a> Iterator for TarIterator<'a> {
-    type Item = TarEntry<'a>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a> TarIterator<'a> {
+    /// gets the next raw entry in the tar file, without handling GNU long name entries or PAX extended headers.
+    /// if a size is given, it's used instead of the one in the header unless this entry is metadata
+    ///
+    /// a header with a size that isn't valid octal is treated as the end of the archive, since there's no way to tell where the next header is
+    fn next_raw(&mut self, size: Option<usize>) -> Option<Result<TarEntry<'a>, TarError>> {
         // spit out first header
-        if self.offset >= self.data.len() || self.offset + size_of::<Header>() > self.data.len() {
+        if self.offset >= self.data.len() || self.data.len() - self.offset < BLOCK_SIZE {
             // make sure we don't overflow the buffer
             None
         } else {
@@ -242,25 +465,302 @@ impl<'a> Iterator for TarIterator<'a> {
 
             // trace!("got header {:?}", header);
 
-            if header.name().is_empty() {
-                None
-            } else {
-                let file_size = header.file_size();
+            // archives end with zeroed blocks
+            if header.name[0] == 0 {
+                return None;
+            }
+
+            let offset = self.offset;
 
-                let contents_offset = if file_size == 0 {
-                    self.offset + size_of::<Header>() // dont bother aligning to nearest block if there's no contents, as it just screws things up
-                } else {
-                    ((self.offset + size_of::<Header>()) & !(BLOCK_SIZE - 1)) + BLOCK_SIZE
-                };
-                let contents_end = contents_offset + file_size;
+            if !verify_checksum(&self.data[offset..offset + BLOCK_SIZE], header.checksum()) {
+                self.offset = self.data.len();
+                return Some(Err(TarError::BadChecksum { offset }));
+            }
 
-                self.offset = (contents_end & !(BLOCK_SIZE - 1)) + BLOCK_SIZE;
+            let file_size = match size {
+                Some(size) if !header.kind().is_metadata() => size,
+                _ => match header.try_file_size() {
+                    Some(size) => size,
+                    None => {
+                        self.offset = self.data.len();
+                        return None;
+                    }
+                },
+            };
+
+            let contents_offset = if file_size == 0 {
+                self.offset + size_of::<Header>() // dont bother aligning to nearest block if there's no contents, as it just screws things up
+            } else {
+                ((self.offset + size_of::<Header>()) & !(BLOCK_SIZE - 1)) + BLOCK_SIZE
+            };
+            let contents_end = match contents_offset.checked_add(file_size) {
+                Some(end) if end <= self.data.len() => end,
+                _ => {
+                    self.offset = self.data.len();
+                    return Some(Err(TarError::Truncated { offset }));
+                }
+            };
+
+            // contents_end is within the buffer, so rounding it up can't overflow
+            self.offset = contents_end.next_multiple_of(BLOCK_SIZE);
+
+            Some(Ok(TarEntry {
+                header,
+                contents: &self.data[contents_offset..contents_end],
+                long_name: None,
+                long_link_name: None,
+                xattr_records: None,
+            }))
+        }
+    }
+}
+
+impl<'a> Iterator for TarIterator<'a> {
+    type Item = Result<TarEntry<'a>, TarError>;
 
-                Some(TarEntry {
-                    header,
-                    contents: &self.data[contents_offset..contents_end],
-                })
+    /// gets the next entry in the tar file
+    ///
+    /// if an entry is bad, an error is returned and iteration stops, since the header can't be trusted to tell us where the next one is
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut long_name = None;
+        let mut long_link_name = None;
+        let mut local = PaxOverrides::default();
+
+        loop {
+            let offset = self.offset;
+            let overrides = local.or(self.global);
+
+            let mut entry = match self.next_raw(overrides.size) {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(err)),
+                // a long name entry or extended header has to be followed by the entry it describes
+                None if long_name.is_some() || long_link_name.is_some() => return Some(Err(TarError::BadLongName { offset })),
+                None if !local.is_empty() => return Some(Err(TarError::BadExtendedHeader { offset })),
+                None => return None,
+            };
+
+            let slot = match entry.header.kind() {
+                EntryKind::GNU_LONG_NAME => &mut long_name,
+                EntryKind::GNU_LONG_LINK_NAME => &mut long_link_name,
+                kind @ (EntryKind::ExtendedHeaderNext | EntryKind::GlobalExtendedHeader) => {
+                    let target = if kind == EntryKind::ExtendedHeaderNext { &mut local } else { &mut self.global };
+
+                    match target.parse(entry.contents) {
+                        Some(parsed) => *target = parsed,
+                        None => {
+                            self.offset = self.data.len();
+                            return Some(Err(TarError::BadExtendedHeader { offset }));
+                        }
+                    }
+
+                    continue;
+                }
+                _ => {
+                    // PAX values take precedence over GNU ones if an archive somehow has both
+                    entry.long_name = overrides.path.or(long_name);
+                    entry.long_link_name = overrides.link_path.or(long_link_name);
+                    entry.xattr_records = overrides.xattr_records;
+
+                    return Some(Ok(entry));
+                }
+            };
+
+            match long_name_from(entry.contents) {
+                Some(name) => *slot = Some(name),
+                None => {
+                    self.offset = self.data.len();
+                    return Some(Err(TarError::BadLongName { offset }));
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{format, string::ToString, vec, vec::Vec};
+
+    /// builds a header block for an entry of the given kind, name, and size, with a valid checksum
+    fn header(name: &str, kind: EntryKind, size: usize) -> Vec<u8> {
+        let mut block = vec![0; BLOCK_SIZE];
+
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        block[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        block[156] = kind as u8;
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+
+        block[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_LENGTH].fill(b' ');
+        let checksum: usize = block.iter().map(|c| *c as usize).sum();
+        block[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 7].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+
+        block
+    }
+
+    /// builds an entry with the given contents, padded out to a whole number of blocks
+    fn entry(name: &str, kind: EntryKind, contents: &[u8]) -> Vec<u8> {
+        let mut data = header(name, kind, contents.len());
+
+        data.extend_from_slice(contents);
+        data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+
+        data
+    }
+
+    /// builds a PAX record for the given key and value
+    fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+        // the length counts its own digits, so adding them can make it need another digit
+        let without_length = key.len() + value.len() + 3;
+        let mut length = without_length + 1;
+
+        while length != without_length + length.to_string().len() {
+            length = without_length + length.to_string().len();
+        }
+
+        let mut record = format!("{length} {key}=").into_bytes();
+        record.extend_from_slice(value);
+        record.push(b'\n');
+
+        record
+    }
+
+    /// adds the zeroed blocks that mark the end of an archive
+    fn finish(mut data: Vec<u8>) -> Vec<u8> {
+        data.resize(data.len() + BLOCK_SIZE * 2, 0);
+        data
+    }
+
+    #[test_case]
+    fn pax_xattrs_are_exposed() {
+        let mut records = pax_record("SCHILY.xattr.user.mime_type", b"text/plain");
+        records.extend(pax_record("SCHILY.xattr.security.capability", &[0xff, 0, 1]));
+        records.extend(pax_record("mtime", b"1234.5"));
+
+        let mut data = entry("PaxHeaders/hello", EntryKind::ExtendedHeaderNext, &records);
+        data.extend(entry("hello", EntryKind::NormalFile, b"world"));
+        data.extend(entry("plain", EntryKind::NormalFile, b"file"));
+        let data = finish(data);
+
+        let entries = TarIterator::new(&data).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name(), "hello");
+        assert_eq!(entries[0].contents, b"world");
+        assert_eq!(entries[0].xattrs().collect::<Vec<_>>(), [("user.mime_type", &b"text/plain"[..]), ("security.capability", &[0xff, 0, 1][..])]);
+
+        // a local extended header only applies to the entry right after it
+        assert_eq!(entries[1].xattrs().count(), 0);
+    }
+
+    #[test_case]
+    fn global_xattrs_apply_to_every_later_entry() {
+        let mut data = entry("pax_global_header", EntryKind::GlobalExtendedHeader, &pax_record("SCHILY.xattr.user.origin", b"initrd"));
+        data.extend(entry("first", EntryKind::NormalFile, b"1"));
+        data.extend(entry("PaxHeaders/second", EntryKind::ExtendedHeaderNext, &pax_record("SCHILY.xattr.user.own", b"yes")));
+        data.extend(entry("second", EntryKind::NormalFile, b"2"));
+        data.extend(entry("third", EntryKind::NormalFile, b"3"));
+        let data = finish(data);
+
+        let entries = TarIterator::new(&data).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(entries[0].xattrs().collect::<Vec<_>>(), [("user.origin", &b"initrd"[..])]);
+        assert_eq!(entries[1].xattrs().collect::<Vec<_>>(), [("user.own", &b"yes"[..])]);
+        assert_eq!(entries[2].xattrs().collect::<Vec<_>>(), [("user.origin", &b"initrd"[..])]);
+    }
+
+    #[test_case]
+    fn oversized_xattrs_are_left_out() {
+        let mut records = pax_record("SCHILY.xattr.user.huge", &vec![b'a'; MAX_XATTR_SIZE + 1]);
+        records.extend(pax_record("SCHILY.xattr.user.max", &vec![b'b'; MAX_XATTR_SIZE]));
+
+        let mut data = entry("PaxHeaders/hello", EntryKind::ExtendedHeaderNext, &records);
+        data.extend(entry("hello", EntryKind::NormalFile, b"world"));
+        let data = finish(data);
+
+        let entry = TarIterator::new(&data).next().unwrap().unwrap();
+        let xattrs = entry.xattrs().collect::<Vec<_>>();
+
+        assert_eq!(xattrs.len(), 1);
+        assert_eq!(xattrs[0].0, "user.max");
+        assert_eq!(xattrs[0].1.len(), MAX_XATTR_SIZE);
+    }
+
+    #[test_case]
+    fn block_aligned_entries_are_followed_correctly() {
+        let mut data = entry("dir/", EntryKind::Directory, &[]);
+        data.extend(entry("dir/aligned", EntryKind::NormalFile, &[b'a'; BLOCK_SIZE]));
+        data.extend(entry("dir/after", EntryKind::NormalFile, b"after"));
+        let data = finish(data);
+
+        let entries = TarIterator::new(&data).collect::<Result<Vec<_>, _>>().unwrap();
+        let names = entries.iter().map(|entry| entry.name()).collect::<Vec<_>>();
+
+        assert_eq!(names, ["dir/", "dir/aligned", "dir/after"]);
+        assert_eq!(entries[1].contents, &[b'a'; BLOCK_SIZE][..]);
+        assert_eq!(entries[2].contents, b"after");
+    }
+
+    #[test_case]
+    fn pax_headers_override_the_name_and_size() {
+        let long_name = "a/".repeat(80) + "file";
+
+        // the size in the header is wrong on purpose, the PAX one has to be used
+        let mut records = pax_record("path", long_name.as_bytes());
+        records.extend(pax_record("size", b"5"));
+
+        let mut data = entry("PaxHeaders/file", EntryKind::ExtendedHeaderNext, &records);
+        data.extend(header("a/a/a/file", EntryKind::NormalFile, 0));
+        data.extend(b"hello");
+        data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+        data.extend(entry("next", EntryKind::NormalFile, b"next"));
+        let data = finish(data);
+
+        let entries = TarIterator::new(&data).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name(), long_name);
+        assert_eq!(entries[0].contents, b"hello");
+        assert_eq!(entries[1].name(), "next");
+    }
+
+    #[test_case]
+    fn gnu_long_names_are_used() {
+        let long_name = "b/".repeat(60) + "file";
+        let long_link_name = "c/".repeat(60) + "target";
+
+        let mut data = entry("././@LongLink", EntryKind::GNU_LONG_NAME, format!("{long_name}\0").as_bytes());
+        data.extend(entry("././@LongLink", EntryKind::GNU_LONG_LINK_NAME, long_link_name.as_bytes()));
+        data.extend(entry("b/b/b/file", EntryKind::SymLink, &[]));
+        data.extend(entry("short", EntryKind::NormalFile, b"short"));
+        let data = finish(data);
+
+        let entries = TarIterator::new(&data).collect::<Result<Vec<_>, _>>().unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name(), long_name);
+        assert_eq!(entries[0].link_name(), long_link_name);
+        assert_eq!(entries[1].name(), "short");
+        assert_eq!(entries[1].link_name(), "");
+    }
+
+    #[test_case]
+    fn truncated_archives_stop_iteration() {
+        let mut data = entry("first", EntryKind::NormalFile, b"first");
+        data.extend(entry("second", EntryKind::NormalFile, &[b's'; 100]));
+
+        // cut off the second entry's contents
+        data.truncate(BLOCK_SIZE * 3 + 50);
+
+        let mut iter = TarIterator::new(&data);
+        assert_eq!(iter.next().unwrap().unwrap().name(), "first");
+        assert!(matches!(iter.next(), Some(Err(TarError::Truncated { offset })) if offset == BLOCK_SIZE * 2));
+        assert!(iter.next().is_none());
+
+        // a header that's cut off isn't read at all
+        let mut iter = TarIterator::new(&data[..BLOCK_SIZE * 2 + 100]);
+        assert_eq!(iter.next().unwrap().unwrap().name(), "first");
+        assert!(iter.next().is_none());
+    }
+}